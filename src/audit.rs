@@ -0,0 +1,276 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the audit log inside the state directory.
+const AUDIT_FILE: &str = "audit.jsonl";
+
+/// A file a run modified.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditFile {
+    pub path: PathBuf,
+    pub matches: usize,
+    /// Where the run kept the file's original, with `--backup`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<PathBuf>,
+}
+
+/// The audit trail entry of one run that modified files: what it changed,
+/// with what rules, by whom and when.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub run_id: String,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub user: String,
+    pub directory: PathBuf,
+    /// The command line the run was started with
+    pub command: Vec<String>,
+    pub pattern: Option<String>,
+    pub replacement: Option<String>,
+    /// Name and version of the rule pack used, if any
+    pub rule_pack: Option<String>,
+    pub files: Vec<AuditFile>,
+}
+
+/// Where the audit log is kept: `$RR_STATE_DIR`, else the user's state
+/// directory.
+pub fn log_path() -> Result<PathBuf> {
+    let dir = match env::var_os("RR_STATE_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => match env::var_os("XDG_STATE_HOME") {
+            Some(state) => PathBuf::from(state).join("regex-replace"),
+            None => PathBuf::from(env::var_os("HOME").context("Cannot find a state directory: HOME is not set")?)
+                .join(".local/state/regex-replace"),
+        },
+    };
+    Ok(dir.join(AUDIT_FILE))
+}
+
+/// The name of the user running the tool, for the audit trail.
+pub fn current_user() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// `secs` since the Unix epoch as an ISO 8601 UTC timestamp.
+pub fn format_utc(secs: u64) -> String {
+    let [year, month, day, hour, minute, second] = civil(secs);
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z")
+}
+
+/// The UTC date and time `secs` after the epoch, formatted as `format` says
+/// with the strftime specifiers `%Y`, `%y`, `%m`, `%d`, `%H`, `%M`, `%S`,
+/// `%j` (day of the year), `%s` (the seconds themselves), `%F` (`%Y-%m-%d`),
+/// `%T` (`%H:%M:%S`) and `%%`.
+pub fn format_date(secs: u64, format: &str) -> Result<String> {
+    let [year, month, day, hour, minute, second] = civil(secs);
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out.push_str(&format!("{year:04}")),
+            Some('y') => out.push_str(&format!("{:02}", year % 100)),
+            Some('m') => out.push_str(&format!("{month:02}")),
+            Some('d') => out.push_str(&format!("{day:02}")),
+            Some('H') => out.push_str(&format!("{hour:02}")),
+            Some('M') => out.push_str(&format!("{minute:02}")),
+            Some('S') => out.push_str(&format!("{second:02}")),
+            Some('j') => out.push_str(&format!("{:03}", (secs - start_of_year(year)) / 86_400 + 1)),
+            Some('s') => out.push_str(&secs.to_string()),
+            Some('F') => out.push_str(&format!("{year:04}-{month:02}-{day:02}")),
+            Some('T') => out.push_str(&format!("{hour:02}:{minute:02}:{second:02}")),
+            Some('%') => out.push('%'),
+            Some(other) => anyhow::bail!("Unknown date format specifier %{other} in '{format}'"),
+            None => anyhow::bail!("Date format '{format}' ends with a lone %"),
+        }
+    }
+    Ok(out)
+}
+
+/// Seconds from the epoch to the start of `year`.
+fn start_of_year(year: u64) -> u64 {
+    let before = year - 1;
+    let days = 365 * (year - 1970) + (before / 4 - before / 100 + before / 400) - (1969 / 4 - 1969 / 100 + 1969 / 400);
+    days * 86_400
+}
+
+/// Year, month, day, hour, minute and second, in UTC, `secs` after the epoch.
+fn civil(secs: u64) -> [u64; 6] {
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    [year as u64, month as u64, day as u64, rem / 3600, rem % 3600 / 60, rem % 60]
+}
+
+impl AuditRecord {
+    /// Appends the record to the audit log at `path`, one JSON object per line.
+    pub fn append_to(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create audit directory: {}", dir.display()))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log: {}", path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write audit log: {}", path.display()))
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "Run:       {}", self.run_id);
+        let _ = writeln!(out, "When:      {}", format_utc(self.timestamp));
+        let _ = writeln!(out, "User:      {}", self.user);
+        let _ = writeln!(out, "Directory: {}", self.directory.display());
+        let _ = writeln!(out, "Command:   {}", self.command.join(" "));
+        if let Some(ref pack) = self.rule_pack {
+            let _ = writeln!(out, "Rule pack: {pack}");
+        }
+        if let Some(ref pattern) = self.pattern {
+            let _ = writeln!(out, "Pattern:   {pattern}");
+        }
+        if let Some(ref replacement) = self.replacement {
+            let _ = writeln!(out, "Replace:   {replacement}");
+        }
+        let _ = writeln!(out, "Files modified: {}", self.files.len());
+        for file in &self.files {
+            let plural = if file.matches == 1 { "" } else { "es" };
+            let _ = writeln!(out, "  {} ({} match{plural})", file.path.display(), file.matches);
+            if let Some(ref backup) = file.backup {
+                let _ = writeln!(out, "    backup: {}", backup.display());
+            }
+        }
+        out
+    }
+
+    /// One line summing the run up, for listings.
+    pub fn summary(&self) -> String {
+        format!(
+            "{}  {}  {:<10}  {:>4} files  {}",
+            self.run_id,
+            format_utc(self.timestamp),
+            self.user,
+            self.files.len(),
+            self.directory.display()
+        )
+    }
+}
+
+/// Every record in the audit log at `path`, oldest first. A missing log
+/// has none.
+pub fn load(path: &Path) -> Result<Vec<AuditRecord>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read audit log: {}", path.display())),
+    };
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid audit record at {}:{}", path.display(), index + 1))
+        })
+        .collect()
+}
+
+/// The record whose run ID is `id`, or starts with it if that is unambiguous.
+pub fn find<'a>(records: &'a [AuditRecord], id: &str) -> Result<&'a AuditRecord> {
+    if let Some(record) = records.iter().find(|record| record.run_id == id) {
+        return Ok(record);
+    }
+    let mut matching = records.iter().filter(|record| record.run_id.starts_with(id));
+    match (matching.next(), matching.next()) {
+        (Some(record), None) => Ok(record),
+        (Some(_), Some(_)) => anyhow::bail!("Run ID prefix {id} is ambiguous"),
+        (None, _) => anyhow::bail!("No run {id} in the audit log"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn record(run_id: &str) -> AuditRecord {
+        AuditRecord {
+            run_id: run_id.to_string(),
+            timestamp: 1_700_000_000,
+            user: "alice".to_string(),
+            directory: PathBuf::from("/repo"),
+            command: vec!["regex-replace".to_string(), "-p".to_string(), "foo".to_string()],
+            pattern: Some("foo".to_string()),
+            replacement: Some("bar".to_string()),
+            rule_pack: None,
+            files: vec![
+                AuditFile { path: PathBuf::from("/repo/a.rs"), matches: 2, backup: None },
+                AuditFile { path: PathBuf::from("/repo/b.rs"), matches: 1, backup: Some(PathBuf::from("/repo/b.rs.bak")) },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(1_700_000_000), "2023-11-14T22:13:20Z");
+        assert_eq!(format_utc(951_782_400), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn test_format_date() -> Result<()> {
+        assert_eq!(format_date(1_700_000_000, "%Y-%m-%d %H:%M:%S")?, "2023-11-14 22:13:20");
+        assert_eq!(format_date(1_700_000_000, "%F %T, day %j of '%y, %s, 100%%")?, "2023-11-14 22:13:20, day 318 of '23, 1700000000, 100%");
+        assert_eq!(format_date(951_782_400, "%j")?, "060");
+        assert_eq!(format_date(0, "%j")?, "001");
+        assert!(format_date(0, "%Q").is_err());
+        assert!(format_date(0, "50%").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_load_and_find() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("state/audit.jsonl");
+        assert!(load(&path)?.is_empty());
+
+        record("abc-1").append_to(&path)?;
+        record("abd-2").append_to(&path)?;
+        let records = load(&path)?;
+        assert_eq!(records, [record("abc-1"), record("abd-2")]);
+
+        assert_eq!(find(&records, "abd")?.run_id, "abd-2");
+        assert!(find(&records, "ab").unwrap_err().to_string().contains("ambiguous"));
+        assert!(find(&records, "x").is_err());
+        let rendered = find(&records, "abc-1")?.render();
+        assert!(rendered.contains("  /repo/a.rs (2 matches)\n  /repo/b.rs (1 match)\n    backup: /repo/b.rs.bak\n"));
+        // Records written before backups were recorded still load
+        let old = r#"{"run_id":"x","timestamp":0,"user":"u","directory":"/","command":[],"pattern":null,"replacement":null,"rule_pack":null,"files":[{"path":"/a","matches":1}]}"#;
+        assert_eq!(serde_json::from_str::<AuditRecord>(old)?.files[0].backup, None);
+        Ok(())
+    }
+}
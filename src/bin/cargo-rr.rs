@@ -0,0 +1,82 @@
+//! `cargo rr` - runs regex-replace against the current cargo workspace.
+//!
+//! Cargo invokes this as `cargo-rr rr <args>`. Unless overridden, the search
+//! directory defaults to the workspace root and only Rust files are
+//! processed; `target/` is excluded by the default ignore patterns.
+
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::{self, Command};
+
+fn main() {
+    let mut args: Vec<OsString> = env::args_os().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "rr") {
+        args.remove(0);
+    }
+
+    if !has_option(&args, "-d", "--directory") {
+        match workspace_root() {
+            Ok(root) => {
+                args.insert(0, root.into_os_string());
+                args.insert(0, "--directory".into());
+            }
+            Err(e) => {
+                eprintln!("cargo-rr: {e}");
+                process::exit(1);
+            }
+        }
+    }
+
+    if !has_option(&args, "-e", "--extensions") {
+        args.insert(0, "rs".into());
+        args.insert(0, "--extensions".into());
+    }
+
+    let status = Command::new(regex_replace_binary())
+        .args(&args)
+        .status()
+        .unwrap_or_else(|e| {
+            eprintln!("cargo-rr: failed to run regex-replace: {e}");
+            process::exit(1);
+        });
+
+    process::exit(status.code().unwrap_or(1));
+}
+
+/// Whether `args` already contain the option in short or long form.
+fn has_option(args: &[OsString], short: &str, long: &str) -> bool {
+    args.iter().filter_map(|arg| arg.to_str()).any(|arg| {
+        arg == long
+            || arg.starts_with(&format!("{long}="))
+            || (arg.starts_with(short) && !arg.starts_with("--"))
+    })
+}
+
+/// The root directory of the workspace containing the current directory.
+fn workspace_root() -> Result<PathBuf, String> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let output = Command::new(cargo)
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .output()
+        .map_err(|e| format!("failed to run cargo locate-project: {e}"))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    let manifest = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    manifest.parent()
+        .map(PathBuf::from)
+        .ok_or_else(|| format!("unexpected manifest path: {}", manifest.display()))
+}
+
+/// Prefers the regex-replace binary installed alongside this one.
+fn regex_replace_binary() -> PathBuf {
+    let name = format!("regex-replace{}", env::consts::EXE_SUFFIX);
+    env::current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name(&name))
+        .filter(|sibling| sibling.exists())
+        .unwrap_or_else(|| PathBuf::from(name))
+}
@@ -0,0 +1,145 @@
+use regex::Regex;
+use std::ops::{Bound, RangeBounds};
+
+use crate::file_processor::Replacement;
+
+/// A capture group, by number or by name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Group {
+    Index(usize),
+    Name(String),
+}
+
+impl std::fmt::Display for Group {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Group::Index(index) => write!(f, "${index}"),
+            Group::Name(name) => write!(f, "${{{name}}}"),
+        }
+    }
+}
+
+/// A condition on a match: the number a capture group holds must lie in a
+/// range, written `$1 in 1000..2000`. The range is `LOW..HIGH`, excluding
+/// HIGH, or `LOW..=HIGH`, and either end may be left open. A match whose
+/// group didn't take part, or doesn't hold a number, fails the condition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureRange {
+    pub group: Group,
+    pub low: Bound<f64>,
+    pub high: Bound<f64>,
+}
+
+impl std::str::FromStr for CaptureRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected a condition like '$1 in 1000..2000', got '{s}'");
+        let (group, range) = s.split_once(" in ").ok_or_else(expected)?;
+        let group = group.trim().strip_prefix('$').ok_or_else(expected)?;
+        let group = group.strip_prefix('{').and_then(|name| name.strip_suffix('}')).unwrap_or(group);
+        let group = match group.parse() {
+            Ok(index) => Group::Index(index),
+            Err(_) if !group.is_empty() && group.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => Group::Name(group.to_string()),
+            Err(_) => return Err(format!("invalid capture group '${group}' in '{s}'")),
+        };
+
+        let (low, high) = range.trim().split_once("..").ok_or_else(expected)?;
+        let (high, inclusive) = match high.strip_prefix('=') {
+            Some(high) => (high, true),
+            None => (high, false),
+        };
+        let bound = |text: &str, included: bool| -> Result<Bound<f64>, String> {
+            match text.trim() {
+                "" => Ok(Bound::Unbounded),
+                text => {
+                    let value = text.parse().map_err(|_| format!("invalid number '{text}' in '{s}'"))?;
+                    Ok(if included { Bound::Included(value) } else { Bound::Excluded(value) })
+                }
+            }
+        };
+        let low = bound(low, true)?;
+        let high = bound(high, inclusive)?;
+        if inclusive && high == Bound::Unbounded {
+            return Err(format!("an inclusive range needs an upper end, in '{s}'"));
+        }
+        Ok(Self { group, low, high })
+    }
+}
+
+impl CaptureRange {
+    /// Whether `regex` has the group this condition tests.
+    pub fn applies_to(&self, regex: &Regex) -> bool {
+        match self.group {
+            Group::Index(index) => index < regex.captures_len(),
+            Group::Name(ref name) => regex.capture_names().flatten().any(|group| group == name),
+        }
+    }
+
+    /// Whether `value` is a number in the range.
+    pub fn contains(&self, value: &str) -> bool {
+        let Ok(value) = value.trim().parse::<f64>() else {
+            return false;
+        };
+        (self.low, self.high).contains(&value)
+    }
+
+    fn holds(&self, caps: &regex::Captures) -> bool {
+        let group = match self.group {
+            Group::Index(index) => caps.get(index),
+            Group::Name(ref name) => caps.name(name),
+        };
+        group.is_some_and(|group| self.contains(group.as_str()))
+    }
+}
+
+/// The replacements of `content`, found by `regex`, whose captures meet
+/// every one of `conditions`.
+pub fn filter(conditions: &[CaptureRange], content: &str, regex: &Regex, replacements: Vec<Replacement>) -> Vec<Replacement> {
+    replacements
+        .into_iter()
+        .filter(|rep| {
+            // Matched again where it was found, so assertions see the same context
+            let caps = regex.captures_at(content, rep.start).filter(|caps| caps.get(0).unwrap().range() == (rep.start..rep.end));
+            caps.is_some_and(|caps| conditions.iter().all(|condition| condition.holds(&caps)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_processor::find_replacements;
+
+    #[test]
+    fn test_parse() {
+        let range: CaptureRange = "$1 in 1000..2000".parse().unwrap();
+        assert_eq!(range, CaptureRange { group: Group::Index(1), low: Bound::Included(1000.0), high: Bound::Excluded(2000.0) });
+        assert!(range.contains("1999") && !range.contains("2000") && !range.contains("999") && !range.contains("port"));
+
+        let range: CaptureRange = "${port} in ..=65535".parse().unwrap();
+        assert_eq!(range.group, Group::Name("port".to_string()));
+        assert!(range.contains("65535") && range.contains("-1"));
+        assert!("$port in 0.5..".parse::<CaptureRange>().unwrap().contains("0.5"));
+
+        assert!("$1 between 1 and 2".parse::<CaptureRange>().is_err());
+        assert!("$1 in 1..x".parse::<CaptureRange>().unwrap_err().contains("invalid number 'x'"));
+        assert!("$1 in 5..=".parse::<CaptureRange>().is_err());
+        assert!("$a-b in 1..2".parse::<CaptureRange>().is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let regex = Regex::new(r"port = (?<port>\d+)").unwrap();
+        let content = "port = 80\nport = 49200\nport = 65535\n";
+        let conditions = ["$port in 49152..=65535".parse().unwrap()];
+        let kept = filter(&conditions, content, &regex, find_replacements(content, &regex, "port = 0"));
+        let kept: Vec<&str> = kept.iter().map(|rep| &content[rep.start..rep.end]).collect();
+        assert_eq!(kept, ["port = 49200", "port = 65535"]);
+
+        let missing: CaptureRange = "$2 in 0..".parse().unwrap();
+        assert!(!missing.applies_to(&regex));
+        assert_eq!(missing.group.to_string(), "$2");
+        assert!(filter(&[missing], content, &regex, find_replacements(content, &regex, "")).is_empty());
+    }
+}
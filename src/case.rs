@@ -0,0 +1,494 @@
+use clap::ValueEnum;
+
+/// How far case folding goes, in matching and in `--preserve-case` output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum FoldMode {
+    /// One character for one, as the regex crate folds: `ß` matches only
+    /// `ß` and `ẞ`, and characters whose uppercase is longer keep their case
+    #[default]
+    Simple,
+    /// Full Unicode folding, where one character may stand for several:
+    /// `ß` also matches `ss` and uppercases to `SS`, `ﬁ` matches `fi`
+    Full,
+}
+
+/// Case folding settings for rules that match regardless of case or
+/// preserve it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CaseFolding {
+    pub mode: FoldMode,
+    /// Pair dotted `i` with `İ` and dotless `ı` with `I`, as Turkish and
+    /// Azerbaijani do, instead of `i` with `I`
+    pub turkic: bool,
+}
+
+/// Characters that fully fold to more than one, with what they fold to.
+const FULL_FOLDS: &[(char, &str)] = &[
+    ('ß', "ss"),
+    ('ẞ', "ss"),
+    ('ﬀ', "ff"),
+    ('ﬁ', "fi"),
+    ('ﬂ', "fl"),
+    ('ﬃ', "ffi"),
+    ('ﬄ', "ffl"),
+    ('ﬅ', "st"),
+    ('ﬆ', "st"),
+    ('ŉ', "\u{2bc}n"),
+];
+
+/// Whether `pattern` turns on case-insensitive matching with a leading
+/// inline flag group such as `(?i)` or `(?ims)`.
+pub fn ignores_case(pattern: &str) -> bool {
+    pattern.strip_prefix("(?")
+        .and_then(|rest| rest.split([')', ':']).next())
+        .is_some_and(|flags| flags.split('-').next().is_some_and(|on| on.contains('i')))
+}
+
+/// A piece of a pattern, for [`CaseFolding::expand_pattern`].
+enum Token<'a> {
+    /// A character matched as itself
+    Literal(char),
+    /// A repetition such as `*` or `{2,3}`, applying to what precedes it
+    Repeat(&'a str),
+    /// Anything else, copied as it is
+    Other(&'a str),
+}
+
+fn tokenize(pattern: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = pattern;
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '\\' => {
+                let escaped = rest[1..].chars().next();
+                let after = &rest[1 + escaped.map_or(0, char::len_utf8)..];
+                match escaped {
+                    // \p{Greek}, \x{1F600} and the like
+                    Some('p' | 'P' | 'x' | 'u' | 'U') if after.starts_with('{') => {
+                        rest.find('}').map_or(rest.len(), |close| close + 1)
+                    }
+                    // \pL
+                    Some('p' | 'P') => 2 + after.chars().next().map_or(0, char::len_utf8),
+                    Some(c) => 1 + c.len_utf8(),
+                    None => 1,
+                }
+            }
+            '[' => {
+                // A class, which may nest others
+                let mut depth = 0;
+                let mut end = rest.len();
+                let mut chars = rest.char_indices();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '[' => depth += 1,
+                        ']' if i > 1 || depth > 1 => {
+                            depth -= 1;
+                            if depth == 0 {
+                                end = i + 1;
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                end
+            }
+            '(' if rest.starts_with("(?") => rest.find([':', ')', '>']).map_or(rest.len(), |end| end + 1),
+            '{' => {
+                let len = rest.find('}').map_or(rest.len(), |end| end + 1);
+                tokens.push(Token::Repeat(&rest[..len]));
+                rest = &rest[len..];
+                continue;
+            }
+            '*' | '+' | '?' => {
+                // Including a lazy marker
+                let len = if rest[1..].starts_with('?') { 2 } else { 1 };
+                tokens.push(Token::Repeat(&rest[..len]));
+                rest = &rest[len..];
+                continue;
+            }
+            '(' | ')' | '|' | '.' | '^' | '$' => 1,
+            c => {
+                tokens.push(Token::Literal(c));
+                rest = &rest[c.len_utf8()..];
+                continue;
+            }
+        };
+        tokens.push(Token::Other(&rest[..len]));
+        rest = &rest[len..];
+    }
+    tokens
+}
+
+impl CaseFolding {
+    /// Rewrites the literal text of `pattern`, a pattern matched regardless
+    /// of case, so it also matches what the regex crate's simple folding
+    /// misses: with full folding `ß` and `ss` match each other, and in
+    /// Turkic mode `i` matches `İ` but not `I`. Character classes are left
+    /// as written.
+    pub fn expand_pattern(&self, pattern: &str) -> String {
+        if *self == CaseFolding::default() {
+            return pattern.to_string();
+        }
+        let tokens = tokenize(pattern);
+        let mut out = String::with_capacity(pattern.len());
+        let mut run: Vec<char> = Vec::new();
+        for (index, token) in tokens.iter().enumerate() {
+            match token {
+                Token::Literal(c) => {
+                    run.push(*c);
+                    // A repetition applies to the last character alone
+                    if matches!(tokens.get(index + 1), Some(Token::Repeat(_))) {
+                        let last = run.pop().unwrap();
+                        self.expand_run(&run, &mut out);
+                        self.expand_run(&[last], &mut out);
+                        run.clear();
+                    }
+                }
+                Token::Repeat(text) | Token::Other(text) => {
+                    self.expand_run(&run, &mut out);
+                    run.clear();
+                    out.push_str(text);
+                }
+            }
+        }
+        self.expand_run(&run, &mut out);
+        out
+    }
+
+    fn expand_run(&self, run: &[char], out: &mut String) {
+        let mut i = 0;
+        'chars: while i < run.len() {
+            let c = run[i];
+            if self.turkic && matches!(c, 'i' | 'İ') {
+                out.push_str("(?-i:[iİ])");
+                i += 1;
+                continue;
+            }
+            if self.turkic && matches!(c, 'I' | 'ı') {
+                out.push_str("(?-i:[Iı])");
+                i += 1;
+                continue;
+            }
+            if self.mode == FoldMode::Full {
+                // A character standing for several, or those several written out
+                if let Some((_, folded)) = FULL_FOLDS.iter().find(|(special, _)| *special == c) {
+                    out.push_str(&alternatives(folded, &c.to_string()));
+                    i += 1;
+                    continue;
+                }
+                let mut folds: Vec<&(char, &str)> = FULL_FOLDS.iter().collect();
+                folds.sort_by_key(|(_, folded)| std::cmp::Reverse(folded.chars().count()));
+                for (_, folded) in folds {
+                    let len = folded.chars().count();
+                    let written: String = run[i..].iter().take(len).collect();
+                    if written.chars().count() == len && written.to_lowercase() == *folded {
+                        out.push_str(&alternatives(folded, &written));
+                        i += len;
+                        continue 'chars;
+                    }
+                }
+            }
+            out.push_str(&regex::escape(&c.to_string()));
+            i += 1;
+        }
+    }
+
+    fn upper(&self, c: char, out: &mut String) {
+        match c {
+            'i' if self.turkic => out.push('İ'),
+            'ı' if self.turkic => out.push('I'),
+            c => push_mapped(c, c.to_uppercase(), self.mode, out),
+        }
+    }
+
+    fn lower(&self, c: char, out: &mut String) {
+        match c {
+            'I' if self.turkic => out.push('ı'),
+            'İ' if self.turkic => out.push('i'),
+            c => push_mapped(c, c.to_lowercase(), self.mode, out),
+        }
+    }
+
+    pub fn to_upper(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        text.chars().for_each(|c| self.upper(c, &mut out));
+        out
+    }
+
+    pub fn to_lower(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        text.chars().for_each(|c| self.lower(c, &mut out));
+        out
+    }
+
+    fn capitalize(&self, text: &str) -> String {
+        let mut chars = text.chars();
+        let mut out = String::with_capacity(text.len());
+        if let Some(first) = chars.next() {
+            self.upper(first, &mut out);
+        }
+        out.push_str(chars.as_str());
+        out
+    }
+}
+
+/// Pushes the case mapping of `c`; in simple mode, one that would take
+/// several characters leaves `c` as it is.
+fn push_mapped(c: char, mapped: impl ExactSizeIterator<Item = char>, mode: FoldMode, out: &mut String) {
+    if mode == FoldMode::Simple && mapped.len() > 1 {
+        out.push(c);
+    } else {
+        out.extend(mapped);
+    }
+}
+
+/// A group matching `written`, text from a pattern, or any character that
+/// fully folds to `folded`.
+fn alternatives(folded: &str, written: &str) -> String {
+    let mut group = format!("(?:{}", regex::escape(written));
+    for (special, _) in FULL_FOLDS.iter().filter(|(_, f)| *f == folded) {
+        if special.to_string() != written {
+            group.push('|');
+            group.push(*special);
+        }
+    }
+    if written.chars().count() == 1 {
+        group.push('|');
+        group.push_str(&regex::escape(folded));
+    }
+    group.push(')');
+    group
+}
+
+/// The casing of a piece of text, judged from its letters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Casing {
+    Lower,
+    Upper,
+    /// An uppercase letter followed only by lowercase ones
+    Capitalized,
+    /// Anything else, including text without letters
+    Mixed,
+}
+
+fn casing(text: &str) -> Casing {
+    // Letters such as `ß` with no single uppercase form are written the same
+    // in uppercase words, so they don't count
+    let mut letters = text.chars().filter(|c| c.is_alphabetic() && c.to_uppercase().len() == 1);
+    let Some(first) = letters.next() else {
+        return Casing::Mixed;
+    };
+    let rest: Vec<char> = letters.collect();
+    if first.is_lowercase() && rest.iter().all(|c| c.is_lowercase()) {
+        Casing::Lower
+    } else if first.is_uppercase() && rest.iter().all(|c| c.is_lowercase()) {
+        // A lone capital reads as capitalized rather than as all caps
+        Casing::Capitalized
+    } else if first.is_uppercase() && rest.iter().all(|c| c.is_uppercase()) {
+        Casing::Upper
+    } else {
+        Casing::Mixed
+    }
+}
+
+fn apply(casing: Casing, text: &str, folding: CaseFolding) -> String {
+    match casing {
+        Casing::Lower => folding.to_lower(text),
+        Casing::Upper => folding.to_upper(text),
+        Casing::Capitalized => folding.capitalize(text),
+        Casing::Mixed => text.to_string(),
+    }
+}
+
+/// Splits `text` into words and the `_`/`-`/space separators between them.
+fn words(text: &str) -> Vec<&str> {
+    text.split(['_', '-', ' ']).collect()
+}
+
+/// Gives `replacement` the casing of the text it replaces: `foo` → `bar`
+/// becomes `Foo` → `Bar` and `FOO` → `BAR`. When `matched` mixes casings,
+/// as in `Foo_bar` or `foo-Bar`, and both split into as many words on `_`,
+/// `-` or spaces, each word takes the casing of its counterpart; otherwise
+/// the replacement is left as written. `folding` decides how characters
+/// change case.
+pub fn preserve_case(matched: &str, replacement: &str, folding: CaseFolding) -> String {
+    match casing(matched) {
+        Casing::Mixed => {}
+        casing => return apply(casing, replacement, folding),
+    }
+
+    let matched_words = words(matched);
+    let replacement_words = words(replacement);
+    if matched_words.len() == 1 || matched_words.len() != replacement_words.len() {
+        return replacement.to_string();
+    }
+    let mut result = String::with_capacity(replacement.len());
+    let mut pos = 0;
+    for (matched_word, word) in matched_words.iter().zip(&replacement_words) {
+        result.push_str(&apply(casing(matched_word), word, folding));
+        pos += word.len();
+        // Keep the replacement's own separator
+        if let Some(separator) = replacement[pos..].chars().next() {
+            result.push(separator);
+            pos += separator.len_utf8();
+        }
+    }
+    result
+}
+
+/// A case conversion a replacement can apply to a capture, as in
+/// `${1:snake_case}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseStyle {
+    Upper,
+    Lower,
+    /// `snake_case`
+    Snake,
+    /// `SCREAMING_SNAKE_CASE`
+    ScreamingSnake,
+    /// `kebab-case`
+    Kebab,
+    /// `camelCase`
+    Camel,
+    /// `PascalCase`
+    Pascal,
+}
+
+impl CaseStyle {
+    /// The style called `name`, written either as a keyword (`snake`) or as
+    /// an example of itself (`snake_case`).
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "upper" | "UPPER" => Self::Upper,
+            "lower" => Self::Lower,
+            "snake" | "snake_case" => Self::Snake,
+            "screaming" | "SCREAMING_SNAKE_CASE" => Self::ScreamingSnake,
+            "kebab" | "kebab-case" => Self::Kebab,
+            "camel" | "camelCase" => Self::Camel,
+            "pascal" | "PascalCase" => Self::Pascal,
+            _ => return None,
+        })
+    }
+
+    /// `text` in this style. Identifier styles first split it into words at
+    /// `_`, `-`, spaces and changes of case, so `HTTPServer`, `http_server`
+    /// and `http-server` all become `httpServer` in camel case.
+    pub fn apply(self, text: &str) -> String {
+        let words = identifier_words(text);
+        let joined = |separator: &str, word: fn(&str) -> String| words.iter().map(|w| word(w)).collect::<Vec<_>>().join(separator);
+        match self {
+            Self::Upper => text.to_uppercase(),
+            Self::Lower => text.to_lowercase(),
+            Self::Snake => joined("_", |w| w.to_lowercase()),
+            Self::ScreamingSnake => joined("_", |w| w.to_uppercase()),
+            Self::Kebab => joined("-", |w| w.to_lowercase()),
+            Self::Pascal => joined("", capitalize_word),
+            Self::Camel => {
+                let mut out = words.first().map(|w| w.to_lowercase()).unwrap_or_default();
+                words.iter().skip(1).for_each(|w| out.push_str(&capitalize_word(w)));
+                out
+            }
+        }
+    }
+}
+
+/// `word` with its first letter uppercase and the rest lowercase.
+fn capitalize_word(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect(),
+        None => String::new(),
+    }
+}
+
+/// Splits an identifier into its words: at `_`, `-` and spaces, before an
+/// uppercase letter following a lowercase one or a digit, and before the
+/// last capital of a run followed by a lowercase letter, as in `HTTPServer`.
+fn identifier_words(text: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    for part in text.split(['_', '-', ' ']).filter(|part| !part.is_empty()) {
+        let chars: Vec<(usize, char)> = part.char_indices().collect();
+        let mut start = 0;
+        for i in 1..chars.len() {
+            let (prev, (at, c)) = (chars[i - 1].1, chars[i]);
+            let next_lower = chars.get(i + 1).is_some_and(|(_, next)| next.is_lowercase());
+            let boundary = c.is_uppercase()
+                && ((prev.is_lowercase() || prev.is_ascii_digit()) || (prev.is_uppercase() && next_lower));
+            if boundary {
+                words.push(&part[start..at]);
+                start = at;
+            }
+        }
+        words.push(&part[start..]);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preserve_case() {
+        let cases = [
+            ("foo", "bar", "bar"),
+            ("Foo", "bar", "Bar"),
+            ("FOO", "bar", "BAR"),
+            ("F", "bar", "Bar"),
+            ("foo", "barBaz", "barbaz"),
+            ("Foo", "barBaz", "BarBaz"),
+            ("fOO", "barBaz", "barBaz"),
+            ("Foo_bar", "old_name", "Old_name"),
+            ("foo-BAR", "new-name", "new-NAME"),
+            ("Foo Bar", "new thing", "New Thing"),
+            ("foo_Bar", "single", "single"),
+            ("123", "abc", "abc"),
+            ("Émile", "zoë", "Zoë"),
+            ("STRAßE", "weg", "WEG"),
+        ];
+        for (matched, replacement, expected) in cases {
+            assert_eq!(preserve_case(matched, replacement, CaseFolding::default()), expected, "{matched} -> {replacement}");
+        }
+    }
+
+    #[test]
+    fn test_case_folding_output() {
+        let simple = CaseFolding::default();
+        let full = CaseFolding { mode: FoldMode::Full, turkic: false };
+        let turkic = CaseFolding { mode: FoldMode::Simple, turkic: true };
+        assert_eq!(preserve_case("FOO", "straße", simple), "STRAßE");
+        assert_eq!(preserve_case("FOO", "straße", full), "STRASSE");
+        assert_eq!(preserve_case("FOO", "istanbul", simple), "ISTANBUL");
+        assert_eq!(preserve_case("FOO", "istanbul", turkic), "İSTANBUL");
+        assert_eq!(preserve_case("foo", "DIYARBAKIR", turkic), "dıyarbakır");
+        assert_eq!(preserve_case("Foo", "ijssel", turkic), "İjssel");
+    }
+
+    fn matches(folding: CaseFolding, pattern: &str, text: &str) -> bool {
+        let expanded = folding.expand_pattern(pattern);
+        regex::RegexBuilder::new(&expanded).case_insensitive(true).build().unwrap().is_match(text)
+    }
+
+    #[test]
+    fn test_expand_pattern() {
+        let full = CaseFolding { mode: FoldMode::Full, turkic: false };
+        let turkic = CaseFolding { mode: FoldMode::Simple, turkic: true };
+        assert!(!matches(CaseFolding::default(), "strasse", "STRAßE"));
+        assert!(matches(full, "strasse", "STRAßE"));
+        assert!(matches(full, "^straße$", "Strasse"));
+        assert!(matches(full, "office", "oﬃce"));
+        assert!(matches(full, r"\bgroß\b", "GROSS"));
+        assert!(!matches(full, "s+", "ß"));
+        assert!(matches(CaseFolding::default(), "ISTANBUL", "istanbul"));
+        assert!(!matches(turkic, "ISTANBUL", "istanbul"));
+        assert!(matches(turkic, "istanbul", "İSTANBUL"));
+        assert!(matches(turkic, "DIYARBAKIR", "dıyarbakır"));
+        assert!(matches(turkic, r"(?i)\p{Arabic}|kind", "KİND"));
+        assert!(matches(turkic, "[a-z]i{2}", "xİİ"));
+        assert!(ignores_case("(?i)foo") && ignores_case("(?xi:foo)") && !ignores_case("(?-i)foo") && !ignores_case("foo"));
+    }
+}
@@ -0,0 +1,62 @@
+use clap::ValueEnum;
+use std::io::IsTerminal;
+use std::ops::Range;
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+/// When to colour matches and replacements in the output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// When standard output is a terminal and `NO_COLOR` isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether output should be coloured.
+    pub fn enabled(self) -> bool {
+        match self {
+            Self::Auto => std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()),
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+/// `text` coloured as removed, in red.
+pub fn removed(text: &str) -> String {
+    format!("{RED}{text}{RESET}")
+}
+
+/// `text` coloured as added, in green.
+pub fn added(text: &str) -> String {
+    format!("{GREEN}{text}{RESET}")
+}
+
+/// `line` with the bytes in `span` coloured as removed. A span running past
+/// the end of the line, as a match spanning lines does, stops there.
+pub fn highlight(line: &str, span: Range<usize>) -> String {
+    let end = span.end.min(line.len());
+    let start = span.start.min(end);
+    if start == end {
+        return line.to_string();
+    }
+    format!("{}{}{}", &line[..start], removed(&line[start..end]), &line[end..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight() {
+        assert_eq!(highlight("let colour = 1;", 4..10), "let \x1b[31mcolour\x1b[0m = 1;");
+        assert_eq!(highlight("colour", 3..20), "col\x1b[31mour\x1b[0m");
+        assert_eq!(highlight("colour", 2..2), "colour");
+        assert_eq!(added("x"), "\x1b[32mx\x1b[0m");
+    }
+}
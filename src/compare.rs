@@ -0,0 +1,176 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::file_processor::LineIndex;
+use crate::filter::FileFilter;
+
+/// One match of the pattern.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Occurrence {
+    pub line: usize,
+    pub text: String,
+}
+
+/// How the matches in one file differ between the two trees.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct FileDifference {
+    /// Path relative to each tree's root
+    pub path: PathBuf,
+    pub in_a: bool,
+    pub in_b: bool,
+    /// Matches in A with no counterpart of the same text in B
+    pub only_in_a: Vec<Occurrence>,
+    pub only_in_b: Vec<Occurrence>,
+}
+
+/// Where a pattern's matches differ between two trees, such as two checkouts
+/// of the same project.
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    pub pattern: String,
+    pub files_compared: usize,
+    pub matches_a: usize,
+    pub matches_b: usize,
+    pub differences: Vec<FileDifference>,
+}
+
+/// Every match of `regex` in each file `filter` selects, keyed by path
+/// relative to the filter's directory.
+fn collect(filter: &FileFilter, directory: &Path, regex: &Regex) -> Result<BTreeMap<PathBuf, Vec<Occurrence>>> {
+    let mut files = BTreeMap::new();
+    for entry in filter.walk()?.files {
+        let content = filter.fs().read_to_string(&entry.path)
+            .with_context(|| format!("Failed to read file: {}", entry.path.display()))?;
+        let lines = LineIndex::new(&content);
+        let occurrences = regex
+            .find_iter(&content)
+            .map(|m| Occurrence { line: lines.line_of(m.start()), text: m.as_str().to_string() })
+            .collect();
+        let relative = entry.path.strip_prefix(directory).unwrap_or(&entry.path).to_path_buf();
+        files.insert(relative, occurrences);
+    }
+    Ok(files)
+}
+
+/// The occurrences in `ours` left over once each one is paired with an
+/// occurrence of the same text in `theirs`. Line numbers are ignored when
+/// pairing, so edits that only move matches around are not differences.
+fn unpaired(ours: &[Occurrence], theirs: &[Occurrence]) -> Vec<Occurrence> {
+    let mut available: HashMap<&str, usize> = HashMap::new();
+    for occurrence in theirs {
+        *available.entry(&occurrence.text).or_default() += 1;
+    }
+    ours.iter()
+        .filter(|occurrence| match available.get_mut(occurrence.text.as_str()) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        })
+        .cloned()
+        .collect()
+}
+
+pub fn compare(a: (&FileFilter, &Path), b: (&FileFilter, &Path), regex: &Regex) -> Result<Comparison> {
+    let files_a = collect(a.0, a.1, regex)?;
+    let files_b = collect(b.0, b.1, regex)?;
+
+    let mut paths: Vec<_> = files_a.keys().chain(files_b.keys()).collect();
+    paths.sort();
+    paths.dedup();
+
+    let none = Vec::new();
+    let differences = paths
+        .iter()
+        .filter_map(|path| {
+            let ours = files_a.get(*path);
+            let theirs = files_b.get(*path);
+            let only_in_a = unpaired(ours.unwrap_or(&none), theirs.unwrap_or(&none));
+            let only_in_b = unpaired(theirs.unwrap_or(&none), ours.unwrap_or(&none));
+            (!only_in_a.is_empty() || !only_in_b.is_empty()).then(|| FileDifference {
+                path: path.to_path_buf(),
+                in_a: ours.is_some(),
+                in_b: theirs.is_some(),
+                only_in_a,
+                only_in_b,
+            })
+        })
+        .collect();
+
+    Ok(Comparison {
+        pattern: regex.as_str().to_string(),
+        files_compared: paths.len(),
+        matches_a: files_a.values().map(Vec::len).sum(),
+        matches_b: files_b.values().map(Vec::len).sum(),
+        differences,
+    })
+}
+
+impl Comparison {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for difference in &self.differences {
+            let note = match (difference.in_a, difference.in_b) {
+                (true, false) => " (only in A)",
+                (false, true) => " (only in B)",
+                _ => "",
+            };
+            let _ = writeln!(out, "{}{note}", difference.path.display());
+            for occurrence in &difference.only_in_a {
+                let _ = writeln!(out, "  - A:{}: {}", occurrence.line, occurrence.text);
+            }
+            for occurrence in &difference.only_in_b {
+                let _ = writeln!(out, "  + B:{}: {}", occurrence.line, occurrence.text);
+            }
+        }
+        let _ = writeln!(
+            out,
+            "\nCompared {} files: {} matches in A, {} in B, {} file{}",
+            self.files_compared,
+            self.matches_a,
+            self.matches_b,
+            self.differences.len(),
+            if self.differences.len() == 1 { " differs" } else { "s differ" }
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+    use crate::project::ProjectSettings;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_compare_trees() -> Result<()> {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/a/moved.rs", "old_api();\nx();\nold_api();");
+        fs.add_file("/b/moved.rs", "x();\nold_api();\nold_api();");
+        fs.add_file("/a/missed.rs", "old_api(); new_api();");
+        fs.add_file("/b/missed.rs", "new_api(); new_api();");
+        fs.add_file("/b/added.rs", "old_api();");
+        fs.add_file("/a/clean.rs", "nothing");
+
+        let filter_a = FileFilter::with_fs(fs.clone(), "/a", ProjectSettings::default(), &[], false)?;
+        let filter_b = FileFilter::with_fs(fs.clone(), "/b", ProjectSettings::default(), &[], false)?;
+        let regex = Regex::new(r"\w+_api")?;
+        let comparison = compare((&filter_a, Path::new("/a")), (&filter_b, Path::new("/b")), &regex)?;
+
+        assert_eq!(comparison.files_compared, 4);
+        assert_eq!(comparison.differences.len(), 2);
+        let added = &comparison.differences[0];
+        assert_eq!(added.path, Path::new("added.rs"));
+        assert!(!added.in_a && added.in_b);
+        let missed = &comparison.differences[1];
+        assert_eq!(missed.only_in_a, [Occurrence { line: 1, text: "old_api".to_string() }]);
+        assert_eq!(missed.only_in_b, [Occurrence { line: 1, text: "new_api".to_string() }]);
+        Ok(())
+    }
+}
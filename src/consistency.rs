@@ -0,0 +1,123 @@
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use crate::file_processor::LineIndex;
+
+/// One match of an `--assert-consistent` pattern: the key it is about and
+/// the value it gives it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Occurrence {
+    /// The `key` group, or empty if the pattern has none
+    pub key: String,
+    /// The `value` group, or else group 1, or else the whole match
+    pub value: String,
+    pub line: usize,
+}
+
+/// Every match of `regex` in `content`.
+pub fn occurrences(content: &str, regex: &Regex) -> Vec<Occurrence> {
+    let lines = LineIndex::new(content);
+    regex.captures_iter(content)
+        .map(|caps| {
+            let whole = caps.get(0).unwrap();
+            let value = caps.name("value").or_else(|| caps.get(1)).unwrap_or(whole);
+            Occurrence {
+                key: caps.name("key").map_or("", |m| m.as_str()).to_string(),
+                value: value.as_str().to_string(),
+                line: lines.line_of(whole.start()),
+            }
+        })
+        .collect()
+}
+
+/// The file and line of each occurrence of a value.
+pub type Places = Vec<(PathBuf, usize)>;
+
+/// A key given different values, with where each value occurs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inconsistency {
+    pub key: String,
+    /// Each value, most common first, with the files and lines it is in
+    pub values: Vec<(String, Places)>,
+}
+
+/// The keys among `found` that aren't given the same value everywhere, in
+/// key order.
+pub fn inconsistencies(found: &[(PathBuf, Occurrence)]) -> Vec<Inconsistency> {
+    let mut keys: BTreeMap<&str, BTreeMap<&str, Places>> = BTreeMap::new();
+    for (path, occurrence) in found {
+        keys.entry(&occurrence.key)
+            .or_default()
+            .entry(&occurrence.value)
+            .or_default()
+            .push((path.clone(), occurrence.line));
+    }
+    keys.into_iter()
+        .filter(|(_, values)| values.len() > 1)
+        .map(|(key, values)| {
+            let mut values: Vec<_> = values.into_iter().map(|(value, places)| (value.to_string(), places)).collect();
+            values.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.0.cmp(&b.0)));
+            Inconsistency { key: key.to_string(), values }
+        })
+        .collect()
+}
+
+/// A report of `inconsistencies`, one block per key.
+pub fn render(inconsistencies: &[Inconsistency]) -> String {
+    let mut out = String::new();
+    for inconsistency in inconsistencies {
+        match inconsistency.key.as_str() {
+            "" => out.push_str("Inconsistent values:\n"),
+            key => {
+                let _ = writeln!(out, "Inconsistent values for {key}:");
+            }
+        }
+        for (value, places) in &inconsistency.values {
+            let places: Vec<String> = places.iter().map(|(path, line)| format!("{}:{line}", path.display())).collect();
+            let _ = writeln!(out, "  \"{value}\" in {}", places.join(", "));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(regex: &str, files: &[(&str, &str)]) -> Vec<(PathBuf, Occurrence)> {
+        let regex = Regex::new(regex).unwrap();
+        files.iter()
+            .flat_map(|&(path, content)| occurrences(content, &regex).into_iter().map(move |occurrence| (PathBuf::from(path), occurrence)))
+            .collect()
+    }
+
+    #[test]
+    fn test_inconsistencies() {
+        let versions = found(r#"version = "([^"]+)""#, &[
+            ("a.toml", "version = \"2.0\"\n"),
+            ("b.toml", "name = \"b\"\nversion = \"1.9\"\n"),
+            ("c.toml", "version = \"2.0\"\n"),
+        ]);
+        let differing = inconsistencies(&versions);
+        assert_eq!(differing, [Inconsistency {
+            key: String::new(),
+            values: vec![
+                ("2.0".to_string(), vec![(PathBuf::from("a.toml"), 1), (PathBuf::from("c.toml"), 1)]),
+                ("1.9".to_string(), vec![(PathBuf::from("b.toml"), 2)]),
+            ],
+        }]);
+        assert_eq!(render(&differing), "Inconsistent values:\n  \"2.0\" in a.toml:1, c.toml:1\n  \"1.9\" in b.toml:2\n");
+
+        let flags = found(r"(?<key>FLAG_\w+)=(?<value>\w+)", &[
+            ("a.env", "FLAG_A=on\nFLAG_B=off\n"),
+            ("b.env", "FLAG_A=on\nFLAG_B=on\n"),
+        ]);
+        let differing = inconsistencies(&flags);
+        assert_eq!(differing.len(), 1);
+        assert!(render(&differing).starts_with("Inconsistent values for FLAG_B:\n"));
+
+        assert!(inconsistencies(&found(r"v\d+", &[("a", "v1 v1"), ("b", "v1")])).is_empty());
+    }
+}
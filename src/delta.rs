@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+use crate::file_processor::Replacement;
+
+/// One replaced span of the original content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Edit {
+    start: usize,
+    end: usize,
+    /// Index of the new text in [`Delta::texts`]
+    text: u32,
+}
+
+/// A staged change to one file, kept as the spans it replaces and their new
+/// text rather than as the whole new content, so holding many staged files
+/// takes memory in proportion to the edits. Each distinct replacement text
+/// is stored once, however many matches it replaces.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Delta {
+    edits: Vec<Edit>,
+    texts: Vec<String>,
+}
+
+impl Delta {
+    /// The delta making `replacements` (sorted and non-overlapping, as
+    /// [`crate::file_processor::find_replacements`] returns them) to
+    /// `content`, leaving out those that would not change anything.
+    pub fn new(content: &str, replacements: &[Replacement]) -> Self {
+        let mut delta = Delta::default();
+        let mut interned: HashMap<&str, u32> = HashMap::new();
+        for rep in replacements {
+            if content[rep.start..rep.end] == rep.text {
+                continue;
+            }
+            let text = *interned.entry(&rep.text).or_insert_with(|| {
+                delta.texts.push(rep.text.clone());
+                (delta.texts.len() - 1) as u32
+            });
+            delta.edits.push(Edit { start: rep.start, end: rep.end, text });
+        }
+        delta
+    }
+
+    /// Whether applying the delta changes nothing.
+    pub fn is_empty(&self) -> bool {
+        self.edits.is_empty()
+    }
+
+    /// Number of spans replaced.
+    pub fn len(&self) -> usize {
+        self.edits.len()
+    }
+
+    /// Rebuilds the new content from the `original` the delta was made against.
+    pub fn apply(&self, original: &str) -> String {
+        let mut result = String::with_capacity(original.len());
+        let mut last = 0;
+        for edit in &self.edits {
+            result.push_str(&original[last..edit.start]);
+            result.push_str(&self.texts[edit.text as usize]);
+            last = edit.end;
+        }
+        result.push_str(&original[last..]);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_processor::{apply_replacements, find_replacements};
+    use regex::Regex;
+
+    #[test]
+    fn test_delta_rebuilds_new_content() {
+        let content = "colour colour color\n".repeat(100);
+        let replacements = find_replacements(&content, &Regex::new("colou?r").unwrap(), "color");
+        let delta = Delta::new(&content, &replacements);
+
+        assert_eq!(delta.apply(&content), apply_replacements(&content, &replacements));
+        // Matches already reading "color" are no-ops and left out
+        assert_eq!(delta.len(), 200);
+        assert_eq!(delta.texts, ["color"]);
+    }
+
+    #[test]
+    fn test_no_op_delta_is_empty() {
+        let content = "same";
+        let replacements = find_replacements(content, &Regex::new("same").unwrap(), "same");
+        assert!(Delta::new(content, &replacements).is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::file_processor::Rule;
+use crate::filesystem::FileSystem;
+
+/// How often a variant of a pattern matched the scanned files.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Hits {
+    matches: usize,
+    files: usize,
+}
+
+impl Hits {
+    fn add(&mut self, matches: usize) {
+        if matches > 0 {
+            self.matches += matches;
+            self.files += 1;
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} time{} in {} file{}",
+            self.matches,
+            if self.matches == 1 { "" } else { "s" },
+            self.files,
+            if self.files == 1 { "" } else { "s" }
+        )
+    }
+}
+
+/// Explains a run that matched nothing by checking the three most common
+/// mistakes: every file was filtered out, the pattern's case is wrong, or the
+/// pattern was meant literally but contains regex metacharacters. `scanned`
+/// lists each file read with the rules applied to it, and `skipped` counts
+/// entries left out per kind of reason.
+pub fn why_no_match(
+    fs: &dyn FileSystem,
+    scanned: &[(PathBuf, Arc<Vec<Rule>>)],
+    skipped: &BTreeMap<&str, usize>,
+) -> Vec<String> {
+    let mut findings = Vec::new();
+
+    if scanned.is_empty() {
+        let total: usize = skipped.values().sum();
+        if total == 0 {
+            findings.push("No files were found to search".to_string());
+        } else {
+            let kinds: Vec<_> = skipped.iter().map(|(kind, count)| format!("{kind}: {count}")).collect();
+            findings.push(format!("Every file was filtered out; {total} skipped ({})", kinds.join(", ")));
+        }
+        return findings;
+    }
+
+    // Each distinct pattern, with its case-insensitive variant and the hits of both readings
+    let mut patterns: Vec<(&str, Option<Regex>, Hits, Hits)> = Vec::new();
+    for (path, rules) in scanned {
+        let Ok(content) = fs.read_to_string(path) else { continue };
+        for rule in rules.iter() {
+            let pattern = rule.regex.as_str();
+            let index = match patterns.iter().position(|(p, ..)| *p == pattern) {
+                Some(index) => index,
+                None => {
+                    let folded = Regex::new(&format!("(?i){pattern}")).ok();
+                    patterns.push((pattern, folded, Hits::default(), Hits::default()));
+                    patterns.len() - 1
+                }
+            };
+            let (_, folded, folded_hits, literal_hits) = &mut patterns[index];
+            if let Some(folded) = folded {
+                folded_hits.add(folded.find_iter(&content).count());
+            }
+            if regex::escape(pattern) != pattern {
+                literal_hits.add(content.matches(pattern).count());
+            }
+        }
+    }
+
+    for (pattern, _, folded_hits, literal_hits) in &patterns {
+        if folded_hits.matches > 0 {
+            findings.push(format!(
+                "/{pattern}/ matches {} when case is ignored; prefix the pattern with (?i)",
+                folded_hits.describe()
+            ));
+        }
+        if literal_hits.matches > 0 {
+            findings.push(format!(
+                "\"{pattern}\" occurs literally {}, but contains regex metacharacters; escape them to match it as text: {}",
+                literal_hits.describe(),
+                regex::escape(pattern)
+            ));
+        }
+    }
+    if findings.is_empty() {
+        findings.push(format!(
+            "{} file{} searched; neither a case-insensitive nor a literal reading of the pattern matches either",
+            scanned.len(),
+            if scanned.len() == 1 { " was" } else { "s were" }
+        ));
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+
+    fn scanned(paths: &[&str], pattern: &str) -> Vec<(PathBuf, Arc<Vec<Rule>>)> {
+        let rules = Arc::new(vec![Rule::new(Regex::new(pattern).unwrap(), "x")]);
+        paths.iter().map(|path| (PathBuf::from(path), rules.clone())).collect()
+    }
+
+    #[test]
+    fn test_case_and_literal_readings() {
+        let fs = MemoryFs::new();
+        fs.add_file("/a.txt", "Error: call foo(1)");
+        fs.add_file("/b.txt", "ERROR twice, error");
+
+        let findings = why_no_match(&fs, &scanned(&["/a.txt", "/b.txt"], "error"), &BTreeMap::new());
+        assert_eq!(findings, ["/error/ matches 3 times in 2 files when case is ignored; prefix the pattern with (?i)"]);
+
+        let findings = why_no_match(&fs, &scanned(&["/a.txt"], "foo(1)"), &BTreeMap::new());
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].starts_with("\"foo(1)\" occurs literally 1 time in 1 file"));
+        assert!(findings[0].ends_with(r"foo\(1\)"));
+    }
+
+    #[test]
+    fn test_everything_filtered_or_nothing_close() {
+        let fs = MemoryFs::new();
+        let skipped = BTreeMap::from([("extension", 4), ("hidden", 1)]);
+        assert_eq!(
+            why_no_match(&fs, &[], &skipped),
+            ["Every file was filtered out; 5 skipped (extension: 4, hidden: 1)"]
+        );
+
+        fs.add_file("/a.txt", "nothing here");
+        let findings = why_no_match(&fs, &scanned(&["/a.txt"], "absent"), &BTreeMap::new());
+        assert!(findings[0].starts_with("1 file was searched; neither"));
+    }
+}
@@ -0,0 +1,261 @@
+use similar::{ChangeTag, DiffOp, TextDiff};
+use std::fmt::Write;
+
+/// Lines of unchanged context shown around each hunk.
+const CONTEXT_LINES: usize = 3;
+
+/// How changes are rendered in diff previews.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffStyle {
+    /// Standard unified diff with whole removed and added lines
+    Unified,
+    /// Unified hunks where modified lines are merged and only the changed
+    /// words are marked, like `git diff --word-diff`
+    Word,
+}
+
+/// Widest a diffstat bar may get before it is scaled down, like git.
+const MAX_BAR_WIDTH: usize = 50;
+
+/// Counts the lines added and removed between `old` and `new`.
+pub fn line_stats(old: &str, new: &str) -> (usize, usize) {
+    let diff = TextDiff::from_lines(old, new);
+    diff.iter_all_changes().fold((0, 0), |(added, removed), change| match change.tag() {
+        ChangeTag::Insert => (added + 1, removed),
+        ChangeTag::Delete => (added, removed + 1),
+        ChangeTag::Equal => (added, removed),
+    })
+}
+
+/// Renders a diff between `old` and `new` labelled with `name`.
+pub fn render_diff(name: &str, old: &str, new: &str, style: DiffStyle) -> String {
+    let diff = TextDiff::from_lines(old, new);
+    match style {
+        DiffStyle::Unified => diff
+            .unified_diff()
+            .context_radius(CONTEXT_LINES)
+            .header(&format!("a/{name}"), &format!("b/{name}"))
+            .to_string(),
+        DiffStyle::Word => render_word_diff(name, &diff),
+    }
+}
+
+fn render_word_diff(name: &str, diff: &TextDiff<'_, '_, '_, str>) -> String {
+    let mut out = format!("--- a/{name}\n+++ b/{name}\n");
+    let old_lines = diff.old_slices();
+    let new_lines = diff.new_slices();
+
+    for group in diff.grouped_ops(CONTEXT_LINES) {
+        let (Some(first), Some(last)) = (group.first(), group.last()) else {
+            continue;
+        };
+        let old_range = first.old_range().start..last.old_range().end;
+        let new_range = first.new_range().start..last.new_range().end;
+        let _ = writeln!(
+            out,
+            "@@ -{},{} +{},{} @@",
+            old_range.start + 1,
+            old_range.len(),
+            new_range.start + 1,
+            new_range.len()
+        );
+
+        for op in &group {
+            let old = &old_lines[op.old_range()];
+            let new = &new_lines[op.new_range()];
+            match op {
+                DiffOp::Equal { .. } => {
+                    for line in old {
+                        push_line(&mut out, ' ', line);
+                    }
+                }
+                _ => {
+                    // Pair removed and added lines up so single-token edits
+                    // render as one line; any surplus is shown whole
+                    let paired = old.len().min(new.len());
+                    for (before, after) in old.iter().zip(new.iter()) {
+                        push_line(&mut out, '~', &word_diff_line(before, after));
+                    }
+                    for line in &old[paired..] {
+                        push_line(&mut out, '-', line);
+                    }
+                    for line in &new[paired..] {
+                        push_line(&mut out, '+', line);
+                    }
+                }
+            }
+        }
+    }
+
+    out
+}
+
+/// Merges two versions of a line, marking removed words as `[-...-]` and
+/// added words as `{+...+}`.
+pub fn word_diff_line(old: &str, new: &str) -> String {
+    let old = old.trim_end_matches(['\n', '\r']);
+    let new = new.trim_end_matches(['\n', '\r']);
+    let diff = TextDiff::from_words(old, new);
+    let mut merged = String::new();
+    let mut pending: Option<ChangeTag> = None;
+
+    for change in diff.iter_all_changes() {
+        let tag = change.tag();
+        if pending != Some(tag) {
+            close_marker(&mut merged, pending);
+            match tag {
+                ChangeTag::Delete => merged.push_str("[-"),
+                ChangeTag::Insert => merged.push_str("{+"),
+                ChangeTag::Equal => {}
+            }
+            pending = Some(tag);
+        }
+        merged.push_str(change.value());
+    }
+    close_marker(&mut merged, pending);
+    merged
+}
+
+fn close_marker(out: &mut String, tag: Option<ChangeTag>) {
+    match tag {
+        Some(ChangeTag::Delete) => out.push_str("-]"),
+        Some(ChangeTag::Insert) => out.push_str("+}"),
+        _ => {}
+    }
+}
+
+fn push_line(out: &mut String, prefix: char, line: &str) {
+    out.push(prefix);
+    out.push_str(line.trim_end_matches(['\n', '\r']));
+    out.push('\n');
+}
+
+/// Renders one file's section of a git-applyable patch.
+///
+/// When `old_name` and `new_name` differ the section carries
+/// `rename from`/`rename to` headers so paths and contents move together.
+pub fn render_git_patch(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    let mut out = format!("diff --git a/{old_name} b/{new_name}\n");
+    if old_name != new_name {
+        let _ = writeln!(out, "rename from {old_name}\nrename to {new_name}");
+    }
+    if old != new {
+        let diff = TextDiff::from_lines(old, new);
+        let _ = write!(
+            out,
+            "{}",
+            diff.unified_diff()
+                .context_radius(CONTEXT_LINES)
+                .header(&format!("a/{old_name}"), &format!("b/{new_name}"))
+        );
+    }
+    out
+}
+
+/// Renders a git-style diffstat for `(name, added, removed)` entries.
+pub fn format_diffstat(entries: &[(String, usize, usize)]) -> String {
+    let mut out = String::new();
+    let name_width = entries.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+    let max_changes = entries.iter().map(|(_, a, r)| a + r).max().unwrap_or(0);
+    let count_width = max_changes.to_string().len();
+
+    for (name, added, removed) in entries {
+        let (plus, minus) = scale_bar(*added, *removed, max_changes);
+        let _ = writeln!(
+            out,
+            " {:<name_width$} | {:>count_width$} {}{}",
+            name,
+            added + removed,
+            "+".repeat(plus),
+            "-".repeat(minus)
+        );
+    }
+
+    let total_added: usize = entries.iter().map(|(_, a, _)| a).sum();
+    let total_removed: usize = entries.iter().map(|(_, _, r)| r).sum();
+    let _ = writeln!(
+        out,
+        " {} file{} changed, {} insertion{}(+), {} deletion{}(-)",
+        entries.len(),
+        plural(entries.len()),
+        total_added,
+        plural(total_added),
+        total_removed,
+        plural(total_removed)
+    );
+    out
+}
+
+fn scale_bar(added: usize, removed: usize, max_changes: usize) -> (usize, usize) {
+    if max_changes <= MAX_BAR_WIDTH {
+        return (added, removed);
+    }
+    let scale = |n: usize| if n == 0 { 0 } else { (n * MAX_BAR_WIDTH / max_changes).max(1) };
+    (scale(added), scale(removed))
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 { "" } else { "s" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_stats() {
+        assert_eq!(line_stats("a\nb\nc\n", "a\nB\nc\n"), (1, 1));
+        assert_eq!(line_stats("a\n", "a\nb\n"), (1, 0));
+        assert_eq!(line_stats("same", "same"), (0, 0));
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let diff = render_diff("f.txt", "a\nold\nc\n", "a\nnew\nc\n", DiffStyle::Unified);
+        assert!(diff.contains("--- a/f.txt\n+++ b/f.txt\n"));
+        assert!(diff.contains("-old\n+new\n"));
+    }
+
+    #[test]
+    fn test_word_diff_marks_only_changed_words() {
+        assert_eq!(
+            word_diff_line("let count = old_name + 1;\n", "let count = new_name + 1;\n"),
+            "let count = [-old_name-]{+new_name+} + 1;"
+        );
+
+        let diff = render_diff("f.rs", "x\nfoo bar\n", "x\nfoo baz\nextra\n", DiffStyle::Word);
+        assert!(diff.contains("@@ -1,2 +1,3 @@\n x\n~foo [-bar-]{+baz+}\n+extra\n"), "{diff}");
+    }
+
+    #[test]
+    fn test_git_patch_headers() {
+        let patch = render_git_patch("src/a.rs", "src/a.rs", "x\n", "y\n");
+        assert!(patch.starts_with("diff --git a/src/a.rs b/src/a.rs\n--- a/src/a.rs\n+++ b/src/a.rs\n"));
+        assert!(!patch.contains("rename"));
+
+        let patch = render_git_patch("old.rs", "new.rs", "x\n", "y\n");
+        assert!(patch.starts_with("diff --git a/old.rs b/new.rs\nrename from old.rs\nrename to new.rs\n--- a/old.rs\n+++ b/new.rs\n"));
+
+        let patch = render_git_patch("old.rs", "new.rs", "x\n", "x\n");
+        assert_eq!(patch, "diff --git a/old.rs b/new.rs\nrename from old.rs\nrename to new.rs\n");
+    }
+
+    #[test]
+    fn test_format_diffstat() {
+        let stat = format_diffstat(&[
+            ("src/main.rs".to_string(), 2, 2),
+            ("a.txt".to_string(), 1, 0),
+        ]);
+        assert!(stat.contains(" src/main.rs | 4 ++--\n"));
+        assert!(stat.contains(" a.txt       | 1 +\n"));
+        assert!(stat.contains(" 2 files changed, 3 insertions(+), 2 deletions(-)"));
+    }
+
+    #[test]
+    fn test_diffstat_bar_is_scaled() {
+        let stat = format_diffstat(&[("big.txt".to_string(), 200, 100)]);
+        let bar = stat.lines().next().unwrap().rsplit(' ').next().unwrap();
+        assert!(bar.len() <= MAX_BAR_WIDTH && bar.len() >= MAX_BAR_WIDTH - 1);
+        assert!(bar.starts_with("+++") && bar.ends_with("---"));
+    }
+}
@@ -0,0 +1,298 @@
+use anyhow::Result;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::file_processor::{FileOutcome, FileStatus, MatchDecision, MatchHook, MatchInfo, ProcessOptions};
+use crate::filesystem::{FileSystem, RealFs};
+use crate::filter::FileFilter;
+use crate::progress::{CancellationToken, Progress};
+use crate::project::{ProjectSettings, RuleConfig};
+use crate::TreeReplace;
+
+/// Builds and runs a replacement over a directory tree, returning structured
+/// results instead of printing them.
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// use regex_replace::ReplaceEngine;
+///
+/// let report = ReplaceEngine::new(r"\bfoo\b", "bar")
+///     .directory("src")
+///     .extensions(["rs"])
+///     .dry_run(true)
+///     .diffs(true)
+///     .run()?;
+/// for (path, diff) in report.diffs() {
+///     println!("{}:\n{diff}", path.display());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ReplaceEngine {
+    rules: Vec<RuleConfig>,
+    directory: String,
+    extensions: Option<Vec<String>>,
+    globs: Vec<String>,
+    include_hidden: bool,
+    gitignore: bool,
+    include_generated: bool,
+    max_file_size: Option<u64>,
+    max_matches_per_file: Option<usize>,
+    dry_run: bool,
+    diffs: bool,
+    fs: Arc<dyn FileSystem>,
+    cancel: Option<CancellationToken>,
+    on_match: Option<MatchHook>,
+}
+
+/// What a [`ReplaceEngine`] run did.
+#[derive(Debug)]
+pub struct EngineReport {
+    /// One outcome per file processed and per entry skipped, sorted by path
+    pub outcomes: Vec<FileOutcome>,
+    pub dry_run: bool,
+    /// Whether the run was cancelled before every file was processed
+    pub cancelled: bool,
+    /// Files not processed because the run was cancelled first
+    pub pending: Vec<PathBuf>,
+}
+
+impl ReplaceEngine {
+    /// An engine replacing `pattern` with `replacement` (which may refer to
+    /// capture groups as `$1` or `${name}`) in the current directory.
+    pub fn new(pattern: impl Into<String>, replacement: impl Into<String>) -> Self {
+        Self {
+            rules: vec![RuleConfig { pattern: pattern.into(), replace: replacement.into(), extensions: None, preserve_case: false }],
+            directory: ".".to_string(),
+            extensions: None,
+            globs: Vec::new(),
+            include_hidden: false,
+            gitignore: true,
+            include_generated: false,
+            max_file_size: None,
+            max_matches_per_file: None,
+            dry_run: false,
+            diffs: false,
+            fs: Arc::new(RealFs),
+            cancel: None,
+            on_match: None,
+        }
+    }
+
+    /// Adds a rule applied after the ones before it, to their output.
+    pub fn rule(mut self, pattern: impl Into<String>, replacement: impl Into<String>) -> Self {
+        self.rules.push(RuleConfig { pattern: pattern.into(), replace: replacement.into(), extensions: None, preserve_case: false });
+        self
+    }
+
+    pub fn directory(mut self, directory: impl Into<String>) -> Self {
+        self.directory = directory.into();
+        self
+    }
+
+    /// Only processes files with one of these extensions.
+    pub fn extensions<S: Into<String>>(mut self, extensions: impl IntoIterator<Item = S>) -> Self {
+        self.extensions = Some(extensions.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Only processes files whose path below the directory matches `glob`;
+    /// may be given several times.
+    pub fn glob(mut self, glob: impl Into<String>) -> Self {
+        self.globs.push(glob.into());
+        self
+    }
+
+    pub fn include_hidden(mut self, include_hidden: bool) -> Self {
+        self.include_hidden = include_hidden;
+        self
+    }
+
+    /// Whether to honor `.gitignore`, `.ignore` and git's exclude files (the
+    /// default).
+    pub fn gitignore(mut self, gitignore: bool) -> Self {
+        self.gitignore = gitignore;
+        self
+    }
+
+    /// Whether to process files marked as generated, which are skipped by
+    /// default.
+    pub fn include_generated(mut self, include_generated: bool) -> Self {
+        self.include_generated = include_generated;
+        self
+    }
+
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Leaves files with more matches than `limit` unchanged and reports them as failed.
+    pub fn max_matches_per_file(mut self, limit: usize) -> Self {
+        self.max_matches_per_file = Some(limit);
+        self
+    }
+
+    /// Computes every change without writing any file.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Records a git-style diff of each modified file in its outcome.
+    pub fn diffs(mut self, diffs: bool) -> Self {
+        self.diffs = diffs;
+        self
+    }
+
+    /// Runs against `fs` instead of the real disk.
+    pub fn filesystem(mut self, fs: Arc<dyn FileSystem>) -> Self {
+        self.fs = fs;
+        self
+    }
+
+    /// Stops the run before the next file once `token` is cancelled.
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Calls `callback` on every match before it is replaced, with its file,
+    /// position, named groups and proposed replacement. The callback can let
+    /// the replacement go ahead, veto it, or substitute other text. With
+    /// several rules, each sees the output of the rules before it.
+    pub fn on_match(mut self, callback: impl Fn(&MatchInfo) -> MatchDecision + Send + Sync + 'static) -> Self {
+        self.on_match = Some(MatchHook::new(callback));
+        self
+    }
+
+    pub fn run(&self) -> Result<EngineReport> {
+        self.run_with_progress(|_| {})
+    }
+
+    /// Runs, calling `on_progress` after each file.
+    pub fn run_with_progress(&self, on_progress: impl FnMut(&Progress)) -> Result<EngineReport> {
+        let defaults = ProjectSettings {
+            extensions: self.extensions.clone(),
+            max_file_size: self.max_file_size,
+            max_matches_per_file: self.max_matches_per_file,
+            rules: Arc::new(self.rules.iter().map(RuleConfig::compile).collect::<Result<_>>()?),
+            ..Default::default()
+        };
+        let mut filter = FileFilter::with_fs(self.fs.clone(), &self.directory, defaults, &self.globs, self.include_hidden)?;
+        if !self.gitignore {
+            filter = filter.no_gitignore();
+        }
+        if self.include_generated {
+            filter = filter.include_generated();
+        }
+        let options = ProcessOptions {
+            dry_run: self.dry_run,
+            patch: self.diffs,
+            root: PathBuf::from(&self.directory),
+            on_match: self.on_match.clone(),
+            ..Default::default()
+        };
+
+        let mut run = TreeReplace::new(&filter, &options).on_progress(on_progress);
+        if let Some(ref token) = self.cancel {
+            run = run.cancellation(token.clone());
+        }
+        let result = run.run()?;
+        Ok(EngineReport { outcomes: result.outcomes, dry_run: self.dry_run, cancelled: result.cancelled, pending: result.pending })
+    }
+}
+
+impl EngineReport {
+    /// Files that were read and matched against, whether or not they changed.
+    pub fn files_scanned(&self) -> usize {
+        self.outcomes.iter().filter(|outcome| outcome.change.is_some()).count()
+    }
+
+    /// Files rewritten, or in a dry run that would have been.
+    pub fn files_modified(&self) -> usize {
+        self.outcomes.iter().filter(|outcome| matches!(outcome.status, FileStatus::Modified)).count()
+    }
+
+    pub fn matches(&self) -> usize {
+        self.outcomes.iter().map(|outcome| outcome.matches).sum()
+    }
+
+    /// Files that could not be processed, with the error.
+    pub fn errors(&self) -> impl Iterator<Item = (&PathBuf, &anyhow::Error)> {
+        self.outcomes.iter().filter_map(|outcome| Some((&outcome.path, outcome.error.as_ref()?)))
+    }
+
+    /// The diff of each modified file, when diffs were requested.
+    pub fn diffs(&self) -> impl Iterator<Item = (&PathBuf, &str)> {
+        self.outcomes
+            .iter()
+            .filter_map(|outcome| Some((&outcome.path, outcome.change.as_ref()?.patch.as_deref()?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+
+    #[test]
+    fn test_engine_dry_run_with_diffs() -> Result<()> {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/repo/src/a.rs", "let foo = 1;\nfoo += 1;\n");
+        fs.add_file("/repo/src/b.rs", "nothing");
+        fs.add_file("/repo/notes.md", "foo");
+
+        let report = ReplaceEngine::new(r"\bfoo\b", "bar")
+            .rule("bar", "baz")
+            .directory("/repo")
+            .extensions(["rs"])
+            .dry_run(true)
+            .diffs(true)
+            .filesystem(fs.clone())
+            .run()?;
+
+        assert_eq!(report.files_scanned(), 2);
+        assert_eq!(report.files_modified(), 1);
+        assert_eq!(report.matches(), 4);
+        let diffs: Vec<_> = report.diffs().collect();
+        assert_eq!(diffs.len(), 1);
+        assert!(diffs[0].1.contains("+let baz = 1;"));
+        assert_eq!(fs.contents("/repo/src/a.rs").as_deref(), Some("let foo = 1;\nfoo += 1;\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_engine_on_match() -> Result<()> {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/repo/a.rs", "v1 v2\nv3\n");
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let log = seen.clone();
+        let report = ReplaceEngine::new(r"v(?<n>\d)", "version $n")
+            .directory("/repo")
+            .filesystem(fs.clone())
+            .on_match(move |m| {
+                log.lock().unwrap().push((m.span.clone(), m.line, m.column, m.captures[0].1.clone()));
+                match m.text {
+                    "v2" => MatchDecision::Veto,
+                    "v3" => MatchDecision::Replace(format!("{}!", m.replacement)),
+                    _ => MatchDecision::Accept,
+                }
+            })
+            .run()?;
+
+        assert_eq!(report.matches(), 2);
+        assert_eq!(fs.contents("/repo/a.rs").as_deref(), Some("version 1 v2\nversion 3!\n"));
+        assert_eq!(seen.lock().unwrap()[1], (3..5, 1, 4, Some("2".to_string())));
+        assert_eq!(seen.lock().unwrap()[2].1, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_engine_reports_invalid_pattern() {
+        let err = ReplaceEngine::new("(", "x").filesystem(Arc::new(MemoryFs::new())).run().unwrap_err();
+        assert!(err.to_string().contains("Invalid regex pattern"));
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::file_processor::MatchRecord;
+use crate::report;
+
+/// The placeholders an [`ExtractTemplate`] may use.
+const PLACEHOLDERS: [&str; 5] = ["relpath", "dir", "name", "stem", "ext"];
+
+/// Where the matches found in each source file are written, as a path with
+/// placeholders for parts of the source file's path relative to the
+/// directory searched: `{relpath}` for all of it, `{dir}`, `{name}`, `{stem}`
+/// and `{ext}`. For example `out/{relpath}.matches` lays the results out like
+/// the source tree.
+///
+/// A `.csv` or `.json` file gets a match report as `--match-report` writes
+/// one; any other file gets the text of each match, one per line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtractTemplate(String);
+
+impl std::str::FromStr for ExtractTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        while let Some(open) = rest.find('{') {
+            let Some(close) = rest[open..].find('}') else {
+                return Err(format!("unclosed placeholder in '{s}'"));
+            };
+            let name = &rest[open + 1..open + close];
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(format!("unknown placeholder {{{name}}} in '{s}': expected one of {{{}}}", PLACEHOLDERS.join("}, {")));
+            }
+            rest = &rest[open + close + 1..];
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl ExtractTemplate {
+    /// The directory every output is written below: the template up to its
+    /// first placeholder.
+    pub fn fixed_dir(&self) -> PathBuf {
+        let prefix = &self.0[..self.0.find('{').unwrap_or(self.0.len())];
+        match prefix.rfind(['/', std::path::MAIN_SEPARATOR]) {
+            Some(end) => PathBuf::from(&prefix[..=end]),
+            None => PathBuf::from("."),
+        }
+    }
+
+    /// Where the matches of the file at `path`, below `root`, are written.
+    pub fn path_for(&self, root: &Path, path: &Path) -> PathBuf {
+        let relative: PathBuf = path.strip_prefix(root).unwrap_or(path)
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        let part = |part: Option<&std::ffi::OsStr>| part.map(|part| part.to_string_lossy().into_owned()).unwrap_or_default();
+        let dir = relative.parent().map(|dir| dir.display().to_string()).unwrap_or_default();
+        let expanded = self.0
+            .replace("{relpath}", &relative.display().to_string())
+            .replace("{dir}", if dir.is_empty() { "." } else { &dir })
+            .replace("{name}", &part(relative.file_name()))
+            .replace("{stem}", &part(relative.file_stem()))
+            .replace("{ext}", &part(relative.extension()));
+        PathBuf::from(expanded)
+    }
+
+    /// Writes `records`, the matches of the file at `path`, to its output,
+    /// creating directories as needed, and returns where.
+    pub fn write(&self, root: &Path, path: &Path, records: &[MatchRecord]) -> Result<PathBuf> {
+        let target = self.path_for(root, path);
+        if let Some(parent) = target.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+        }
+        let records: Vec<(PathBuf, MatchRecord)> = records.iter().map(|record| (path.to_path_buf(), record.clone())).collect();
+        let rendered = match target.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => report::render_matches_csv(&records),
+            Some("json") => report::render_matches_json(&records),
+            _ => records.iter().map(|(_, record)| format!("{}\n", record.text)).collect(),
+        };
+        fs::write(&target, rendered).with_context(|| format!("Failed to write extracted matches: {}", target.display()))?;
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_paths() {
+        let template: ExtractTemplate = "out/{relpath}.matches".parse().unwrap();
+        assert_eq!(template.path_for(Path::new("./src"), Path::new("./src/net/http.rs")), Path::new("out/net/http.rs.matches"));
+        assert_eq!(template.fixed_dir(), Path::new("out/"));
+
+        let template: ExtractTemplate = "{dir}/{stem}-{ext}.csv".parse().unwrap();
+        assert_eq!(template.path_for(Path::new("/src"), Path::new("/src/a.rs")), Path::new("./a-rs.csv"));
+        assert_eq!(template.fixed_dir(), Path::new("."));
+
+        assert!("out/{path}".parse::<ExtractTemplate>().unwrap_err().contains("unknown placeholder {path}"));
+        assert!("out/{relpath".parse::<ExtractTemplate>().is_err());
+    }
+}
@@ -1,53 +1,1605 @@
 use anyhow::{Context, Result};
-use regex::Regex;
-use std::fs;
-use std::path::Path;
+use clap::ValueEnum;
+use regex::{Captures, Regex};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::case::{self, CaseFolding};
+use crate::color;
+use crate::consistency::{self, Occurrence};
+use crate::diff::{self, DiffStyle};
+use crate::filesystem::{FileSystem, RealFs};
+use crate::filter::SkipReason;
+use crate::headers;
+use crate::preview::{self, PreviewFile};
+use crate::capture_range::{self, CaptureRange};
+use crate::regions::RegionGuards;
+use crate::replacement::ReplacementTemplate;
+use crate::reversibility::{self, Mapping};
+use crate::shadow;
+use crate::undo::JournalFile;
+use crate::utf8::InvalidUtf8;
+
+/// How a run dealt with one file.
+#[derive(Debug)]
+pub enum FileStatus {
+    /// Rewritten, or in a dry run would have been
+    Modified,
+    /// Processed without any match
+    Unchanged,
+    /// Left unchanged because matching exceeded the time budget
+    Abandoned,
+    /// Left unchanged because it changed on disk after it was read
+    ChangedOnDisk,
+    /// Left out by a filter before being read
+    Skipped(SkipReason),
+    /// Processing failed; see [`FileOutcome::error`]
+    Failed,
+}
+
+/// Everything an embedder needs to report on one file without re-reading it.
+#[derive(Debug)]
+pub struct FileOutcome {
+    pub path: PathBuf,
+    pub status: FileStatus,
+    pub matches: usize,
+    /// Change in size, in bytes, of the new content over the old
+    pub bytes_delta: i64,
+    pub error: Option<anyhow::Error>,
+    pub duration: Duration,
+    /// The full details, when the file was processed successfully
+    pub change: Option<FileChange>,
+}
+
+impl FileOutcome {
+    pub fn skipped(path: PathBuf, reason: SkipReason) -> Self {
+        Self {
+            path,
+            status: FileStatus::Skipped(reason),
+            matches: 0,
+            bytes_delta: 0,
+            error: None,
+            duration: Duration::ZERO,
+            change: None,
+        }
+    }
+}
+
+/// What happened to a single file.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FileChange {
+    /// Whether the pattern matched (the file was, or in a dry run would be, rewritten)
+    pub modified: bool,
+    pub matches: usize,
+    /// Change in size, in bytes, of the new content over the old
+    pub bytes_delta: i64,
+    pub lines_added: usize,
+    pub lines_removed: usize,
+    /// This file's section of a git patch, when one was requested
+    pub patch: Option<String>,
+    /// SHA-256 of the content the patch applies to
+    pub patch_hash: Option<String>,
+    /// Matches of the `remaining` pattern left in the new content
+    pub remaining: Vec<RemainingMatch>,
+    /// Matches of the `consistency` pattern in the new content
+    pub occurrences: Vec<Occurrence>,
+    /// How the shadow command's result differs from the new content, as a
+    /// diff, if it does
+    pub shadow_diff: Option<String>,
+    /// Distinct rewrites made, when reversibility analysis was requested
+    pub mappings: Vec<Mapping>,
+    /// Replacements a second pass over the new content would still make
+    pub further_changes: usize,
+    /// Time spent finding matches
+    pub match_time: Duration,
+    /// The matches were those found in an earlier file with the same
+    /// content, not found again
+    pub reused: bool,
+    /// How the file was decoded, if it wasn't valid UTF-8
+    pub utf8_fallback: Option<InvalidUtf8>,
+    /// Matching was abandoned because it exceeded the time budget
+    pub abandoned: bool,
+    /// The file changed on disk after it was read, so the new content was
+    /// not written over it
+    pub changed_on_disk: bool,
+    /// Every replacement made, when match recording was requested
+    pub found: Vec<MatchRecord>,
+    /// The change as line hunks, when a preview was requested
+    pub preview: Option<PreviewFile>,
+    /// What to restore to undo the change, when journalling was requested
+    pub journal: Option<JournalFile>,
+}
+
+/// Text that should not be left behind by a run, and where it was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemainingMatch {
+    pub line: usize,
+    pub text: String,
+}
+
+/// One replacement made in a file, with the values of the pattern's named groups.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchRecord {
+    /// Line of the match in the content the rule ran against
+    pub line: usize,
+    /// 1-based column of the match on its line, in characters
+    pub column: usize,
+    /// Byte offsets of the match in the content the rule ran against
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+    pub replacement: String,
+    pub captures: Vec<(String, Option<String>)>,
+    /// The lines around the match, when asked for
+    pub context: Option<MatchContext>,
+}
+
+/// The lines before and after a match, for reviewing it without the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchContext {
+    pub before: Vec<String>,
+    /// Lines after the last line of the match
+    pub after: Vec<String>,
+}
+
+/// A pattern and the replacement template applied to its matches.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub regex: Regex,
+    pub replacement: String,
+    /// Only applied to files with one of these extensions
+    pub extensions: Option<Vec<String>>,
+    /// Give each replacement the casing of the text it replaces
+    pub preserve_case: bool,
+    /// How replacements change case when preserving it
+    pub folding: CaseFolding,
+    /// Rules applied in turn to the text of each replacement, and nowhere else
+    pub then: Vec<Rule>,
+    /// The literal replacement for each text the pattern can match, used
+    /// instead of expanding `replacement`
+    pub targets: Option<Arc<HashMap<String, String>>>,
+    /// Matches numbered so far by a `${counter:scope=global}` in the
+    /// replacement, shared by every copy of the rule
+    numbered: Arc<AtomicUsize>,
+}
+
+impl Rule {
+    pub fn new(regex: Regex, replacement: impl Into<String>) -> Self {
+        Self {
+            regex,
+            replacement: replacement.into(),
+            extensions: None,
+            preserve_case: false,
+            then: Vec::new(),
+            targets: None,
+            folding: CaseFolding::default(),
+            numbered: Arc::default(),
+        }
+    }
+
+    /// Adds a rule applied only within the text this rule produces, after
+    /// any added before it.
+    pub fn then(mut self, rule: Rule) -> Self {
+        self.then.push(rule);
+        self
+    }
+
+    /// Replaces each match with the text `targets` maps it to, as for a
+    /// pattern matching any of a list of literals.
+    pub fn with_targets(mut self, targets: HashMap<String, String>) -> Self {
+        self.targets = Some(Arc::new(targets));
+        self
+    }
+
+    /// Finishes the replacements this rule found in `content`: looks up each
+    /// one's text if the rule has targets, gives them the matched casing if
+    /// the rule preserves case, then applies the [`Rule::then`] rules within
+    /// each replacement's text.
+    fn finish(&self, content: &str, mut replacements: Vec<Replacement>) -> Vec<Replacement> {
+        if let Some(ref targets) = self.targets {
+            for rep in &mut replacements {
+                if let Some(target) = targets.get(&content[rep.start..rep.end]) {
+                    rep.text.clone_from(target);
+                }
+            }
+        }
+        let mut replacements = if self.preserve_case { match_case(content, replacements, self.folding) } else { replacements };
+        for rule in &self.then {
+            for rep in &mut replacements {
+                let inner = rule.find(&rep.text);
+                let inner = rule.finish(&rep.text, inner);
+                if !inner.is_empty() {
+                    rep.text = apply_replacements(&rep.text, &inner);
+                }
+            }
+        }
+        replacements
+    }
+
+    /// Makes the rule give each replacement the casing of the matched text.
+    pub fn preserving_case(mut self) -> Self {
+        self.preserve_case = true;
+        self
+    }
+
+    /// Changes case as `folding` says when preserving it.
+    pub fn with_case_folding(mut self, folding: CaseFolding) -> Self {
+        self.folding = folding;
+        self
+    }
+
+    /// Limits the rule to files with one of `extensions`.
+    pub fn for_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.extensions = Some(extensions);
+        self
+    }
+
+    /// Whether the rule applies to the file at `path`.
+    pub fn applies_to(&self, path: &Path) -> bool {
+        self.extensions.as_ref().is_none_or(|exts| {
+            path.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| exts.iter().any(|e| e == ext))
+        })
+    }
+
+    /// The replacements this rule makes in `content`, finished as when
+    /// processing a file.
+    pub fn replacements(&self, content: &str) -> Vec<Replacement> {
+        self.finish(content, self.find(content))
+    }
+
+    /// Finds every match in `content` and expands the replacement for it,
+    /// continuing the run's numbering if the replacement has a global
+    /// `${counter}`.
+    fn find(&self, content: &str) -> Vec<Replacement> {
+        let template = ReplacementTemplate::lenient(&self.replacement);
+        if !template.counts_globally() {
+            return expand_matches(content, &self.regex, &template, 0);
+        }
+        let count = self.regex.find_iter(content).count();
+        let first = self.numbered.fetch_add(count, Ordering::Relaxed);
+        expand_matches(content, &self.regex, &template, first)
+    }
+
+    /// Like [`Rule::find`], but matching each line of `content` on its own,
+    /// so matches never span lines and `^` and `$` match at the ends of
+    /// every line. Line endings, `\r\n` included, are never matched.
+    fn find_in_lines(&self, content: &str) -> Vec<Replacement> {
+        let template = ReplacementTemplate::lenient(&self.replacement);
+        let lines = content.split_inclusive('\n').scan(0, |start, line| {
+            let text = line.strip_suffix('\n').unwrap_or(line);
+            let text = text.strip_suffix('\r').unwrap_or(text);
+            let span = *start..*start + text.len();
+            *start += line.len();
+            Some(span)
+        });
+        let mut number = 0;
+        if template.counts_globally() {
+            let count: usize = lines.clone().map(|span| self.regex.find_iter(&content[span]).count()).sum();
+            number = self.numbered.fetch_add(count, Ordering::Relaxed);
+        }
+        let mut replacements = Vec::new();
+        for span in lines {
+            let found = expand_matches(&content[span.clone()], &self.regex, &template, number);
+            number += found.len();
+            replacements.extend(found.into_iter().map(|rep| Replacement { start: rep.start + span.start, end: rep.end + span.start, ..rep }));
+        }
+        replacements
+    }
+
+    /// `text` with every match of this rule replaced, as for a file name.
+    pub fn apply(&self, text: &str) -> String {
+        apply_replacements(text, &self.replacements(text))
+    }
+}
+
+/// Options controlling how files are rewritten and reported.
+#[derive(Debug, Default, Clone)]
+pub struct ProcessOptions {
+    pub dry_run: bool,
+    pub verbose: bool,
+    /// Show a diff of each modified file in this style
+    pub diff: Option<DiffStyle>,
+    /// Directory that file names in diffs are shown relative to
+    pub root: PathBuf,
+    /// Render a git patch section for each modified file
+    pub patch: bool,
+    /// Refuse to modify files with more matches than this
+    pub max_matches: Option<usize>,
+    /// Replace only the first this many matches of each file
+    pub max_per_file: Option<usize>,
+    /// Replace only as many matches as are left in this budget, shared by
+    /// every file of a run
+    pub max_total: Option<ReplacementBudget>,
+    /// Report matches of this pattern left in each file's new content
+    pub remaining: Option<Regex>,
+    /// Record matches of this pattern in each file's new content, to check
+    /// that they agree across files
+    pub consistency: Option<Regex>,
+    /// Run this command on each file too, as [`shadow::run`] does, and
+    /// report where its result differs from the new content
+    pub shadow: Option<String>,
+    /// Match each distinct content once, sharing the result with every file
+    /// that has it
+    pub dedupe: Option<ContentCache>,
+    /// Record the distinct rewrites made, for reversibility analysis
+    pub analyze: bool,
+    /// Apply the rules to the new content again and count further changes
+    pub check_idempotent: bool,
+    /// Match content larger than the chunk size chunk by chunk
+    pub chunking: Option<Chunking>,
+    /// Abandon a chunked file once matching has taken this long
+    pub match_budget: Option<Duration>,
+    /// Record every replacement made, for match reports
+    pub record_matches: bool,
+    /// With `record_matches`, also record this many lines around each match
+    pub context_lines: Option<usize>,
+    /// Record each change as line hunks, so it can be applied later as previewed
+    pub preview: bool,
+    /// Read each written file back and fail it if it doesn't hold exactly the new content
+    pub verify_roundtrip: bool,
+    /// Write files even if their size or modification time changed after
+    /// they were read, overwriting whatever changed them
+    pub overwrite_concurrent: bool,
+    /// Read each written file back and fail it if a rule's pattern matches it
+    /// a different number of times than it matches the new content
+    pub verify_matches: bool,
+    /// Copy each file aside before rewriting it
+    pub backup: Option<Backup>,
+    /// Write rewritten files into this mirror of the tree, leaving the
+    /// originals as they are
+    pub mirror: Option<Mirror>,
+    /// Give each rewritten file, and its backup, the owner and group the file
+    /// had before
+    pub preserve_owner: bool,
+    /// Give each rewritten file, and its backup, the modification time the
+    /// file had before
+    pub preserve_times: bool,
+    /// Record each change so it can be undone later
+    pub journal: bool,
+    /// How matches of the empty string are treated
+    pub insertions: Insertions,
+    /// Only replace matches starting on a line in one of these ranges, if any
+    /// are given
+    pub lines: Vec<LineRange>,
+    /// Match each line on its own, and report every match by line and column
+    pub line_mode: bool,
+    /// Colour matched text red and replacements green in the output
+    pub color: bool,
+    /// Show every match with this many lines around it, in dry runs and
+    /// verbose output
+    pub show_context: Option<usize>,
+    /// What to do with files that aren't valid UTF-8
+    pub invalid_utf8: InvalidUtf8,
+    /// Only replace matches inside, or outside, regions between delimiters
+    pub regions: RegionGuards,
+    /// Only replace matches whose numeric captures are in these ranges
+    pub capture_ranges: Vec<CaptureRange>,
+    /// Consulted on every match before it is replaced
+    pub on_match: Option<MatchHook>,
+    /// Append this note, as a comment in the file's own syntax, to every
+    /// file modified; files whose comment syntax isn't known are left as
+    /// the rules leave them
+    pub annotate: Option<String>,
+}
+
+/// A match about to be replaced, as shown to a [`MatchHook`].
+#[derive(Debug)]
+pub struct MatchInfo<'a> {
+    pub path: &'a Path,
+    /// Byte offsets of the match in the content the rule runs against
+    pub span: Range<usize>,
+    pub line: usize,
+    /// 1-based, in characters
+    pub column: usize,
+    pub text: &'a str,
+    /// Text of each of the pattern's groups by number, group 0 being the
+    /// whole match
+    pub groups: Vec<Option<&'a str>>,
+    /// Values of the pattern's named groups, in the order they appear in it
+    pub captures: &'a [(String, Option<String>)],
+    /// The replacement the rule would make
+    pub replacement: &'a str,
+}
+
+/// What a [`MatchHook`] decides to do with a match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatchDecision {
+    /// Replace it as the rule would
+    Accept,
+    /// Leave the matched text as it is
+    Veto,
+    /// Replace it with this text instead
+    Replace(String),
+    /// Fail the file, for this reason, leaving it unchanged
+    Fail(String),
+}
+
+type MatchCallback = dyn Fn(&MatchInfo) -> MatchDecision + Send + Sync;
+
+/// A callback consulted on every match before it is replaced, which may
+/// veto the replacement or substitute its own.
+#[derive(Clone)]
+pub struct MatchHook(Arc<MatchCallback>);
+
+impl MatchHook {
+    pub fn new(callback: impl Fn(&MatchInfo) -> MatchDecision + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    /// The replacements left, and their text, once the callback has decided
+    /// on each of `replacements` of `content`, which `regex` found.
+    fn decide(&self, path: &Path, content: &str, regex: &Regex, replacements: Vec<Replacement>) -> Result<Vec<Replacement>> {
+        let lines = LineIndex::new(content);
+        let mut decided = Vec::with_capacity(replacements.len());
+        for mut rep in replacements {
+            // Matched again where it was found, so assertions see the same context
+            let caps = regex.captures_at(content, rep.start).filter(|caps| caps.get(0).unwrap().range() == (rep.start..rep.end));
+            let info = MatchInfo {
+                path,
+                span: rep.start..rep.end,
+                line: lines.line_of(rep.start),
+                column: lines.column_of(content, rep.start),
+                text: &content[rep.start..rep.end],
+                groups: match caps {
+                    Some(ref caps) => caps.iter().map(|group| group.map(|m| m.as_str())).collect(),
+                    None => vec![Some(&content[rep.start..rep.end])],
+                },
+                captures: &rep.captures,
+                replacement: &rep.text,
+            };
+            match (self.0)(&info) {
+                MatchDecision::Accept => {}
+                MatchDecision::Veto => continue,
+                MatchDecision::Replace(text) => rep.text = text,
+                MatchDecision::Fail(reason) => {
+                    anyhow::bail!("{reason} (match at line {}, column {})", info.line, info.column)
+                }
+            }
+            decided.push(rep);
+        }
+        Ok(decided)
+    }
+}
+
+impl std::fmt::Debug for MatchHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MatchHook(..)")
+    }
+}
+
+/// How matches of zero length, which insert their replacement rather than
+/// replacing any text, are treated.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Insertions {
+    /// Fail files in which a rule matches the empty string
+    pub forbid: bool,
+    /// Keep only the first this many insertions of a rule on any one line
+    pub max_per_line: Option<usize>,
+}
+
+/// Replacements a whole run may still make, shared by every file and
+/// thread, for `--max-total`.
+#[derive(Debug, Clone)]
+pub struct ReplacementBudget(Arc<AtomicUsize>);
+
+impl ReplacementBudget {
+    pub fn new(limit: usize) -> Self {
+        Self(Arc::new(AtomicUsize::new(limit)))
+    }
+
+    /// Takes up to `wanted` replacements from the budget, returning how many
+    /// it had left to give.
+    pub fn take(&self, wanted: usize) -> usize {
+        let left = self.0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| Some(left - left.min(wanted)))
+            .expect("the update always succeeds");
+        left.min(wanted)
+    }
+}
+
+/// What the rules made of each distinct content seen in a run, so files
+/// with the same content, such as vendored copies, are matched only once.
+/// Entries are keyed by a hash of the content and of the rules applied to
+/// it, as rules can differ between files.
+#[derive(Debug, Clone, Default)]
+pub struct ContentCache(Arc<Mutex<HashMap<ContentKey, Arc<Matched>>>>);
+
+/// Hashes of a file's content and of the rules applied to it.
+type ContentKey = (String, String);
+
+impl ContentCache {
+    /// Whether what `rules` make of a file depends only on its content: not
+    /// when a hook sees each match, a budget is shared across files or a
+    /// `${counter}` numbers matches across the run.
+    fn can_share(rules: &[Rule], options: &ProcessOptions) -> bool {
+        fn counts_globally(rule: &Rule) -> bool {
+            ReplacementTemplate::lenient(&rule.replacement).counts_globally() || rule.then.iter().any(counts_globally)
+        }
+        options.on_match.is_none() && options.max_total.is_none() && !rules.iter().any(counts_globally)
+    }
+
+    /// What `rules` make of `content`, from `compute` unless an earlier file
+    /// had the same content, and whether it was reused. Abandoned matching
+    /// isn't kept, so the next file tries again.
+    fn get_or_match(
+        &self,
+        content: &str,
+        rules: &[Rule],
+        compute: impl FnOnce() -> Result<Option<Matched>>,
+    ) -> Result<(Option<Arc<Matched>>, bool)> {
+        let key = (preview::content_hash(content), preview::content_hash(fingerprint(rules)));
+        if let Some(matched) = self.0.lock().unwrap().get(&key) {
+            return Ok((Some(Arc::clone(matched)), true));
+        }
+        // Matched without the lock held, so other files aren't kept waiting
+        let matched = compute()?.map(Arc::new);
+        if let Some(ref matched) = matched {
+            self.0.lock().unwrap().insert(key, Arc::clone(matched));
+        }
+        Ok((matched, false))
+    }
+}
+
+/// Everything about `rules` that decides what they make of a file.
+pub fn fingerprint(rules: &[Rule]) -> String {
+    let mut out = String::new();
+    for rule in rules {
+        let mut targets: Vec<_> = rule.targets.iter().flat_map(|targets| targets.iter()).collect();
+        targets.sort();
+        out.push_str(&format!(
+            "{:?} {:?} {} {:?} {:?} [{}]\n",
+            rule.regex.as_str(),
+            rule.replacement,
+            rule.preserve_case,
+            rule.folding,
+            targets,
+            fingerprint(&rule.then)
+        ));
+    }
+    out
+}
+
+/// Lines of a file, from `first` to `last` inclusive, 1-based: written
+/// `10-50`, `10-` for line 10 onwards or `12` for one line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRange {
+    pub first: usize,
+    /// The last line, or `None` for the end of the file
+    pub last: Option<usize>,
+}
+
+impl LineRange {
+    pub fn contains(&self, line: usize) -> bool {
+        line >= self.first && self.last.is_none_or(|last| line <= last)
+    }
+}
+
+impl std::str::FromStr for LineRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = |text: &str| match text.trim().parse::<usize>() {
+            Ok(0) | Err(_) => Err(format!("expected a line range such as 10-50, 10- or 12, got '{s}'")),
+            Ok(line) => Ok(line),
+        };
+        let range = match s.split_once('-') {
+            Some((first, "")) => Self { first: number(first)?, last: None },
+            Some((first, last)) => Self { first: number(first)?, last: Some(number(last)?) },
+            None => Self { first: number(s)?, last: Some(number(s)?) },
+        };
+        if range.last.is_some_and(|last| last < range.first) {
+            return Err(format!("line range '{s}' ends before it starts"));
+        }
+        Ok(range)
+    }
+}
+
+/// Where the original of each rewritten file is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backup {
+    /// Appended to the file's name, e.g. `.bak`
+    pub suffix: String,
+    /// Overwrite a backup left by an earlier run instead of failing the file
+    pub force: bool,
+}
+
+impl Backup {
+    pub fn path_for(&self, path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(&self.suffix);
+        PathBuf::from(name)
+    }
+}
+
+/// A tree mirroring the one being processed, into which rewritten files are
+/// written instead of over the originals.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mirror {
+    pub dir: PathBuf,
+    /// What the mirror gets for files the run leaves unchanged
+    pub unchanged: MirrorUnchanged,
+}
+
+/// What a [`Mirror`] gets for a file the run leaves unchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum MirrorUnchanged {
+    /// Nothing, so the mirror holds only the rewritten files
+    #[default]
+    Skip,
+    /// A copy of the file
+    Copy,
+    /// A symbolic link to the file
+    Symlink,
+}
+
+impl Mirror {
+    /// Where the mirror keeps the file at `path`, which is below `root`.
+    pub fn path_for(&self, root: &Path, path: &Path) -> PathBuf {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        self.dir.join(relative.components().filter(|component| matches!(component, Component::Normal(_))).collect::<PathBuf>())
+    }
+
+    /// Puts the file at `path` into the mirror: `new_content` if the run
+    /// changed it, or else the file as it is, unless unchanged files are
+    /// skipped.
+    fn put(&self, fs: &dyn FileSystem, root: &Path, path: &Path, new_content: Option<&[u8]>) -> Result<()> {
+        if new_content.is_none() && self.unchanged == MirrorUnchanged::Skip {
+            return Ok(());
+        }
+        let target = self.path_for(root, path);
+        if let Some(parent) = target.parent() {
+            fs.create_dir_all(parent).with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+        }
+        match new_content {
+            Some(content) => fs.write(&target, content),
+            None if self.unchanged == MirrorUnchanged::Copy => fs.copy(path, &target),
+            None => fs.symlink(&std::path::absolute(path)?, &target),
+        }
+        .with_context(|| format!("Failed to write file: {}", target.display()))
+    }
+}
+
+/// How large contents are split up for matching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunking {
+    pub size: usize,
+    /// Bytes past the end of each chunk that a match may extend into
+    pub overlap: usize,
+    /// Search the chunks of a file on several threads of the current rayon pool
+    pub parallel: bool,
+}
+
+/// Processes a single file of `fs` like [`process_file_in`], capturing any
+/// error and the time taken in the returned outcome.
+pub fn process_path(
+    fs: &dyn FileSystem,
+    path: &Path,
+    rules: &[Rule],
+    options: &ProcessOptions,
+    out: &mut dyn Write,
+) -> FileOutcome {
+    let started = Instant::now();
+    let result = process_file_in(fs, path, rules, options, out);
+    let duration = started.elapsed();
+
+    match result {
+        Ok(change) => FileOutcome {
+            path: path.to_path_buf(),
+            status: if change.abandoned {
+                FileStatus::Abandoned
+            } else if change.changed_on_disk {
+                FileStatus::ChangedOnDisk
+            } else if change.modified {
+                FileStatus::Modified
+            } else {
+                FileStatus::Unchanged
+            },
+            matches: change.matches,
+            bytes_delta: change.bytes_delta,
+            error: None,
+            duration,
+            change: Some(change),
+        },
+        Err(e) => FileOutcome {
+            path: path.to_path_buf(),
+            status: FileStatus::Failed,
+            matches: 0,
+            bytes_delta: 0,
+            error: Some(e),
+            duration,
+            change: None,
+        },
+    }
+}
+
+/// What the rules make of a file's content before any of it is reported or
+/// written.
+#[derive(Debug, Clone, Default)]
+struct Matched {
+    /// Each stage is the content a rule ran against and the replacements it made
+    stages: Vec<(String, Vec<Replacement>)>,
+    mappings: Vec<Mapping>,
+    /// Matches left alone under the replacement limits
+    over_limit: Vec<RemainingMatch>,
+    new_content: String,
+}
+
+/// Applies `rules` in turn to `content`, the content of the file at `path`,
+/// or returns `None` if matching passed `deadline` and was abandoned.
+fn match_rules(
+    path: &Path,
+    content: &str,
+    rules: &[Rule],
+    options: &ProcessOptions,
+    deadline: Option<Instant>,
+) -> Result<Option<Matched>> {
+    let mut stages = Vec::new();
+    let mut mappings = Vec::new();
+    // Replacements made so far, and the matches left alone, under the limits
+    let mut kept = 0;
+    let mut over_limit = Vec::new();
+    let mut new_content = content.to_string();
+    for rule in rules {
+        let replacements = match options.chunking {
+            _ if options.line_mode => rule.find_in_lines(&new_content),
+            // Matches can only be numbered in order, which chunks may not be searched in
+            Some(chunking) if new_content.len() > chunking.size && !ReplacementTemplate::lenient(&rule.replacement).has_counter() => {
+                match find_replacements_chunked(&new_content, rule, chunking, deadline) {
+                    Some(replacements) => replacements,
+                    None => return Ok(None),
+                }
+            }
+            _ => rule.find(&new_content),
+        };
+        let replacements = rule.finish(&new_content, replacements);
+        let replacements = if replacements.iter().any(|rep| rep.start == rep.end) {
+            if options.insertions.forbid {
+                anyhow::bail!(
+                    "Pattern {} matches the empty string; pass --allow-empty-matches to insert the replacement there",
+                    rule.regex
+                );
+            }
+            place_insertions(&new_content, replacements, options.insertions)
+        } else {
+            replacements
+        };
+        let replacements = if options.lines.is_empty() {
+            replacements
+        } else {
+            let lines = LineIndex::new(&new_content);
+            replacements.into_iter()
+                .filter(|rep| {
+                    let line = lines.line_of(rep.start);
+                    options.lines.iter().any(|range| range.contains(line))
+                })
+                .collect()
+        };
+        let replacements = if options.regions.is_empty() {
+            replacements
+        } else {
+            options.regions.filter(&new_content, replacements)
+        };
+        let replacements = if options.capture_ranges.is_empty() {
+            replacements
+        } else {
+            capture_range::filter(&options.capture_ranges, &new_content, &rule.regex, replacements)
+        };
+        let mut replacements = match options.on_match {
+            Some(ref hook) => hook.decide(path, &new_content, &rule.regex, replacements)?,
+            None => replacements,
+        };
+        if options.max_per_file.is_some() || options.max_total.is_some() {
+            let mut allowed = replacements.len().min(options.max_per_file.map_or(usize::MAX, |limit| limit - kept));
+            if let Some(ref budget) = options.max_total {
+                allowed = budget.take(allowed);
+            }
+            if allowed < replacements.len() {
+                let lines = LineIndex::new(&new_content);
+                over_limit.extend(replacements.drain(allowed..).map(|rep| RemainingMatch {
+                    line: lines.line_of(rep.start),
+                    text: new_content[rep.start..rep.end].to_string(),
+                }));
+            }
+            kept += replacements.len();
+        }
+        if options.analyze {
+            mappings.extend(reversibility::mappings(rule, &new_content, &replacements));
+        }
+        if !replacements.is_empty() {
+            let next = apply_replacements(&new_content, &replacements);
+            stages.push((std::mem::replace(&mut new_content, next), replacements));
+        }
+    }
+
+    Ok(Some(Matched { stages, mappings, over_limit, new_content }))
+}
+
+/// Processes a single file, writing any per-file report lines to `out`.
+///
+/// Rules are applied in order, each one to the output of the previous one.
+/// Callers that need output in traversal order hand in a buffer and emit it
+/// once the file is done; callers that want to stream pass stdout directly.
 pub fn process_file(
     path: &Path,
-    regex: &Regex,
-    replacement: &str,
-    dry_run: bool,
-    verbose: bool,
-) -> Result<bool> {
-    let content = fs::read_to_string(path)
+    rules: &[Rule],
+    options: &ProcessOptions,
+    out: &mut dyn Write,
+) -> Result<FileChange> {
+    process_file_in(&RealFs, path, rules, options, out)
+}
+
+/// Processes a single file of `fs`, as [`process_file`] does on disk.
+pub fn process_file_in(
+    fs: &dyn FileSystem,
+    path: &Path,
+    rules: &[Rule],
+    options: &ProcessOptions,
+    out: &mut dyn Write,
+) -> Result<FileChange> {
+    let ProcessOptions { dry_run, verbose, .. } = *options;
+
+    // Taken before reading, so a change made during the read is caught too
+    let seen = fs.metadata(path).ok().map(|metadata| (metadata.len, metadata.modified));
+    let bytes = fs.read(path)
         .with_context(|| format!("Failed to read file: {}", path.display()))?;
+    // The file as read, kept when it isn't valid UTF-8 and had to be decoded
+    let (content, original) = match String::from_utf8(bytes) {
+        Ok(content) => (content, None),
+        Err(e) if options.invalid_utf8 == InvalidUtf8::Fail => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e)).with_context(|| {
+                format!("{} is not valid UTF-8; pass --bytes-fallback or --lossy-utf8 to process it", path.display())
+            });
+        }
+        Err(e) => {
+            let content = options.invalid_utf8.decode(e.as_bytes())
+                .with_context(|| format!("Failed to decode file: {}", path.display()))?;
+            writeln!(out, "Warning: {} is not valid UTF-8; {}", path.display(), options.invalid_utf8.describe())?;
+            (content, Some(e.into_bytes()))
+        }
+    };
+    let utf8_fallback = original.is_some().then_some(options.invalid_utf8);
+    let original = original.as_deref().unwrap_or(content.as_bytes());
+
+    // Rules scoped to other extensions are left out for this file
+    let scoped: Vec<Rule>;
+    let rules = if rules.iter().all(|rule| rule.applies_to(path)) {
+        rules
+    } else {
+        scoped = rules.iter().filter(|rule| rule.applies_to(path)).cloned().collect();
+        &scoped
+    };
+
+    let started = Instant::now();
+    let deadline = options.match_budget.map(|budget| started + budget);
+    let (matched, reused) = match options.dedupe {
+        Some(ref cache) if ContentCache::can_share(rules, options) => {
+            cache.get_or_match(&content, rules, || match_rules(path, &content, rules, options, deadline))?
+        }
+        _ => (match_rules(path, &content, rules, options, deadline)?.map(Arc::new), false),
+    };
+    let Some(matched) = matched else {
+        return Ok(FileChange { match_time: started.elapsed(), abandoned: true, ..Default::default() });
+    };
+    let Matched { stages, mappings, over_limit, mut new_content } = Arc::unwrap_or_clone(matched);
+
+    let remaining = options.remaining.as_ref()
+        .map(|regex| find_remaining(&new_content, regex))
+        .unwrap_or_default();
+
+    let occurrences = options.consistency.as_ref()
+        .map(|regex| consistency::occurrences(&new_content, regex))
+        .unwrap_or_default();
+
+    let shadow_diff = match options.shadow {
+        Some(ref command) => {
+            let theirs = shadow::run(command, path, &content)?;
+            (theirs != new_content).then(|| {
+                let name = path.strip_prefix(&options.root).unwrap_or(path).display().to_string();
+                diff::render_diff(&name, &new_content, &theirs, DiffStyle::Unified)
+            })
+        }
+        None => None,
+    };
+    if let Some(ref diff) = shadow_diff {
+        writeln!(out, "\nShadow command differs on {} (- this tool, + shadow):", path.display())?;
+        write!(out, "{diff}")?;
+    }
+
+    let match_time = started.elapsed();
+    let match_count: usize = stages.iter().map(|(_, reps)| reps.len()).sum();
+    if match_count == 0 {
+        if dry_run && !over_limit.is_empty() {
+            writeln!(out, "\nFile: {}", path.display())?;
+            write_over_limit(out, &over_limit)?;
+        }
+        if !dry_run && let Some(ref mirror) = options.mirror {
+            mirror.put(fs, &options.root, path, None)?;
+        }
+        return Ok(FileChange { remaining, occurrences, shadow_diff, match_time, reused, utf8_fallback, ..Default::default() });
+    }
+
+    if let Some(limit) = options.max_matches
+        && match_count > limit
+    {
+        anyhow::bail!("{match_count} matches exceeds the limit of {limit} per file");
+    }
+
+    if let Some(ref note) = options.annotate
+        && new_content != content
+        && let Some(style) = headers::comment_style(path)
+    {
+        new_content = headers::append_note(&new_content, note, style);
+    }
+
+    if options.line_mode && (verbose || dry_run) {
+        let mut first = true;
+        for (stage_content, replacements) in &stages {
+            let lines = LineIndex::new(stage_content);
+            for rep in replacements {
+                let line = lines.line_of(rep.start);
+                let context = options.show_context.map(|count| lines.context(stage_content, rep.start..rep.end, count));
+                if context.is_some() && !std::mem::take(&mut first) {
+                    writeln!(out, "--")?;
+                }
+                if let Some(ref context) = context {
+                    for (number, text) in (line - context.before.len()..).zip(&context.before) {
+                        writeln!(out, "{}-{number}-{text}", path.display())?;
+                    }
+                }
+                let (old, new) = shown_change(&stage_content[rep.start..rep.end], &rep.text, options.color);
+                writeln!(
+                    out,
+                    "{}:{}:{}: \"{}\" -> \"{}\"",
+                    path.display(),
+                    line,
+                    lines.column_of(stage_content, rep.start),
+                    old,
+                    new
+                )?;
+                if let Some(ref context) = context {
+                    for (number, text) in (line + 1..).zip(&context.after) {
+                        writeln!(out, "{}-{number}-{text}", path.display())?;
+                    }
+                }
+            }
+        }
+        if dry_run {
+            write_over_limit(out, &over_limit)?;
+        }
+    } else if verbose || dry_run {
+        writeln!(out, "\nFile: {}", path.display())?;
+
+        if verbose {
+            writeln!(out, "Found {} matches", match_count)?;
+        }
+        if (verbose && dry_run) || options.show_context.is_some() {
+            let mut number = 0;
+            for (stage_content, replacements) in &stages {
+                let lines = LineIndex::new(stage_content);
+                for rep in replacements {
+                    number += 1;
+                    let line = lines.line_of(rep.start);
+                    let (old, new) = shown_change(&stage_content[rep.start..rep.end], &rep.text, options.color);
+                    writeln!(out, "  Match {} (line {}): \"{}\" -> \"{}\"", number, line, old, new)?;
+                    match options.show_context {
+                        Some(count) => write_context(out, stage_content, &lines, rep, count, options.color)?,
+                        None => {
+                            let text = lines.line_text(stage_content, line);
+                            let shown = text.trim();
+                            if options.color {
+                                // Offsets of the match within the line as shown, without its indentation
+                                let start = lines.line_start(line) + (text.len() - text.trim_start().len());
+                                let span = rep.start.saturating_sub(start)..rep.end.saturating_sub(start);
+                                writeln!(out, "      {}", color::highlight(shown, span))?;
+                            } else {
+                                writeln!(out, "      {shown}")?;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if dry_run {
+            write_over_limit(out, &over_limit)?;
+        }
+    }
+
+    let name = path.strip_prefix(&options.root).unwrap_or(path).display().to_string();
+    if let Some(style) = options.diff {
+        write!(out, "{}", diff::render_diff(&name, &content, &new_content, style))?;
+    }
+    let patch = options.patch
+        .then(|| diff::render_git_patch(&name, &name, &content, &new_content));
+    let patch_hash = options.patch.then(|| preview::content_hash(&content));
+
+    if !dry_run && let Some(ref mirror) = options.mirror {
+        let encoded = encode(&new_content, utf8_fallback);
+        mirror.put(fs, &options.root, path, (new_content != content).then_some(&encoded))?;
+    } else if !dry_run {
+        // Only write if content actually changed (saves disk I/O)
+        if new_content != content {
+            let before = fs.metadata(path).ok();
+            if !options.overwrite_concurrent && before.as_ref().map(|metadata| (metadata.len, metadata.modified)) != seen {
+                writeln!(
+                    out,
+                    "Warning: {} changed on disk while it was being processed; left it as it is (use --overwrite-concurrent to write anyway)",
+                    path.display()
+                )?;
+                return Ok(FileChange { matches: match_count, match_time, reused, changed_on_disk: true, ..Default::default() });
+            }
+            let ownership = before.as_ref()
+                .filter(|_| options.preserve_owner)
+                .map(|metadata| (metadata.owner, metadata.group));
+            let modified = before.and_then(|metadata| metadata.modified).filter(|_| options.preserve_times);
+            if let Some(ref backup) = options.backup {
+                write_backup(fs, path, original, backup)?;
+                if let Some(ownership) = ownership {
+                    restore_owner(fs, &backup.path_for(path), ownership, out)?;
+                }
+                if let Some(modified) = modified {
+                    restore_modified(fs, &backup.path_for(path), modified, out)?;
+                }
+            }
+            let encoded = encode(&new_content, utf8_fallback);
+            fs.write(path, &encoded)
+                .with_context(|| format!("Failed to write file: {}", path.display()))?;
+            if let Some(ownership) = ownership {
+                restore_owner(fs, path, ownership, out)?;
+            }
+            if let Some(modified) = modified {
+                restore_modified(fs, path, modified, out)?;
+            }
+            if options.verify_roundtrip {
+                verify_written(fs, path, &encoded)?;
+            }
+            if options.verify_matches {
+                verify_matches(fs, path, rules, &new_content, utf8_fallback)?;
+            }
+        }
+    }
+
+    let further_changes = if options.check_idempotent { count_changes(&new_content, rules) } else { 0 };
+    if further_changes > 0 {
+        writeln!(
+            out,
+            "Warning: {} is not idempotent: a second pass would make {} more replacement{}",
+            path.display(),
+            further_changes,
+            if further_changes == 1 { "" } else { "s" }
+        )?;
+    }
+
+    let found = if options.record_matches {
+        stages.iter()
+            .flat_map(|(stage_content, replacements)| {
+                let lines = LineIndex::new(stage_content);
+                replacements.iter().map(move |rep| MatchRecord {
+                    line: lines.line_of(rep.start),
+                    column: lines.column_of(stage_content, rep.start),
+                    start: rep.start,
+                    end: rep.end,
+                    text: stage_content[rep.start..rep.end].to_string(),
+                    replacement: rep.text.clone(),
+                    captures: rep.captures.clone(),
+                    context: options.context_lines.map(|count| lines.context(stage_content, rep.start..rep.end, count)),
+                })
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    // Previews hold text, which a decoded file can't be restored from, so
+    // its journal keeps the original bytes instead
+    let preview = (options.preview && utf8_fallback.is_none()).then(|| PreviewFile::new(path, &content, &new_content));
+    let journal = options.journal.then(|| match utf8_fallback {
+        Some(_) => JournalFile::with_bytes(path, original, &encode(&new_content, utf8_fallback)),
+        None => JournalFile::new(path, &content, &new_content),
+    });
+    let (lines_added, lines_removed) = diff::line_stats(&content, &new_content);
+    Ok(FileChange {
+        modified: true,
+        matches: match_count,
+        bytes_delta: new_content.len() as i64 - content.len() as i64,
+        lines_added,
+        lines_removed,
+        patch,
+        patch_hash,
+        remaining,
+        occurrences,
+        shadow_diff,
+        mappings,
+        further_changes,
+        match_time,
+        reused,
+        utf8_fallback,
+        abandoned: false,
+        changed_on_disk: false,
+        found,
+        preview,
+        journal,
+    })
+}
+
+/// The bytes to write for `content`, encoded as the file it came from was
+/// decoded.
+fn encode(content: &str, utf8_fallback: Option<InvalidUtf8>) -> Cow<'_, [u8]> {
+    match utf8_fallback {
+        Some(decoding) => decoding.encode(content),
+        None => Cow::Borrowed(content.as_bytes()),
+    }
+}
+
+/// The matched text and its replacement as a dry run shows them: in red and
+/// green when `color` is set.
+fn shown_change(old: &str, new: &str, color: bool) -> (String, String) {
+    if color { (color::removed(old), color::added(new)) } else { (old.to_string(), new.to_string()) }
+}
+
+/// Shows the lines `rep` spans in `content`, numbered as `grep -n -C` does:
+/// `N:` for the match's lines and `N-` for up to `count` lines around them.
+fn write_context(
+    out: &mut dyn Write,
+    content: &str,
+    lines: &LineIndex,
+    rep: &Replacement,
+    count: usize,
+    color: bool,
+) -> Result<()> {
+    let first = lines.line_of(rep.start);
+    let last = lines.line_of(rep.end.saturating_sub(1).max(rep.start));
+    let context = lines.context(content, rep.start..rep.end, count);
+    for (number, text) in (first - context.before.len()..).zip(&context.before) {
+        writeln!(out, "      {number}-{text}")?;
+    }
+    for number in first..=last {
+        let text = lines.line_text(content, number);
+        if color {
+            let start = lines.line_start(number);
+            let span = rep.start.saturating_sub(start)..rep.end.saturating_sub(start);
+            writeln!(out, "      {number}:{}", color::highlight(text, span))?;
+        } else {
+            writeln!(out, "      {number}:{text}")?;
+        }
+    }
+    for (number, text) in (last + 1..).zip(&context.after) {
+        writeln!(out, "      {number}-{text}")?;
+    }
+    Ok(())
+}
+
+/// Lists the matches a replacement limit left alone, as a dry run shows them.
+fn write_over_limit(out: &mut dyn Write, over_limit: &[RemainingMatch]) -> Result<()> {
+    for m in over_limit {
+        writeln!(out, "  Skipped (line {}): \"{}\" is over the replacement limit", m.line, m.text)?;
+    }
+    Ok(())
+}
+
+/// Reads `path` back and checks it holds exactly `expected`, so a write that
+/// the filesystem or an encoding layer altered is reported rather than
+/// silently left behind.
+fn verify_written(fs: &dyn FileSystem, path: &Path, expected: &[u8]) -> Result<()> {
+    let written = fs.read(path)
+        .with_context(|| format!("Failed to read back file: {}", path.display()))?;
+    if written == expected {
+        return Ok(());
+    }
+    if std::str::from_utf8(&written).is_err() && std::str::from_utf8(expected).is_ok() {
+        anyhow::bail!("Round-trip check failed for {}: content read back no longer decodes as UTF-8", path.display());
+    }
+    let offset = written.iter().zip(expected).take_while(|(a, b)| a == b).count();
+    anyhow::bail!(
+        "Round-trip check failed for {}: content read back differs from what was written at line {}",
+        path.display(),
+        expected[..offset].iter().filter(|&&byte| byte == b'\n').count() + 1
+    )
+}
+
+/// Reads `path` back and checks each rule's pattern matches it as often as
+/// it matches `expected`, the content written: usually not at all. A
+/// concurrent writer or an encoding layer that changed the file shows up as
+/// matches that should not be there, or missing ones that should.
+fn verify_matches(fs: &dyn FileSystem, path: &Path, rules: &[Rule], expected: &str, utf8_fallback: Option<InvalidUtf8>) -> Result<()> {
+    let written = fs.read(path)
+        .with_context(|| format!("Failed to read back file: {}", path.display()))?;
+    let written = match (String::from_utf8(written), utf8_fallback) {
+        (Ok(written), _) => written,
+        (Err(e), Some(decoding)) => decoding.decode(e.as_bytes())?,
+        (Err(_), None) => {
+            anyhow::bail!("Verification failed for {}: content read back no longer decodes as UTF-8", path.display())
+        }
+    };
+    for rule in rules {
+        let wanted = rule.regex.find_iter(expected).count();
+        let found = rule.regex.find_iter(&written).count();
+        if found != wanted {
+            anyhow::bail!(
+                "Verification failed for {}: pattern {} matches {} time{} after writing, expected {}",
+                path.display(),
+                rule.regex,
+                found,
+                if found == 1 { "" } else { "s" },
+                wanted
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Number of replacements `rules` would make to `content` that actually
+/// change it, so rules whose output matches themselves unchanged count as stable.
+pub fn count_changes(content: &str, rules: &[Rule]) -> usize {
+    let mut content = content.to_string();
+    let mut changes = 0;
+    for rule in rules {
+        let replacements = find_replacements(&content, &rule.regex, &rule.replacement);
+        let replacements = rule.finish(&content, replacements);
+        changes += replacements.iter().filter(|rep| content[rep.start..rep.end] != rep.text).count();
+        if !replacements.is_empty() {
+            content = apply_replacements(&content, &replacements);
+        }
+    }
+    changes
+}
+
+/// Every match of `regex` in `content`, with its line number.
+pub fn find_remaining(content: &str, regex: &Regex) -> Vec<RemainingMatch> {
+    let lines = LineIndex::new(content);
+    regex
+        .find_iter(content)
+        .map(|m| RemainingMatch { line: lines.line_of(m.start()), text: m.as_str().to_string() })
+        .collect()
+}
+
+/// A single match within a file and the text it will be replaced with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replacement {
+    pub start: usize,
+    pub end: usize,
+    /// The replacement with capture group references already expanded
+    pub text: String,
+    /// Values of the pattern's named groups, in the order they appear in it
+    pub captures: Vec<(String, Option<String>)>,
+}
+
+/// Finds every match of `regex` in `content` and expands `replacement` for it.
+pub fn find_replacements(content: &str, regex: &Regex, replacement: &str) -> Vec<Replacement> {
+    expand_matches(content, regex, &ReplacementTemplate::lenient(replacement), 0)
+}
+
+/// Expands `template` for every match of `regex` in `content`, numbering
+/// them for `${counter}` from `first`.
+fn expand_matches(content: &str, regex: &Regex, template: &ReplacementTemplate, first: usize) -> Vec<Replacement> {
+    regex.captures_iter(content)
+        .enumerate()
+        .map(|(index, caps)| {
+            let mat = caps.get(0).unwrap();
+            let mut text = String::new();
+            template.expand(&caps, first + index, &mut text);
+            Replacement { start: mat.start(), end: mat.end(), text, captures: named_captures(regex, &caps) }
+        })
+        .collect()
+}
+
+/// Gives each of `replacements` the casing of the text of `content` it
+/// replaces, for rules that preserve case.
+pub fn match_case(content: &str, mut replacements: Vec<Replacement>, folding: CaseFolding) -> Vec<Replacement> {
+    for rep in &mut replacements {
+        rep.text = case::preserve_case(&content[rep.start..rep.end], &rep.text, folding);
+    }
+    replacements
+}
+
+/// Settles where the insertions (empty matches) among `replacements` go:
+///
+/// - one between the `\r` and `\n` of a CRLF line ending, where `(?m)$`
+///   matches, moves before the `\r` so the line ending stays intact;
+/// - none is made after the final newline of `content`, where `(?m)^` and
+///   `(?m)$` match although no line follows;
+/// - with `max_per_line`, only the first that many on each line are kept.
+pub fn place_insertions(content: &str, replacements: Vec<Replacement>, insertions: Insertions) -> Vec<Replacement> {
+    let bytes = content.as_bytes();
+    let lines = insertions.max_per_line.map(|_| LineIndex::new(content));
+    let mut placed: Vec<Replacement> = Vec::with_capacity(replacements.len());
+    let mut line_count = (0, 0);
+    for mut rep in replacements {
+        if rep.start == rep.end {
+            if rep.start == content.len() && content.ends_with('\n') {
+                continue;
+            }
+            if rep.start > 0
+                && bytes[rep.start - 1] == b'\r'
+                && bytes.get(rep.start) == Some(&b'\n')
+                && placed.last().is_none_or(|prev| prev.end < rep.start)
+            {
+                rep.start -= 1;
+                rep.end -= 1;
+                if placed.last().is_some_and(|prev| prev.start == rep.start && prev.end == rep.end) {
+                    continue;
+                }
+            }
+            if let (Some(limit), Some(lines)) = (insertions.max_per_line, &lines) {
+                let line = lines.line_of(rep.start);
+                if line_count.0 != line {
+                    line_count = (line, 0);
+                }
+                if line_count.1 == limit {
+                    continue;
+                }
+                line_count.1 += 1;
+            }
+        }
+        placed.push(rep);
+    }
+    placed
+}
+
+/// Like [`find_replacements`], but searches `content` one chunk at a time so
+/// the deadline can be checked in between. Returns `None` once it has passed.
+///
+/// Each search sees the chunk plus the overlap; a match that runs into the
+/// end of that window is searched again against the full content. Matches
+/// that need text beyond the window to match at all can still be missed, so
+/// the overlap must exceed the longest expected match and chunking is opt-in.
+pub fn find_replacements_chunked(
+    content: &str,
+    rule: &Rule,
+    chunking: Chunking,
+    deadline: Option<Instant>,
+) -> Option<Vec<Replacement>> {
+    if chunking.parallel {
+        return find_replacements_parallel(content, rule, chunking, deadline);
+    }
+
+    let mut replacements = Vec::new();
+    let mut pos = 0;
+    let mut last_end = None;
+
+    loop {
+        if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+            return None;
+        }
+
+        let chunk_end = content.ceil_char_boundary(pos + chunking.size.max(1));
+        let at = search_chunk(content, rule, chunking.overlap, pos..chunk_end, &mut last_end, &mut replacements);
+        if chunk_end == content.len() {
+            break;
+        }
+        pos = at.max(chunk_end);
+    }
+
+    Some(replacements)
+}
+
+/// Like [`find_replacements_chunked`], but searches every chunk at once on
+/// the current rayon thread pool, then stitches the results together. A
+/// chunk whose start was overrun by the previous chunk's last match is
+/// searched again from where that match ended, so the result is the same.
+fn find_replacements_parallel(
+    content: &str,
+    rule: &Rule,
+    chunking: Chunking,
+    deadline: Option<Instant>,
+) -> Option<Vec<Replacement>> {
+    let mut bounds = vec![0];
+    loop {
+        let chunk_end = content.ceil_char_boundary(bounds[bounds.len() - 1] + chunking.size.max(1));
+        bounds.push(chunk_end);
+        if chunk_end == content.len() {
+            break;
+        }
+    }
+
+    let found: Vec<Option<(Vec<Replacement>, usize)>> = bounds
+        .par_windows(2)
+        .map(|chunk| {
+            if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+                return None;
+            }
+            let mut replacements = Vec::new();
+            let at = search_chunk(content, rule, chunking.overlap, chunk[0]..chunk[1], &mut None, &mut replacements);
+            Some((replacements, at))
+        })
+        .collect();
+
+    let mut replacements = Vec::new();
+    let mut last_end = None;
+    let mut resume = 0;
+    for (chunk, result) in bounds.windows(2).zip(found) {
+        let (mut chunk_replacements, mut at) = result?;
+        let overrun = resume > chunk[0]
+            || chunk_replacements.first().is_some_and(|rep| rep.start == rep.end && last_end == Some(rep.start));
+        if overrun {
+            chunk_replacements.clear();
+            at = search_chunk(content, rule, chunking.overlap, resume.max(chunk[0])..chunk[1], &mut last_end, &mut chunk_replacements);
+        }
+        if let Some(last) = chunk_replacements.last() {
+            last_end = Some(last.end);
+        }
+        replacements.append(&mut chunk_replacements);
+        resume = at;
+    }
+
+    Some(replacements)
+}
+
+/// Finds the matches starting in `chunk`, searching the content up to the
+/// end of the chunk plus `overlap`. `last_end` is where the previous match
+/// ended, and is updated. Returns the position after the last match, from
+/// which the search continues.
+fn search_chunk(
+    content: &str,
+    rule: &Rule,
+    overlap: usize,
+    chunk: Range<usize>,
+    last_end: &mut Option<usize>,
+    replacements: &mut Vec<Replacement>,
+) -> usize {
+    let chunk_end = chunk.end;
+    let window_end = content.ceil_char_boundary(chunk_end + overlap);
+    let window = &content[..window_end];
+    let template = ReplacementTemplate::lenient(&rule.replacement);
+
+    let mut at = chunk.start;
+    while at <= window_end {
+        let Some(mut caps) = rule.regex.captures_at(window, at) else { break };
+        let mut mat = caps.get(0).unwrap();
+        if mat.start() >= chunk_end && chunk_end < content.len() {
+            break;
+        }
+        if mat.end() == window_end && window_end < content.len() {
+            // Possibly cut short by the window; let the full content decide
+            caps = rule.regex.captures_at(content, mat.start()).unwrap();
+            mat = caps.get(0).unwrap();
+        }
+        // Like captures_iter, don't allow an empty match right after the previous match
+        if mat.is_empty() && *last_end == Some(mat.start()) {
+            at = next_char(content, mat.start());
+            continue;
+        }
+
+        let mut text = String::new();
+        template.expand(&caps, replacements.len(), &mut text);
+        replacements.push(Replacement {
+            start: mat.start(),
+            end: mat.end(),
+            text,
+            captures: named_captures(&rule.regex, &caps),
+        });
+        *last_end = Some(mat.end());
+        at = if mat.is_empty() { next_char(content, mat.end()) } else { mat.end() };
+    }
+    at
+}
+
+fn named_captures(regex: &Regex, caps: &Captures) -> Vec<(String, Option<String>)> {
+    regex.capture_names()
+        .flatten()
+        .map(|name| (name.to_string(), caps.name(name).map(|m| m.as_str().to_string())))
+        .collect()
+}
+
+fn next_char(content: &str, pos: usize) -> usize {
+    content[pos..].chars().next().map_or(pos + 1, |c| pos + c.len_utf8())
+}
+
+/// Builds the new content by splicing `replacements` (sorted, non-overlapping) into `content`.
+pub fn apply_replacements(content: &str, replacements: &[Replacement]) -> String {
+    let mut result = String::with_capacity(content.len());
+    let mut last = 0;
+    for rep in replacements {
+        result.push_str(&content[last..rep.start]);
+        result.push_str(&rep.text);
+        last = rep.end;
+    }
+    result.push_str(&content[last..]);
+    result
+}
+
+/// Copies `path`, whose original is `content`, to its backup file, refusing
+/// to replace an existing backup unless forced. The copy is a clone where
+/// the filesystem allows, so backups of large trees cost next to nothing;
+/// if it can't be made, or its content isn't what was read because the file
+/// changed since, `content` is written out instead.
+fn write_backup(fs: &dyn FileSystem, path: &Path, content: &[u8], backup: &Backup) -> Result<()> {
+    let backup_path = backup.path_for(path);
+    if !backup.force && fs.exists(&backup_path) {
+        anyhow::bail!("Backup already exists: {} (use --force-backup to overwrite it)", backup_path.display());
+    }
+    let copied = fs.copy(path, &backup_path).is_ok()
+        && fs.read(&backup_path).is_ok_and(|copy| preview::content_hash(copy) == preview::content_hash(content));
+    if copied {
+        return Ok(());
+    }
+    fs.write(&backup_path, content)
+        .with_context(|| format!("Failed to write backup: {}", backup_path.display()))
+}
+
+/// Gives `path` the owner and group in `ownership` if writing it left it with
+/// others, as when a privileged run creates or replaces a file. Failing to
+/// is reported as a warning, since the content is already written.
+fn restore_owner(
+    fs: &dyn FileSystem,
+    path: &Path,
+    (owner, group): (Option<u32>, Option<u32>),
+    out: &mut dyn Write,
+) -> Result<()> {
+    let current = fs.metadata(path).ok();
+    let kept = |before: Option<u32>, after: Option<u32>| before.is_none() || before == after;
+    if current.is_some_and(|metadata| kept(owner, metadata.owner) && kept(group, metadata.group)) {
+        return Ok(());
+    }
+    if let Err(e) = fs.chown(path, owner, group) {
+        writeln!(out, "Warning: could not restore the owner of {}: {e}", path.display())?;
+    }
+    Ok(())
+}
 
-    if !regex.is_match(&content) {
-        return Ok(false);
+/// Gives `path` back the modification time it had before it was written,
+/// warning if that fails.
+fn restore_modified(fs: &dyn FileSystem, path: &Path, modified: SystemTime, out: &mut dyn Write) -> Result<()> {
+    if let Err(e) = fs.set_modified(path, modified) {
+        writeln!(out, "Warning: could not restore the modification time of {}: {e}", path.display())?;
     }
+    Ok(())
+}
 
-    let new_content = regex.replace_all(&content, replacement);
+/// Maps byte offsets in a file's content to 1-based line numbers.
+pub struct LineIndex {
+    starts: Vec<usize>,
+}
 
-    if verbose || dry_run {
-        println!("\nFile: {}", path.display());
+impl LineIndex {
+    pub fn new(content: &str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(content.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { starts }
+    }
 
-        if verbose {
-            let matches: Vec<_> = regex.find_iter(&content).collect();
-            println!("Found {} matches", matches.len());
-
-            if dry_run {
-                for (i, mat) in matches.iter().enumerate() {
-                    println!(
-                        "  Match {}: \"{}\" -> \"{}\"",
-                        i + 1,
-                        &content[mat.start()..mat.end()],
-                        replacement
-                    );
-                }
-            }
-        }
+    /// The 1-based line containing byte `offset`.
+    pub fn line_of(&self, offset: usize) -> usize {
+        self.starts.partition_point(|&start| start <= offset)
     }
 
-    if !dry_run {
-        // Only write if content actually changed (saves disk I/O)
-        if new_content != content {
-            fs::write(path, new_content.as_ref())
-                .with_context(|| format!("Failed to write file: {}", path.display()))?;
-        }
+    /// The byte offset at which 1-based `line` starts.
+    pub fn line_start(&self, line: usize) -> usize {
+        self.starts[line - 1]
+    }
+
+    /// The 1-based column, in characters, of byte `offset` on its line.
+    pub fn column_of(&self, content: &str, offset: usize) -> usize {
+        let start = self.starts[self.line_of(offset) - 1];
+        content[start..offset].chars().count() + 1
     }
 
-    Ok(true)
+    /// The text of 1-based `line`, without its line terminator.
+    pub fn line_text<'a>(&self, content: &'a str, line: usize) -> &'a str {
+        let start = self.starts[line - 1];
+        let end = self.starts.get(line).map(|&next| next - 1).unwrap_or(content.len());
+        content[start..end].trim_end_matches('\r')
+    }
+
+    /// Up to `count` lines before and after the lines `span` covers.
+    pub fn context(&self, content: &str, span: Range<usize>, count: usize) -> MatchContext {
+        let first = self.line_of(span.start);
+        let last = self.line_of(span.end.saturating_sub(1).max(span.start));
+        let lines = |range: Range<usize>| range.map(|line| self.line_text(content, line).to_string()).collect();
+        // A trailing newline ends the last line rather than starting another
+        let line_count = self.starts.len() - usize::from(content.ends_with('\n'));
+        MatchContext {
+            before: lines(first.saturating_sub(count).max(1)..first),
+            after: lines(last + 1..(last + 1 + count).min(line_count + 1)),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -63,9 +1615,9 @@ mod tests {
         fs::write(&file_path, "Hello 123 World 456")?;
 
         let regex = Regex::new(r"\d+")?;
-        let modified = process_file(&file_path, &regex, "XXX", false, false)?;
+        let modified = process_file(&file_path, &[Rule::new(regex, "XXX")], &ProcessOptions::default(), &mut std::io::sink())?;
 
-        assert!(modified);
+        assert!(modified.modified);
         let content = fs::read_to_string(&file_path)?;
         assert_eq!(content, "Hello XXX World XXX");
 
@@ -79,15 +1631,327 @@ mod tests {
         fs::write(&file_path, "Hello World")?;
 
         let regex = Regex::new(r"\d+")?;
-        let modified = process_file(&file_path, &regex, "XXX", false, false)?;
+        let modified = process_file(&file_path, &[Rule::new(regex, "XXX")], &ProcessOptions::default(), &mut std::io::sink())?;
 
-        assert!(!modified);
+        assert!(!modified.modified);
         let content = fs::read_to_string(&file_path)?;
         assert_eq!(content, "Hello World");
 
         Ok(())
     }
 
+    #[test]
+    fn test_process_file_reports_remaining() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test.txt");
+        fs::write(&file_path, "name: {{name}}\nport: {{port}}\n")?;
+
+        let options = ProcessOptions {
+            dry_run: true,
+            remaining: Some(Regex::new(r"\{\{\w+\}\}")?),
+            ..Default::default()
+        };
+        let change = process_file(&file_path, &[Rule::new(Regex::new(r"\{\{name\}\}")?, "demo")], &options, &mut std::io::sink())?;
+
+        assert_eq!(change.remaining, [RemainingMatch { line: 2, text: "{{port}}".to_string() }]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_idempotent() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test.txt");
+        fs::write(&file_path, "foo bar")?;
+
+        let options = ProcessOptions { dry_run: true, check_idempotent: true, ..Default::default() };
+        let doubling = Rule::new(Regex::new("foo")?, "foofoo");
+        let change = process_file(&file_path, &[doubling], &options, &mut std::io::sink())?;
+        assert_eq!(change.further_changes, 2);
+
+        let stable = Rule::new(Regex::new(r"\bbar\b")?, "baz");
+        let change = process_file(&file_path, &[stable], &options, &mut std::io::sink())?;
+        assert_eq!(change.further_changes, 0);
+
+        // Rewriting a match to itself changes nothing, so it counts as stable
+        assert_eq!(count_changes("color", &[Rule::new(Regex::new("colou?r")?, "color")]), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunked_matches_agree_with_unchunked() {
+        let content = "alpha beta\ngamma ümlaut delta\n\nepsilon zeta eta theta\n";
+        let cases = [(r"\w+", "<$0>"), (r"a*", "-"), (r"(?m)^", "> "), (r"\b", "|"), (r"(?m)\s+$", ""), (r"ü\w+", "u")];
+        for (pattern, replacement) in cases {
+            let rule = Rule::new(Regex::new(pattern).unwrap(), replacement);
+            let expected = find_replacements(content, &rule.regex, replacement);
+            for size in [1, 3, 7, 16] {
+                let chunking = Chunking { size, overlap: 8, parallel: false };
+                let chunked = find_replacements_chunked(content, &rule, chunking, None).unwrap();
+                assert_eq!(chunked, expected, "pattern {pattern:?}, chunk size {size}");
+                let parallel = Chunking { parallel: true, ..chunking };
+                let chunked = find_replacements_chunked(content, &rule, parallel, None).unwrap();
+                assert_eq!(chunked, expected, "pattern {pattern:?}, chunk size {size}, in parallel");
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunked_match_across_chunk_boundary() {
+        let content = format!("{}start xxxx end{}", "-".repeat(8), "-".repeat(20));
+        let rule = Rule::new(Regex::new(r"start x+ end").unwrap(), "short");
+        let chunked = find_replacements_chunked(&content, &rule, Chunking { size: 10, overlap: 16, parallel: false }, None).unwrap();
+        assert_eq!(chunked, find_replacements(&content, &rule.regex, "short"));
+
+        // Greedy matches cut off by the window are extended against the full content
+        let content = "a".repeat(50);
+        let rule = Rule::new(Regex::new("a+").unwrap(), "b");
+        let chunked = find_replacements_chunked(&content, &rule, Chunking { size: 10, overlap: 4, parallel: false }, None).unwrap();
+        assert_eq!(chunked, find_replacements(&content, &rule.regex, "b"));
+        let chunked = find_replacements_chunked(&content, &rule, Chunking { size: 10, overlap: 4, parallel: true }, None).unwrap();
+        assert_eq!(chunked, find_replacements(&content, &rule.regex, "b"));
+    }
+
+    #[test]
+    fn test_chunked_matching_gives_up_after_deadline() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test.txt");
+        fs::write(&file_path, "a".repeat(1000))?;
+
+        let options = ProcessOptions {
+            chunking: Some(Chunking { size: 10, overlap: 2, parallel: false }),
+            match_budget: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        std::thread::sleep(Duration::from_millis(1));
+        let change = process_file(&file_path, &[Rule::new(Regex::new("a")?, "b")], &options, &mut std::io::sink())?;
+        assert!(change.abandoned);
+        assert!(!change.modified);
+        assert_eq!(fs::read_to_string(&file_path)?, "a".repeat(1000));
+        Ok(())
+    }
+
+    #[test]
+    fn test_files_changed_during_the_run_are_left_alone() -> Result<()> {
+        let fs = Arc::new(crate::filesystem::MemoryFs::new());
+        fs.add_file("/a.txt", "old");
+        // Another writer gets in between the read and the write
+        let writer = fs.clone();
+        let interfere = MatchHook::new(move |_| {
+            writer.add_file("/a.txt", "newer content");
+            MatchDecision::Accept
+        });
+        let rules = [Rule::new(Regex::new("old")?, "new")];
+        let mut options = ProcessOptions { on_match: Some(interfere), ..Default::default() };
+
+        let mut out = Vec::new();
+        let outcome = process_path(fs.as_ref(), Path::new("/a.txt"), &rules, &options, &mut out);
+        assert!(matches!(outcome.status, FileStatus::ChangedOnDisk));
+        assert!(String::from_utf8(out)?.contains("Warning: /a.txt changed on disk"));
+        assert_eq!(fs.contents("/a.txt").as_deref(), Some("newer content"));
+
+        fs.add_file("/a.txt", "old");
+        options.overwrite_concurrent = true;
+        let outcome = process_path(fs.as_ref(), Path::new("/a.txt"), &rules, &options, &mut std::io::sink());
+        assert!(matches!(outcome.status, FileStatus::Modified));
+        assert_eq!(fs.contents("/a.txt").as_deref(), Some("new"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_ranges() -> Result<()> {
+        assert_eq!("10-50".parse(), Ok(LineRange { first: 10, last: Some(50) }));
+        assert_eq!("10-".parse(), Ok(LineRange { first: 10, last: None }));
+        assert_eq!("12".parse(), Ok(LineRange { first: 12, last: Some(12) }));
+        assert!("50-10".parse::<LineRange>().is_err());
+        assert!("0-3".parse::<LineRange>().is_err());
+        assert!("a-b".parse::<LineRange>().is_err());
+
+        let fs = crate::filesystem::MemoryFs::new();
+        fs.add_file("/a.txt", "x\nx x\nx\nx\nx\n");
+        let options = ProcessOptions { lines: vec!["2".parse().unwrap(), "4-".parse().unwrap()], ..Default::default() };
+        let change = process_file_in(&fs, Path::new("/a.txt"), &[Rule::new(Regex::new("x")?, "y")], &options, &mut std::io::sink())?;
+        assert_eq!(change.matches, 4);
+        assert_eq!(fs.contents("/a.txt").as_deref(), Some("x\ny y\nx\ny\ny\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_replacement_limits() -> Result<()> {
+        let fs = crate::filesystem::MemoryFs::new();
+        fs.add_file("/a.txt", "x x\nx\n");
+        fs.add_file("/b.txt", "x x\n");
+        let rules = [Rule::new(Regex::new("x")?, "y")];
+        let budget = ReplacementBudget::new(3);
+        let options = ProcessOptions { max_per_file: Some(2), max_total: Some(budget.clone()), ..Default::default() };
+
+        let change = process_file_in(&fs, Path::new("/a.txt"), &rules, &options, &mut std::io::sink())?;
+        assert_eq!(change.matches, 2);
+        assert_eq!(fs.contents("/a.txt").as_deref(), Some("y y\nx\n"));
+
+        let dry_run = ProcessOptions { dry_run: true, ..options.clone() };
+        let mut out = Vec::new();
+        let change = process_file_in(&fs, Path::new("/b.txt"), &rules, &dry_run, &mut out)?;
+        assert_eq!(change.matches, 1);
+        assert!(String::from_utf8(out)?.contains("Skipped (line 1): \"x\" is over the replacement limit"));
+
+        let change = process_file_in(&fs, Path::new("/b.txt"), &rules, &options, &mut std::io::sink())?;
+        assert_eq!(change.matches, 0);
+        assert_eq!(budget.take(1), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedupe_content() -> Result<()> {
+        let fs = crate::filesystem::MemoryFs::new();
+        fs.add_file("/a/lib.txt", "x x\n");
+        fs.add_file("/b/lib.txt", "x x\n");
+        fs.add_file("/c.txt", "x\n");
+        fs.add_file("/d.md", "x x\n");
+        let rules = [Rule::new(Regex::new("x")?, "y"), Rule::new(Regex::new("x")?, "z").for_extensions(vec!["md".to_string()])];
+        let options = ProcessOptions { dedupe: Some(ContentCache::default()), ..Default::default() };
+
+        let mut changes = Vec::new();
+        for path in ["/a/lib.txt", "/b/lib.txt", "/c.txt", "/d.md"] {
+            changes.push(process_file_in(&fs, Path::new(path), &rules, &options, &mut std::io::sink())?);
+        }
+        let reused: Vec<bool> = changes.iter().map(|change| change.reused).collect();
+        assert_eq!(reused, [false, true, false, false]);
+        assert_eq!(changes[1].matches, 2);
+        assert_eq!(fs.contents("/b/lib.txt").as_deref(), Some("y y\n"));
+        assert_eq!(fs.contents("/c.txt").as_deref(), Some("y\n"));
+
+        // The rules would number matches across files, so nothing is shared
+        fs.add_file("/a/lib.txt", "x x\n");
+        fs.add_file("/b/lib.txt", "x x\n");
+        let rules = [Rule::new(Regex::new("x")?, "${counter:scope=global}")];
+        for path in ["/a/lib.txt", "/b/lib.txt"] {
+            assert!(!process_file_in(&fs, Path::new(path), &rules, &options, &mut std::io::sink())?.reused);
+        }
+        assert_eq!(fs.contents("/b/lib.txt").as_deref(), Some("3 4\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_mirror() -> Result<()> {
+        let fs = crate::filesystem::MemoryFs::new();
+        fs.add_file("/src/a/x.txt", "old\n");
+        fs.add_file("/src/y.txt", "kept\n");
+        let rules = [Rule::new(Regex::new("old")?, "new")];
+        let mirror = Mirror { dir: PathBuf::from("/out"), unchanged: MirrorUnchanged::Skip };
+        let options = ProcessOptions { root: PathBuf::from("/src"), mirror: Some(mirror.clone()), ..Default::default() };
+
+        for path in ["/src/a/x.txt", "/src/y.txt"] {
+            process_file_in(&fs, Path::new(path), &rules, &options, &mut std::io::sink())?;
+        }
+        assert_eq!(fs.contents("/out/a/x.txt").as_deref(), Some("new\n"));
+        assert_eq!(fs.contents("/src/a/x.txt").as_deref(), Some("old\n"));
+        assert_eq!(fs.contents("/out/y.txt"), None);
+
+        let copying = ProcessOptions { mirror: Some(Mirror { unchanged: MirrorUnchanged::Copy, ..mirror.clone() }), ..options };
+        process_file_in(&fs, Path::new("/src/y.txt"), &rules, &copying, &mut std::io::sink())?;
+        assert_eq!(fs.contents("/out/y.txt").as_deref(), Some("kept\n"));
+
+        // With several roots, files are kept under their full path
+        assert_eq!(mirror.path_for(Path::new(""), Path::new("/src/a/x.txt")), Path::new("/out/src/a/x.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_mode() -> Result<()> {
+        let fs = crate::filesystem::MemoryFs::new();
+        fs.add_file("/a.txt", "ab\r\nb a\nab");
+        let options = ProcessOptions { line_mode: true, dry_run: true, ..Default::default() };
+        let mut out = Vec::new();
+        let rules = [Rule::new(Regex::new(r"^a|b$|b\s+b")?, "<$0>")];
+        process_file_in(&fs, Path::new("/a.txt"), &rules, &options, &mut out)?;
+        assert_eq!(String::from_utf8(out)?, "/a.txt:1:1: \"a\" -> \"<a>\"\n/a.txt:1:2: \"b\" -> \"<b>\"\n/a.txt:3:1: \"a\" -> \"<a>\"\n/a.txt:3:2: \"b\" -> \"<b>\"\n");
+
+        let rule = Rule::new(Regex::new("x")?, "${counter}");
+        assert_eq!(apply_replacements("x\nx x", &rule.find_in_lines("x\nx x")), "1\n2 3");
+        Ok(())
+    }
+
+    #[test]
+    fn test_colored_dry_run() -> Result<()> {
+        let fs = crate::filesystem::MemoryFs::new();
+        fs.add_file("/a.txt", "    let colour = 1;\n");
+        let options = ProcessOptions { dry_run: true, verbose: true, color: true, ..Default::default() };
+        let mut out = Vec::new();
+        process_file_in(&fs, Path::new("/a.txt"), &[Rule::new(Regex::new("colour")?, "color")], &options, &mut out)?;
+        let out = String::from_utf8(out)?;
+        assert!(out.contains("  Match 1 (line 1): \"\x1b[31mcolour\x1b[0m\" -> \"\x1b[32mcolor\x1b[0m\"\n"), "{out}");
+        assert!(out.contains("      let \x1b[31mcolour\x1b[0m = 1;\n"), "{out}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_utf8() -> Result<()> {
+        let fs = crate::filesystem::MemoryFs::new();
+        let latin1 = b"name = caf\xe9\nversion = 1\n";
+        fs.add_file("/a.txt", latin1.to_vec());
+        let rules = [Rule::new(Regex::new("version = 1")?, "version = 2")];
+
+        let err = process_file_in(&fs, Path::new("/a.txt"), &rules, &ProcessOptions::default(), &mut std::io::sink()).unwrap_err();
+        assert!(format!("{err:#}").contains("invalid utf-8"), "{err:#}");
+
+        let bytes = ProcessOptions { invalid_utf8: InvalidUtf8::Bytes, ..Default::default() };
+        let mut out = Vec::new();
+        let change = process_file_in(&fs, Path::new("/a.txt"), &rules, &bytes, &mut out)?;
+        assert_eq!(change.utf8_fallback, Some(InvalidUtf8::Bytes));
+        assert!(String::from_utf8(out)?.contains("Warning: /a.txt is not valid UTF-8; matched byte for byte"));
+        assert_eq!(fs.read(Path::new("/a.txt"))?, b"name = caf\xe9\nversion = 2\n");
+
+        let lossy = ProcessOptions { invalid_utf8: InvalidUtf8::Lossy, ..Default::default() };
+        process_file_in(&fs, Path::new("/a.txt"), &[Rule::new(Regex::new("2")?, "3")], &lossy, &mut std::io::sink())?;
+        assert_eq!(fs.contents("/a.txt").as_deref(), Some("name = caf\u{FFFD}\nversion = 3\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_show_context() -> Result<()> {
+        let fs = crate::filesystem::MemoryFs::new();
+        fs.add_file("/a.txt", "one\ntwo\n  old\nfour\nfive\nsix\n");
+        let options = ProcessOptions { dry_run: true, show_context: Some(1), ..Default::default() };
+        let mut out = Vec::new();
+        process_file_in(&fs, Path::new("/a.txt"), &[Rule::new(Regex::new("old")?, "new")], &options, &mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "\nFile: /a.txt\n  Match 1 (line 3): \"old\" -> \"new\"\n      2-two\n      3:  old\n      4-four\n"
+        );
+
+        let line_mode = ProcessOptions { line_mode: true, ..options };
+        let mut out = Vec::new();
+        process_file_in(&fs, Path::new("/a.txt"), &[Rule::new(Regex::new("^(one|six)$")?, "x")], &line_mode, &mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "/a.txt:1:1: \"one\" -> \"x\"\n/a.txt-2-two\n--\n/a.txt-5-five\n/a.txt:6:1: \"six\" -> \"x\"\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_path_outcomes() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test.txt");
+        fs::write(&file_path, "a1 b22")?;
+
+        let rules = [Rule::new(Regex::new(r"\d+")?, "#")];
+        let outcome = process_path(&RealFs, &file_path, &rules, &ProcessOptions::default(), &mut std::io::sink());
+        assert!(matches!(outcome.status, FileStatus::Modified));
+        assert_eq!(outcome.matches, 2);
+        assert_eq!(outcome.bytes_delta, -1);
+        assert!(outcome.error.is_none());
+
+        let outcome = process_path(&RealFs, &file_path, &rules, &ProcessOptions::default(), &mut std::io::sink());
+        assert!(matches!(outcome.status, FileStatus::Unchanged));
+
+        let missing = temp_dir.path().join("missing.txt");
+        let outcome = process_path(&RealFs, &missing, &rules, &ProcessOptions::default(), &mut std::io::sink());
+        assert!(matches!(outcome.status, FileStatus::Failed));
+        assert!(outcome.error.unwrap().to_string().contains("Failed to read file"));
+        Ok(())
+    }
+
     #[test]
     fn test_process_file_dry_run() -> Result<()> {
         let temp_dir = TempDir::new()?;
@@ -96,9 +1960,9 @@ mod tests {
         fs::write(&file_path, original_content)?;
 
         let regex = Regex::new(r"\d+")?;
-        let modified = process_file(&file_path, &regex, "XXX", true, false)?;
+        let modified = process_file(&file_path, &[Rule::new(regex, "XXX")], &ProcessOptions { dry_run: true, ..Default::default() }, &mut std::io::sink())?;
 
-        assert!(modified);
+        assert!(modified.modified);
         let content = fs::read_to_string(&file_path)?;
         assert_eq!(content, original_content);
 
@@ -112,9 +1976,9 @@ mod tests {
         fs::write(&file_path, "foo bar foo baz foo")?;
 
         let regex = Regex::new(r"foo")?;
-        let modified = process_file(&file_path, &regex, "replaced", false, false)?;
+        let modified = process_file(&file_path, &[Rule::new(regex, "replaced")], &ProcessOptions::default(), &mut std::io::sink())?;
 
-        assert!(modified);
+        assert!(modified.modified);
         let content = fs::read_to_string(&file_path)?;
         assert_eq!(content, "replaced bar replaced baz replaced");
 
@@ -128,21 +1992,400 @@ mod tests {
         fs::write(&file_path, "Hello $world$ and $universe$")?;
 
         let regex = Regex::new(r"\$(\w+)\$")?;
-        let modified = process_file(&file_path, &regex, "[$1]", false, false)?;
+        let modified = process_file(&file_path, &[Rule::new(regex, "[$1]")], &ProcessOptions::default(), &mut std::io::sink())?;
 
-        assert!(modified);
+        assert!(modified.modified);
         let content = fs::read_to_string(&file_path)?;
         assert_eq!(content, "Hello [world] and [universe]");
 
         Ok(())
     }
 
+    #[test]
+    fn test_dry_run_preview_shows_expanded_replacement() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test.txt");
+        fs::write(&file_path, "Hello $world$")?;
+
+        let regex = Regex::new(r"\$(\w+)\$")?;
+        let mut out = Vec::new();
+        process_file(&file_path, &[Rule::new(regex, "[$1]")], &ProcessOptions { dry_run: true, verbose: true, ..Default::default() }, &mut out)?;
+
+        let out = String::from_utf8(out)?;
+        assert!(out.contains(r#"Match 1 (line 1): "$world$" -> "[world]""#), "unexpected output: {out}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run_preview_shows_line_context() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test.rs");
+        fs::write(&file_path, "fn main() {\n    let x = 42;\r\n}\n")?;
+
+        let regex = Regex::new(r"\d+")?;
+        let mut out = Vec::new();
+        process_file(&file_path, &[Rule::new(regex, "7")], &ProcessOptions { dry_run: true, verbose: true, ..Default::default() }, &mut out)?;
+
+        let out = String::from_utf8(out)?;
+        assert!(out.contains(r#"Match 1 (line 2): "42" -> "7""#), "unexpected output: {out}");
+        assert!(out.contains("      let x = 42;\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_index() {
+        let content = "a\nbb\n\nccc";
+        let lines = LineIndex::new(content);
+        assert_eq!(lines.line_of(0), 1);
+        assert_eq!(lines.line_of(1), 1);
+        assert_eq!(lines.line_of(2), 2);
+        assert_eq!(lines.line_of(5), 3);
+        assert_eq!(lines.line_of(6), 4);
+        assert_eq!(lines.line_text(content, 2), "bb");
+        assert_eq!(lines.line_text(content, 3), "");
+        assert_eq!(lines.line_text(content, 4), "ccc");
+    }
+
+    #[test]
+    fn test_match_context() {
+        let content = "one\ntwo\r\nthree x\ny\nfour\n";
+        let lines = LineIndex::new(content);
+        let strings = |lines: &[&str]| lines.iter().map(|line| line.to_string()).collect::<Vec<_>>();
+        // A match spanning lines 3 and 4
+        let context = lines.context(content, 15..18, 2);
+        assert_eq!(context.before, strings(&["one", "two"]));
+        assert_eq!(context.after, strings(&["four"]));
+        let context = lines.context(content, 0..3, 1);
+        assert!(context.before.is_empty());
+        assert_eq!(context.after, strings(&["two"]));
+    }
+
+    #[test]
+    fn test_preserve_case_rule() -> Result<()> {
+        let fs = crate::filesystem::MemoryFs::new();
+        fs.add_file("/a.rs", "let foo = Foo::new(FOO_MAX); // fOo\n");
+        let regex = regex::RegexBuilder::new("foo").case_insensitive(true).build()?;
+        let rules = [Rule::new(regex, "bar").preserving_case()];
+
+        let options = ProcessOptions { check_idempotent: true, ..Default::default() };
+        let change = process_file_in(&fs, Path::new("/a.rs"), &rules, &options, &mut std::io::sink())?;
+        assert_eq!(fs.contents("/a.rs").as_deref(), Some("let bar = Bar::new(BAR_MAX); // bar\n"));
+        assert_eq!(change.further_changes, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_then_rules_apply_within_matches() {
+        let content = "fn add(a: i32, b: i32) -> i32 { let c: i32 = a; c }\n";
+        let rule = Rule::new(Regex::new(r"fn \w+\([^)]*\)").unwrap(), "$0")
+            .then(Rule::new(Regex::new(r"\bi32\b").unwrap(), "i64"))
+            .then(Rule::new(Regex::new(r"\b([ab]): ").unwrap(), "${1}_: "));
+        let replacements = rule.finish(content, find_replacements(content, &rule.regex, &rule.replacement));
+
+        assert_eq!(
+            apply_replacements(content, &replacements),
+            "fn add(a_: i64, b_: i64) -> i32 { let c: i32 = a; c }\n"
+        );
+        assert_eq!(count_changes(content, &[rule]), 1);
+    }
+
+    #[test]
+    fn test_place_insertions() {
+        let place = |content: &str, pattern: &str, max_per_line: Option<usize>| {
+            let replacements = find_replacements(content, &Regex::new(pattern).unwrap(), "|");
+            let insertions = Insertions { forbid: false, max_per_line };
+            apply_replacements(content, &place_insertions(content, replacements, insertions))
+        };
+
+        // No phantom line after the final newline
+        assert_eq!(place("a\nb\n", "(?m)^", None), "|a\n|b\n");
+        assert_eq!(place("a\nb", "(?m)$", None), "a|\nb|");
+        // CRLF line endings are kept whole
+        assert_eq!(place("a\r\nb\r\n", "(?m)$", None), "a|\r\nb|\r\n");
+        assert_eq!(place("\r\n", "x*", None), "|\r\n");
+        // Only the first insertions on each line
+        assert_eq!(place("one two three\nfour five\n", r"\b", Some(2)), "|one| two three\n|four| five\n");
+        assert_eq!(place("aXa\n", "X*", Some(1)), "|a|a\n");
+    }
+
+    #[test]
+    fn test_empty_matches_can_be_forbidden() -> Result<()> {
+        let fs = crate::filesystem::MemoryFs::new();
+        fs.add_file("/a.txt", "line\n");
+        let options = ProcessOptions { insertions: Insertions { forbid: true, max_per_line: None }, ..Default::default() };
+
+        let err = process_file_in(&fs, Path::new("/a.txt"), &[Rule::new(Regex::new("(?m)^")?, "> ")], &options, &mut std::io::sink()).unwrap_err();
+        assert!(err.to_string().contains("--allow-empty-matches"));
+        process_file_in(&fs, Path::new("/a.txt"), &[Rule::new(Regex::new("(?m)^line")?, "> line")], &options, &mut std::io::sink())?;
+        assert_eq!(fs.contents("/a.txt").as_deref(), Some("> line\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rules_scoped_by_extension() -> Result<()> {
+        let fs = crate::filesystem::MemoryFs::new();
+        fs.add_file("/a.py", "# TODO fix\n");
+        fs.add_file("/b.rs", "// TODO fix\n");
+        fs.add_file("/c", "TODO\n");
+        let rules = [
+            Rule::new(Regex::new("# TODO")?, "# FIXME").for_extensions(vec!["py".to_string()]),
+            Rule::new(Regex::new("// TODO")?, "// FIXME").for_extensions(vec!["rs".to_string(), "js".to_string()]),
+            Rule::new(Regex::new("fix")?, "fix it"),
+        ];
+
+        for path in ["/a.py", "/b.rs", "/c"] {
+            process_file_in(&fs, Path::new(path), &rules, &ProcessOptions::default(), &mut std::io::sink())?;
+        }
+        assert_eq!(fs.contents("/a.py").as_deref(), Some("# FIXME fix it\n"));
+        assert_eq!(fs.contents("/b.rs").as_deref(), Some("// FIXME fix it\n"));
+        assert_eq!(fs.contents("/c").as_deref(), Some("TODO\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rules_apply_in_sequence() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test.txt");
+        fs::write(&file_path, "alpha beta")?;
+
+        let rules = [
+            Rule::new(Regex::new("alpha")?, "beta"),
+            Rule::new(Regex::new("beta")?, "gamma"),
+        ];
+        let change = process_file(&file_path, &rules, &ProcessOptions::default(), &mut std::io::sink())?;
+
+        assert_eq!(change.matches, 3);
+        assert_eq!(fs::read_to_string(&file_path)?, "gamma gamma");
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_matches_limit() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test.txt");
+        fs::write(&file_path, "1 2 3")?;
+
+        let options = ProcessOptions { max_matches: Some(2), ..Default::default() };
+        let result = process_file(&file_path, &[Rule::new(Regex::new(r"\d")?, "N")], &options, &mut std::io::sink());
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&file_path)?, "1 2 3");
+        Ok(())
+    }
+
     #[test]
     fn test_process_file_nonexistent() {
         let path = Path::new("/nonexistent/file.txt");
         let regex = Regex::new(r"test").unwrap();
-        let result = process_file(path, &regex, "replacement", false, false);
+        let result = process_file(path, &[Rule::new(regex, "replacement")], &ProcessOptions::default(), &mut std::io::sink());
 
         assert!(result.is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_records_named_captures() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("test.txt");
+        fs::write(&file_path, "v1.2\nv3")?;
+
+        let regex = Regex::new(r"v(?<major>\d+)(?:\.(?<minor>\d+))?")?;
+        let options = ProcessOptions { dry_run: true, record_matches: true, ..Default::default() };
+        let change = process_file(&file_path, &[Rule::new(regex, "${major}.x")], &options, &mut std::io::sink())?;
+
+        assert_eq!(change.found.len(), 2);
+        assert_eq!(change.found[0].replacement, "1.x");
+        assert_eq!(change.found[0].captures, [
+            ("major".to_string(), Some("1".to_string())),
+            ("minor".to_string(), Some("2".to_string())),
+        ]);
+        assert_eq!(change.found[1].line, 2);
+        assert_eq!((change.found[1].column, change.found[1].start, change.found[1].end), (1, 5, 7));
+        assert_eq!(change.found[1].captures[1], ("minor".to_string(), None));
+        Ok(())
+    }
+
+    /// Rewrites line endings on write, like a misconfigured network share.
+    struct CrlfFs(crate::filesystem::MemoryFs);
+
+    impl FileSystem for CrlfFs {
+        fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+            self.0.read(path)
+        }
+
+        fn write(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+            let text = String::from_utf8_lossy(contents).replace('\n', "\r\n");
+            self.0.write(path, text.as_bytes())
+        }
+
+        fn metadata(&self, path: &Path) -> std::io::Result<crate::filesystem::Metadata> {
+            self.0.metadata(path)
+        }
+
+        fn read_dir(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+            self.0.read_dir(dir)
+        }
+
+        fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+            self.0.canonicalize(path)
+        }
+    }
+
+    #[test]
+    fn test_verify_roundtrip() -> Result<()> {
+        let fs = CrlfFs(crate::filesystem::MemoryFs::new());
+        fs.0.add_file("/a.txt", "one\ntwo\n");
+        let rules = [Rule::new(Regex::new("two")?, "2")];
+
+        let options = ProcessOptions { verify_roundtrip: true, ..Default::default() };
+        let err = process_file_in(&fs, Path::new("/a.txt"), &rules, &options, &mut std::io::sink()).unwrap_err();
+        assert!(err.to_string().contains("differs from what was written at line 1"), "{err}");
+
+        fs.0.add_file("/b.txt", "two");
+        process_file_in(&fs, Path::new("/b.txt"), &rules, &options, &mut std::io::sink())?;
+        assert_eq!(fs.0.contents("/b.txt").as_deref(), Some("2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_matches() -> Result<()> {
+        let fs = CrlfFs(crate::filesystem::MemoryFs::new());
+        fs.0.add_file("/a.txt", "one\r\ntwo\n");
+        let options = ProcessOptions { verify_matches: true, ..Default::default() };
+
+        let rules = [Rule::new(Regex::new("\r")?, "")];
+        let err = process_file_in(&fs, Path::new("/a.txt"), &rules, &options, &mut std::io::sink()).unwrap_err();
+        assert!(err.to_string().contains("pattern \r matches 2 times after writing, expected 0"), "{err}");
+
+        // Output that still matches the pattern is expected to, once per match written
+        let rules = [Rule::new(Regex::new("one")?, "one more")];
+        process_file_in(&fs, Path::new("/a.txt"), &rules, &options, &mut std::io::sink())?;
+        Ok(())
+    }
+
+    /// Leaves files it writes owned by root, like a privileged run replacing
+    /// them, and may refuse to change owners.
+    struct RootFs {
+        fs: crate::filesystem::MemoryFs,
+        can_chown: bool,
+    }
+
+    impl FileSystem for RootFs {
+        fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+            self.fs.read(path)
+        }
+
+        fn write(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+            self.fs.write(path, contents)?;
+            self.fs.chown(path, Some(0), Some(0))
+        }
+
+        fn metadata(&self, path: &Path) -> std::io::Result<crate::filesystem::Metadata> {
+            self.fs.metadata(path)
+        }
+
+        fn read_dir(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+            self.fs.read_dir(dir)
+        }
+
+        fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+            self.fs.canonicalize(path)
+        }
+
+        fn chown(&self, path: &Path, owner: Option<u32>, group: Option<u32>) -> std::io::Result<()> {
+            if !self.can_chown {
+                return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "not permitted"));
+            }
+            self.fs.chown(path, owner, group)
+        }
+    }
+
+    #[test]
+    fn test_preserve_owner() -> Result<()> {
+        let fs = RootFs { fs: crate::filesystem::MemoryFs::new(), can_chown: true };
+        fs.fs.add_file("/a.txt", "old");
+        fs.fs.chown(Path::new("/a.txt"), Some(1000), Some(100))?;
+        let rules = [Rule::new(Regex::new("old")?, "new")];
+        let backup = Backup { suffix: ".bak".to_string(), force: false };
+        let options = ProcessOptions { preserve_owner: true, backup: Some(backup), ..Default::default() };
+
+        process_file_in(&fs, Path::new("/a.txt"), &rules, &options, &mut std::io::sink())?;
+        for path in ["/a.txt", "/a.txt.bak"] {
+            let metadata = fs.metadata(Path::new(path))?;
+            assert_eq!((metadata.owner, metadata.group), (Some(1000), Some(100)), "{path}");
+        }
+
+        let fs = RootFs { can_chown: false, ..fs };
+        fs.fs.add_file("/b.txt", "old");
+        fs.fs.chown(Path::new("/b.txt"), Some(1000), Some(100))?;
+        let options = ProcessOptions { preserve_owner: true, ..Default::default() };
+        let mut out = Vec::new();
+        process_file_in(&fs, Path::new("/b.txt"), &rules, &options, &mut out)?;
+        assert_eq!(fs.fs.contents("/b.txt").as_deref(), Some("new"));
+        assert!(String::from_utf8(out)?.contains("Warning: could not restore the owner of /b.txt: not permitted"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserve_times() -> Result<()> {
+        let fs = crate::filesystem::MemoryFs::new();
+        fs.add_file("/a.txt", "old");
+        let earlier = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        fs.set_modified(Path::new("/a.txt"), earlier)?;
+        let rules = [Rule::new(Regex::new("old")?, "new")];
+        let backup = Backup { suffix: ".bak".to_string(), force: false };
+        let options = ProcessOptions { preserve_times: true, backup: Some(backup), ..Default::default() };
+
+        process_file_in(&fs, Path::new("/a.txt"), &rules, &options, &mut std::io::sink())?;
+        assert_eq!(fs.contents("/a.txt").as_deref(), Some("new"));
+        for path in ["/a.txt", "/a.txt.bak"] {
+            assert_eq!(fs.metadata(Path::new(path))?.modified, Some(earlier), "{path}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_counter_scopes() -> Result<()> {
+        let fs = crate::filesystem::MemoryFs::new();
+        let run = |replacement: &str| -> Result<Vec<String>> {
+            let rules = [Rule::new(Regex::new(r"test_\d+")?, replacement)];
+            for name in ["/a.rs", "/b.rs"] {
+                fs.add_file(name, "test_7 test_3\n");
+                process_file_in(&fs, Path::new(name), &rules, &ProcessOptions::default(), &mut std::io::sink())?;
+            }
+            Ok(["/a.rs", "/b.rs"].map(|name| fs.contents(name).unwrap()).to_vec())
+        };
+        assert_eq!(run("test_${counter}")?, ["test_1 test_2\n", "test_1 test_2\n"]);
+        assert_eq!(run("test_${counter:scope=global:pad=2}")?, ["test_01 test_02\n", "test_03 test_04\n"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup() -> Result<()> {
+        let fs = crate::filesystem::MemoryFs::new();
+        fs.add_file("/a.txt", "old");
+        fs.add_file("/b.txt", "old");
+        fs.add_file("/b.txt.orig", "earlier backup");
+        let rules = [Rule::new(Regex::new("old")?, "new")];
+        let backup = Backup { suffix: ".orig".to_string(), force: false };
+        let options = ProcessOptions { backup: Some(backup.clone()), ..Default::default() };
+
+        process_file_in(&fs, Path::new("/a.txt"), &rules, &options, &mut std::io::sink())?;
+        assert_eq!(fs.contents("/a.txt").as_deref(), Some("new"));
+        assert_eq!(fs.contents("/a.txt.orig").as_deref(), Some("old"));
+
+        let err = process_file_in(&fs, Path::new("/b.txt"), &rules, &options, &mut std::io::sink()).unwrap_err();
+        assert!(err.to_string().contains("Backup already exists: /b.txt.orig"), "{err}");
+        assert_eq!(fs.contents("/b.txt").as_deref(), Some("old"));
+
+        let options = ProcessOptions { backup: Some(Backup { force: true, ..backup.clone() }), ..Default::default() };
+        process_file_in(&fs, Path::new("/b.txt"), &rules, &options, &mut std::io::sink())?;
+        assert_eq!(fs.contents("/b.txt.orig").as_deref(), Some("old"));
+
+        // A file rewritten to the same length after it was read still gets
+        // the content that was read as its backup
+        fs.add_file("/c.txt", "two");
+        write_backup(&fs, Path::new("/c.txt"), b"one", &backup)?;
+        assert_eq!(fs.contents("/c.txt.orig").as_deref(), Some("one"));
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Writes `files`, absolute paths, to `path` one per line, so a later run
+/// can be limited to exactly the same files with [`load`].
+pub fn save(path: &Path, files: &[PathBuf]) -> Result<()> {
+    let mut text = String::new();
+    for file in files {
+        let line = file.to_str()
+            .with_context(|| format!("Cannot record a path that is not UTF-8: {}", file.display()))?;
+        if line.contains('\n') {
+            anyhow::bail!("Cannot record a path containing a newline: {}", file.display());
+        }
+        text.push_str(line);
+        text.push('\n');
+    }
+    fs::write(path, text).with_context(|| format!("Failed to write file list: {}", path.display()))
+}
+
+/// The files listed in the file list at `path`, ignoring blank lines.
+pub fn load(path: &Path) -> Result<Vec<PathBuf>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read file list: {}", path.display()))?;
+    text.lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let file = PathBuf::from(line);
+            if file.is_relative() {
+                anyhow::bail!("File list {} holds a relative path: {line}", path.display());
+            }
+            Ok(file)
+        })
+        .collect()
+}
+
+/// The paths in `text`, one per line or, if it holds a NUL, separated by
+/// NULs as printed by `fd -0` or `git ls-files -z`. Empty entries are
+/// skipped.
+pub fn parse(text: &[u8]) -> Result<Vec<PathBuf>> {
+    let text = std::str::from_utf8(text).context("File list is not UTF-8")?;
+    let entries: Vec<&str> = if text.contains('\0') {
+        text.split('\0').collect()
+    } else {
+        text.lines().map(|line| line.trim_end_matches('\r')).collect()
+    };
+    Ok(entries.into_iter().filter(|entry| !entry.is_empty()).map(PathBuf::from).collect())
+}
+
+/// The paths listed in `source`, or on standard input if it is `-`; see
+/// [`parse`]. Relative paths are relative to the current directory.
+pub fn read_from(source: &Path) -> Result<Vec<PathBuf>> {
+    let mut text = Vec::new();
+    if source == Path::new("-") {
+        io::stdin().read_to_end(&mut text).context("Failed to read the file list from standard input")?;
+    } else {
+        text = fs::read(source).with_context(|| format!("Failed to read file list: {}", source.display()))?;
+    }
+    parse(&text)
+}
+
+/// The absolute paths of `files`, as named on the command line, each of
+/// which must be an existing file. Duplicates are dropped.
+pub fn resolve(files: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut resolved = Vec::new();
+    for file in files {
+        let path = fs::canonicalize(file).with_context(|| format!("Failed to access file: {}", file.display()))?;
+        if !path.is_file() {
+            anyhow::bail!("{} is not a file; use --directory to process a directory", file.display());
+        }
+        resolved.push(path);
+    }
+    resolved.sort();
+    resolved.dedup();
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_save_and_load() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("files.txt");
+        let files = [PathBuf::from("/repo/a b.rs"), PathBuf::from("/repo/src/c.rs")];
+        save(&path, &files)?;
+        assert_eq!(load(&path)?, files);
+
+        fs::write(&path, "/repo/a.rs\r\n\nsrc/b.rs\n")?;
+        assert!(load(&path).unwrap_err().to_string().contains("relative path: src/b.rs"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!(parse(b"a.rs\r\n\nsrc/b c.rs\n")?, [PathBuf::from("a.rs"), PathBuf::from("src/b c.rs")]);
+        assert_eq!(parse(b"a.rs\0odd\nname.rs\0")?, [PathBuf::from("a.rs"), PathBuf::from("odd\nname.rs")]);
+        assert!(parse(b"")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file = temp_dir.path().join("a.rs");
+        fs::write(&file, "")?;
+        let dotted = temp_dir.path().join(".").join("a.rs");
+        assert_eq!(resolve(&[file.clone(), dotted])?, [fs::canonicalize(&file)?]);
+        assert!(resolve(&[temp_dir.path().to_path_buf()]).unwrap_err().to_string().contains("is not a file"));
+        assert!(resolve(&[temp_dir.path().join("missing.rs")]).is_err());
+        Ok(())
+    }
+}
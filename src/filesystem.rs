@@ -0,0 +1,631 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Number of leading bytes inspected when sniffing for binary content.
+const BINARY_SNIFF_LEN: usize = 8192;
+
+/// What kind of entry a path names. Symbolic links are never followed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: EntryKind,
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    /// User id of the owner, where the platform has one
+    pub owner: Option<u32>,
+    /// Group id, where the platform has one
+    pub group: Option<u32>,
+    pub readonly: bool,
+}
+
+/// An entry found by [`FileSystem::walk`].
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    pub path: PathBuf,
+    pub metadata: Metadata,
+}
+
+/// The file operations the engine needs, so runs can target something other
+/// than the real disk, such as a [`MemoryFs`].
+pub trait FileSystem: Send + Sync {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    /// Metadata for `path` itself, not what it links to.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// The paths of the entries directly inside `dir`, sorted by file name.
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|m| m.kind == EntryKind::File)
+    }
+
+    /// Whether the current user may write to `path`.
+    fn is_writable(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|m| !m.readonly)
+    }
+
+    /// Changes the owner and group of `path`; `None` leaves that one as it is.
+    fn chown(&self, path: &Path, owner: Option<u32>, group: Option<u32>) -> io::Result<()> {
+        let _ = (owner, group);
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("Cannot change the owner of {}", path.display())))
+    }
+
+    /// Sets the modification time of the file at `path`.
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        let _ = modified;
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("Cannot set the modification time of {}", path.display())))
+    }
+
+    /// Copies the file at `from` to `to`, replacing any file already there.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.write(to, &self.read(from)?)
+    }
+
+    /// Creates the directory `dir` and any parents it is missing.
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("Cannot create directory {}", dir.display())))
+    }
+
+    /// Makes `link` a symbolic link to the file `target`, replacing any file
+    /// already there.
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        let _ = target;
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("Cannot create symbolic link {}", link.display())))
+    }
+
+    /// Moves the file or directory at `from` to `to`, replacing nothing:
+    /// callers check that `to` is free first.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let _ = to;
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("Cannot rename {}", from.display())))
+    }
+
+    /// The first `len` bytes of the file at `path`, or all of it if shorter.
+    fn head(&self, path: &Path, len: usize) -> io::Result<Vec<u8>> {
+        let mut contents = self.read(path)?;
+        contents.truncate(len);
+        Ok(contents)
+    }
+
+    /// Whether `path` looks binary: its first few kilobytes contain a NUL byte.
+    fn is_binary(&self, path: &Path) -> io::Result<bool> {
+        Ok(self.head(path, BINARY_SNIFF_LEN)?.contains(&0))
+    }
+
+    /// Every entry below `root`, including `root` itself, depth first in file
+    /// name order. Entries for which `enter` returns false are left out along
+    /// with everything below them. Unreadable directories are skipped.
+    fn walk(&self, root: &Path, enter: &mut dyn FnMut(&Path) -> bool) -> io::Result<Vec<WalkEntry>> {
+        let mut entries = Vec::new();
+        self.walk_each(root, enter, &mut |entry| entries.push(entry))?;
+        Ok(entries)
+    }
+
+    /// Like [`FileSystem::walk`], but hands each entry to `visit` as soon as
+    /// it is found instead of collecting them.
+    fn walk_each(
+        &self,
+        root: &Path,
+        enter: &mut dyn FnMut(&Path) -> bool,
+        visit: &mut dyn FnMut(WalkEntry),
+    ) -> io::Result<()> {
+        let mut stack = vec![root.to_path_buf()];
+        while let Some(path) = stack.pop() {
+            let metadata = match self.metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) if path == root => return Err(e),
+                Err(_) => continue,
+            };
+            if !enter(&path) {
+                continue;
+            }
+            if metadata.kind == EntryKind::Dir
+                && let Ok(children) = self.read_dir(&path)
+            {
+                stack.extend(children.into_iter().rev());
+            }
+            visit(WalkEntry { path, metadata });
+        }
+        Ok(())
+    }
+}
+
+/// The real filesystem.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RealFs;
+
+impl FileSystem for RealFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    /// Rewrites an existing file in place rather than replacing it, so its
+    /// mode, owner, ACLs and hard links are all kept; only its modification
+    /// time changes.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = fs::symlink_metadata(path)?;
+        let kind = if metadata.is_symlink() {
+            EntryKind::Symlink
+        } else if metadata.is_dir() {
+            EntryKind::Dir
+        } else {
+            EntryKind::File
+        };
+        #[cfg(unix)]
+        let (owner, group) = {
+            use std::os::unix::fs::MetadataExt;
+            (Some(metadata.uid()), Some(metadata.gid()))
+        };
+        #[cfg(not(unix))]
+        let (owner, group) = (None, None);
+        Ok(Metadata {
+            kind,
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            owner,
+            group,
+            readonly: metadata.permissions().readonly(),
+        })
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut entries = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+        Ok(entries)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    /// Asks the OS, so ownership, group and ACL rules all count.
+    #[cfg(unix)]
+    fn is_writable(&self, path: &Path) -> bool {
+        use std::os::unix::ffi::OsStrExt;
+        let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else { return false };
+        unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+    }
+
+    #[cfg(unix)]
+    fn chown(&self, path: &Path, owner: Option<u32>, group: Option<u32>) -> io::Result<()> {
+        std::os::unix::fs::chown(path, owner, group)
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        File::options().write(true).open(path)?.set_modified(modified)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        match fs::remove_file(link) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        #[cfg(unix)]
+        return std::os::unix::fs::symlink(target, link);
+        #[cfg(windows)]
+        return std::os::windows::fs::symlink_file(target, link);
+    }
+
+    /// Clones the file where the filesystem supports it (btrfs, XFS, APFS),
+    /// so the copy shares its blocks with the original until either changes;
+    /// elsewhere, copies it.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if reflink(from, to).is_err() {
+            fs::copy(from, to)?;
+        }
+        Ok(())
+    }
+
+    /// Reads only the bytes asked for rather than the whole file.
+    fn head(&self, path: &Path, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(len);
+        File::open(path)?.take(len as u64).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    File(FileNode),
+    Dir,
+}
+
+#[derive(Debug, Clone)]
+struct FileNode {
+    contents: Vec<u8>,
+    modified: SystemTime,
+    owner: Option<u32>,
+    group: Option<u32>,
+    readonly: bool,
+}
+
+/// An in-memory tree, rooted at `/`. Relative paths are taken relative to
+/// the root, and parent directories are created as files are added.
+#[derive(Debug)]
+pub struct MemoryFs {
+    nodes: Mutex<BTreeMap<PathBuf, Node>>,
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        Self { nodes: Mutex::new(BTreeMap::from([(PathBuf::from("/"), Node::Dir)])) }
+    }
+
+    /// Adds (or replaces) a file, creating its parent directories.
+    pub fn add_file(&self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) {
+        let path = normalize(path.as_ref());
+        let mut nodes = self.nodes.lock().unwrap();
+        for ancestor in path.ancestors().skip(1) {
+            nodes.insert(ancestor.to_path_buf(), Node::Dir);
+        }
+        nodes.insert(path, Node::File(FileNode {
+            contents: contents.into(),
+            modified: SystemTime::now(),
+            owner: None,
+            group: None,
+            readonly: false,
+        }));
+    }
+
+    /// Sets the owner reported for a file added earlier.
+    pub fn set_owner(&self, path: impl AsRef<Path>, owner: u32) {
+        if let Some(Node::File(file)) = self.nodes.lock().unwrap().get_mut(&normalize(path.as_ref())) {
+            file.owner = Some(owner);
+        }
+    }
+
+    /// Makes a file added earlier read-only, so writing to it fails.
+    pub fn set_readonly(&self, path: impl AsRef<Path>, readonly: bool) {
+        if let Some(Node::File(file)) = self.nodes.lock().unwrap().get_mut(&normalize(path.as_ref())) {
+            file.readonly = readonly;
+        }
+    }
+
+    /// The contents of a file as a string, if it exists and is valid UTF-8.
+    pub fn contents(&self, path: impl AsRef<Path>) -> Option<String> {
+        self.read_to_string(path.as_ref()).ok()
+    }
+}
+
+/// Makes `path` absolute under `/` and resolves `.` and `..` lexically.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{}: no such file or directory", path.display()))
+}
+
+impl FileSystem for MemoryFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.nodes.lock().unwrap().get(&normalize(path)) {
+            Some(Node::File(file)) => Ok(file.contents.clone()),
+            Some(Node::Dir) => Err(io::Error::new(io::ErrorKind::IsADirectory, path.display().to_string())),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let path = normalize(path);
+        let mut nodes = self.nodes.lock().unwrap();
+        match path.parent().map(|parent| nodes.get(parent)) {
+            Some(Some(Node::Dir)) => {}
+            _ => return Err(not_found(&path)),
+        }
+        match nodes.get_mut(&path) {
+            Some(Node::Dir) => Err(io::Error::new(io::ErrorKind::IsADirectory, path.display().to_string())),
+            Some(Node::File(file)) if file.readonly => {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, path.display().to_string()))
+            }
+            Some(Node::File(file)) => {
+                file.contents = contents.to_vec();
+                file.modified = SystemTime::now();
+                Ok(())
+            }
+            None => {
+                nodes.insert(path, Node::File(FileNode {
+                    contents: contents.to_vec(),
+                    modified: SystemTime::now(),
+                    owner: None,
+                    group: None,
+                    readonly: false,
+                }));
+                Ok(())
+            }
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        match self.nodes.lock().unwrap().get(&normalize(path)) {
+            Some(Node::File(file)) => Ok(Metadata {
+                kind: EntryKind::File,
+                len: file.contents.len() as u64,
+                modified: Some(file.modified),
+                owner: file.owner,
+                group: file.group,
+                readonly: file.readonly,
+            }),
+            Some(Node::Dir) => Ok(Metadata { kind: EntryKind::Dir, len: 0, modified: None, owner: None, group: None, readonly: false }),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let normalized = normalize(dir);
+        let nodes = self.nodes.lock().unwrap();
+        if !matches!(nodes.get(&normalized), Some(Node::Dir)) {
+            return Err(not_found(dir));
+        }
+        // Keep the caller's spelling of `dir`, as std::fs::read_dir does
+        Ok(nodes
+            .keys()
+            .filter(|path| path.parent() == Some(normalized.as_path()) && **path != normalized)
+            .map(|path| dir.join(path.file_name().unwrap()))
+            .collect())
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let normalized = normalize(path);
+        if self.nodes.lock().unwrap().contains_key(&normalized) {
+            Ok(normalized)
+        } else {
+            Err(not_found(path))
+        }
+    }
+
+    fn chown(&self, path: &Path, owner: Option<u32>, group: Option<u32>) -> io::Result<()> {
+        match self.nodes.lock().unwrap().get_mut(&normalize(path)) {
+            Some(Node::File(file)) => {
+                file.owner = owner.or(file.owner);
+                file.group = group.or(file.group);
+                Ok(())
+            }
+            Some(Node::Dir) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        match self.nodes.lock().unwrap().get_mut(&normalize(path)) {
+            Some(Node::File(file)) => {
+                file.modified = modified;
+                Ok(())
+            }
+            Some(Node::Dir) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        for dir in normalize(dir).ancestors() {
+            match nodes.get(dir) {
+                Some(Node::Dir) => break,
+                Some(Node::File(_)) => return Err(io::Error::new(io::ErrorKind::NotADirectory, dir.display().to_string())),
+                None => {
+                    nodes.insert(dir.to_path_buf(), Node::Dir);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves `from` and, for a directory, everything below it.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (normalize(from), normalize(to));
+        let mut nodes = self.nodes.lock().unwrap();
+        if !nodes.contains_key(&from) {
+            return Err(not_found(&from));
+        }
+        if !matches!(to.parent().map(|parent| nodes.get(parent)), Some(Some(Node::Dir))) {
+            return Err(not_found(&to));
+        }
+        let moved: Vec<PathBuf> = nodes.keys().filter(|path| path.starts_with(&from)).cloned().collect();
+        for path in moved {
+            let node = nodes.remove(&path).unwrap();
+            nodes.insert(to.join(path.strip_prefix(&from).unwrap()), node);
+        }
+        Ok(())
+    }
+}
+
+/// Makes `to` a copy-on-write clone of the file `from`.
+#[cfg(target_os = "linux")]
+pub fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let source = File::open(from)?;
+    let target = File::create(to)?;
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Makes `to` a copy-on-write clone of the file `from`.
+#[cfg(target_os = "macos")]
+pub fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = |path: &Path| CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+    let (source, target) = (c_path(from)?, c_path(to)?);
+    // clonefile won't replace an existing file
+    match fs::remove_file(to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    if unsafe { libc::clonefile(source.as_ptr(), target.as_ptr(), 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    let _ = to;
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("Cannot clone {} on this platform", from.display())))
+}
+
+/// The user id of the account named `user`, which may also be given as a
+/// number.
+#[cfg(unix)]
+pub fn user_id(user: &str) -> io::Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = std::ffi::CString::new(user).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No such user: {user}")));
+    }
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+#[cfg(not(unix))]
+pub fn user_id(user: &str) -> io::Result<u32> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("Cannot look up user {user} on this platform")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn walked(fs: &dyn FileSystem, root: &Path) -> Vec<PathBuf> {
+        fs.walk(root, &mut |path| path.file_name().is_none_or(|name| name != "skip"))
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect()
+    }
+
+    #[test]
+    fn test_memory_fs() {
+        let fs = MemoryFs::new();
+        fs.add_file("/src/b.rs", "b");
+        fs.add_file("src/a.rs", "a");
+        fs.add_file("/src/skip/c.rs", "c");
+
+        assert_eq!(fs.contents("/src/a.rs").as_deref(), Some("a"));
+        assert_eq!(fs.metadata(Path::new("/src")).unwrap().kind, EntryKind::Dir);
+        assert_eq!(fs.canonicalize(Path::new("./src/../src")).unwrap(), Path::new("/src"));
+        assert_eq!(walked(&fs, Path::new("src")), ["src", "src/a.rs", "src/b.rs"].map(PathBuf::from));
+
+        fs.write(Path::new("/src/a.rs"), b"changed").unwrap();
+        assert_eq!(fs.contents("/src/a.rs").as_deref(), Some("changed"));
+        assert!(fs.write(Path::new("/missing/x.rs"), b"x").is_err());
+        assert!(fs.read(Path::new("/src")).is_err());
+
+        fs.set_readonly("/src/b.rs", true);
+        assert!(!fs.is_writable(Path::new("/src/b.rs")));
+        assert!(fs.write(Path::new("/src/b.rs"), b"x").is_err());
+    }
+
+    #[test]
+    fn test_real_fs_walk_matches_memory_fs() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let memory = MemoryFs::new();
+        for (path, contents) in [("b.txt", "b"), ("a/z.txt", "z"), ("a/skip/x.txt", "x"), ("c/y.txt", "\0")] {
+            fs::create_dir_all(root.join(path).parent().unwrap())?;
+            fs::write(root.join(path), contents)?;
+            memory.add_file(Path::new("/tree").join(path), contents);
+        }
+
+        let real: Vec<_> = walked(&RealFs, root).iter().map(|p| p.strip_prefix(root).unwrap().to_path_buf()).collect();
+        let virt: Vec<_> = walked(&memory, Path::new("/tree")).iter().map(|p| p.strip_prefix("/tree").unwrap().to_path_buf()).collect();
+        assert_eq!(real, virt);
+        assert!(RealFs.is_binary(&root.join("c/y.txt"))?);
+        assert!(memory.is_binary(Path::new("/tree/c/y.txt"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let (from, to) = (temp_dir.path().join("a.txt"), temp_dir.path().join("a.txt.bak"));
+        fs::write(&from, "original")?;
+        fs::write(&to, "earlier backup")?;
+        // Cloned or copied, depending on the filesystem the tests run on
+        RealFs.copy(&from, &to)?;
+        assert_eq!(fs::read_to_string(&to)?, "original");
+        fs::write(&from, "changed")?;
+        assert_eq!(fs::read_to_string(&to)?, "original");
+
+        let memory = MemoryFs::new();
+        memory.add_file("/a.txt", "original");
+        memory.copy(Path::new("/a.txt"), Path::new("/b.txt"))?;
+        assert_eq!(memory.contents("/b.txt").as_deref(), Some("original"));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_user_id() {
+        assert_eq!(user_id("root").unwrap(), 0);
+        assert_eq!(user_id("1234").unwrap(), 1234);
+        assert!(user_id("no-such-user-here").is_err());
+    }
+}
@@ -0,0 +1,722 @@
+use anyhow::{Context, Result};
+use globset::GlobSet;
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use crate::filesystem::{EntryKind, FileSystem, RealFs, WalkEntry};
+use crate::gitignore::GitIgnores;
+use crate::ignore::{self, IgnorePattern, IgnoreRules};
+use crate::project::{ProjectResolver, ProjectSettings};
+use crate::undo::UNDO_DIR;
+
+/// Why a file or directory is left out of a run.
+#[derive(Debug)]
+pub enum SkipReason {
+    Hidden,
+    Ignored(IgnorePattern),
+    Extension,
+    Glob,
+    Symlink,
+    Binary,
+    /// Larger than the `max_file_size` limit
+    TooLarge(u64),
+    /// Owned by someone other than the `--owner` user
+    Owner,
+    /// The current user may not write to it
+    NotWritable,
+    /// A backup left by `--backup`
+    Backup,
+    /// The undo journals of earlier runs
+    Journal,
+    /// Listed by `--filelist` but no longer a file
+    Missing,
+    /// Marked as generated near the top, by the given marker
+    Generated(String),
+    /// Metadata stored beside a file, such as a macOS AppleDouble `._*`
+    /// file, a resource fork or an NTFS alternate data stream
+    Companion,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Hidden => write!(f, "hidden (use --include-hidden)"),
+            SkipReason::Ignored(pattern) => write!(f, "ignored by pattern {pattern}"),
+            SkipReason::Extension => write!(f, "extension not selected by --extensions"),
+            SkipReason::Glob => write!(f, "path does not match any --glob pattern"),
+            SkipReason::Symlink => write!(f, "symbolic links are not followed"),
+            SkipReason::Binary => write!(f, "binary file"),
+            SkipReason::TooLarge(limit) => write!(f, "larger than max_file_size ({limit} bytes)"),
+            SkipReason::Owner => write!(f, "not owned by the --owner user"),
+            SkipReason::NotWritable => write!(f, "not writable by the current user"),
+            SkipReason::Backup => write!(f, "a backup made by --backup"),
+            SkipReason::Journal => write!(f, "undo journals of earlier runs"),
+            SkipReason::Missing => write!(f, "listed by --filelist but no longer exists"),
+            SkipReason::Generated(marker) => write!(f, "generated file, marked '{marker}' (use --include-generated)"),
+            SkipReason::Companion => write!(f, "resource fork or alternate data stream of another file (use --include-ads)"),
+        }
+    }
+}
+
+impl SkipReason {
+    /// A short name for the kind of reason, for tallies.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SkipReason::Hidden => "hidden",
+            SkipReason::Ignored(_) => "ignored",
+            SkipReason::Extension => "extension",
+            SkipReason::Glob => "glob",
+            SkipReason::Symlink => "symlink",
+            SkipReason::Binary => "binary",
+            SkipReason::TooLarge(_) => "too large",
+            SkipReason::Owner => "owner",
+            SkipReason::NotWritable => "not writable",
+            SkipReason::Backup => "backup",
+            SkipReason::Journal => "journal",
+            SkipReason::Missing => "missing",
+            SkipReason::Generated(_) => "generated",
+            SkipReason::Companion => "companion",
+        }
+    }
+}
+
+/// Treats a file on disk as binary if its first few kilobytes contain a NUL byte.
+pub fn is_binary(path: &Path) -> io::Result<bool> {
+    RealFs.is_binary(path)
+}
+
+/// Whether `path` holds metadata belonging to another file rather than a
+/// file of its own: a macOS AppleDouble file (`._name`, written beside
+/// `name` on filesystems without native resource forks), a path into a
+/// resource fork (`name/..namedfork/rsrc`) or, on Windows, an NTFS
+/// alternate data stream (`name:stream`). Rewriting one corrupts the
+/// metadata of the file it belongs to.
+pub fn is_companion(path: &Path) -> bool {
+    if path.components().any(|component| component.as_os_str() == "..namedfork") {
+        return true;
+    }
+    let Some(name) = path.file_name().map(|name| name.to_string_lossy()) else {
+        return false;
+    };
+    // ':' is an ordinary file name character outside Windows
+    name.starts_with("._") || (cfg!(windows) && name.contains(':'))
+}
+
+/// Everything found by [`FileFilter::walk`].
+pub struct Walk {
+    /// Files that pass every filter, in file name order
+    pub files: Vec<WalkEntry>,
+    /// Files and directories left out, with the reason; a skipped
+    /// directory stands for everything below it
+    pub skipped: Vec<(PathBuf, SkipReason)>,
+}
+
+/// Text that, near the top of a file, marks it as produced by a tool.
+pub const DEFAULT_GENERATED_MARKERS: &[&str] = &[
+    "@generated",
+    "DO NOT EDIT",
+    "Code generated by",
+    "<auto-generated",
+    "This file was automatically generated",
+    "This file is automatically generated",
+    "Automatically generated by",
+];
+
+/// Lines at the top of a file searched for a generated-file marker.
+const GENERATED_MARKER_LINES: usize = 10;
+
+/// The first of `markers` (the built-in list if `None`) in the first lines
+/// of the file at `path`. Binary and unreadable files are left to the
+/// checks that report them.
+fn generated_marker(fs: &dyn FileSystem, path: &Path, markers: Option<&[String]>) -> Option<String> {
+    if markers.is_some_and(<[String]>::is_empty) {
+        return None;
+    }
+    let head = fs.head(path, 4096).ok().filter(|head| !head.contains(&0))?;
+    let head = String::from_utf8_lossy(&head);
+    let top: Vec<&str> = head.lines().take(GENERATED_MARKER_LINES).collect();
+    let found = |marker: &str| top.iter().any(|line| line.contains(marker));
+    match markers {
+        Some(markers) => markers.iter().find(|m| found(m)).cloned(),
+        None => DEFAULT_GENERATED_MARKERS.iter().find(|m| found(m)).map(|m| m.to_string()),
+    }
+}
+
+/// The filters deciding which files under the search directory get processed.
+pub struct FileFilter {
+    fs: Arc<dyn FileSystem>,
+    directory: PathBuf,
+    base_dir: PathBuf,
+    ignore_rules: IgnoreRules,
+    gitignore: Option<GitIgnores>,
+    projects: ProjectResolver,
+    include_set: Option<GlobSet>,
+    include_hidden: bool,
+    owner: Option<u32>,
+    writable_only: bool,
+    backup_suffix: Option<String>,
+    include_generated: bool,
+    include_ads: bool,
+}
+
+impl FileFilter {
+    /// Creates a filter for `directory`; `defaults` are the settings given on
+    /// the command line, refined by `.rr.toml` files found during the walk.
+    pub fn new(
+        directory: &str,
+        defaults: ProjectSettings,
+        globs: &[String],
+        include_hidden: bool,
+    ) -> Result<Self> {
+        Self::with_fs(Arc::new(RealFs), directory, defaults, globs, include_hidden)
+    }
+
+    /// Like [`FileFilter::new`], but reading everything through `fs`.
+    pub fn with_fs(
+        fs: Arc<dyn FileSystem>,
+        directory: &str,
+        defaults: ProjectSettings,
+        globs: &[String],
+        include_hidden: bool,
+    ) -> Result<Self> {
+        let base_dir = fs.canonicalize(Path::new(directory))
+            .with_context(|| format!("Failed to access directory: {directory}"))?;
+        let ignore_rules = IgnoreRules::load(&*fs, directory, &base_dir)?;
+        let gitignore = GitIgnores::load(fs.clone(), &base_dir)?;
+        let include_set = if globs.is_empty() {
+            None
+        } else {
+            Some(ignore::build_glob_set(globs, "--glob")?)
+        };
+
+        Ok(Self {
+            directory: PathBuf::from(directory),
+            projects: ProjectResolver::new(fs.clone(), &base_dir, defaults),
+            fs,
+            base_dir,
+            ignore_rules,
+            gitignore: Some(gitignore),
+            include_set,
+            include_hidden,
+            owner: None,
+            writable_only: false,
+            backup_suffix: None,
+            include_generated: false,
+            include_ads: false,
+        })
+    }
+
+    /// Only accepts files owned by the user with id `uid`.
+    pub fn owned_by(mut self, uid: u32) -> Self {
+        self.owner = Some(uid);
+        self
+    }
+
+    /// Only accepts files the current user may write to, so they are skipped
+    /// up front rather than failing one by one at write time.
+    pub fn writable_only(mut self) -> Self {
+        self.writable_only = true;
+        self
+    }
+
+    /// Stops honoring `.gitignore`, `.ignore` and git's exclude files, leaving
+    /// only `.rr_ignore` and project config patterns.
+    pub fn no_gitignore(mut self) -> Self {
+        self.gitignore = None;
+        self
+    }
+
+    /// Processes files marked as generated instead of skipping them.
+    pub fn include_generated(mut self) -> Self {
+        self.include_generated = true;
+        self
+    }
+
+    /// Processes resource forks, AppleDouble files and alternate data
+    /// streams (see [`is_companion`]) instead of skipping them.
+    pub fn include_ads(mut self) -> Self {
+        self.include_ads = true;
+        self
+    }
+
+    /// Skips files whose name ends with `suffix`, so backups made by one
+    /// run are not rewritten by the next.
+    pub fn skip_backups(mut self, suffix: impl Into<String>) -> Self {
+        self.backup_suffix = Some(suffix.into());
+        self
+    }
+
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
+
+    /// The filesystem this filter walks, which processing should use too.
+    pub fn fs(&self) -> &dyn FileSystem {
+        &*self.fs
+    }
+
+    /// Path of a walked entry relative to the search directory.
+    fn relative<'p>(&self, path: &'p Path) -> &'p Path {
+        path.strip_prefix(&self.directory).unwrap_or(path)
+    }
+
+    /// Absolute path of a walked entry.
+    pub fn absolute(&self, path: &Path) -> PathBuf {
+        self.base_dir.join(self.relative(path))
+    }
+
+    /// The project settings that apply to the walked file at `path`.
+    pub fn settings_for(&self, path: &Path) -> Result<Arc<ProjectSettings>> {
+        let absolute = self.absolute(path);
+        self.projects.settings_for(absolute.parent().unwrap_or(&absolute))
+    }
+
+    /// Checks the traversal filters that apply to both files and directories.
+    pub fn check_entry(&self, path: &Path) -> Result<Option<SkipReason>> {
+        // Always accept the root directory we're starting from
+        if path == self.directory {
+            return Ok(None);
+        }
+
+        // Never rewrite the journals needed to undo earlier runs
+        if path.file_name().is_some_and(|name| name == UNDO_DIR) {
+            return Ok(Some(SkipReason::Journal));
+        }
+
+        // Filter out hidden entries unless include_hidden is set
+        let hidden = path.file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.starts_with('.'))
+            .unwrap_or(false);
+        if !self.include_hidden && hidden {
+            return Ok(Some(SkipReason::Hidden));
+        }
+
+        // Filter out ignored paths (both files and directories)
+        let absolute = self.absolute(path);
+        let settings = self.settings_for(path)?;
+        let pattern = self.ignore_rules.matching_pattern(&absolute)
+            .or_else(|| settings.ignored_by(&absolute));
+        if let Some(pattern) = pattern {
+            return Ok(Some(SkipReason::Ignored(pattern.clone())));
+        }
+
+        // Then git's ignore rules, unless turned off
+        if let Some(ref gitignore) = self.gitignore {
+            let is_dir = self.fs.metadata(path).is_ok_and(|m| m.kind == EntryKind::Dir);
+            if let Some(pattern) = gitignore.matching_pattern(&absolute, is_dir)? {
+                return Ok(Some(SkipReason::Ignored(pattern)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Checks the filters that only apply to files.
+    pub fn check_file(&self, path: &Path) -> Result<Option<SkipReason>> {
+        let settings = self.settings_for(path)?;
+
+        if !self.include_ads && is_companion(path) {
+            return Ok(Some(SkipReason::Companion));
+        }
+
+        if let Some(ref suffix) = self.backup_suffix
+            && path.file_name().is_some_and(|name| name.to_string_lossy().ends_with(suffix.as_str()))
+        {
+            return Ok(Some(SkipReason::Backup));
+        }
+
+        if let Some(ref exts) = settings.extensions {
+            let has_valid_extension = path.extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| exts.iter().any(|e| e == ext))
+                .unwrap_or(false);
+
+            if !has_valid_extension {
+                return Ok(Some(SkipReason::Extension));
+            }
+        }
+
+        if let Some(ref include_set) = self.include_set
+            && !include_set.is_match(self.relative(path))
+        {
+            return Ok(Some(SkipReason::Glob));
+        }
+
+        if let Some(limit) = settings.max_file_size
+            && self.fs.metadata(path).map(|m| m.len).unwrap_or(0) > limit
+        {
+            return Ok(Some(SkipReason::TooLarge(limit)));
+        }
+
+        if let Some(uid) = self.owner
+            && self.fs.metadata(path).ok().and_then(|m| m.owner) != Some(uid)
+        {
+            return Ok(Some(SkipReason::Owner));
+        }
+
+        if self.writable_only && !self.fs.is_writable(path) {
+            return Ok(Some(SkipReason::NotWritable));
+        }
+
+        if !self.include_generated {
+            let markers = settings.generated_markers.as_deref();
+            if let Some(marker) = generated_marker(&*self.fs, path, markers) {
+                return Ok(Some(SkipReason::Generated(marker)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Walks the search directory, applying every filter.
+    pub fn walk(&self) -> Result<Walk> {
+        let mut files = Vec::new();
+        let skipped = self.walk_each(&mut |entry| files.push(entry))?;
+        Ok(Walk { files, skipped })
+    }
+
+    /// Walks the search directory like [`FileFilter::walk`], handing each
+    /// file that passes the filters to `on_file` as soon as it is found, in
+    /// the same order. Returns the entries skipped, sorted by path.
+    pub fn walk_each(&self, on_file: &mut dyn FnMut(WalkEntry)) -> Result<Vec<(PathBuf, SkipReason)>> {
+        let walk_error = RefCell::new(None);
+        let skipped = RefCell::new(Vec::new());
+
+        let mut enter = |path: &Path| match self.check_entry(path) {
+            Ok(Some(reason)) => {
+                skipped.borrow_mut().push((path.to_path_buf(), reason));
+                false
+            }
+            Ok(None) => true,
+            Err(err) => {
+                walk_error.borrow_mut().get_or_insert(err);
+                false
+            }
+        };
+        let mut visit = |entry: WalkEntry| {
+            match entry.metadata.kind {
+                EntryKind::Dir => return,
+                EntryKind::Symlink => {
+                    skipped.borrow_mut().push((entry.path, SkipReason::Symlink));
+                    return;
+                }
+                EntryKind::File => {}
+            }
+            if walk_error.borrow().is_some() {
+                return;
+            }
+            let reason = match self.check_file(&entry.path) {
+                Ok(None) if self.fs.is_binary(&entry.path).unwrap_or(false) => Some(SkipReason::Binary),
+                Ok(reason) => reason,
+                Err(err) => {
+                    walk_error.borrow_mut().get_or_insert(err);
+                    return;
+                }
+            };
+            match reason {
+                Some(reason) => skipped.borrow_mut().push((entry.path, reason)),
+                None => on_file(entry),
+            }
+        };
+        self.fs.walk_each(&self.directory, &mut enter, &mut visit)?;
+
+        if let Some(err) = walk_error.into_inner() {
+            return Err(err);
+        }
+        let mut skipped = skipped.into_inner();
+        skipped.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(skipped)
+    }
+
+    /// The files of `listed` (absolute paths, as recorded by
+    /// `--emit-filelist`) that lie in the search directory, named as a walk
+    /// would name them. They are taken as they are, without applying the
+    /// filters again, except that those that are no longer files are skipped,
+    /// as are resource forks and alternate data streams unless included.
+    pub fn walk_listed(&self, listed: &[PathBuf]) -> Walk {
+        let mut files = Vec::new();
+        let mut skipped = Vec::new();
+        for absolute in listed {
+            let Ok(relative) = absolute.strip_prefix(&self.base_dir) else {
+                continue;
+            };
+            let path = self.directory.join(relative);
+            if !self.include_ads && is_companion(&path) {
+                skipped.push((path, SkipReason::Companion));
+                continue;
+            }
+            match self.fs.metadata(&path) {
+                Ok(metadata) if metadata.kind == EntryKind::File => files.push(WalkEntry { path, metadata }),
+                _ => skipped.push((path, SkipReason::Missing)),
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Walk { files, skipped }
+    }
+
+    /// Explains whether `target` would be processed.
+    ///
+    /// Returns the path that was excluded (the file itself or one of its
+    /// parent directories, which prunes everything below it) and the reason,
+    /// or `None` if the file would be processed.
+    pub fn explain(&self, target: &Path) -> Result<Option<(PathBuf, SkipReason)>> {
+        let canonical = self.fs.canonicalize(target)
+            .with_context(|| format!("Failed to access file: {}", target.display()))?;
+        let relative = canonical.strip_prefix(&self.base_dir)
+            .with_context(|| format!(
+                "{} is not inside the search directory {}",
+                target.display(),
+                self.base_dir.display()
+            ))?;
+
+        let mut walked = self.directory.clone();
+        for component in relative.components() {
+            if let Component::Normal(name) = component {
+                walked.push(name);
+                if let Some(reason) = self.check_entry(&walked)? {
+                    return Ok(Some((walked, reason)));
+                }
+            }
+        }
+
+        Ok(self.check_file(&walked)?.map(|reason| (walked, reason)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_explain_reports_each_filter() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        fs::create_dir(root.join(".hidden"))?;
+        fs::write(root.join(".hidden").join("a.rs"), "")?;
+        fs::write(root.join("b.txt"), "")?;
+        fs::write(root.join("c.rs"), "")?;
+        fs::write(root.join("d.log"), "")?;
+        fs::write(root.join(".rr_ignore"), "*.log\n")?;
+
+        let defaults = ProjectSettings {
+            extensions: Some(vec!["rs".to_string(), "log".to_string()]),
+            ..Default::default()
+        };
+        let filter = FileFilter::new(root.to_str().unwrap(), defaults, &[], false)?;
+
+        let (path, reason) = filter.explain(&root.join(".hidden").join("a.rs"))?.unwrap();
+        assert!(matches!(reason, SkipReason::Hidden));
+        assert!(path.ends_with(".hidden"));
+        assert!(matches!(filter.explain(&root.join("b.txt"))?.unwrap().1, SkipReason::Extension));
+        assert!(matches!(filter.explain(&root.join("d.log"))?.unwrap().1, SkipReason::Ignored(_)));
+        assert!(filter.explain(&root.join("c.rs"))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_project_config_filters() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        let sub = root.join("web");
+        fs::create_dir(&sub)?;
+        fs::write(sub.join(".rr.toml"), "extensions = [\"js\"]\nignore = [\"dist/\"]\nmax_file_size = 4\n")?;
+        fs::create_dir(sub.join("dist"))?;
+        fs::write(sub.join("dist").join("app.js"), "")?;
+        fs::write(sub.join("small.js"), "ok")?;
+        fs::write(sub.join("big.js"), "too big")?;
+        fs::write(sub.join("main.rs"), "")?;
+        fs::write(root.join("main.rs"), "")?;
+
+        let defaults = ProjectSettings { extensions: Some(vec!["rs".to_string()]), ..Default::default() };
+        let filter = FileFilter::new(root.to_str().unwrap(), defaults, &[], false)?;
+
+        assert!(filter.explain(&root.join("main.rs"))?.is_none());
+        assert!(filter.explain(&sub.join("small.js"))?.is_none());
+        assert!(matches!(filter.explain(&sub.join("main.rs"))?.unwrap().1, SkipReason::Extension));
+        assert!(matches!(filter.explain(&sub.join("big.js"))?.unwrap().1, SkipReason::TooLarge(4)));
+        let (path, reason) = filter.explain(&sub.join("dist").join("app.js"))?.unwrap();
+        assert!(path.ends_with("dist"));
+        assert!(matches!(reason, SkipReason::Ignored(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_reports_files_and_skips() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        fs::create_dir(root.join("build"))?;
+        fs::write(root.join("build").join("out.rs"), "")?;
+        fs::write(root.join("a.rs"), "fn a() {}")?;
+        fs::write(root.join("b.txt"), "")?;
+        fs::write(root.join("c.rs"), b"\x00binary")?;
+        fs::write(root.join(".rr_ignore"), "build/\n")?;
+
+        let defaults = ProjectSettings { extensions: Some(vec!["rs".to_string()]), ..Default::default() };
+        let filter = FileFilter::new(root.to_str().unwrap(), defaults, &[], false)?;
+        let walk = filter.walk()?;
+
+        let files: Vec<_> = walk.files.iter().map(|e| e.path.file_name().unwrap().to_owned()).collect();
+        assert_eq!(files, ["a.rs"]);
+        let skipped: Vec<_> = walk.skipped.iter()
+            .map(|(path, reason)| (path.file_name().unwrap().to_str().unwrap(), reason.to_string()))
+            .collect();
+        assert_eq!(skipped.len(), 4);
+        assert_eq!(skipped[0].0, ".rr_ignore");
+        assert!(skipped.iter().any(|(name, reason)| *name == "build" && reason.starts_with("ignored by pattern 'build/'")));
+        assert!(skipped.contains(&("b.txt", SkipReason::Extension.to_string())));
+        assert!(skipped.contains(&("c.rs", SkipReason::Binary.to_string())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_binary() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let text = temp_dir.path().join("text.txt");
+        let binary = temp_dir.path().join("data.bin");
+        fs::write(&text, "plain text\n")?;
+        fs::write(&binary, b"\x7fELF\x00\x01")?;
+
+        assert!(!is_binary(&text)?);
+        assert!(is_binary(&binary)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_owner_and_writable_filters() -> Result<()> {
+        let fs = Arc::new(crate::filesystem::MemoryFs::new());
+        for name in ["mine.txt", "theirs.txt", "locked.txt"] {
+            fs.add_file(format!("/shared/{name}"), "");
+            fs.set_owner(format!("/shared/{name}"), 1000);
+        }
+        fs.set_owner("/shared/theirs.txt", 1001);
+        fs.set_readonly("/shared/locked.txt", true);
+
+        let filter = FileFilter::with_fs(fs, "/shared", ProjectSettings::default(), &[], false)?
+            .owned_by(1000)
+            .writable_only();
+        let walk = filter.walk()?;
+        let files: Vec<_> = walk.files.iter().map(|e| e.path.display().to_string()).collect();
+        assert_eq!(files, ["/shared/mine.txt"]);
+        assert!(matches!(walk.skipped[0], (_, SkipReason::NotWritable)));
+        assert!(matches!(walk.skipped[1], (_, SkipReason::Owner)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_listed() -> Result<()> {
+        let fs = Arc::new(crate::filesystem::MemoryFs::new());
+        fs.add_file("/repo/a.txt", "");
+        fs.add_file("/repo/sub/b.log", "");
+        fs.add_file("/repo/new.txt", "");
+
+        let defaults = ProjectSettings { extensions: Some(vec!["txt".to_string()]), ..Default::default() };
+        let filter = FileFilter::with_fs(fs, "/repo", defaults, &[], false)?;
+        let listed = ["/repo/sub/b.log", "/repo/a.txt", "/repo/gone.txt", "/elsewhere/c.txt"].map(PathBuf::from);
+        let walk = filter.walk_listed(&listed);
+
+        // Exactly the listed files, even those the filters would now leave out
+        let files: Vec<_> = walk.files.iter().map(|e| e.path.display().to_string()).collect();
+        assert_eq!(files, ["/repo/a.txt", "/repo/sub/b.log"]);
+        assert!(matches!(&walk.skipped[..], [(path, SkipReason::Missing)] if path.ends_with("gone.txt")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gitignore_layers_under_rr_ignore() -> Result<()> {
+        let fs = Arc::new(crate::filesystem::MemoryFs::new());
+        fs.add_file("/repo/.git/HEAD", "");
+        fs.add_file("/repo/.gitignore", "dist/\n*.tmp\n");
+        fs.add_file("/repo/.rr_ignore", "*.tmp\n");
+        fs.add_file("/repo/dist/app.js", "");
+        fs.add_file("/repo/scratch.tmp", "");
+        fs.add_file("/repo/main.rs", "");
+
+        let filter = FileFilter::with_fs(fs.clone(), "/repo", ProjectSettings::default(), &[], false)?;
+        let walk = filter.walk()?;
+        let files: Vec<_> = walk.files.iter().map(|e| e.path.display().to_string()).collect();
+        assert_eq!(files, ["/repo/main.rs"]);
+        let reasons: Vec<_> = walk.skipped.iter().map(|(_, reason)| reason.to_string()).collect();
+        assert!(reasons.contains(&"ignored by pattern 'dist/' from /repo/.gitignore:1".to_string()));
+        // .rr_ignore is consulted first
+        assert!(reasons.contains(&"ignored by pattern '*.tmp' from /repo/.rr_ignore:1".to_string()));
+
+        let filter = FileFilter::with_fs(fs, "/repo", ProjectSettings::default(), &[], false)?.no_gitignore();
+        assert_eq!(filter.walk()?.files.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_backups() -> Result<()> {
+        let fs = Arc::new(crate::filesystem::MemoryFs::new());
+        fs.add_file("/repo/a.rs", "");
+        fs.add_file("/repo/a.rs.bak", "");
+
+        let filter = FileFilter::with_fs(fs, "/repo", ProjectSettings::default(), &[], false)?.skip_backups(".bak");
+        let walk = filter.walk()?;
+        assert_eq!(walk.files.len(), 1);
+        assert!(matches!(walk.skipped[..], [(_, SkipReason::Backup)]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generated_files() -> Result<()> {
+        let fs = Arc::new(crate::filesystem::MemoryFs::new());
+        fs.add_file("/repo/schema.go", "// Code generated by protoc-gen-go. DO NOT EDIT.\npackage schema\n");
+        fs.add_file("/repo/main.go", "package main\n\n// @generated\n");
+        fs.add_file("/repo/plain.go", "// Not generated: edit freely\n");
+        fs.add_file("/repo/late.go", format!("{}// @generated\n", "\n".repeat(GENERATED_MARKER_LINES)));
+        fs.add_file("/repo/lib/.rr.toml", "generated_markers = [\"GENERATED FILE\"]\n");
+        fs.add_file("/repo/lib/a.go", "// GENERATED FILE\n");
+        fs.add_file("/repo/lib/b.go", "// DO NOT EDIT\n");
+
+        let filter = FileFilter::with_fs(fs.clone(), "/repo", ProjectSettings::default(), &[], false)?;
+        let generated = |walk: Walk| -> Vec<_> {
+            walk.skipped.iter()
+                .filter(|(_, reason)| matches!(reason, SkipReason::Generated(_)))
+                .map(|(path, reason)| (path.display().to_string(), reason.to_string()))
+                .collect()
+        };
+        assert_eq!(generated(filter.walk()?), [
+            ("/repo/lib/a.go".to_string(), "generated file, marked 'GENERATED FILE' (use --include-generated)".to_string()),
+            ("/repo/main.go".to_string(), "generated file, marked '@generated' (use --include-generated)".to_string()),
+            ("/repo/schema.go".to_string(), "generated file, marked 'DO NOT EDIT' (use --include-generated)".to_string()),
+        ]);
+
+        let filter = FileFilter::with_fs(fs, "/repo", ProjectSettings::default(), &[], false)?.include_generated();
+        assert!(generated(filter.walk()?).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_companions() -> Result<()> {
+        let fs = Arc::new(crate::filesystem::MemoryFs::new());
+        fs.add_file("/repo/notes.txt", "text\n");
+        fs.add_file("/repo/._notes.txt", "text\n");
+        fs.add_file("/repo/a/._b.txt", "text\n");
+
+        let skipped = |filter: &FileFilter| -> Result<Vec<_>> {
+            Ok(filter.walk()?.skipped.into_iter()
+                .filter(|(_, reason)| matches!(reason, SkipReason::Companion))
+                .map(|(path, _)| path)
+                .collect())
+        };
+        let filter = FileFilter::with_fs(fs.clone(), "/repo", ProjectSettings::default(), &[], true)?;
+        assert_eq!(skipped(&filter)?, [PathBuf::from("/repo/._notes.txt"), PathBuf::from("/repo/a/._b.txt")]);
+        let listed = filter.walk_listed(&[PathBuf::from("/repo/._notes.txt"), PathBuf::from("/repo/notes.txt")]);
+        assert_eq!(listed.files.len(), 1);
+        assert!(matches!(listed.skipped.as_slice(), [(path, SkipReason::Companion)] if path == Path::new("/repo/._notes.txt")));
+
+        let filter = FileFilter::with_fs(fs, "/repo", ProjectSettings::default(), &[], true)?.include_ads();
+        assert!(skipped(&filter)?.is_empty());
+
+        assert!(is_companion(Path::new("/repo/song.m4a/..namedfork/rsrc")));
+        assert!(!is_companion(Path::new("/repo/.config")));
+        assert_eq!(is_companion(Path::new("/repo/a.txt:Zone.Identifier")), cfg!(windows));
+        Ok(())
+    }
+}
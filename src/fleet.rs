@@ -0,0 +1,221 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::preview::content_hash;
+
+/// Hex digits of a root's path hash in the name of its log.
+const LOG_HASH_LEN: usize = 8;
+
+/// How often a running root is checked for having finished or run out of time.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Reads a fleet roots file: one directory per line, relative to the file's
+/// own directory. Blank lines and lines starting with `#` are ignored.
+pub fn load_roots(path: &Path) -> Result<Vec<PathBuf>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read roots list: {}", path.display()))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    let roots: Vec<PathBuf> = text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| base.join(line))
+        .collect();
+    if roots.is_empty() {
+        anyhow::bail!("Roots list names no directories: {}", path.display());
+    }
+    Ok(roots)
+}
+
+/// How the run over one root ended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum RootStatus {
+    /// The run finished; a rerun of the fleet skips the root
+    Done {
+        files: usize,
+        modified: usize,
+        /// Files that couldn't be processed, or whose hooks failed
+        errors: usize,
+    },
+    /// The run failed or timed out; a rerun of the fleet tries the root again
+    Failed { error: String },
+}
+
+impl std::fmt::Display for RootStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RootStatus::Done { files, modified, errors: 0 } => write!(f, "{files} files, {modified} modified"),
+            RootStatus::Done { files, modified, errors } => write!(f, "{files} files, {modified} modified, {errors} errors"),
+            RootStatus::Failed { error } => write!(f, "failed: {error}"),
+        }
+    }
+}
+
+/// What a fleet has done so far, by root, saved after every root so an
+/// interrupted fleet picks up where it stopped.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FleetState {
+    pub roots: BTreeMap<PathBuf, RootStatus>,
+}
+
+impl FleetState {
+    /// Reads the state at `path`, or an empty one if there is none yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read fleet state: {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("Invalid fleet state: {}", path.display()))
+    }
+
+    /// Writes the state to `path`, replacing the old one only once the new
+    /// one is complete.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write fleet state: {}", partial.display()))?;
+        fs::rename(&partial, path).with_context(|| format!("Failed to write fleet state: {}", path.display()))
+    }
+
+    pub fn is_done(&self, root: &Path) -> bool {
+        matches!(self.roots.get(root), Some(RootStatus::Done { .. }))
+    }
+}
+
+/// Where the output of the run over `root` is kept, in `dir`: the root's
+/// path made safe as a file name, with a hash of the path itself so roots
+/// whose paths make the same name don't share a log.
+pub fn log_path(dir: &Path, root: &Path) -> PathBuf {
+    let name: String = root.display().to_string()
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect();
+    let hash = content_hash(root.as_os_str().as_encoded_bytes());
+    dir.join(format!("{}-{}.log", name.trim_start_matches('_'), &hash[..LOG_HASH_LEN]))
+}
+
+/// Reads how a run went from the `rr: status=...` trailer it ends its
+/// output with, or `None` if `log` has none.
+pub fn parse_trailer(log: &str) -> Option<RootStatus> {
+    let trailer = log.lines().rev().find_map(|line| line.strip_prefix("rr: "))?;
+    let fields: BTreeMap<&str, &str> = trailer.split_whitespace().filter_map(|field| field.split_once('=')).collect();
+    let count = |key| fields.get(key).and_then(|value| value.parse().ok());
+    match *fields.get("status")? {
+        "ok" => Some(RootStatus::Done { files: count("files")?, modified: count("modified")?, errors: count("errors")? }),
+        _ => Some(RootStatus::Failed { error: failure(log) }),
+    }
+}
+
+/// The error a failed run reported: its `Error:` line, or else the last
+/// line of its output besides the trailer.
+fn failure(log: &str) -> String {
+    let mut lines = log.lines().rev().filter(|line| !line.starts_with("rr: ") && !line.trim().is_empty());
+    let last = lines.clone().next();
+    lines.find_map(|line| line.strip_prefix("Error: "))
+        .or(last)
+        .unwrap_or("run failed")
+        .to_string()
+}
+
+/// Runs `command`, a run over one root, with its output going to `log`,
+/// killing it if it takes longer than `timeout`.
+pub fn run_root(mut command: Command, log: &Path, timeout: Option<Duration>) -> RootStatus {
+    match run_logged(&mut command, log, timeout) {
+        Ok(Some(status)) => status,
+        Ok(None) => RootStatus::Failed { error: format!("timed out after {}s", timeout.unwrap_or_default().as_secs()) },
+        Err(e) => RootStatus::Failed { error: format!("{e:#}") },
+    }
+}
+
+fn run_logged(command: &mut Command, log: &Path, timeout: Option<Duration>) -> Result<Option<RootStatus>> {
+    let file = File::create(log).with_context(|| format!("Failed to create log: {}", log.display()))?;
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(file.try_clone()?)
+        .stderr(file)
+        .spawn()
+        .context("Failed to start run")?;
+    let started = Instant::now();
+    let exit = loop {
+        if let Some(exit) = child.try_wait()? {
+            break exit;
+        }
+        if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    let output = String::from_utf8_lossy(&fs::read(log)?).into_owned();
+    Ok(Some(match parse_trailer(&output) {
+        Some(status) => status,
+        None => RootStatus::Failed { error: format!("run exited with {exit}: {}", failure(&output)) },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_load_roots() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("roots.txt");
+        fs::write(&path, "# mounts\nweb\n\n  /mnt/docs  \n")?;
+        assert_eq!(load_roots(&path)?, [temp_dir.path().join("web"), PathBuf::from("/mnt/docs")]);
+
+        fs::write(&path, "# nothing yet\n")?;
+        assert!(load_roots(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_trailer() {
+        let log = "Summary:\nFiles modified: 3\nrr: status=ok files=10 modified=3 errors=1 dry_run=0 run_id=x\n";
+        assert_eq!(parse_trailer(log), Some(RootStatus::Done { files: 10, modified: 3, errors: 1 }));
+
+        let log = "rr: status=failed files=0 modified=0 errors=1 dry_run=0 run_id=x\nError: Failed to read directory: /mnt/a\n\nStack backtrace:\n   0: main\n";
+        assert_eq!(parse_trailer(log), Some(RootStatus::Failed { error: "Failed to read directory: /mnt/a".to_string() }));
+
+        assert_eq!(parse_trailer("error: unexpected argument\n"), None);
+    }
+
+    #[test]
+    fn test_log_path() {
+        let spaced = log_path(Path::new("logs"), Path::new("/mnt/team a/src"));
+        let underscored = log_path(Path::new("logs"), Path::new("/mnt/team_a/src"));
+        assert!(spaced.starts_with("logs"), "{}", spaced.display());
+        assert!(spaced.file_name().unwrap().to_string_lossy().starts_with("mnt_team_a_src-"));
+        assert_ne!(spaced, underscored);
+        assert_eq!(spaced, log_path(Path::new("logs"), Path::new("/mnt/team a/src")));
+        assert_eq!(RootStatus::Done { files: 4, modified: 1, errors: 0 }.to_string(), "4 files, 1 modified");
+    }
+
+    #[test]
+    fn test_state_round_trip() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("state.json");
+        let mut state = FleetState::load(&path)?;
+        assert!(state.roots.is_empty());
+
+        state.roots.insert(PathBuf::from("/a"), RootStatus::Done { files: 2, modified: 1, errors: 0 });
+        state.roots.insert(PathBuf::from("/b"), RootStatus::Failed { error: "timed out after 5s".to_string() });
+        state.save(&path)?;
+
+        let state = FleetState::load(&path)?;
+        assert!(state.is_done(Path::new("/a")));
+        assert!(!state.is_done(Path::new("/b")));
+        assert!(!state.is_done(Path::new("/c")));
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A line added by the staged changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddedLine {
+    pub path: String,
+    pub line: usize,
+    pub text: String,
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .with_context(|| format!("Failed to run git {}", args.join(" ")))?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Directory git runs hooks from, honouring `core.hooksPath`.
+pub fn hooks_dir(dir: &Path) -> Result<PathBuf> {
+    let hooks = git(dir, &["rev-parse", "--git-path", "hooks"])?;
+    Ok(dir.join(hooks.trim()))
+}
+
+/// Lines added in the index relative to HEAD, with their line numbers in the staged file.
+pub fn staged_added_lines(dir: &Path) -> Result<Vec<AddedLine>> {
+    let diff = git(dir, &["diff", "--cached", "--unified=0", "--no-color", "--no-ext-diff", "--diff-filter=ACMR"])?;
+    Ok(parse_added_lines(&diff))
+}
+
+fn parse_added_lines(diff: &str) -> Vec<AddedLine> {
+    let mut added = Vec::new();
+    let mut path = String::new();
+    let mut line = 0;
+    // Whether the last line was a file header's `---`, which a `+++` follows
+    let mut old_path_seen = false;
+    // New-side lines of the current hunk still to come
+    let mut remaining = 0;
+
+    for text in diff.lines() {
+        if remaining > 0 {
+            match text.chars().next() {
+                Some('+') => {
+                    added.push(AddedLine { path: path.clone(), line, text: text[1..].to_string() });
+                    line += 1;
+                    remaining -= 1;
+                }
+                Some(' ') => {
+                    line += 1;
+                    remaining -= 1;
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        if let Some(new_path) = text.strip_prefix("+++ ").filter(|_| old_path_seen) {
+            let new_path = unquote(new_path);
+            path = new_path.strip_prefix("b/").map(str::to_string).unwrap_or(new_path);
+        } else if let Some(hunk) = text.strip_prefix("@@ ") {
+            // @@ -old,count +new,count @@
+            let (start, count) = hunk.split_whitespace()
+                .find_map(|part| part.strip_prefix('+'))
+                .map(|range| range.split_once(',').unwrap_or((range, "1")))
+                .and_then(|(start, count)| Some((start.parse().ok()?, count.parse().ok()?)))
+                .unwrap_or((0, 0));
+            line = start;
+            remaining = count;
+        }
+        old_path_seen = text.starts_with("--- ");
+    }
+
+    added
+}
+
+/// A path as git prints it in a diff header: as it is, or in double quotes
+/// with C-style escapes when it holds unusual characters.
+fn unquote(path: &str) -> String {
+    let Some(quoted) = path.strip_prefix('"').and_then(|path| path.strip_suffix('"')) else {
+        return path.to_string();
+    };
+    let mut bytes = Vec::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some(digit @ '0'..='7') => {
+                let octal: String = std::iter::once(digit).chain(chars.clone().take(2)).collect();
+                chars.nth(1);
+                bytes.push(u8::from_str_radix(&octal, 8).unwrap_or(b'?'));
+            }
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('r') => bytes.push(b'\r'),
+            Some('a') => bytes.push(0x07),
+            Some('b') => bytes.push(0x08),
+            Some('f') => bytes.push(0x0c),
+            Some('v') => bytes.push(0x0b),
+            Some(other) => {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(other.encode_utf8(&mut buf).as_bytes());
+            }
+            None => bytes.push(b'\\'),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_added_lines() {
+        let diff = "\
+diff --git a/src/a.rs b/src/a.rs
+index 111..222 100644
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -3 +3,2 @@ fn main() {
+-    old();
++    new();
++    dbg!(x);
+@@ -10,0 +12 @@
++// TODO
+";
+        let added = parse_added_lines(diff);
+        assert_eq!(added.len(), 3);
+        assert_eq!(added[0], AddedLine { path: "src/a.rs".into(), line: 3, text: "    new();".into() });
+        assert_eq!(added[1].line, 4);
+        assert_eq!(added[2].line, 12);
+    }
+
+    #[test]
+    fn test_added_lines_like_headers() {
+        let diff = "\
+diff --git a/a.c b/a.c
+index 111..222 100644
+--- a/a.c
++++ b/a.c
+@@ -1,0 +2,2 @@
++++ i;
++dbg!(x);
+diff --git \"a/caf\\303\\251.rs\" \"b/caf\\303\\251.rs\"
+--- \"a/caf\\303\\251.rs\"
++++ \"b/caf\\303\\251.rs\"
+@@ -0,0 +1 @@
++--- not a header
+";
+        let added = parse_added_lines(diff);
+        assert_eq!(added, [
+            AddedLine { path: "a.c".into(), line: 2, text: "++ i;".into() },
+            AddedLine { path: "a.c".into(), line: 3, text: "dbg!(x);".into() },
+            AddedLine { path: "café.rs".into(), line: 1, text: "--- not a header".into() },
+        ]);
+        assert_eq!(unquote(r#""tab\there \"q\" \\""#), "tab\there \"q\" \\");
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::{Context, Result};
+use ::ignore::Match;
+use ::ignore::gitignore::{self, Gitignore, GitignoreBuilder};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::filesystem::FileSystem;
+use crate::ignore::IgnorePattern;
+
+/// Ignore files read in every directory, later ones taking precedence.
+/// `.gitignore` only counts inside a git repository; `.ignore` counts anywhere.
+pub const GITIGNORE_FILE: &str = ".gitignore";
+pub const IGNORE_FILE: &str = ".ignore";
+
+/// The ignore files of one directory, or the repository-wide excludes,
+/// compiled into one matcher.
+struct Layer {
+    matcher: Gitignore,
+    /// Every pattern line added: its file, 1-based line number and text
+    lines: Vec<(PathBuf, usize, String)>,
+}
+
+impl Layer {
+    fn load(fs: &dyn FileSystem, root: &Path, files: &[PathBuf]) -> Result<Option<Self>> {
+        let mut builder = GitignoreBuilder::new(root);
+        let mut lines = Vec::new();
+        for file in files {
+            let Ok(text) = fs.read_to_string(file) else {
+                continue;
+            };
+            for (index, line) in text.lines().enumerate() {
+                builder.add_line(Some(file.clone()), line)
+                    .with_context(|| format!("Invalid ignore pattern at {}:{}: {line}", file.display(), index + 1))?;
+                lines.push((file.clone(), index + 1, line.trim_end().to_string()));
+            }
+        }
+        if lines.is_empty() {
+            return Ok(None);
+        }
+        let matcher = builder.build().context("Failed to build ignore pattern set")?;
+        Ok(Some(Self { matcher, lines }))
+    }
+
+    /// Whether the layer ignores `path`, re-includes it with a `!` pattern,
+    /// or has no say, with the pattern that decided.
+    fn matched(&self, path: &Path, is_dir: bool) -> Match<IgnorePattern> {
+        let pattern = |glob: &gitignore::Glob| {
+            let source = self.lines.iter()
+                .rev()
+                .find(|(file, _, line)| Some(file.as_path()) == glob.from() && line == glob.original())
+                .map(|(file, number, _)| (file.clone(), *number));
+            IgnorePattern { pattern: glob.original().to_string(), source }
+        };
+        match self.matcher.matched(path, is_dir) {
+            Match::None => Match::None,
+            Match::Ignore(glob) => Match::Ignore(pattern(glob)),
+            Match::Whitelist(glob) => Match::Whitelist(pattern(glob)),
+        }
+    }
+}
+
+/// The `.gitignore` and `.ignore` rules that apply below a search directory,
+/// with the repository's `.git/info/exclude` and the user's global git
+/// excludes file. Like git, rules in deeper directories override those
+/// above them, and a `!pattern` re-includes what an outer rule ignored.
+pub struct GitIgnores {
+    fs: Arc<dyn FileSystem>,
+    /// The enclosing git repository, if any
+    repo_root: Option<PathBuf>,
+    /// Outermost directory whose ignore files are read
+    top: PathBuf,
+    /// Repository-wide excludes, consulted after every directory's files
+    excludes: Vec<Layer>,
+    cache: Mutex<HashMap<PathBuf, Option<Arc<Layer>>>>,
+}
+
+impl GitIgnores {
+    /// Loads the rules for the absolute directory `base_dir`, whose ancestors
+    /// up to the repository root contribute their ignore files too.
+    pub fn load(fs: Arc<dyn FileSystem>, base_dir: &Path) -> Result<Self> {
+        let repo_root = base_dir.ancestors().find(|dir| fs.exists(&dir.join(".git"))).map(Path::to_path_buf);
+        let mut excludes = Vec::new();
+        if let Some(ref root) = repo_root {
+            let info = [root.join(".git/info/exclude")];
+            excludes.extend(Layer::load(&*fs, root, &info)?);
+            if let Some(global) = gitignore::gitconfig_excludes_path() {
+                excludes.extend(Layer::load(&*fs, root, &[global])?);
+            }
+        }
+        Ok(Self {
+            top: repo_root.clone().unwrap_or_else(|| base_dir.to_path_buf()),
+            fs,
+            repo_root,
+            excludes,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The rules read from the ignore files in the absolute directory `dir`.
+    fn layer(&self, dir: &Path) -> Result<Option<Arc<Layer>>> {
+        if let Some(layer) = self.cache.lock().unwrap().get(dir) {
+            return Ok(layer.clone());
+        }
+        let mut files = Vec::new();
+        if self.repo_root.is_some() {
+            files.push(dir.join(GITIGNORE_FILE));
+        }
+        files.push(dir.join(IGNORE_FILE));
+        let layer = Layer::load(&*self.fs, dir, &files)?.map(Arc::new);
+        self.cache.lock().unwrap().insert(dir.to_path_buf(), layer.clone());
+        Ok(layer)
+    }
+
+    /// The pattern ignoring the absolute `path`, if any.
+    pub fn matching_pattern(&self, path: &Path, is_dir: bool) -> Result<Option<IgnorePattern>> {
+        let dirs = path.ancestors().skip(1).take_while(|dir| dir.starts_with(&self.top));
+        for dir in dirs {
+            if let Some(layer) = self.layer(dir)? {
+                match layer.matched(path, is_dir) {
+                    Match::None => continue,
+                    Match::Ignore(pattern) => return Ok(Some(pattern)),
+                    Match::Whitelist(_) => return Ok(None),
+                }
+            }
+        }
+        for layer in &self.excludes {
+            match layer.matched(path, is_dir) {
+                Match::None => continue,
+                Match::Ignore(pattern) => return Ok(Some(pattern)),
+                Match::Whitelist(_) => return Ok(None),
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+
+    fn ignored(rules: &GitIgnores, path: &str, is_dir: bool) -> Option<String> {
+        rules.matching_pattern(Path::new(path), is_dir).unwrap().map(|pattern| pattern.to_string())
+    }
+
+    #[test]
+    fn test_nested_gitignores() -> Result<()> {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/repo/.git/info/exclude", "*.secret\n");
+        fs.add_file("/repo/.gitignore", "# build output\nbuild/\n*.log\n");
+        fs.add_file("/repo/app/.gitignore", "!keep.log\n");
+        fs.add_file("/repo/app/.ignore", "fixtures/\n");
+
+        let rules = GitIgnores::load(fs, Path::new("/repo/app"))?;
+        assert_eq!(ignored(&rules, "/repo/app/build", true).as_deref(), Some("'build/' from /repo/.gitignore:2"));
+        assert_eq!(ignored(&rules, "/repo/app/build", false), None);
+        assert!(ignored(&rules, "/repo/app/debug.log", false).is_some());
+        assert_eq!(ignored(&rules, "/repo/app/keep.log", false), None);
+        assert_eq!(ignored(&rules, "/repo/app/fixtures", true).as_deref(), Some("'fixtures/' from /repo/app/.ignore:1"));
+        assert!(ignored(&rules, "/repo/app/key.secret", false).unwrap().contains("info/exclude"));
+        assert_eq!(ignored(&rules, "/repo/app/main.rs", false), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gitignore_needs_a_repository() -> Result<()> {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/plain/.gitignore", "*.txt\n");
+        fs.add_file("/plain/.ignore", "*.log\n");
+
+        let rules = GitIgnores::load(fs, Path::new("/plain"))?;
+        assert_eq!(ignored(&rules, "/plain/a.txt", false), None);
+        assert!(ignored(&rules, "/plain/a.log", false).is_some());
+        Ok(())
+    }
+}
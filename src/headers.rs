@@ -0,0 +1,365 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+use crate::filter::FileFilter;
+
+/// Default pattern recognising an existing license or copyright header.
+pub const DEFAULT_EXISTING: &str = r"(?i)copyright|licen[cs]e|spdx-license-identifier";
+
+/// How a language writes the comment holding a header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommentStyle {
+    /// Every line starts with the marker, e.g. `//` or `#`
+    Line(&'static str),
+    /// A block comment; inner lines start with `prefix`
+    Block { open: &'static str, prefix: &'static str, close: &'static str },
+}
+
+const C_BLOCK: CommentStyle = CommentStyle::Block { open: "/*", prefix: " * ", close: " */" };
+const XML_BLOCK: CommentStyle = CommentStyle::Block { open: "<!--", prefix: "  ", close: "-->" };
+
+/// The comment syntax for `path`, from its extension or, for files such as
+/// `Makefile`, its name. `None` if the language is not known.
+pub fn comment_style(path: &Path) -> Option<CommentStyle> {
+    let name = path.file_name()?.to_str()?;
+    if matches!(name, "Makefile" | "Dockerfile" | "CMakeLists.txt" | "BUILD" | "WORKSPACE") {
+        return Some(CommentStyle::Line("#"));
+    }
+    let style = match path.extension()?.to_str()? {
+        "rs" | "c" | "h" | "cc" | "cpp" | "cxx" | "hpp" | "hh" | "cs" | "java" | "js" | "jsx" | "mjs" | "cjs"
+        | "ts" | "tsx" | "go" | "kt" | "kts" | "scala" | "swift" | "dart" | "groovy" | "proto" | "zig" => {
+            CommentStyle::Line("//")
+        }
+        "py" | "sh" | "bash" | "zsh" | "rb" | "pl" | "pm" | "r" | "R" | "toml" | "yaml" | "yml" | "cmake"
+        | "ps1" | "tf" | "nix" | "jl" | "mk" | "cfg" | "ini" => CommentStyle::Line("#"),
+        "sql" | "lua" | "hs" | "elm" | "ada" => CommentStyle::Line("--"),
+        "el" | "lisp" | "clj" | "cljs" | "scm" => CommentStyle::Line(";;"),
+        "tex" | "erl" | "hrl" => CommentStyle::Line("%"),
+        "css" | "scss" | "less" => C_BLOCK,
+        "html" | "htm" | "xml" | "svg" | "vue" | "xsd" | "xsl" => XML_BLOCK,
+        _ => return None,
+    };
+    Some(style)
+}
+
+/// Which files to give the header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum HeaderMode {
+    /// Insert the header where there is none and replace existing ones
+    #[default]
+    Ensure,
+    /// Only insert the header into files without one
+    Insert,
+    /// Only replace existing headers
+    Replace,
+}
+
+/// What giving one file the header did.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderEdit {
+    /// The file already had exactly this header, or the mode left it alone
+    Unchanged,
+    Inserted(String),
+    Replaced(String),
+}
+
+/// `text` as a comment in `style`, one line per line of text, ending with a
+/// newline.
+pub fn render(text: &str, style: CommentStyle, newline: &str) -> String {
+    let mut out = String::new();
+    match style {
+        CommentStyle::Line(marker) => {
+            for line in text.lines() {
+                out.push_str(marker);
+                if !line.is_empty() {
+                    out.push(' ');
+                    out.push_str(line);
+                }
+                out.push_str(newline);
+            }
+        }
+        CommentStyle::Block { open, prefix, close } => {
+            out.push_str(open);
+            out.push_str(newline);
+            for line in text.lines() {
+                out.push_str(if line.is_empty() { prefix.trim_end() } else { prefix });
+                out.push_str(line);
+                out.push_str(newline);
+            }
+            out.push_str(close);
+            out.push_str(newline);
+        }
+    }
+    out
+}
+
+/// `text` as a single-line comment in `style`, ending with `newline`.
+pub fn render_line(text: &str, style: CommentStyle, newline: &str) -> String {
+    match style {
+        CommentStyle::Line(marker) => format!("{marker} {text}{newline}"),
+        CommentStyle::Block { open, close, .. } => format!("{open} {text} {}{newline}", close.trim_start()),
+    }
+}
+
+/// `content` with `note` appended as a comment on a line of its own.
+pub fn append_note(content: &str, note: &str, style: CommentStyle) -> String {
+    let newline = newline_of(content);
+    let mut out = content.to_string();
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push_str(newline);
+    }
+    out.push_str(&render_line(note, style, newline));
+    out
+}
+
+/// The line ending `content` uses.
+pub fn newline_of(content: &str) -> &'static str {
+    if content.contains("\r\n") { "\r\n" } else { "\n" }
+}
+
+/// Whether `line` must stay above a header: a shebang, an XML declaration
+/// or doctype, or an editor encoding line.
+fn is_prelude(line: &str) -> bool {
+    // `#![...]` is a Rust inner attribute, not a shebang
+    (line.starts_with("#!") && !line.starts_with("#!["))
+        || line.starts_with("<?xml")
+        || line.to_ascii_lowercase().starts_with("<!doctype")
+        || (line.starts_with('#') && (line.contains("coding:") || line.contains("coding=")))
+}
+
+/// Byte offsets just past each line of `content`.
+fn line_ends(content: &str) -> Vec<usize> {
+    content.split_inclusive('\n').scan(0, |end, line| {
+        *end += line.len();
+        Some(*end)
+    }).collect()
+}
+
+/// The range of the leading comment in `content` starting at `start`, if
+/// there is one, including any blank lines after it.
+fn leading_comment(content: &str, start: usize, style: CommentStyle) -> Option<(usize, usize)> {
+    let rest = &content[start..];
+    let ends = line_ends(rest);
+    let lines: Vec<&str> = rest.split_inclusive('\n').collect();
+    let mut count = match style {
+        CommentStyle::Line(marker) => lines.iter().take_while(|line| line.trim_start().starts_with(marker)).count(),
+        CommentStyle::Block { open, close, .. } => {
+            if !lines.first()?.trim_start().starts_with(open) {
+                return None;
+            }
+            let close = close.trim();
+            // The first line can hold the whole comment, as in `/* ... */`
+            let first = &lines[0].trim_start()[open.len()..];
+            if first.contains(close) {
+                1
+            } else {
+                lines.iter().position(|line| line.contains(close))? + 1
+            }
+        }
+    };
+    if count == 0 {
+        return None;
+    }
+    count += lines[count..].iter().take_while(|line| line.trim().is_empty()).count();
+    Some((start, start + ends[count - 1]))
+}
+
+/// Gives `content` the already rendered `header`, replacing a leading
+/// comment that matches `existing` or inserting the header above everything
+/// except a prelude such as a shebang. Running it again on its own output
+/// changes nothing.
+pub fn apply_header(content: &str, header: &str, style: CommentStyle, existing: &Regex, mode: HeaderMode) -> HeaderEdit {
+    let newline = newline_of(content);
+    let prelude_lines = content.split_inclusive('\n').take_while(|line| is_prelude(line)).count();
+    let start = match prelude_lines {
+        0 => 0,
+        n => line_ends(content)[n - 1],
+    };
+    let mut prelude = content[..start].to_string();
+    if !prelude.is_empty() && !prelude.ends_with('\n') {
+        prelude.push_str(newline);
+    }
+
+    let current = leading_comment(content, start, style).filter(|&(from, to)| existing.is_match(&content[from..to]));
+    let (rest, replacing) = match current {
+        Some((_, end)) => (&content[end..], true),
+        None => (&content[start..], false),
+    };
+    if (replacing && mode == HeaderMode::Insert) || (!replacing && mode == HeaderMode::Replace) {
+        return HeaderEdit::Unchanged;
+    }
+
+    let mut updated = prelude;
+    updated.push_str(header);
+    if !rest.is_empty() {
+        updated.push_str(newline);
+        updated.push_str(rest);
+    }
+    if updated == content {
+        HeaderEdit::Unchanged
+    } else if replacing {
+        HeaderEdit::Replaced(updated)
+    } else {
+        HeaderEdit::Inserted(updated)
+    }
+}
+
+/// What happened to one file selected by the filter.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderChange {
+    Inserted(PathBuf),
+    Replaced(PathBuf),
+    Unchanged(PathBuf),
+    /// No comment syntax is known for the file's language
+    Unsupported(PathBuf),
+    /// The file couldn't be read or written; the rest of the tree is
+    /// updated regardless
+    Failed(PathBuf, String),
+}
+
+/// Gives every file `filter` selects the header `text`, written in each
+/// file's own comment syntax.
+pub fn update_tree(filter: &FileFilter, text: &str, existing: &Regex, mode: HeaderMode, dry_run: bool) -> Result<Vec<HeaderChange>> {
+    let mut changes = Vec::new();
+    for entry in filter.walk()?.files {
+        let change = update_file(filter, &entry.path, text, existing, mode, dry_run)
+            .unwrap_or_else(|e| HeaderChange::Failed(entry.path, format!("{e:#}")));
+        changes.push(change);
+    }
+    Ok(changes)
+}
+
+fn update_file(filter: &FileFilter, path: &Path, text: &str, existing: &Regex, mode: HeaderMode, dry_run: bool) -> Result<HeaderChange> {
+    let fs = filter.fs();
+    let path = path.to_path_buf();
+    let Some(style) = comment_style(&path) else {
+        return Ok(HeaderChange::Unsupported(path));
+    };
+    let content = fs.read_to_string(&path).with_context(|| format!("Failed to read file: {}", path.display()))?;
+    let header = render(text, style, newline_of(&content));
+    let (updated, change) = match apply_header(&content, &header, style, existing, mode) {
+        HeaderEdit::Unchanged => return Ok(HeaderChange::Unchanged(path)),
+        HeaderEdit::Inserted(updated) => (updated, HeaderChange::Inserted(path.clone())),
+        HeaderEdit::Replaced(updated) => (updated, HeaderChange::Replaced(path.clone())),
+    };
+    if !dry_run {
+        fs.write(&path, updated.as_bytes()).with_context(|| format!("Failed to write file: {}", path.display()))?;
+    }
+    Ok(change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+    use crate::project::ProjectSettings;
+    use std::sync::Arc;
+
+    const TEXT: &str = "Copyright 2024 Example Ltd.\n\nSPDX-License-Identifier: MIT";
+
+    fn existing() -> Regex {
+        Regex::new(DEFAULT_EXISTING).unwrap()
+    }
+
+    fn ensure(content: &str, style: CommentStyle) -> String {
+        let header = render(TEXT, style, newline_of(content));
+        match apply_header(content, &header, style, &existing(), HeaderMode::Ensure) {
+            HeaderEdit::Unchanged => content.to_string(),
+            HeaderEdit::Inserted(updated) | HeaderEdit::Replaced(updated) => updated,
+        }
+    }
+
+    #[test]
+    fn test_insert_is_idempotent() {
+        let style = CommentStyle::Line("//");
+        let once = ensure("//! Crate docs\nfn main() {}\n", style);
+        assert_eq!(
+            once,
+            "// Copyright 2024 Example Ltd.\n//\n// SPDX-License-Identifier: MIT\n\n//! Crate docs\nfn main() {}\n"
+        );
+        assert_eq!(ensure(&once, style), once);
+    }
+
+    #[test]
+    fn test_replaces_old_header_below_shebang() {
+        let style = CommentStyle::Line("#");
+        let old = "#!/usr/bin/env python3\n# Copyright 2019 Example Ltd.\n\n\nprint('hi')\n";
+        let updated = ensure(old, style);
+        assert_eq!(
+            updated,
+            "#!/usr/bin/env python3\n# Copyright 2024 Example Ltd.\n#\n# SPDX-License-Identifier: MIT\n\nprint('hi')\n"
+        );
+        let header = render(TEXT, style, "\n");
+        assert_eq!(apply_header(old, &header, style, &existing(), HeaderMode::Insert), HeaderEdit::Unchanged);
+    }
+
+    #[test]
+    fn test_append_note() {
+        assert_eq!(append_note("fn main() {}\n", "edited", CommentStyle::Line("//")), "fn main() {}\n// edited\n");
+        assert_eq!(append_note("a: 1", "edited", CommentStyle::Line("#")), "a: 1\n# edited\n");
+        assert_eq!(append_note("p {}\r\n", "edited", C_BLOCK), "p {}\r\n/* edited */\r\n");
+        assert_eq!(append_note("<a/>\n", "edited", XML_BLOCK), "<a/>\n<!-- edited -->\n");
+    }
+
+    #[test]
+    fn test_block_comments() {
+        let old = "/* Copyright 2019 */\nbody { color: red; }\n";
+        assert_eq!(
+            ensure(old, C_BLOCK),
+            "/*\n * Copyright 2024 Example Ltd.\n *\n * SPDX-License-Identifier: MIT\n */\n\nbody { color: red; }\n"
+        );
+        let xml = "<?xml version=\"1.0\"?>\r\n<root/>\r\n";
+        let updated = ensure(xml, XML_BLOCK);
+        assert!(updated.starts_with("<?xml version=\"1.0\"?>\r\n<!--\r\n  Copyright 2024"));
+        assert_eq!(ensure(&updated, XML_BLOCK), updated);
+    }
+
+    #[test]
+    fn test_unrelated_comment_is_kept() {
+        let style = CommentStyle::Line("//");
+        let header = render(TEXT, style, "\n");
+        let content = "// Helpers for parsing\nfn parse() {}\n";
+        assert_eq!(apply_header(content, &header, style, &existing(), HeaderMode::Replace), HeaderEdit::Unchanged);
+        assert!(ensure(content, style).ends_with("\n\n// Helpers for parsing\nfn parse() {}\n"));
+    }
+
+    #[test]
+    fn test_update_tree() -> Result<()> {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/repo/main.rs", "fn main() {}\n");
+        fs.add_file("/repo/Makefile", "all:\n");
+        fs.add_file("/repo/notes.txt", "notes\n");
+        let filter = FileFilter::with_fs(fs.clone(), "/repo", ProjectSettings::default(), &[], false)?;
+
+        let changes = update_tree(&filter, "Copyright 2024", &existing(), HeaderMode::Ensure, false)?;
+        assert_eq!(
+            changes,
+            [
+                HeaderChange::Inserted("/repo/Makefile".into()),
+                HeaderChange::Inserted("/repo/main.rs".into()),
+                HeaderChange::Unsupported("/repo/notes.txt".into()),
+            ]
+        );
+        assert_eq!(fs.contents("/repo/main.rs").as_deref(), Some("// Copyright 2024\n\nfn main() {}\n"));
+        let again = update_tree(&filter, "Copyright 2024", &existing(), HeaderMode::Ensure, false)?;
+        assert_eq!(again[1], HeaderChange::Unchanged("/repo/main.rs".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_tree_carries_on_past_failures() -> Result<()> {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/repo/a.rs", "fn a() {}\n");
+        fs.add_file("/repo/b.rs", b"// caf\xe9\n".to_vec());
+        fs.add_file("/repo/c.rs", "fn c() {}\n");
+        let filter = FileFilter::with_fs(fs.clone(), "/repo", ProjectSettings::default(), &[], false)?;
+
+        let changes = update_tree(&filter, "Copyright 2024", &existing(), HeaderMode::Ensure, false)?;
+        assert!(matches!(changes[1], HeaderChange::Failed(ref path, _) if path == Path::new("/repo/b.rs")));
+        assert_eq!(changes[2], HeaderChange::Inserted("/repo/c.rs".into()));
+        assert_eq!(fs.contents("/repo/c.rs").as_deref(), Some("// Copyright 2024\n\nfn c() {}\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+use anyhow::{Context, Result};
+use std::process::Command;
+
+/// Environment variables describing the current run, handed to every hook.
+pub type HookEnv<'a> = [(&'a str, String)];
+
+/// Runs a user supplied shell command, failing if it exits unsuccessfully.
+pub fn run_hook(kind: &str, command: &str, env: &HookEnv) -> Result<()> {
+    let status = shell(command)
+        .envs(env.iter().map(|(key, value)| (*key, value)))
+        .status()
+        .with_context(|| format!("Failed to run {kind} command: {command}"))?;
+
+    if !status.success() {
+        anyhow::bail!("{kind} command failed ({status}): {command}");
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+pub(crate) fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_hook_receives_environment() {
+        let env = [("RR_TEST_VALUE", "42".to_string())];
+        assert!(run_hook("test", "test \"$RR_TEST_VALUE\" = 42", &env).is_ok());
+        assert!(run_hook("test", "test \"$RR_TEST_VALUE\" = 7", &env).is_err());
+    }
+}
@@ -0,0 +1,398 @@
+use anyhow::{Context, Result};
+use globset::{ErrorKind, Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::{Path, PathBuf};
+
+use crate::filesystem::FileSystem;
+
+pub const IGNORE_FILE: &str = ".rr_ignore";
+
+/// A single ignore pattern and where it was defined.
+#[derive(Debug, Clone)]
+pub struct IgnorePattern {
+    pub pattern: String,
+    /// The ignore file and 1-based line number, or `None` for built-in defaults
+    pub source: Option<(PathBuf, usize)>,
+}
+
+impl std::fmt::Display for IgnorePattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.source {
+            Some((file, line)) => write!(f, "'{}' from {}:{}", self.pattern, file.display(), line),
+            None => write!(f, "'{}' (built-in default)", self.pattern),
+        }
+    }
+}
+
+/// A set of ignore patterns together with the directory they are relative to.
+#[derive(Debug)]
+struct IgnoreLayer {
+    root: PathBuf,
+    set: GlobSet,
+    patterns: Vec<IgnorePattern>,
+}
+
+/// Accumulates globs alongside the pattern each one came from.
+struct LayerBuilder {
+    builder: GlobSetBuilder,
+    patterns: Vec<IgnorePattern>,
+}
+
+impl LayerBuilder {
+    fn new() -> Self {
+        Self { builder: GlobSetBuilder::new(), patterns: Vec::new() }
+    }
+
+    fn add(&mut self, glob: Glob, pattern: IgnorePattern) {
+        self.builder.add(glob);
+        self.patterns.push(pattern);
+    }
+
+    fn build(self, root: PathBuf) -> Result<IgnoreLayer> {
+        Ok(IgnoreLayer {
+            root,
+            set: self.builder.build().with_context(|| "Failed to build ignore pattern set")?,
+            patterns: self.patterns,
+        })
+    }
+}
+
+/// All ignore patterns that apply to a run.
+///
+/// Patterns from the default list, the current directory, the target
+/// directory and `~/.rr_ignore` are matched relative to the base directory.
+/// Patterns from `.rr_ignore` files in ancestors of the base directory are
+/// matched relative to the directory that defines them, like git does.
+#[derive(Debug)]
+pub struct IgnoreRules {
+    layers: Vec<IgnoreLayer>,
+}
+
+impl IgnoreRules {
+    pub fn load(fs: &dyn FileSystem, working_dir: &str, base_dir: &Path) -> Result<Self> {
+        let mut layers = vec![build_base_layer(fs, working_dir)?.build(base_dir.to_path_buf())?];
+
+        for ancestor in ancestor_ignore_dirs(fs, base_dir) {
+            let mut builder = LayerBuilder::new();
+            load_ignore_file(fs, &ancestor.join(IGNORE_FILE), &mut builder)?;
+            layers.push(builder.build(ancestor)?);
+        }
+
+        Ok(Self { layers })
+    }
+
+    /// Builds rules from already parsed patterns, matched relative to `root`.
+    pub fn from_patterns(root: &Path, patterns: Vec<IgnorePattern>) -> Result<Self> {
+        let mut builder = LayerBuilder::new();
+        for pattern in patterns {
+            let glob = compile_glob(&pattern.pattern).map_err(|e| {
+                let location = match &pattern.source {
+                    Some((file, line)) => format!("{}:{}:{}", file.display(), line, e.column),
+                    None => format!("column {}", e.column),
+                };
+                anyhow::anyhow!("Invalid glob pattern in {}: {}: {}", location, pattern.pattern, e.message)
+            })?;
+            builder.add(glob, pattern);
+        }
+        Ok(Self { layers: vec![builder.build(root.to_path_buf())?] })
+    }
+
+    /// Returns true if the absolute `path` matches any ignore pattern.
+    #[cfg(test)]
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        self.matching_pattern(path).is_some()
+    }
+
+    /// Returns the first ignore pattern matching the absolute `path`, if any.
+    pub fn matching_pattern(&self, path: &Path) -> Option<&IgnorePattern> {
+        self.layers.iter().find_map(|layer| {
+            let rel = path.strip_prefix(&layer.root).ok()?;
+            let index = layer.set.matches(rel).into_iter().next()?;
+            Some(&layer.patterns[index])
+        })
+    }
+}
+
+/// Finds ancestors of `base_dir` that contain an ignore file, stopping at the
+/// repository root (a directory containing `.git`) or the filesystem root.
+fn ancestor_ignore_dirs(fs: &dyn FileSystem, base_dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+
+    if fs.exists(&base_dir.join(".git")) {
+        return dirs;
+    }
+
+    for ancestor in base_dir.ancestors().skip(1) {
+        if fs.is_file(&ancestor.join(IGNORE_FILE)) {
+            dirs.push(ancestor.to_path_buf());
+        }
+        if fs.exists(&ancestor.join(".git")) {
+            break;
+        }
+    }
+
+    dirs
+}
+
+fn build_base_layer(fs: &dyn FileSystem, working_dir: &str) -> Result<LayerBuilder> {
+    let mut builder = LayerBuilder::new();
+    
+    // Add some common patterns by default for better performance
+    let default_patterns = [".git/**", ".svn/**", "target/**", "node_modules/**"];
+    for pattern in &default_patterns {
+        if let Ok(glob) = compile_glob(pattern) {
+            builder.add(glob, IgnorePattern { pattern: pattern.to_string(), source: None });
+        }
+    }
+    
+    // Load .rr_ignore from current working directory
+    let cwd_ignore = Path::new(".").join(IGNORE_FILE);
+    if fs.exists(&cwd_ignore) {
+        load_ignore_file(fs, &cwd_ignore, &mut builder)?;
+    }
+    
+    // Load .rr_ignore from target directory
+    if working_dir != "." {
+        let local_ignore = Path::new(working_dir).join(IGNORE_FILE);
+        if fs.exists(&local_ignore) && local_ignore != cwd_ignore {
+            load_ignore_file(fs, &local_ignore, &mut builder)?;
+        }
+    }
+    
+    // Load ~/.rr_ignore from home directory
+    if let Ok(home_dir) = std::env::var("HOME") {
+        let home_ignore = PathBuf::from(home_dir).join(IGNORE_FILE);
+        if fs.exists(&home_ignore) {
+            load_ignore_file(fs, &home_ignore, &mut builder)?;
+        }
+    }
+    
+    Ok(builder)
+}
+
+fn load_ignore_file(fs: &dyn FileSystem, path: &Path, builder: &mut LayerBuilder) -> Result<()> {
+    let content = fs.read_to_string(path)
+        .with_context(|| format!("Failed to read ignore file: {}", path.display()))?;
+    
+    for (index, raw_line) in content.lines().enumerate() {
+        let line = raw_line.trim();
+        
+        // Skip empty lines and comments
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        
+        // Add the glob pattern, reporting errors against the untrimmed line
+        let glob = compile_glob(line).map_err(|e| {
+            let column = e.column + raw_line.len() - raw_line.trim_start().len();
+            anyhow::anyhow!(
+                "Invalid glob pattern in {}:{}:{}: {}: {}",
+                path.display(), index + 1, column, line, e.message
+            )
+        })?;
+        builder.add(glob, IgnorePattern {
+            pattern: line.to_string(),
+            source: Some((path.to_path_buf(), index + 1)),
+        });
+    }
+    
+    Ok(())
+}
+
+/// Builds a set from `--glob` style command line patterns.
+pub fn build_glob_set(patterns: &[String], source: &str) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = compile_glob(pattern).map_err(|e| {
+            anyhow::anyhow!("Invalid glob pattern in {}:{}: {}: {}", source, e.column, pattern, e.message)
+        })?;
+        builder.add(glob);
+    }
+    builder.build()
+        .with_context(|| format!("Failed to build {source} pattern set"))
+}
+
+/// A glob syntax error with the 1-based column it was detected at.
+#[derive(Debug)]
+pub struct GlobSyntaxError {
+    pub column: usize,
+    pub message: String,
+}
+
+/// Compiles a gitignore-style glob.
+///
+/// `*`, `?` and character classes never match `/`, `**` spans any number of
+/// directories, `{a,b}` expands alternatives, a leading `/` anchors the
+/// pattern to the root, a trailing `/` matches a directory and its contents
+/// and a pattern without any other `/` matches at any depth.
+pub fn compile_glob(pattern: &str) -> std::result::Result<Glob, GlobSyntaxError> {
+    let anchored = pattern.strip_prefix('/');
+    let body = anchored.unwrap_or(pattern);
+    let directory = body.strip_suffix('/');
+    let body = directory.unwrap_or(body);
+
+    let mut effective = if anchored.is_none() && !body.trim_end_matches("/**").contains('/') {
+        format!("**/{body}")
+    } else {
+        body.to_string()
+    };
+
+    // A trailing slash matches the directory itself (pruning the walk) and everything below it
+    if directory.is_some() {
+        effective = format!("{{{effective},{effective}/**}}");
+    }
+
+    GlobBuilder::new(&effective)
+        .literal_separator(true)
+        .backslash_escape(true)
+        .build()
+        .map_err(|e| GlobSyntaxError {
+            column: error_column(pattern, e.kind()),
+            message: e.kind().to_string(),
+        })
+}
+
+/// Locates the character in `pattern` responsible for a glob parse error.
+fn error_column(pattern: &str, kind: &ErrorKind) -> usize {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut brace_stack = Vec::new();
+    let mut class_start = None;
+    let mut nested = None;
+    let mut unopened = None;
+    let mut escaped = false;
+
+    for (i, &c) in chars.iter().enumerate() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '[' if class_start.is_none() => class_start = Some(i),
+            ']' if class_start.is_some_and(|start| i > start + 1) => class_start = None,
+            '{' if class_start.is_none() => {
+                if !brace_stack.is_empty() && nested.is_none() {
+                    nested = Some(i);
+                }
+                brace_stack.push(i);
+            }
+            '}' if class_start.is_none() && brace_stack.pop().is_none() && unopened.is_none() => {
+                unopened = Some(i);
+            }
+            _ => {}
+        }
+    }
+
+    let index = match kind {
+        ErrorKind::UnclosedClass => class_start,
+        ErrorKind::UnclosedAlternates => brace_stack.first().copied(),
+        ErrorKind::UnopenedAlternates => unopened,
+        ErrorKind::NestedAlternates => nested,
+        ErrorKind::DanglingEscape => Some(chars.len().saturating_sub(1)),
+        ErrorKind::InvalidRange(start, end) => {
+            let range: Vec<char> = vec![*start, '-', *end];
+            chars.windows(3).position(|w| w == range.as_slice())
+        }
+        ErrorKind::InvalidRecursive => {
+            chars.windows(2).position(|w| w == ['*', '*'])
+        }
+        _ => None,
+    };
+
+    index.map(|i| i + 1).unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::RealFs;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_ancestor_patterns_relative_to_defining_dir() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path().canonicalize()?;
+        fs::create_dir(root.join(".git"))?;
+        let nested = root.join("a").join("b");
+        fs::create_dir_all(&nested)?;
+        fs::write(root.join(IGNORE_FILE), "a/b/skip.txt\n")?;
+
+        let rules = IgnoreRules::load(&RealFs, nested.to_str().unwrap(), &nested)?;
+
+        assert!(rules.is_ignored(&nested.join("skip.txt")));
+        assert!(!rules.is_ignored(&nested.join("keep.txt")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_glob_gitignore_semantics() {
+        let matches = |pattern: &str, path: &str| {
+            compile_glob(pattern).unwrap().compile_matcher().is_match(path)
+        };
+
+        assert!(matches("*.log", "app.log"));
+        assert!(matches("*.log", "logs/deep/app.log"));
+        assert!(matches("*.{log,tmp}", "x/y.tmp"));
+        assert!(matches("build/*.o", "build/a.o"));
+        assert!(!matches("build/*.o", "build/sub/a.o"));
+        assert!(matches("build/**/*.o", "build/sub/a.o"));
+        assert!(matches("/top.txt", "top.txt"));
+        assert!(!matches("/top.txt", "sub/top.txt"));
+        assert!(matches("cache/", "cache/file"));
+        assert!(matches("cache/", "deep/cache"));
+        assert!(matches("file[0-9].txt", "dir/file7.txt"));
+        assert!(!matches("file[0-9].txt", "fileA.txt"));
+    }
+
+    #[test]
+    fn test_compile_glob_error_columns() {
+        assert_eq!(compile_glob("abc[def").unwrap_err().column, 4);
+        assert_eq!(compile_glob("*.{log,tmp").unwrap_err().column, 3);
+        assert_eq!(compile_glob("file[z-a]").unwrap_err().column, 6);
+    }
+
+    #[test]
+    fn test_ignore_file_error_location() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join(IGNORE_FILE);
+        fs::write(&path, "# comment\n*.log\n  bad[\n")?;
+
+        let mut builder = LayerBuilder::new();
+        let err = load_ignore_file(&RealFs, &path, &mut builder).unwrap_err().to_string();
+
+        assert!(err.contains(":3:6:"), "unexpected error: {err}");
+        assert!(err.contains("bad["));
+        Ok(())
+    }
+
+    #[test]
+    fn test_matching_pattern_reports_source() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path().canonicalize()?;
+        fs::write(root.join(IGNORE_FILE), "# logs\n*.log\n")?;
+
+        let rules = IgnoreRules::load(&RealFs, root.to_str().unwrap(), &root)?;
+        let pattern = rules.matching_pattern(&root.join("app.log")).unwrap();
+
+        assert_eq!(pattern.pattern, "*.log");
+        assert_eq!(pattern.source.as_ref().unwrap().1, 2);
+        assert!(rules.matching_pattern(&root.join("target/debug/x")).unwrap().source.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ancestor_search_stops_at_repository_root() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let outer = temp_dir.path().canonicalize()?;
+        let repo = outer.join("repo");
+        let nested = repo.join("src");
+        fs::create_dir_all(repo.join(".git"))?;
+        fs::create_dir_all(&nested)?;
+        fs::write(outer.join(IGNORE_FILE), "**/*.txt\n")?;
+
+        let rules = IgnoreRules::load(&RealFs, nested.to_str().unwrap(), &nested)?;
+
+        assert!(!rules.is_ignored(&nested.join("file.txt")));
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+//! Regex search and replace across directory trees.
+//!
+//! The `regex-replace` binary is a command line front end over this library.
+//! Most embedders only need [`ReplaceEngine`], a builder taking the pattern,
+//! replacement, directory and filters that returns an [`EngineReport`].
+//!
+//! For more control, run a whole tree with [`replace_in_tree`], or walk a
+//! [`FileFilter`] themselves and hand each file to [`process_path`].
+//! [`TreeReplace`] adds progress reporting and cancellation to a whole-tree run.
+//!
+//! All file access goes through a [`FileSystem`]. [`FileFilter::new`] uses
+//! the real disk; [`FileFilter::with_fs`] runs against any other
+//! implementation, such as the in-memory [`MemoryFs`].
+
+use anyhow::Result;
+use std::io;
+use std::path::PathBuf;
+
+pub mod audit;
+pub mod capture_range;
+pub mod case;
+pub mod color;
+pub mod compare;
+pub mod consistency;
+pub mod delta;
+pub mod diagnose;
+pub mod diff;
+pub mod engine;
+pub mod extract;
+pub mod file_processor;
+pub mod filelist;
+pub mod filesystem;
+pub mod filter;
+pub mod fleet;
+pub mod git;
+pub mod gitignore;
+pub mod headers;
+pub mod hooks;
+pub mod ignore;
+pub mod notify;
+pub mod patch;
+pub mod pattern_list;
+pub mod preview;
+pub mod progress;
+pub mod project;
+pub mod queue;
+pub mod recipes;
+pub mod refine;
+pub mod regions;
+pub mod rename;
+pub mod replacement;
+pub mod report;
+pub mod rerun;
+pub mod reversibility;
+pub mod roots;
+pub mod rule_pack;
+pub mod sample;
+pub mod script;
+pub mod search;
+pub mod server;
+pub mod scrub;
+pub mod shadow;
+pub mod template;
+pub mod throttle;
+pub mod undo;
+pub mod utf8;
+
+pub use engine::{EngineReport, ReplaceEngine};
+pub use file_processor::{process_path, FileChange, FileOutcome, FileStatus, MatchDecision, MatchHook, MatchInfo, ProcessOptions, Rule};
+pub use filesystem::{FileSystem, MemoryFs, RealFs};
+pub use filter::{FileFilter, SkipReason};
+pub use progress::{CancellationToken, Progress};
+
+/// Processes every file `filter` selects, returning one outcome per file and
+/// per skipped entry, sorted by path. Per-file report output is discarded.
+pub fn replace_in_tree(filter: &FileFilter, options: &ProcessOptions) -> Result<Vec<FileOutcome>> {
+    Ok(TreeReplace::new(filter, options).run()?.outcomes)
+}
+
+type ProgressCallback<'a> = Box<dyn FnMut(&Progress) + 'a>;
+
+/// A run over a whole tree like [`replace_in_tree`], with progress reporting
+/// and cancellation for interactive embedders.
+pub struct TreeReplace<'a> {
+    filter: &'a FileFilter,
+    options: &'a ProcessOptions,
+    on_progress: Option<ProgressCallback<'a>>,
+    cancel: Option<CancellationToken>,
+}
+
+/// What a [`TreeReplace`] run did, which is only part of the tree if it was
+/// cancelled.
+#[derive(Debug)]
+pub struct TreeResult {
+    /// Outcomes of the files processed and entries skipped, sorted by path
+    pub outcomes: Vec<FileOutcome>,
+    pub cancelled: bool,
+    /// Files not processed because the run was cancelled first
+    pub pending: Vec<PathBuf>,
+}
+
+impl<'a> TreeReplace<'a> {
+    pub fn new(filter: &'a FileFilter, options: &'a ProcessOptions) -> Self {
+        Self { filter, options, on_progress: None, cancel: None }
+    }
+
+    /// Calls `callback` after each file is processed.
+    pub fn on_progress(mut self, callback: impl FnMut(&Progress) + 'a) -> Self {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Stops the run before the next file once `token` is cancelled. The file
+    /// being processed at the time is always finished, so none is left half
+    /// written.
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    pub fn run(mut self) -> Result<TreeResult> {
+        let walk = self.filter.walk()?;
+        let files_total = walk.files.len();
+
+        let mut outcomes: Vec<_> = walk.skipped
+            .into_iter()
+            .map(|(path, reason)| FileOutcome::skipped(path, reason))
+            .collect();
+        let mut files = walk.files.into_iter().enumerate();
+        let mut pending = Vec::new();
+        for (index, entry) in files.by_ref() {
+            if self.cancel.as_ref().is_some_and(CancellationToken::is_cancelled) {
+                pending.push(entry.path);
+                break;
+            }
+
+            let settings = self.filter.settings_for(&entry.path)?;
+            let file_options = ProcessOptions {
+                max_matches: settings.max_matches_per_file,
+                ..self.options.clone()
+            };
+            let outcome = process_path(self.filter.fs(), &entry.path, &settings.rules, &file_options, &mut io::sink());
+            if let Some(callback) = &mut self.on_progress {
+                callback(&Progress { files_done: index + 1, files_total, path: &entry.path, outcome: &outcome });
+            }
+            outcomes.push(outcome);
+        }
+        pending.extend(files.map(|(_, entry)| entry.path));
+
+        outcomes.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(TreeResult { outcomes, cancelled: !pending.is_empty(), pending })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use project::ProjectSettings;
+    use regex::Regex;
+    use std::fs;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_replace_in_tree() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        fs::write(root.join("a.txt"), "old old")?;
+        fs::write(root.join("b.txt"), "nothing")?;
+        fs::write(root.join("c.bin"), b"old\x00")?;
+
+        let defaults = ProjectSettings {
+            rules: Arc::new(vec![Rule::new(Regex::new("old")?, "new")]),
+            ..Default::default()
+        };
+        let filter = FileFilter::new(root.to_str().unwrap(), defaults, &[], false)?;
+        let outcomes = replace_in_tree(&filter, &ProcessOptions::default())?;
+
+        assert_eq!(outcomes.len(), 3);
+        assert!(matches!(outcomes[0].status, FileStatus::Modified));
+        assert_eq!(outcomes[0].matches, 2);
+        assert!(matches!(outcomes[1].status, FileStatus::Unchanged));
+        assert!(matches!(outcomes[2].status, FileStatus::Skipped(SkipReason::Binary)));
+        assert_eq!(fs::read_to_string(root.join("a.txt"))?, "new new");
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_in_memory_tree() -> Result<()> {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/project/src/main.rs", "old_name();");
+        fs.add_file("/project/src/lib.rs", "pub fn old_name() {}");
+        fs.add_file("/project/.rr_ignore", "**/lib.rs\n");
+
+        let defaults = ProjectSettings {
+            rules: Arc::new(vec![Rule::new(Regex::new("old_name")?, "new_name")]),
+            ..Default::default()
+        };
+        let filter = FileFilter::with_fs(fs.clone(), "/project", defaults, &[], false)?;
+        let outcomes = replace_in_tree(&filter, &ProcessOptions::default())?;
+
+        let paths: Vec<_> = outcomes.iter().map(|o| o.path.display().to_string()).collect();
+        assert_eq!(paths, ["/project/.rr_ignore", "/project/src/lib.rs", "/project/src/main.rs"]);
+        assert!(matches!(outcomes[0].status, FileStatus::Skipped(SkipReason::Hidden)));
+        assert!(matches!(outcomes[1].status, FileStatus::Skipped(SkipReason::Ignored(_))));
+        assert!(matches!(outcomes[2].status, FileStatus::Modified));
+        assert_eq!(fs.contents("/project/src/main.rs").as_deref(), Some("new_name();"));
+        assert_eq!(fs.contents("/project/src/lib.rs").as_deref(), Some("pub fn old_name() {}"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress_and_cancellation() -> Result<()> {
+        let fs = Arc::new(MemoryFs::new());
+        for name in ["a", "b", "c"] {
+            fs.add_file(format!("/tree/{name}.txt"), "old");
+        }
+        let defaults = ProjectSettings {
+            rules: Arc::new(vec![Rule::new(Regex::new("old")?, "new")]),
+            ..Default::default()
+        };
+        let filter = FileFilter::with_fs(fs.clone(), "/tree", defaults, &[], false)?;
+        let options = ProcessOptions::default();
+
+        let token = CancellationToken::new();
+        let mut seen = Vec::new();
+        let result = TreeReplace::new(&filter, &options)
+            .on_progress(|progress| {
+                seen.push((progress.files_done, progress.files_total));
+                token.cancel();
+            })
+            .cancellation(token.clone())
+            .run()?;
+
+        assert_eq!(seen, [(1, 3)]);
+        assert!(result.cancelled);
+        assert_eq!(result.outcomes.len(), 1);
+        assert_eq!(result.pending, [PathBuf::from("/tree/b.txt"), PathBuf::from("/tree/c.txt")]);
+        assert_eq!(fs.contents("/tree/a.txt").as_deref(), Some("new"));
+        assert_eq!(fs.contents("/tree/b.txt").as_deref(), Some("old"));
+        Ok(())
+    }
+}
@@ -1,201 +1,2266 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use globset::{Glob, GlobSet, GlobSetBuilder};
+use clap::{Parser, Subcommand, ValueEnum};
+use rayon::ThreadPool;
+use rayon::prelude::*;
 use regex::Regex;
-use std::fs;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use walkdir::{DirEntry, WalkDir};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
 
-mod file_processor;
-use file_processor::process_file;
+use regex_replace::audit::{self, AuditFile, AuditRecord};
+use regex_replace::capture_range::CaptureRange;
+use regex_replace::case::{CaseFolding, FoldMode};
+use regex_replace::color::{self, ColorChoice};
+use regex_replace::compare;
+use regex_replace::consistency::{self, Occurrence};
+use regex_replace::diagnose;
+use regex_replace::diff::{self, DiffStyle};
+use regex_replace::file_processor::{process_path, Backup, Chunking, ContentCache, FileOutcome, FileStatus, Insertions, LineRange, MatchRecord, Mirror, MirrorUnchanged, ProcessOptions, RemainingMatch, ReplacementBudget, Rule};
+use regex_replace::extract::ExtractTemplate;
+use regex_replace::filelist;
+use regex_replace::filesystem::{self, RealFs, WalkEntry};
+use regex_replace::filter::{FileFilter, SkipReason};
+use regex_replace::fleet::{self, FleetState, RootStatus};
+use regex_replace::headers::{self, HeaderChange, HeaderMode};
+use regex_replace::notify::{self, NotifyTarget};
+use regex_replace::preview::{Applied, Preview, PreviewFile};
+use regex_replace::project::{ProjectSettings, RuleConfig};
+use regex_replace::report::{self, FileMatches, FileReport, JsonReport, ReportTotals, RunSummary};
+use regex_replace::refine::{self, Corpus};
+use regex_replace::replacement;
+use regex_replace::regions::{Delimiters, RegionGuards};
+use regex_replace::rename;
+use regex_replace::reversibility::Analysis;
+use regex_replace::roots::{RootConfig, RootsConfig};
+use regex_replace::rerun::{self, LastRuns};
+use regex_replace::rule_pack::RulePack;
+use regex_replace::sample::{Reservoir, SplitMix64};
+use regex_replace::script::ReplaceScript;
+use regex_replace::scrub::{self, ScrubStyle};
+use regex_replace::template::{self, TemplateChange};
+use regex_replace::throttle::{self, Throttle};
+use regex_replace::undo::{Journal, JournalFile, Undone};
+use regex_replace::utf8::InvalidUtf8;
+use regex_replace::pattern_list;
+use regex_replace::queue::{self, QueueStats};
+use regex_replace::{git, hooks, patch, recipes, search, server};
+
+/// Chunk size used when `--match-budget` is given without `--chunk-size`.
+const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+/// Files handed to each worker thread per batch with `--threads`.
+const FILES_PER_THREAD: usize = 16;
+
+/// Exit status of a `--check-diff` run that found changes to make; errors
+/// exit with 1.
+const CHANGES_PENDING_EXIT_CODE: i32 = 2;
+
+/// Most walked files waiting for a worker with `--threads`.
+const DEFAULT_QUEUE_DEPTH: usize = 1024;
+
+/// Note appended by `--annotate` when none is given.
+const DEFAULT_ANNOTATION: &str = "edited by rr run {run_id} on {date}";
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long, help = "Regex pattern to search for")]
-    pattern: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short, long, required_unless_present_any = ["rules", "roots", "rule_pack", "apply_last", "undo", "patterns_from"], help = "Regex pattern to search for")]
+    pattern: Option<String>,
+
+    #[arg(short, long, required_unless_present_any = ["pattern", "hook", "rules", "roots", "rule_pack", "apply_last", "replace_stdin", "replace_script", "undo", "patterns_from"], help = "Replacement text; without one, -p only searches, listing every match")]
+    replace: Option<String>,
+
+    #[arg(long, num_args = 4, allow_hyphen_values = true, value_names = ["-p", "P2", "-r", "R2"], requires = "pattern", help = "Apply a second pattern and replacement only within the text each -p/-r match is replaced with (repeatable, each applying to the previous one's output)")]
+    then: Vec<String>,
+
+    #[arg(long = "rule", value_name = "PATTERN=>REPLACEMENT[@EXT,...]", conflicts_with_all = ["hook", "rule_pack", "apply_last", "undo"], help = "A rule applied after -p/-r, optionally only to files with the given extensions (repeatable)")]
+    rules: Vec<RuleConfig>,
+
+    #[arg(long, conflicts_with_all = ["replace", "hook"], help = "Read the replacement text from standard input, up to EOF")]
+    replace_stdin: bool,
+
+    #[arg(long, value_name = "FILE", requires = "pattern", conflicts_with_all = ["hook", "explain", "apply_last", "undo"], help = "Compute each match's replacement with a Rhai script, which sees the match's text, groups, path, line and column and returns the new text, or () to leave the match")]
+    replace_script: Option<PathBuf>,
+
+    #[arg(long, value_name = "FILE|-", conflicts_with_all = ["pattern", "replace_stdin", "hook", "rule_pack", "apply_last", "undo"], help = "Replace every literal listed in FILE, or on stdin with -, one per line, with -r or with the replacement after '=>' on its line; matched in a single pass however long the list")]
+    patterns_from: Option<PathBuf>,
+
+    #[arg(long, value_name = "PATH|URL", conflicts_with_all = ["pattern", "replace", "replace_stdin", "hook"], help = "Load named rules, scope and limits from a rule pack file or URL")]
+    rule_pack: Option<String>,
+
+    #[arg(long, global = true, value_name = "ID|TAG,...", value_delimiter = ',', help = "Run only the rule pack's rules with these names or tags; naming a disabled rule runs it")]
+    only_rules: Vec<String>,
+
+    #[arg(long, global = true, value_name = "ID|TAG,...", value_delimiter = ',', help = "Leave out the rule pack's rules with these names or tags")]
+    skip_rules: Vec<String>,
+
+    #[arg(long, value_name = "FILE", conflicts_with = "hook", help = "TOML file listing several root directories, each with its own rules and filters, to process in one run")]
+    roots: Option<PathBuf>,
 
-    #[arg(short, long, help = "Replacement text")]
-    replace: String,
+    #[arg(long, conflicts_with_all = ["pattern", "replace", "replace_stdin", "rule_pack", "roots", "hook"], help = "Apply exactly the changes shown by the last --dry-run --diff, refusing files changed since")]
+    apply_last: bool,
 
-    #[arg(short, long, default_value = ".", help = "Directory to search in")]
+    #[arg(long, value_name = "RUN_ID", num_args = 0..=1, conflicts_with_all = ["pattern", "replace", "replace_stdin", "rule_pack", "roots", "apply_last", "hook", "dry_run"], help = "Restore the files modified by the last run over --directory, or by run RUN_ID, from its undo journal")]
+    undo: Option<Option<String>>,
+
+    #[arg(long, help = "Pre-commit hook mode: fail if staged changes add lines matching the pattern")]
+    hook: bool,
+
+    #[arg(long, value_name = "SAMPLE", num_args = 0..=1, requires_all = ["pattern", "replace"], conflicts_with_all = ["hook", "apply_last", "undo"], help = "Show how the replacement expands for the first match in SAMPLE, or in the files searched, group by group, without changing anything")]
+    explain: Option<Option<String>>,
+
+    #[arg(value_name = "FILE", conflicts_with_all = ["filelist", "roots", "apply_last", "undo", "hook"], help = "Process just these files, which must be inside --directory, instead of walking it; filters don't apply to them")]
+    files: Vec<PathBuf>,
+
+    #[arg(short, long, global = true, default_value = ".", help = "Directory to search in")]
     directory: String,
 
-    #[arg(short, long, help = "File extensions to include (e.g., txt,rs,js)")]
+    #[arg(short, long, global = true, help = "File extensions to include (e.g., txt,rs,js)")]
     extensions: Option<String>,
 
-    #[arg(short = 'n', long, help = "Dry run - show what would be changed without modifying files")]
+    #[arg(short, long = "glob", global = true, value_name = "GLOB", help = "Only process files whose path matches this glob (repeatable)")]
+    globs: Vec<String>,
+
+    #[arg(short = 'n', long, global = true, help = "Dry run - show what would be changed without modifying files")]
     dry_run: bool,
 
-    #[arg(short, long, help = "Verbose output")]
+    #[arg(short, long, global = true, help = "Verbose output")]
     verbose: bool,
 
-    #[arg(long, help = "Include hidden files and directories")]
+    #[arg(long, global = true, help = "Show a unified diff of every change")]
+    diff: bool,
+
+    #[arg(long, global = true, help = "Show diffs with only the changed words marked, like git diff --word-diff")]
+    word_diff: bool,
+
+    #[arg(long, help = "Match -p and --rule patterns regardless of case and give each replacement the casing of the text it replaces (foo/Foo/FOO -> bar/Bar/BAR)")]
+    preserve_case: bool,
+
+    #[arg(long, value_enum, default_value_t = FoldMode::Simple, help = "How far case folding goes when -p, --rule and --then patterns ignore case and in --preserve-case output: simple maps one character to one, full also matches ß with ss and uppercases it to SS")]
+    case_fold: FoldMode,
+
+    #[arg(long, help = "Fold case the Turkish and Azerbaijani way, pairing i with İ and ı with I")]
+    turkic_i: bool,
+
+    #[arg(long, num_args = 2, value_names = ["START", "END"], help = "Only replace matches inside a region opened by a match of START and closed by the next match of END, such as a generated block (repeatable; any region will do)")]
+    only_within: Vec<String>,
+
+    #[arg(long, num_args = 2, value_names = ["START", "END"], help = "Only replace matches outside every region opened by a match of START and closed by the next match of END, delimiters included (repeatable)")]
+    not_within: Vec<String>,
+
+    #[arg(long, value_name = "CONDITION", help = "Only replace matches whose capture group holds a number in a range, as in '$1 in 1000..2000' or '${port} in 49152..=65535' (repeatable; every condition must hold)")]
+    capture_range: Vec<CaptureRange>,
+
+    #[arg(long, value_name = "N", help = "Replace only the first N matches of each file, leaving the rest as they are")]
+    max_per_file: Option<usize>,
+
+    #[arg(long, value_name = "N", help = "Replace only the first N matches of the whole run, in the order files are processed; files are processed one at a time, even with --threads")]
+    max_total: Option<usize>,
+
+    #[arg(long, value_name = "RANGE", help = "Only replace matches starting on these lines of each file: 10-50, 10- for line 10 onwards, or 12 (repeatable)")]
+    lines: Vec<LineRange>,
+
+    #[arg(long, help = "Match each line on its own, so matches never span lines and ^ and $ match at every line's ends, and report each match as FILE:LINE:COLUMN in verbose and dry-run output")]
+    line_mode: bool,
+
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto, help = "Colour matched text red and replacements green in verbose and dry-run output: auto (when stdout is a terminal and NO_COLOR isn't set), always or never")]
+    color: ColorChoice,
+
+    #[arg(short = 'C', long, value_name = "N", help = "List every match with N lines before and after it in dry-run and verbose output, as grep -C does")]
+    context: Option<usize>,
+
+    #[arg(long, conflicts_with = "bytes_fallback", help = "Process files that aren't valid UTF-8 with each invalid sequence replaced by U+FFFD, which is written back, warning about each such file")]
+    lossy_utf8: bool,
+
+    #[arg(long, help = "Process files that aren't valid UTF-8 by matching their bytes, writing invalid sequences back as they were, warning about each such file")]
+    bytes_fallback: bool,
+
+    #[arg(long, help = "Let patterns match the empty string (e.g. '(?m)^'), inserting the replacement there; such files fail otherwise")]
+    allow_empty_matches: bool,
+
+    #[arg(long, value_name = "N", requires = "allow_empty_matches", help = "Make at most the first N insertions of each rule on any one line")]
+    max_insertions_per_line: Option<usize>,
+
+    #[arg(long, visible_alias = "emit-patch", value_name = "FILE|-", help = "Write a git-applyable patch of all changes to FILE, or alone to stdout with -, instead of modifying files")]
+    patch_out: Option<PathBuf>,
+
+    #[arg(long, value_name = "FILE", help = "Write every replacement, with the values of named capture groups, to FILE as CSV (.csv) or JSON (anything else)")]
+    match_report: Option<PathBuf>,
+
+    #[arg(long, value_name = "TEMPLATE", help = "Write each file's matches to its own file, named by TEMPLATE from the file's path below the directory searched: {relpath}, {dir}, {name}, {stem} and {ext}, as in 'out/{relpath}.matches'; CSV or JSON for .csv or .json, otherwise the matched text, one per line")]
+    extract_to: Option<ExtractTemplate>,
+
+    #[arg(long, value_enum, help = "Report format (default text); json prints a machine-readable report of every processed file, match position and the totals instead of the usual output, and jsonl prints it as one JSON object per match, then the totals")]
+    output: Option<OutputFormat>,
+
+    #[arg(long, value_name = "FILE", help = "Write the JSON report to FILE, keeping the usual output on stdout; implies --output json unless --output jsonl is given")]
+    report_file: Option<PathBuf>,
+
+    #[arg(long, value_name = "N", help = "With --output json or jsonl, include the N lines before and after each match, for review tools that don't read the files")]
+    export_context: Option<usize>,
+
+    #[arg(long, global = true, value_name = "REGEX", help = "Fail if any text matching REGEX is left in the output, e.g. unfilled placeholders")]
+    assert_no_remaining: Option<String>,
+
+    #[arg(long, value_name = "REGEX", help = "Fail unless every match of REGEX in the output captures the same value (group 1, or the 'value' group, per 'key' group if the pattern has one), e.g. a version string across the tree")]
+    assert_consistent: Option<String>,
+
+    #[arg(long, global = true, help = "Include hidden files and directories")]
     include_hidden: bool,
+
+    #[arg(long, global = true, help = "Don't honor .gitignore, .ignore and git exclude files; .rr_ignore still applies")]
+    no_gitignore: bool,
+
+    #[arg(long, global = true, help = "Process files marked as generated (\"@generated\", \"DO NOT EDIT\", ...) instead of skipping them")]
+    include_generated: bool,
+
+    #[arg(long, global = true, help = "Process macOS resource forks and AppleDouble (._*) files and NTFS alternate data streams instead of skipping them")]
+    include_ads: bool,
+
+    #[arg(long = "generated-marker", global = true, value_name = "TEXT", help = "Text marking a file as generated when it appears in its first lines; replaces the built-in markers, may be repeated")]
+    generated_markers: Vec<String>,
+
+    #[arg(long, global = true, value_name = "USER", help = "Only process files owned by USER (a name or numeric id)")]
+    owner: Option<String>,
+
+    #[arg(long, global = true, help = "Skip files the current user cannot write to instead of failing on them")]
+    writable_only: bool,
+
+    #[arg(long, help = "Report whether an inverse replacement could undo the run: colliding matches, deletions and replacements that already occur")]
+    analyze_reversibility: bool,
+
+    #[arg(long, help = "Read every written file back and report it as failed unless it holds exactly the new content")]
+    verify_roundtrip: bool,
+
+    #[arg(long, help = "Write files even if another process changed them after they were read; by default such files are left as they are, with a warning")]
+    overwrite_concurrent: bool,
+
+    #[arg(long, help = "Give every rewritten file, and its backup, back the owner and group it had, warning when that isn't permitted; for runs as root over files owned by others")]
+    preserve_owner: bool,
+
+    #[arg(long, help = "Give every rewritten file, and its backup, back the modification time it had, so rewrites don't trigger mtime-based rebuilds or syncs")]
+    preserve_times: bool,
+
+    #[arg(long, help = "Also apply the rules to the names of the files processed, renaming them; a dry run shows each as old -> new")]
+    rename_files: bool,
+
+    #[arg(long, help = "Also apply the rules to the names of the directories holding the files processed, below the search directory")]
+    rename_dirs: bool,
+
+    #[arg(long, value_name = "NOTE", num_args = 0..=1, default_missing_value = DEFAULT_ANNOTATION, help = "Append NOTE as a comment, in each file's own comment syntax, to every file modified; {run_id} and {date} are filled in (default: \"edited by rr run {run_id} on {date}\")")]
+    annotate: Option<String>,
+
+    #[arg(long, help = "Read every written file back and report it as failed if a pattern matches it more or fewer times than expected, e.g. because another process changed it")]
+    verify: bool,
+
+    #[arg(long, help = "Apply the rules a second time in memory and warn about files that would change again")]
+    check_idempotent: bool,
+
+    #[arg(long, value_name = "BYTES", help = "Match files larger than BYTES in chunks of this size, so the time budget can be enforced")]
+    chunk_size: Option<usize>,
+
+    #[arg(long, value_name = "BYTES", default_value_t = 4096, help = "How far a match may extend past the end of its chunk")]
+    chunk_overlap: usize,
+
+    #[arg(long, value_name = "BYTES", conflicts_with = "chunk_overlap", help = "Longest match expected; turns on chunked matching with this much overlap, so --threads can split large files")]
+    max_pattern_span: Option<usize>,
+
+    #[arg(long, value_name = "MS", help = "Report files whose match step takes longer than MS milliseconds, and abandon chunked files that exceed it")]
+    match_budget: Option<u64>,
+
+    #[arg(long, help = "If nothing matches, check whether every file was filtered out and whether the pattern would match ignoring case or read literally")]
+    why_no_match: bool,
+
+    #[arg(long, requires = "dry_run", help = "Exit with status 2 if any file would change, 0 if none would, so CI can check a tree is already migrated")]
+    check_diff: bool,
+
+    #[arg(long, value_name = "N", requires = "dry_run", help = "Show the output of only a random sample of N matching files, while still counting every match")]
+    sample: Option<usize>,
+
+    #[arg(long, value_name = "SEED", requires = "sample", help = "Seed for --sample, to see the same sample again")]
+    seed: Option<u64>,
+
+    #[arg(long, value_name = "N", help = "List the N files with the most matches, with matches per KB, to prioritize review")]
+    top_files: Option<usize>,
+
+    #[arg(long, help = "Show the modified files as an indented tree, with how many files changed and how many matches they had under each directory")]
+    tree: bool,
+
+    #[arg(long, help = "Report every skipped file or directory and the reason it was skipped")]
+    explain_skips: bool,
+
+    #[arg(long, value_name = "FILE", help = "Write the absolute path of every file the run selects, after filtering, to FILE")]
+    emit_filelist: Option<PathBuf>,
+
+    #[arg(long, value_name = "FILE", help = "Process exactly the files listed in FILE by an earlier --emit-filelist instead of walking the tree")]
+    filelist: Option<PathBuf>,
+
+    #[arg(long, value_name = "FILE|-", conflicts_with_all = ["files", "filelist", "roots", "apply_last", "undo", "hook", "replace_stdin"], help = "Process just the files listed in FILE, or on stdin with -, one per line or NUL-separated (as from fd -0 or git ls-files -z); like file arguments, they must be inside --directory")]
+    files_from: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t = SortOrder::Path, help = "Order in which files are processed")]
+    sort: SortOrder,
+
+    #[arg(long, value_name = "SUFFIX", num_args = 0..=1, require_equals = true, default_missing_value = ".bak", help = "Copy each file to FILE<SUFFIX> (default .bak) before modifying it")]
+    backup: Option<String>,
+
+    #[arg(long, requires = "backup", help = "Overwrite backups left by earlier runs instead of failing those files")]
+    force_backup: bool,
+
+    #[arg(long, help = "Run even when the last run with the same options left the tree as it is now, which is otherwise skipped as already applied")]
+    force_rerun: bool,
+
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["backup", "dry_run", "apply_last", "undo", "rename_files", "rename_dirs"], help = "Write rewritten files into a mirror of the tree under DIR instead of over the originals, which are left as they are")]
+    output_dir: Option<PathBuf>,
+
+    #[arg(long, value_enum, value_name = "HOW", default_value_t = MirrorUnchanged::Skip, requires = "output_dir", help = "What the --output-dir mirror gets for files left unchanged")]
+    output_unchanged: MirrorUnchanged,
+
+    #[arg(long, help = "Stream per-file output as soon as it is produced instead of buffering it in traversal order")]
+    no_ordered_output: bool,
+
+    #[arg(long, value_name = "N", help = "Process files on N threads (0 for one per CPU); output stays in traversal order")]
+    threads: Option<usize>,
+
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_QUEUE_DEPTH, help = "With --threads, let at most N walked files wait for a worker before the walk pauses")]
+    queue_depth: usize,
+
+    #[arg(long, help = "Report how the queue between the directory walk and the worker threads filled")]
+    stats: bool,
+
+    #[arg(long, value_name = "MBPS", help = "Limit file read/write bandwidth to this many megabytes per second")]
+    throttle: Option<f64>,
+
+    #[arg(long, help = "Lower process priority so the run doesn't starve other work")]
+    nice: bool,
+
+    #[arg(long, value_name = "CMD", help = "Also run CMD, such as the sed pipeline being replaced, on a sandboxed copy of each file and report where its result differs: as a filter from stdin to stdout, or in place on the copy named by {}")]
+    shadow_cmd: Option<String>,
+
+    #[arg(long, help = "Match each distinct file content once and reuse the result for every file with the same content, such as vendored copies")]
+    dedupe_content: bool,
+
+    #[arg(long, value_name = "CMD", help = "Shell command to run before processing; the run aborts if it fails")]
+    pre_cmd: Option<String>,
+
+    #[arg(long, value_name = "CMD", help = "Shell command to run after a successful run")]
+    post_cmd: Option<String>,
+
+    #[arg(long, value_name = "CMD", help = "Shell command to run before each file; the file is skipped if it fails")]
+    pre_file_cmd: Option<String>,
+
+    #[arg(long, value_name = "CMD", help = "Shell command to run after each modified file")]
+    post_file_cmd: Option<String>,
+
+    #[arg(long, value_name = "TARGET", help = "Announce when the run finishes or fails: 'desktop' or 'webhook=URL' (repeatable)")]
+    notify: Vec<NotifyTarget>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Explain whether a file would be processed and, if not, which filter excludes it
+    Why {
+        /// File to check
+        path: PathBuf,
+    },
+    /// Show which files a run modified, with what rules, by whom and when; lists all runs without RUN_ID
+    Audit {
+        /// Run ID, or an unambiguous prefix of one
+        run_id: Option<String>,
+    },
+    /// Install a git pre-commit hook that rejects commits adding lines matching a pattern
+    InstallHook {
+        /// Regex pattern that staged changes must not introduce
+        #[arg(short, long)]
+        pattern: String,
+
+        /// Print a pre-commit framework configuration instead of installing a hook
+        #[arg(long)]
+        pre_commit_config: bool,
+
+        /// Overwrite an existing pre-commit hook
+        #[arg(long)]
+        force: bool,
+    },
+    /// Fill in {{placeholder}} tokens in the contents and names of a template tree
+    Template {
+        /// TOML file mapping placeholder names to values
+        #[arg(long, value_name = "FILE")]
+        vars: PathBuf,
+
+        /// Copy this template tree to DIR first instead of instantiating DIR in place
+        #[arg(long, value_name = "TEMPLATE")]
+        from: Option<PathBuf>,
+
+        /// Directory to instantiate
+        dir: PathBuf,
+    },
+    /// Insert or update a license header in each file, written in the file's comment syntax
+    Headers {
+        /// File holding the header text, without comment markers
+        #[arg(long, value_name = "FILE")]
+        header: PathBuf,
+
+        /// Which files to give the header
+        #[arg(long, value_enum, default_value_t = HeaderMode::Ensure)]
+        mode: HeaderMode,
+
+        /// Regex identifying an existing header in a file's leading comment
+        #[arg(long, value_name = "REGEX", default_value = headers::DEFAULT_EXISTING)]
+        existing: String,
+    },
+    /// Load the tree once, then edit the pattern and replacement interactively, previewing each try
+    Refine {
+        /// Initial regex pattern
+        #[arg(short, long)]
+        pattern: Option<String>,
+
+        /// Initial replacement text
+        #[arg(short, long, default_value = "")]
+        replace: String,
+    },
+    /// List every match of a pattern, by file, line and column, without changing anything
+    Search {
+        /// Regex pattern to search for
+        #[arg(short, long)]
+        pattern: String,
+    },
+    /// Serve replacement previews to an editor: JSON requests on stdin, one per line, each answered on stdout
+    #[command(visible_alias = "lsp")]
+    Serve,
+    /// Report where a pattern's matches differ between two directory trees
+    Compare {
+        /// Regex pattern whose matches are compared
+        #[arg(short, long)]
+        pattern: String,
+
+        /// Print the comparison as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// First tree, e.g. a checkout before a migration
+        dir_a: PathBuf,
+
+        /// Second tree, e.g. a checkout after it
+        dir_b: PathBuf,
+    },
+    /// Apply a patch written by --emit-patch to --directory, refusing if any file in it changed since
+    Apply {
+        /// Patch file
+        patch: PathBuf,
+    },
+    /// Run a replacement, given after `--`, over each directory in a list, one run per root, resuming where an interrupted fleet stopped
+    Fleet(FleetArgs),
+    /// Replace secrets (AWS keys, JWTs, private key blocks, API tokens) with placeholders and report where they were
+    Scrub {
+        /// What each secret is replaced with
+        #[arg(long, value_enum, default_value_t = ScrubStyle::Placeholder)]
+        style: ScrubStyle,
+    },
+    /// List or run the built-in codemod recipes
+    Recipes {
+        #[command(subcommand)]
+        action: RecipesCommand,
+    },
+}
+
+#[derive(clap::Args)]
+struct FleetArgs {
+    /// File listing the directories to process, one per line
+    #[arg(long, value_name = "FILE")]
+    roots: PathBuf,
+
+    /// Where each root's status and run log are kept [default: the roots file with a .fleet extension]
+    #[arg(long, value_name = "DIR")]
+    state_dir: Option<PathBuf>,
+
+    /// Process this many roots at once
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    jobs: usize,
+
+    /// Give up on a root whose run takes longer than SECS, marking it failed
+    #[arg(long, value_name = "SECS")]
+    root_timeout: Option<u64>,
+
+    /// Process every root again, including those an earlier run of the fleet finished
+    #[arg(long)]
+    restart: bool,
+
+    /// Write the status of every root to FILE as JSON
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Options of the run over each root, such as -p PATTERN -r REPLACEMENT
+    #[arg(last = true, required = true, value_name = "RUN")]
+    run: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum RecipesCommand {
+    /// List the available recipes
+    List,
+    /// Apply a recipe to the search directory
+    Run {
+        /// Recipe name, as shown by `recipes list`
+        name: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable progress and summary
+    Text,
+    /// A JSON report, for CI and other tools
+    Json,
+    /// The JSON report as JSON Lines: one object per match, then the totals
+    Jsonl,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SortOrder {
+    /// Lexicographic by path
+    Path,
+    /// Smallest files first, ties broken by path
+    Size,
+    /// Oldest modification time first, ties broken by path
+    Mtime,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    let regex = Regex::new(&args.pattern)
-        .with_context(|| format!("Invalid regex pattern: {}", args.pattern))?;
+    match args.command {
+        Some(Command::Why { ref path }) => run_why(&args, path),
+        Some(Command::Audit { ref run_id }) => run_audit(run_id.as_deref()),
+        Some(Command::InstallHook { ref pattern, pre_commit_config, force }) => {
+            run_install_hook(&args, pattern, pre_commit_config, force)
+        }
+        Some(Command::Recipes { action: RecipesCommand::List }) => {
+            run_recipes_list();
+            Ok(())
+        }
+        Some(Command::Recipes { action: RecipesCommand::Run { ref name } }) => {
+            run_and_notify(&args, || recipes::find(name).map(Some))
+        }
+        Some(Command::Headers { ref header, mode, ref existing }) => run_headers(&args, header, mode, existing),
+        Some(Command::Scrub { style }) => run_scrub(&args, style),
+        Some(Command::Apply { ref patch }) => run_apply_patch(&args, patch),
+        Some(Command::Fleet(ref fleet)) => run_fleet(fleet),
+        Some(Command::Serve) => server::serve(io::stdin().lock(), io::stdout().lock(), io::stderr()),
+        Some(Command::Refine { ref pattern, ref replace }) => run_refine(&args, pattern.as_deref(), replace),
+        Some(Command::Search { ref pattern }) => run_search(&args, pattern),
+        Some(Command::Compare { ref pattern, json, ref dir_a, ref dir_b }) => {
+            run_compare(&args, pattern, json, dir_a, dir_b)
+        }
+        Some(Command::Template { ref vars, ref from, ref dir }) => {
+            run_template(&args, vars, from.as_deref(), dir)
+        }
+        None if args.hook => run_hook_check(&args),
+        None if args.explain.is_some() => run_explain(&args, args.explain.clone().flatten().as_deref()),
+        None if args.apply_last => run_apply_last(&args),
+        None if args.undo.is_some() => run_undo(&args, args.undo.clone().flatten().as_deref()),
+        None if let Some(ref pattern) = args.pattern
+            && searches_only(&args) =>
+        {
+            run_search(&args, pattern)
+        }
+        None => run_and_notify(&args, || args.rule_pack.as_deref().map(RulePack::load).transpose()),
+    }
+}
 
-    let extensions: Option<Vec<&str>> = args.extensions.as_ref().map(|ext| {
-        ext.split(',').collect()
-    });
+/// Runs a replacement with the rule pack returned by `load_pack`, if any,
+/// sends the outcome to every `--notify` target and ends with a summary
+/// trailer on stderr.
+fn run_and_notify(args: &Args, load_pack: impl FnOnce() -> Result<Option<RulePack>>) -> Result<()> {
+    let run_id = Uuid::new_v4().to_string();
+    let result = load_pack().and_then(|pack| run_replace(args, &run_id, pack));
 
-    let ignore_set = Arc::new(build_ignore_set(&args.directory)?);
-    let base_dir = std::fs::canonicalize(&args.directory)?;
-    
-    if args.verbose {
-        println!("Base directory: {}", base_dir.display());
-        println!("Starting directory walk from: {}", args.directory);
+    let summary = match &result {
+        Ok(summary) => summary.clone(),
+        Err(e) => RunSummary {
+            run_id: run_id.clone(),
+            dry_run: args.dry_run,
+            error: Some(format!("{e:#}")),
+            ..Default::default()
+        },
+    };
+    for target in &args.notify {
+        if let Err(e) = notify::send(target, &summary) {
+            eprintln!("Warning: notification failed: {e:#}");
+        }
     }
+    io::stdout().flush()?;
+    eprintln!("{}", summary.trailer());
 
-    let mut total_files = 0;
-    let mut modified_files = 0;
+    if args.check_diff && result.as_ref().is_ok_and(|summary| summary.files_modified > 0) {
+        io::stdout().flush()?;
+        std::process::exit(CHANGES_PENDING_EXIT_CODE);
+    }
+    result.map(|_| ())
+}
 
-    let walker = WalkDir::new(&args.directory)
-        .into_iter()
-        .filter_entry(|e| {
-            let path = e.path();
-            
-            // Always accept the root directory we're starting from
-            if path == Path::new(&args.directory) {
-                return true;
-            }
-            
-            // Filter out hidden entries unless include_hidden is set
-            if !args.include_hidden && is_hidden(e) {
-                return false;
-            }
-            
-            // Filter out ignored paths (both files and directories)
-            if should_ignore_fast(path, &base_dir, &ignore_set) {
-                if args.verbose {
-                    println!("Ignoring: {}", path.display());
-                }
-                return false;
+fn run_audit(run_id: Option<&str>) -> Result<()> {
+    let path = audit::log_path()?;
+    let records = audit::load(&path)?;
+    match run_id {
+        Some(id) => print!("{}", audit::find(&records, id)?.render()),
+        None if records.is_empty() => println!("No runs recorded in {}", path.display()),
+        None => {
+            for record in &records {
+                println!("{}", record.summary());
             }
-            
-            true
-        })
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file());
+        }
+    }
+    Ok(())
+}
+
+fn run_recipes_list() {
+    let recipes = recipes::all();
+    let width = recipes.iter().map(|pack| pack.name.len()).max().unwrap_or(0);
+    for pack in &recipes {
+        println!(
+            "{:width$}  {}  {}",
+            pack.name,
+            pack.version,
+            pack.description.as_deref().unwrap_or_default()
+        );
+    }
+}
+
+/// Settings given on the command line; `rules` are the command line rules,
+/// which `.rr.toml` files in nested projects may override.
+fn cli_defaults(args: &Args, rules: Vec<Rule>) -> ProjectSettings {
+    ProjectSettings {
+        extensions: args.extensions.as_ref().map(|ext| ext.split(',').map(str::to_string).collect()),
+        rules: Arc::new(rules),
+        generated_markers: (!args.generated_markers.is_empty()).then(|| args.generated_markers.clone()),
+        ..Default::default()
+    }
+}
 
-    for entry in walker {
-        let path = entry.path();
+fn build_filter(args: &Args, rules: Vec<Rule>) -> Result<FileFilter> {
+    new_filter(args, &args.directory, cli_defaults(args, rules), &args.globs)
+}
 
-        if let Some(ref exts) = extensions {
-            let has_valid_extension = path.extension()
-                .and_then(|ext| ext.to_str())
-                .map(|ext| exts.contains(&ext))
-                .unwrap_or(false);
-            
-            if !has_valid_extension {
-                continue;
-            }
+/// A filter for `directory` with the file filters given on the command line.
+fn new_filter(args: &Args, directory: &str, defaults: ProjectSettings, globs: &[String]) -> Result<FileFilter> {
+    let mut filter = FileFilter::new(directory, defaults, globs, args.include_hidden)?;
+    if let Some(ref owner) = args.owner {
+        let uid = filesystem::user_id(owner).with_context(|| format!("Invalid --owner: {owner}"))?;
+        filter = filter.owned_by(uid);
+    }
+    if args.writable_only {
+        filter = filter.writable_only();
+    }
+    if let Some(ref suffix) = args.backup {
+        filter = filter.skip_backups(suffix);
+    }
+    if args.no_gitignore {
+        filter = filter.no_gitignore();
+    }
+    if args.include_generated {
+        filter = filter.include_generated();
+    }
+    if args.include_ads {
+        filter = filter.include_ads();
+    }
+    Ok(filter)
+}
+
+fn run_why(args: &Args, target: &Path) -> Result<()> {
+    let filter = build_filter(args, Vec::new())?;
+
+    if target.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink()) {
+        println!("{}: skipped", target.display());
+        println!("  symbolic links are not followed");
+        return Ok(());
+    }
+    if !target.is_file() {
+        anyhow::bail!("Not a regular file: {}", target.display());
+    }
+
+    let explanation = filter.explain(target)?;
+    let settings = filter.settings_for(target)?;
+    match explanation {
+        None => println!("{}: would be processed", target.display()),
+        Some((excluded, reason)) => {
+            println!("{}: skipped", target.display());
+            println!("  {}: {}", excluded.display(), reason);
         }
+    }
+    if let Some(ref config) = settings.config_file {
+        println!("  project config: {}", config.display());
+    }
+
+    Ok(())
+}
 
-        match process_file(path, &regex, &args.replace, args.dry_run, args.verbose) {
-            Ok(modified) => {
-                total_files += 1;
-                if modified {
-                    modified_files += 1;
+fn run_headers(args: &Args, header_file: &Path, mode: HeaderMode, existing: &str) -> Result<()> {
+    let text = std::fs::read_to_string(header_file)
+        .with_context(|| format!("Failed to read header file: {}", header_file.display()))?;
+    let existing = Regex::new(existing).with_context(|| format!("Invalid regex pattern: {existing}"))?;
+    let filter = build_filter(args, Vec::new())?;
+
+    let (mut inserted, mut replaced, mut unchanged, mut unsupported, mut failed) = (0, 0, 0, 0, 0);
+    for change in headers::update_tree(&filter, &text, &existing, mode, args.dry_run)? {
+        match change {
+            HeaderChange::Inserted(path) => {
+                inserted += 1;
+                if args.verbose || args.dry_run {
+                    println!("Inserted: {}", path.display());
+                }
+            }
+            HeaderChange::Replaced(path) => {
+                replaced += 1;
+                if args.verbose || args.dry_run {
+                    println!("Replaced: {}", path.display());
+                }
+            }
+            HeaderChange::Unchanged(_) => unchanged += 1,
+            HeaderChange::Unsupported(path) => {
+                unsupported += 1;
+                if args.verbose {
+                    println!("Skipped (no known comment syntax): {}", path.display());
                 }
             }
-            Err(e) => {
-                eprintln!("Error processing {}: {}", path.display(), e);
+            HeaderChange::Failed(path, error) => {
+                failed += 1;
+                eprintln!("Error processing {}: {}", path.display(), error);
             }
         }
     }
 
     println!("\nSummary:");
-    println!("Total files processed: {total_files}");
-    println!("Files modified: {modified_files}");
+    println!("Headers inserted: {inserted}");
+    println!("Headers replaced: {replaced}");
+    println!("Files unchanged: {unchanged}");
+    println!("Files skipped (no known comment syntax): {unsupported}");
+    if failed > 0 {
+        println!("Files failed: {failed}");
+    }
+    if args.dry_run {
+        println!("(Dry run - no files were actually modified)");
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} file(s) could not be given the header");
+    }
+    Ok(())
+}
+
+fn run_scrub(args: &Args, style: ScrubStyle) -> Result<()> {
+    let filter = build_filter(args, Vec::new())?;
+    let scrub::Scrubbed { findings, failed } = scrub::scrub_tree(&filter, &scrub::detectors(), style, args.dry_run)?;
+    for (path, error) in &failed {
+        eprintln!("Error processing {}: {}", path.display(), error);
+    }
+
+    // Only fingerprints are printed, so the report is as safe to share as the files
+    let mut per_detector: BTreeMap<&str, usize> = BTreeMap::new();
+    for finding in &findings {
+        println!(
+            "{}:{}:{}: {} ({})",
+            finding.path.display(),
+            finding.line,
+            finding.column,
+            finding.detector,
+            finding.fingerprint
+        );
+        *per_detector.entry(finding.detector).or_default() += 1;
+    }
+    let files: HashSet<_> = findings.iter().map(|finding| &finding.path).collect();
+
+    println!("\nSummary:");
+    println!("Secrets scrubbed: {} in {} file(s)", findings.len(), files.len());
+    for (detector, count) in per_detector {
+        println!("  {detector}: {count}");
+    }
+    if !failed.is_empty() {
+        println!("Files failed: {}", failed.len());
+    }
     if args.dry_run {
         println!("(Dry run - no files were actually modified)");
     }
+    if !failed.is_empty() {
+        anyhow::bail!("{} file(s) could not be scrubbed and may still hold secrets", failed.len());
+    }
+    Ok(())
+}
 
+fn run_refine(args: &Args, pattern: Option<&str>, replacement: &str) -> Result<()> {
+    let pattern = pattern
+        .map(|pattern| Regex::new(pattern).with_context(|| format!("Invalid regex pattern: {pattern}")))
+        .transpose()?;
+    let filter = build_filter(args, Vec::new())?;
+    let corpus = Corpus::load(&filter)?;
+    refine::run_session(
+        &corpus,
+        Path::new(&args.directory),
+        pattern,
+        replacement.to_string(),
+        &mut io::stdin().lock(),
+        &mut io::stdout(),
+    )?;
     Ok(())
 }
 
+/// Whether the command line gives a pattern but nothing to replace its
+/// matches with, so is a search.
+fn searches_only(args: &Args) -> bool {
+    args.replace.is_none()
+        && !args.replace_stdin
+        && args.replace_script.is_none()
+        && args.rules.is_empty()
+        && args.roots.is_none()
+        && args.rule_pack.is_none()
+        && args.patterns_from.is_none()
+}
+
+fn run_search(args: &Args, pattern: &str) -> Result<()> {
+    let regex = Regex::new(pattern).with_context(|| format!("Invalid regex pattern: {pattern}"))?;
+    let filter = build_filter(args, Vec::new())?;
+    let hits = search::search_tree(&filter, &regex)?;
+
+    let color = args.color.enabled();
+    for hit in &hits {
+        let text = if color { color::removed(&hit.text) } else { hit.text.clone() };
+        println!("{}:{}:{}: {}", hit.path.display(), hit.line, hit.column, text);
+    }
+    let files: HashSet<_> = hits.iter().map(|hit| &hit.path).collect();
+    println!("\nSummary:");
+    println!("Matches: {} in {} file(s)", hits.len(), files.len());
+    Ok(())
+}
+
+/// Runs the replacement in `fleet.run` over each root listed, as a separate
+/// process per root with its output kept in a log, recording how each went
+/// so a rerun skips the roots already done.
+fn run_fleet(fleet: &FleetArgs) -> Result<()> {
+    // Checked once here rather than failing the same way in every root
+    let run = Args::try_parse_from(std::iter::once("regex-replace").chain(fleet.run.iter().map(String::as_str)))?;
+    if run.command.is_some() {
+        anyhow::bail!("fleet runs a replacement over each root; give its options after --, not a subcommand");
+    }
+    if run.directory != "." || run.roots.is_some() {
+        anyhow::bail!("fleet sets the directory of each run; leave --directory and --roots out of the options after --");
+    }
+
+    let roots = fleet::load_roots(&fleet.roots)?;
+    let state_dir = fleet.state_dir.clone().unwrap_or_else(|| fleet.roots.with_extension("fleet"));
+    let logs = state_dir.join("logs");
+    std::fs::create_dir_all(&logs).with_context(|| format!("Failed to create directory: {}", logs.display()))?;
+    let state_path = state_dir.join("state.json");
+    let state = if fleet.restart { FleetState::default() } else { FleetState::load(&state_path)? };
+    let pending: Vec<&PathBuf> = roots.iter().filter(|root| !state.is_done(root)).collect();
+    let skipped = roots.len() - pending.len();
+    if skipped > 0 {
+        println!("Skipping {skipped} root(s) done by an earlier run; pass --restart to process them again");
+    }
 
-fn build_ignore_set(working_dir: &str) -> Result<GlobSet> {
-    let mut builder = GlobSetBuilder::new();
-    
-    // Add some common patterns by default for better performance
-    let default_patterns = [".git/**", ".svn/**", "target/**", "node_modules/**"];
-    for pattern in &default_patterns {
-        if let Ok(glob) = Glob::new(pattern) {
-            builder.add(glob);
+    let exe = std::env::current_exe().context("Failed to find the regex-replace executable")?;
+    let timeout = fleet.root_timeout.map(Duration::from_secs);
+    let next = AtomicUsize::new(0);
+    let finished = AtomicUsize::new(skipped);
+    let state = Mutex::new(state);
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..fleet.jobs.max(1))
+            .map(|_| scope.spawn(|| -> Result<()> {
+                while let Some(&root) = pending.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let mut command = std::process::Command::new(&exe);
+                    command.args(&fleet.run).arg("-d").arg(root);
+                    let status = fleet::run_root(command, &fleet::log_path(&logs, root), timeout);
+
+                    let mut state = state.lock().unwrap();
+                    state.roots.insert(root.clone(), status.clone());
+                    state.save(&state_path)?;
+                    let done = finished.fetch_add(1, Ordering::Relaxed) + 1;
+                    println!("[{done}/{}] {}: {status}", roots.len(), root.display());
+                }
+                Ok(())
+            }))
+            .collect();
+        workers.into_iter().try_for_each(|worker| worker.join().unwrap())
+    })?;
+
+    let state = state.into_inner().unwrap();
+    let listed = FleetState {
+        roots: roots.iter().filter_map(|root| Some((root.clone(), state.roots.get(root)?.clone()))).collect(),
+    };
+    let (mut files, mut modified, mut errors) = (0, 0, 0);
+    let mut failed = Vec::new();
+    for (root, status) in &listed.roots {
+        match status {
+            RootStatus::Done { files: f, modified: m, errors: e } => {
+                files += f;
+                modified += m;
+                errors += e;
+            }
+            RootStatus::Failed { error } => failed.push((root, error)),
         }
     }
-    
-    // Load .rr_ignore from current working directory
-    let cwd_ignore = Path::new(".").join(".rr_ignore");
-    if cwd_ignore.exists() {
-        load_ignore_file(&cwd_ignore, &mut builder)?;
+    if let Some(ref report_path) = fleet.report {
+        listed.save(report_path)?;
+    }
+
+    println!("\nSummary:");
+    println!("Roots: {}", roots.len());
+    println!("Roots done: {} ({skipped} by an earlier run)", roots.len() - failed.len());
+    println!("Roots failed: {}", failed.len());
+    println!("Total files processed: {files}");
+    println!("Files modified: {modified}");
+    if errors > 0 {
+        println!("Files with errors: {errors}");
+    }
+    for (root, error) in &failed {
+        println!("  {}: {error} (log: {})", root.display(), fleet::log_path(&logs, root).display());
+    }
+    println!("Status and logs in {}", state_dir.display());
+    if !failed.is_empty() {
+        anyhow::bail!("{} of {} roots failed; run the fleet again to retry them", failed.len(), roots.len());
+    }
+    Ok(())
+}
+
+fn run_compare(args: &Args, pattern: &str, json: bool, dir_a: &Path, dir_b: &Path) -> Result<()> {
+    let regex = Regex::new(pattern).with_context(|| format!("Invalid regex pattern: {pattern}"))?;
+    let filter = |dir: &Path| new_filter(args, &dir.display().to_string(), cli_defaults(args, Vec::new()), &args.globs);
+    let (filter_a, filter_b) = (filter(dir_a)?, filter(dir_b)?);
+    let comparison = compare::compare((&filter_a, dir_a), (&filter_b, dir_b), &regex)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&comparison)?);
+    } else {
+        print!("{}", comparison.render());
+    }
+    if !comparison.differences.is_empty() {
+        anyhow::bail!("Matches differ in {} file(s)", comparison.differences.len());
+    }
+    Ok(())
+}
+
+/// Quotes `value` for safe inclusion in a POSIX shell command line.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+fn run_install_hook(args: &Args, pattern: &str, pre_commit_config: bool, force: bool) -> Result<()> {
+    Regex::new(pattern).with_context(|| format!("Invalid regex pattern: {pattern}"))?;
+
+    if pre_commit_config {
+        println!("repos:");
+        println!("  - repo: local");
+        println!("    hooks:");
+        println!("      - id: regex-replace");
+        println!("        name: regex-replace banned patterns");
+        println!("        entry: regex-replace --hook -p {}", shell_quote(pattern));
+        println!("        language: system");
+        println!("        pass_filenames: false");
+        return Ok(());
+    }
+
+    let hooks_dir = git::hooks_dir(Path::new(&args.directory))?;
+    let hook_path = hooks_dir.join("pre-commit");
+    if hook_path.exists() && !force {
+        anyhow::bail!("{} already exists (use --force to overwrite)", hook_path.display());
+    }
+
+    let script = format!(
+        "#!/bin/sh\n# Installed by regex-replace install-hook\nexec regex-replace --hook -p {}\n",
+        shell_quote(pattern)
+    );
+    std::fs::create_dir_all(&hooks_dir)?;
+    std::fs::write(&hook_path, script)
+        .with_context(|| format!("Failed to write hook: {}", hook_path.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&hook_path, std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    println!("Installed pre-commit hook at {}", hook_path.display());
+    Ok(())
+}
+
+/// The compiled `--assert-no-remaining` pattern, if one was given.
+fn remaining_regex(args: &Args) -> Result<Option<Regex>> {
+    args.assert_no_remaining
+        .as_deref()
+        .map(|pattern| Regex::new(pattern).with_context(|| format!("Invalid regex pattern: {pattern}")))
+        .transpose()
+}
+
+/// Reports text left behind that matches `--assert-no-remaining` and fails
+/// the run if there is any.
+fn check_remaining(remaining: &[(PathBuf, RemainingMatch)]) -> Result<()> {
+    if remaining.is_empty() {
+        return Ok(());
     }
-    
-    // Load .rr_ignore from target directory
-    if working_dir != "." {
-        let local_ignore = Path::new(working_dir).join(".rr_ignore");
-        if local_ignore.exists() && local_ignore != cwd_ignore {
-            load_ignore_file(&local_ignore, &mut builder)?;
+    for (path, m) in remaining {
+        match m.line {
+            0 => eprintln!("{}: {} (in name)", path.display(), m.text),
+            line => eprintln!("{}:{}: {}", path.display(), line, m.text),
         }
     }
-    
-    // Load ~/.rr_ignore from home directory
-    if let Ok(home_dir) = std::env::var("HOME") {
-        let home_ignore = PathBuf::from(home_dir).join(".rr_ignore");
-        if home_ignore.exists() {
-            load_ignore_file(&home_ignore, &mut builder)?;
+    anyhow::bail!(
+        "{} match{} of --assert-no-remaining left in the output",
+        remaining.len(),
+        if remaining.len() == 1 { "" } else { "es" }
+    )
+}
+
+/// Reports the keys `--assert-consistent` found with differing values and
+/// fails the run if there are any.
+fn check_consistent(occurrences: &[(PathBuf, Occurrence)]) -> Result<()> {
+    let differing = consistency::inconsistencies(occurrences);
+    if differing.is_empty() {
+        return Ok(());
+    }
+    eprint!("{}", consistency::render(&differing));
+    anyhow::bail!(
+        "--assert-consistent found differing values for {} key{}",
+        differing.len(),
+        if differing.len() == 1 { "" } else { "s" }
+    )
+}
+
+fn run_template(args: &Args, vars_file: &Path, from: Option<&Path>, dir: &Path) -> Result<()> {
+    let vars = template::load_vars(vars_file)?;
+    let remaining = remaining_regex(args)?;
+    if let Some(from) = from {
+        if args.dry_run {
+            println!("Would copy {} to {}", from.display(), dir.display());
+            // Preview against the template itself, since nothing is copied
+            return report_template(args, template::instantiate(from, &vars, true, remaining.as_ref())?);
         }
+        template::copy_tree(from, dir)?;
     }
-    
-    builder.build()
-        .with_context(|| "Failed to build ignore pattern set")
+    report_template(args, template::instantiate(dir, &vars, args.dry_run, remaining.as_ref())?)
 }
 
-fn load_ignore_file(path: &Path, builder: &mut GlobSetBuilder) -> Result<()> {
-    let content = fs::read_to_string(path)
-        .with_context(|| format!("Failed to read ignore file: {}", path.display()))?;
-    
-    for line in content.lines() {
-        let line = line.trim();
-        
-        // Skip empty lines and comments
-        if line.is_empty() || line.starts_with('#') {
-            continue;
+fn report_template(args: &Args, changes: Vec<TemplateChange>) -> Result<()> {
+    let mut updated = 0;
+    let mut renamed = 0;
+    let mut remaining = Vec::new();
+    for change in changes {
+        match change {
+            TemplateChange::Updated(path) => {
+                updated += 1;
+                if args.verbose || args.dry_run {
+                    println!("Updated: {}", path.display());
+                }
+            }
+            TemplateChange::Renamed(from, to) => {
+                renamed += 1;
+                if args.verbose || args.dry_run {
+                    println!("Renamed: {} -> {}", from.display(), to.display());
+                }
+            }
+            TemplateChange::Remaining(path, m) => remaining.push((path, m)),
         }
-        
-        // Add the glob pattern
-        let glob = Glob::new(line)
-            .with_context(|| format!("Invalid glob pattern in {}: {}", path.display(), line))?;
-        builder.add(glob);
     }
-    
-    Ok(())
+
+    println!("\nSummary:");
+    println!("Files updated: {updated}");
+    println!("Paths renamed: {renamed}");
+    if args.dry_run {
+        println!("(Dry run - no files were actually modified)");
+    }
+    check_remaining(&remaining)
 }
 
-fn should_ignore_fast(path: &Path, base_dir: &Path, ignore_set: &Arc<GlobSet>) -> bool {
-    // Get relative path from base directory
-    let relative_path = match path.strip_prefix(base_dir) {
-        Ok(rel) => rel,
-        Err(_) => return false,
+fn run_hook_check(args: &Args) -> Result<()> {
+    let Some(pattern) = &args.pattern else {
+        anyhow::bail!("--pattern is required");
     };
-    
-    // Check if the path matches any ignore pattern
-    ignore_set.is_match(relative_path)
-}
+    let regex = Regex::new(pattern)
+        .with_context(|| format!("Invalid regex pattern: {pattern}"))?;
 
-fn is_hidden(entry: &DirEntry) -> bool {
-    entry.file_name()
-         .to_str()
-         .map(|s| s.starts_with('.'))
-         .unwrap_or(false)
+    let violations: Vec<_> = git::staged_added_lines(Path::new(&args.directory))?
+        .into_iter()
+        .filter(|added| regex.is_match(&added.text))
+        .collect();
+
+    for added in &violations {
+        eprintln!("{}:{}: {}", added.path, added.line, added.text.trim());
+    }
+
+    if !violations.is_empty() {
+        anyhow::bail!(
+            "Banned pattern {} found in {} staged line{}",
+            pattern,
+            violations.len(),
+            if violations.len() == 1 { "" } else { "s" }
+        );
+    }
+    Ok(())
+}
+
+/// Prints how the `-p`/`-r` rule's replacement expands for its first match
+/// in `sample`, or failing that, in the files searched.
+fn run_explain(args: &Args, sample: Option<&str>) -> Result<()> {
+    let (Some(pattern), Some(replace)) = (&args.pattern, &args.replace) else {
+        anyhow::bail!("--explain needs --pattern and --replace");
+    };
+    let folding = CaseFolding { mode: args.case_fold, turkic: args.turkic_i };
+    let stamped = stamp_replacement(replace, audit::now())?;
+    let config = RuleConfig { pattern: pattern.clone(), replace: stamped.clone(), extensions: None, preserve_case: args.preserve_case };
+    let rule = config.compile_with(folding)?;
+    println!("Pattern:     {pattern}");
+    println!("Replacement: {replace}");
+    if stamped != *replace {
+        println!("Stamped:     {stamped}");
+    }
+
+    let found = match sample {
+        Some(sample) => rule.regex.is_match(sample).then(|| ("the sample".to_string(), sample.to_string())),
+        None => {
+            let filter = build_filter(args, vec![rule.clone()])?;
+            let mut found = None;
+            for entry in filter.walk()?.files {
+                let Ok(content) = filter.fs().read_to_string(&entry.path) else {
+                    continue;
+                };
+                if let Some(m) = rule.regex.find(&content) {
+                    let line = content[..m.start()].matches('\n').count() + 1;
+                    found = Some((format!("{}:{line}", entry.path.display()), content));
+                    break;
+                }
+            }
+            found
+        }
+    };
+    let Some((location, content)) = found else {
+        println!("\nNo match found to explain.");
+        return Ok(());
+    };
+
+    let caps = rule.regex.captures(&content).expect("the pattern matched");
+    let names: Vec<Option<&str>> = rule.regex.capture_names().collect();
+    println!("\nFirst match, in {location}:");
+    for (index, name) in names.iter().enumerate() {
+        let label = match name {
+            Some(name) => format!("${index} ${{{name}}}"),
+            None => format!("${index}"),
+        };
+        match caps.get(index) {
+            Some(m) => println!("  {label:<16} = {:?}", m.as_str()),
+            None => println!("  {label:<16} (did not take part; expands to \"\")"),
+        }
+    }
+    let expanded = rule.replacements(&content).into_iter().next().map(|rep| rep.text).unwrap_or_default();
+    println!("\nExpands to:  {expanded:?}");
+    Ok(())
+}
+
+/// One directory tree processed by a run, with its own filter and rules.
+struct Root {
+    directory: String,
+    filter: FileFilter,
+}
+
+/// Counts and output accumulated across every root of a run.
+#[derive(Default)]
+struct RunTotals {
+    files_processed: usize,
+    files_modified: usize,
+    /// Files that couldn't be processed, or whose hooks failed
+    files_failed: usize,
+    diffstat: Vec<(String, usize, usize)>,
+    patch: String,
+    /// Name and hash of each file in `patch`, for its header
+    patch_hashes: Vec<(String, String)>,
+    skipped: Vec<(PathBuf, String)>,
+    remaining: Vec<(PathBuf, RemainingMatch)>,
+    occurrences: Vec<(PathBuf, Occurrence)>,
+    analysis: Analysis,
+    files_not_idempotent: usize,
+    /// Files left as they were because they changed on disk during the run
+    files_changed_on_disk: usize,
+    /// Files on which the `--shadow-cmd` result differs from this run's
+    files_shadow_differs: usize,
+    /// Files whose matches were reused from an earlier file with the same content
+    files_reused: usize,
+    /// Files that weren't valid UTF-8, processed with `--lossy-utf8` or `--bytes-fallback`
+    invalid_utf8: Vec<PathBuf>,
+    /// Files whose match step exceeded `--match-budget`, and whether they were abandoned
+    over_budget: Vec<(PathBuf, Duration, bool)>,
+    found: Vec<(PathBuf, MatchRecord)>,
+    preview: Vec<PreviewFile>,
+    /// Files searched and their rules, kept for `--why-no-match`
+    scanned: Vec<(PathBuf, Arc<Vec<Rule>>)>,
+    /// Entries skipped, per kind of reason
+    skip_kinds: BTreeMap<&'static str, usize>,
+    /// Match counts per file, kept for `--top-files`
+    file_matches: Vec<FileMatches>,
+    /// Files modified and their match counts, kept for `--tree`
+    tree: Vec<(PathBuf, usize)>,
+    matches: usize,
+    lines_added: usize,
+    lines_removed: usize,
+    /// With `--sample`, the output and diffstat entry of the sampled files
+    sample: Option<Reservoir<SampledFile>>,
+    /// Files written, for the audit log
+    audited: Vec<AuditFile>,
+    /// How to restore the files written, for the undo journal
+    journal: Vec<JournalFile>,
+    /// With `--filelist` or file arguments, the files to process instead of
+    /// walking each root
+    listed: Option<Vec<PathBuf>>,
+    /// Every file selected, for `--emit-filelist`
+    selected: Vec<PathBuf>,
+    /// Every file processed, for `--output json`
+    reports: Vec<FileReport>,
+    /// Leave stdout to the JSON report instead of printing the usual output
+    quiet: bool,
+    /// How the queue between the walk and the workers filled, for `--stats`
+    queue: Option<QueueStats>,
+    /// Files processed and their rules, kept for `--rename-files` and `--rename-dirs`
+    renamable: Vec<(PathBuf, Arc<Vec<Rule>>)>,
+    paths_renamed: usize,
+    /// The renames made, for the undo journal
+    journal_renames: Vec<rename::Rename>,
+}
+
+/// A file kept by `--sample`: its report output and diffstat entry.
+struct SampledFile {
+    output: Vec<u8>,
+    stat: (String, usize, usize),
+}
+
+/// The roots a run covers: the entries of `--roots`, or just `--directory`.
+/// `cli_rules` come from `-p`/`-r`; a rule pack's rules and scope are layered
+/// on top, and each root's own settings on top of that.
+fn build_roots(args: &Args, cli_rules: Vec<Rule>, pack: Option<&RulePack>) -> Result<Vec<Root>> {
+    let roots = match args.roots {
+        Some(ref roots_file) => RootsConfig::load(roots_file)?.roots,
+        None if cli_rules.is_empty() && pack.is_none() => anyhow::bail!("--pattern and --replace are required"),
+        None => vec![RootConfig { directory: PathBuf::from(&args.directory), ..Default::default() }],
+    };
+
+    roots
+        .into_iter()
+        .map(|root| {
+            let directory = root.directory.display().to_string();
+            let mut defaults = cli_defaults(args, cli_rules.clone());
+            let mut globs = args.globs.clone();
+            if let Some(pack) = pack {
+                pack.apply(&mut defaults, &mut globs)?;
+            }
+            if let Some(rules) = root.rules {
+                defaults.rules = rules.iter().map(RuleConfig::compile).collect::<Result<Vec<_>>>()
+                    .with_context(|| format!("Invalid rule for root {directory}"))?
+                    .into();
+            } else if defaults.rules.is_empty() {
+                anyhow::bail!("Root {directory} has no rules and no --pattern/--replace or --rule-pack was given");
+            }
+            if root.extensions.is_some() {
+                defaults.extensions = root.extensions;
+            }
+            globs.extend(root.globs);
+
+            let filter = new_filter(args, &directory, defaults, &globs)?;
+            Ok(Root { directory, filter })
+        })
+        .collect()
+}
+
+fn run_replace(args: &Args, run_id: &str, pack: Option<RulePack>) -> Result<RunSummary> {
+    let pack = match pack {
+        Some(mut pack) => {
+            pack.select(&args.only_rules, &args.skip_rules)?;
+            Some(pack)
+        }
+        None if !args.only_rules.is_empty() || !args.skip_rules.is_empty() => {
+            anyhow::bail!("--only-rules and --skip-rules select rules of a --rule-pack or recipe")
+        }
+        None => None,
+    };
+    let replacement = if args.replace_stdin {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text).context("Failed to read the replacement from standard input")?;
+        Some(text)
+    } else {
+        args.replace.clone()
+    };
+    // The script decides every replacement, so the rule needs none of its own
+    let replacement = replacement.or_else(|| args.replace_script.as_ref().map(|_| String::new()));
+    // Environment variables and dates are read once, so every file gets the same values
+    let now = audit::now();
+    let mut stamped = Vec::new();
+    let mut stamp = |text: &str| -> Result<String> {
+        let result = stamp_replacement(text, now)?;
+        if result != text {
+            stamped.push(result.clone());
+        }
+        Ok(result)
+    };
+    let replacement = replacement.as_deref().map(&mut stamp).transpose()?;
+    let replace_script = args.replace_script.as_deref().map(ReplaceScript::load).transpose()?;
+    let folding = CaseFolding { mode: args.case_fold, turkic: args.turkic_i };
+    let mut cli_rules = match (&args.pattern, &replacement) {
+        (Some(pattern), Some(replacement)) => {
+            let rule = RuleConfig {
+                pattern: pattern.clone(),
+                replace: replacement.clone(),
+                extensions: None,
+                preserve_case: args.preserve_case,
+            };
+            let mut rule = rule.compile_with(folding)?;
+            for then in args.then.chunks(4) {
+                rule = rule.then(then_rule(then, args.preserve_case, folding, &mut stamp)?);
+            }
+            vec![rule]
+        }
+        (None, None) => Vec::new(),
+        (None, Some(_)) if args.patterns_from.is_some() => Vec::new(),
+        _ => anyhow::bail!("--pattern and --replace must be given together"),
+    };
+    if let Some(ref source) = args.patterns_from {
+        if source == Path::new("-") && args.files_from.as_deref() == Some(Path::new("-")) {
+            anyhow::bail!("--patterns-from and --files-from cannot both read standard input");
+        }
+        let mut rule = pattern_list::compile(&pattern_list::read_from(source)?, replacement.as_deref())?;
+        if args.preserve_case {
+            rule = rule.preserving_case().with_case_folding(folding);
+        }
+        cli_rules.push(rule);
+    }
+    for rule in &args.rules {
+        let rule = RuleConfig { preserve_case: args.preserve_case, replace: stamp(&rule.replace)?, ..rule.clone() };
+        cli_rules.push(rule.compile_with(folding).with_context(|| format!("Invalid --rule {}=>{}", rule.pattern, rule.replace))?);
+    }
+
+    if let Some(rule) = cli_rules.first()
+        && let Some(condition) = args.capture_range.iter().find(|condition| !condition.applies_to(&rule.regex))
+    {
+        anyhow::bail!("--capture-range tests {}, a group the pattern {} doesn't have", condition.group, rule.regex);
+    }
+
+    if args.nice && let Err(e) = throttle::lower_priority() {
+        eprintln!("Warning: failed to lower process priority: {e}");
+    }
+
+    let throttle = match args.throttle {
+        Some(rate) if rate > 0.0 => Some(Throttle::new(rate)),
+        Some(rate) => anyhow::bail!("Invalid throttle rate: {rate} (must be greater than zero)"),
+        None => None,
+    };
+
+    let roots = build_roots(args, cli_rules, pack.as_ref())?;
+
+    // The --max-total budget goes to files in the order they are processed,
+    // which only one thread keeps the same from run to run
+    let pool = args.threads
+        .filter(|_| args.max_total.is_none())
+        .map(|threads| rayon::ThreadPoolBuilder::new().num_threads(threads).build())
+        .transpose()
+        .context("Failed to start worker threads")?;
+
+    let dry_run = args.dry_run || args.patch_out.is_some();
+    let format = output_format(args);
+    if args.report_file.is_some() && format == OutputFormat::Text {
+        anyhow::bail!("--report-file writes the JSON report, so it needs --output json or jsonl rather than text");
+    }
+    let json = matches!(format, OutputFormat::Json | OutputFormat::Jsonl);
+    if args.export_context.is_some() && !json {
+        anyhow::bail!("--export-context needs --output json or --output jsonl");
+    }
+    let patch_to_stdout = args.patch_out.as_deref() == Some(Path::new("-"));
+    if patch_to_stdout && json && args.report_file.is_none() {
+        anyhow::bail!("--patch-out - and the JSON report can't both go to stdout; pass --report-file for the report");
+    }
+    // The JSON report, or the patch, has stdout to itself unless it goes to a file
+    let quiet = (json && args.report_file.is_none()) || patch_to_stdout;
+    let mut out: Box<dyn Write> = if quiet { Box::new(io::sink()) } else { Box::new(io::stdout()) };
+    let options = ProcessOptions {
+        dry_run,
+        verbose: args.verbose,
+        diff: if args.word_diff {
+            Some(DiffStyle::Word)
+        } else if args.diff {
+            Some(DiffStyle::Unified)
+        } else {
+            None
+        },
+        root: PathBuf::new(),
+        patch: args.patch_out.is_some(),
+        max_matches: None,
+        max_per_file: args.max_per_file,
+        max_total: args.max_total.map(ReplacementBudget::new),
+        remaining: remaining_regex(args)?,
+        shadow: args.shadow_cmd.clone(),
+        dedupe: args.dedupe_content.then(ContentCache::default),
+        consistency: args.assert_consistent
+            .as_deref()
+            .map(|pattern| Regex::new(pattern).with_context(|| format!("Invalid regex pattern: {pattern}")))
+            .transpose()?,
+        analyze: args.analyze_reversibility,
+        check_idempotent: args.check_idempotent,
+        // A budget is only enforceable between chunks, and a pattern span only
+        // matters to them, so either turns chunking on
+        chunking: args.chunk_size
+            .or((args.match_budget.is_some() || args.max_pattern_span.is_some()).then_some(DEFAULT_CHUNK_SIZE))
+            .map(|size| Chunking {
+                size,
+                overlap: args.max_pattern_span.unwrap_or(args.chunk_overlap),
+                parallel: args.threads.is_some(),
+            }),
+        match_budget: args.match_budget.map(Duration::from_millis),
+        record_matches: args.match_report.is_some() || args.extract_to.is_some() || json,
+        context_lines: args.export_context,
+        // A dry run showing every diff is a preview that --apply-last can apply later
+        preview: args.dry_run && args.patch_out.is_none() && args.sample.is_none() && (args.diff || args.word_diff),
+        verify_roundtrip: args.verify_roundtrip,
+        overwrite_concurrent: args.overwrite_concurrent,
+        verify_matches: args.verify,
+        preserve_owner: args.preserve_owner,
+        preserve_times: args.preserve_times,
+        journal: !dry_run && args.output_dir.is_none(),
+        lines: args.lines.clone(),
+        line_mode: args.line_mode,
+        color: args.color.enabled(),
+        show_context: args.context,
+        invalid_utf8: if args.lossy_utf8 {
+            InvalidUtf8::Lossy
+        } else if args.bytes_fallback {
+            InvalidUtf8::Bytes
+        } else {
+            InvalidUtf8::Fail
+        },
+        regions: RegionGuards {
+            only_within: args.only_within.chunks(2).map(|pair| Delimiters::new(&pair[0], &pair[1])).collect::<Result<_>>()?,
+            not_within: args.not_within.chunks(2).map(|pair| Delimiters::new(&pair[0], &pair[1])).collect::<Result<_>>()?,
+        },
+        capture_ranges: args.capture_range.clone(),
+        insertions: Insertions { forbid: !args.allow_empty_matches, max_per_line: args.max_insertions_per_line },
+        on_match: replace_script.map(ReplaceScript::into_hook),
+        annotate: args.annotate.as_ref().map(|note| {
+            note.replace("{run_id}", run_id).replace("{date}", &audit::format_utc(audit::now())[..10])
+        }),
+        backup: match args.backup.as_deref() {
+            Some("") => anyhow::bail!("--backup suffix must not be empty"),
+            Some(suffix) => Some(Backup { suffix: suffix.to_string(), force: args.force_backup }),
+            None => None,
+        },
+        mirror: args.output_dir.clone().map(|dir| Mirror { dir, unchanged: args.output_unchanged }),
+    };
+    if let Some(ref extract) = args.extract_to {
+        let dir = std::path::absolute(extract.fixed_dir())?;
+        for root in &roots {
+            if dir.starts_with(std::path::absolute(&root.directory)?) {
+                anyhow::bail!("--extract-to writes below {}, inside {}, which is being processed", extract.fixed_dir().display(), root.directory);
+            }
+        }
+    }
+    if let Some(ref mirror) = options.mirror {
+        let dir = std::path::absolute(&mirror.dir)?;
+        for root in &roots {
+            if dir.starts_with(std::path::absolute(&root.directory)?) {
+                anyhow::bail!("--output-dir {} is inside {}, which is being processed", mirror.dir.display(), root.directory);
+            }
+        }
+    }
+
+    let directories = std::env::join_paths(roots.iter().map(|root| &root.directory))
+        .context("Root directories cannot be joined into RR_DIRECTORY")?;
+    let run_env = [
+        ("RR_RUN_ID", run_id.to_string()),
+        ("RR_PATTERN", args.pattern.clone().unwrap_or_default()),
+        ("RR_REPLACEMENT", replacement.clone().unwrap_or_default()),
+        ("RR_DIRECTORY", directories.to_string_lossy().into_owned()),
+        ("RR_DRY_RUN", if dry_run { "1" } else { "0" }.to_string()),
+    ];
+
+    if args.verbose {
+        writeln!(out, "Run ID: {run_id}")?;
+        if let Some(ref pack) = pack {
+            writeln!(out, "Using rule pack: {} {}", pack.name, pack.version)?;
+            if let Some(ref description) = pack.description {
+                writeln!(out, "  {description}")?;
+            }
+            for rule in &pack.rules {
+                match rule.description {
+                    Some(ref description) => writeln!(out, "  rule {}: {}", rule.name, description)?,
+                    None => writeln!(out, "  rule {}", rule.name)?,
+                }
+            }
+        }
+    }
+    if args.verbose || dry_run {
+        for text in &stamped {
+            writeln!(out, "Stamped replacement: {text}")?;
+        }
+    }
+
+    // A run over the tree exactly as the same run last left it would change nothing
+    let last_run = match LastRuns::path() {
+        Ok(path) if reruns_skippable(args, &options, quiet) => {
+            let directories = roots.iter().map(|root| std::path::absolute(&root.directory)).collect::<io::Result<Vec<_>>>()?;
+            let command: Vec<String> = std::env::args().skip(1).filter(|arg| arg != "--force-rerun").collect();
+            Some((path, rerun::run_key(&directories, &command)))
+        }
+        _ => None,
+    };
+    let filters: Vec<&FileFilter> = roots.iter().map(|root| &root.filter).collect();
+    if let Some((ref path, ref key)) = last_run
+        && !args.force_rerun
+        && LastRuns::load(path).already_applied(key, &rerun::tree_hash(&filters)?)
+    {
+        writeln!(out, "Already applied: the last run with these options left the tree as it is now; pass --force-rerun to run it again")?;
+        return Ok(RunSummary { run_id: run_id.to_string(), success: true, dry_run, ..Default::default() });
+    }
+
+    if let Some(ref command) = args.pre_cmd {
+        hooks::run_hook("pre", command, &run_env)?;
+    }
+
+    let named = match args.files_from {
+        Some(ref source) => Some(filelist::read_from(source)?),
+        None => (!args.files.is_empty()).then(|| args.files.clone()),
+    };
+    let listed = if let Some(named) = named {
+        let files = filelist::resolve(&named)?;
+        if let Some(outside) = files.iter().find(|file| !roots.iter().any(|root| file.starts_with(root.filter.base_dir()))) {
+            anyhow::bail!("{} is outside the search directory {}; pass --directory to choose another", outside.display(), args.directory);
+        }
+        Some(files)
+    } else {
+        args.filelist.as_deref().map(filelist::load).transpose()?
+    };
+    if let Some(ref listed) = listed
+        && let Some(outside) = listed.iter().find(|file| !roots.iter().any(|root| file.starts_with(root.filter.base_dir())))
+    {
+        anyhow::bail!("{} in the file list is outside the search directory", outside.display());
+    }
+
+    let seed = args.seed.unwrap_or_else(SplitMix64::random_seed);
+    let mut totals = RunTotals {
+        sample: args.sample.map(|size| Reservoir::new(size, seed)),
+        listed,
+        quiet,
+        ..Default::default()
+    };
+    for root in &roots {
+        // With several roots, name files by their full path so they stay distinct
+        let options = ProcessOptions {
+            root: if roots.len() == 1 { PathBuf::from(&root.directory) } else { PathBuf::new() },
+            ..options.clone()
+        };
+        process_root(args, root, &options, throttle.as_ref(), pool.as_ref(), &run_env, &mut totals)?;
+    }
+
+    if let Some(sample) = totals.sample.take() {
+        writeln!(
+            out,
+            "\nSample of {} of {} matching files (seed {seed}; pass --seed {seed} to see it again):",
+            sample.seen().min(args.sample.unwrap_or_default()),
+            sample.seen()
+        )?;
+        for file in sample.into_sorted() {
+            out.write_all(&file.output)?;
+            totals.diffstat.push(file.stat);
+        }
+    }
+
+    if args.explain_skips && !totals.skipped.is_empty() {
+        writeln!(out, "\nSkipped:")?;
+        for (path, reason) in &totals.skipped {
+            writeln!(out, "  {}: {}", path.display(), reason)?;
+        }
+    }
+
+    if let Some(budget) = args.match_budget
+        && !totals.over_budget.is_empty()
+    {
+        writeln!(out, "\nOver the {budget}ms match budget:")?;
+        for (path, elapsed, abandoned) in &totals.over_budget {
+            let note = if *abandoned { " (abandoned, left unchanged)" } else { "" };
+            writeln!(out, "  {}: {}ms{}", path.display(), elapsed.as_millis(), note)?;
+        }
+    }
+
+    if !totals.diffstat.is_empty() {
+        write!(out, "\n{}", diff::format_diffstat(&totals.diffstat))?;
+    }
+
+    if args.tree && !totals.tree.is_empty() {
+        write!(out, "{}", report::render_tree(&totals.tree))?;
+    }
+
+    if let Some(limit) = args.top_files {
+        write!(out, "{}", report::render_top_files(&totals.file_matches, limit))?;
+    }
+
+    if args.stats {
+        writeln!(out, "\nStats:")?;
+        match totals.queue {
+            Some(ref queue) => writeln!(
+                out,
+                "  Walk queue: depth {}, {} files queued, at most {} waiting, walk paused {} time(s) for {}ms",
+                queue.depth,
+                queue.sent,
+                queue.max_queued,
+                queue.blocked,
+                queue.blocked_time.as_millis()
+            )?,
+            None => writeln!(out, "  Walk queue: not used (files are queued only with --threads and --sort path)")?,
+        }
+    }
+
+    if args.analyze_reversibility {
+        write!(out, "{}", totals.analysis.render())?;
+    }
+
+    if !totals.patch.is_empty() {
+        totals.patch.insert_str(0, &patch::header(&totals.patch_hashes));
+    }
+    if patch_to_stdout {
+        print!("{}", totals.patch);
+    } else if let Some(ref patch_path) = args.patch_out {
+        std::fs::write(patch_path, &totals.patch)
+            .with_context(|| format!("Failed to write patch: {}", patch_path.display()))?;
+    }
+
+    if let Some(ref filelist_path) = args.emit_filelist {
+        filelist::save(filelist_path, &totals.selected)?;
+    }
+
+    if let Some(ref report_path) = args.match_report {
+        let rendered = if report_path.extension().is_some_and(|ext| ext == "csv") {
+            report::render_matches_csv(&totals.found)
+        } else {
+            report::render_matches_json(&totals.found)
+        };
+        std::fs::write(report_path, rendered)
+            .with_context(|| format!("Failed to write match report: {}", report_path.display()))?;
+    }
+
+    let preview_path = if options.preview {
+        Some(Preview { run_id: run_id.to_string(), files: totals.preview }.save()?)
+    } else {
+        None
+    };
+
+    writeln!(out, "\nSummary:")?;
+    if roots.len() > 1 {
+        writeln!(out, "Roots: {}", roots.len())?;
+    }
+    writeln!(out, "Total files processed: {}", totals.files_processed)?;
+    writeln!(out, "Files modified: {}", totals.files_modified)?;
+    if let Some(ref dir) = args.output_dir {
+        writeln!(out, "Written to: {}", dir.display())?;
+    }
+    if args.rename_files || args.rename_dirs {
+        writeln!(out, "Paths renamed: {}", totals.paths_renamed)?;
+    }
+    if args.sample.is_some() {
+        writeln!(out, "Matches: {}", totals.matches)?;
+        writeln!(out, "Lines changed: +{} -{}", totals.lines_added, totals.lines_removed)?;
+    }
+    if args.check_idempotent {
+        writeln!(out, "Files not idempotent: {}", totals.files_not_idempotent)?;
+    }
+    if args.shadow_cmd.is_some() {
+        writeln!(out, "Shadow command differs on: {} of {} files", totals.files_shadow_differs, totals.files_processed)?;
+    }
+    if args.dedupe_content {
+        writeln!(out, "Files sharing an earlier file's content, matched once: {} of {}", totals.files_reused, totals.files_processed)?;
+    }
+    if !totals.invalid_utf8.is_empty() {
+        let decoding = if args.lossy_utf8 { "invalid sequences replaced" } else { "matched as bytes" };
+        writeln!(out, "Files not valid UTF-8 ({decoding}): {}", totals.invalid_utf8.len())?;
+        for path in &totals.invalid_utf8 {
+            writeln!(out, "  {}", path.display())?;
+        }
+    }
+    if totals.files_changed_on_disk > 0 {
+        writeln!(out, "Files changed during the run, left as they were: {}", totals.files_changed_on_disk)?;
+    }
+    if args.explain_skips {
+        writeln!(out, "Files skipped: {}", totals.skipped.len())?;
+    }
+    if let Some(ref patch_path) = args.patch_out {
+        writeln!(out, "(Patch written to {} - no files were actually modified)", patch_path.display())?;
+    } else if args.dry_run {
+        writeln!(out, "(Dry run - no files were actually modified)")?;
+    }
+    if let Some(preview_path) = preview_path {
+        writeln!(out, "Preview saved to {}; run with --apply-last to apply it", preview_path.display())?;
+    }
+    if !totals.journal.is_empty() || !totals.journal_renames.is_empty() {
+        let journal = Journal {
+            run_id: run_id.to_string(),
+            timestamp: audit::now(),
+            files: std::mem::take(&mut totals.journal),
+            renames: std::mem::take(&mut totals.journal_renames),
+        };
+        journal.save(&Journal::dir(Path::new(&args.directory)))?;
+        writeln!(out, "Undo journal saved; run with --undo {run_id} to restore the modified files")?;
+    }
+    if !totals.audited.is_empty() {
+        let record = AuditRecord {
+            run_id: run_id.to_string(),
+            timestamp: audit::now(),
+            user: audit::current_user(),
+            directory: std::path::absolute(&args.directory)?,
+            command: std::env::args().collect(),
+            pattern: args.pattern.clone(),
+            replacement: replacement.clone(),
+            rule_pack: pack.as_ref().map(|pack| format!("{} {}", pack.name, pack.version)),
+            files: std::mem::take(&mut totals.audited),
+        };
+        match audit::log_path().and_then(|path| record.append_to(&path)) {
+            Ok(()) => writeln!(out, "Run ID: {run_id} (see `regex-replace audit {run_id}`)")?,
+            Err(e) => eprintln!("Warning: failed to record run {run_id} in the audit log: {e:#}"),
+        }
+    }
+
+    if args.why_no_match && totals.files_modified == 0 {
+        writeln!(out, "\nNo matches found. Possible reasons:")?;
+        for finding in diagnose::why_no_match(&RealFs, &totals.scanned, &totals.skip_kinds) {
+            writeln!(out, "  - {finding}")?;
+        }
+    }
+
+    if json {
+        let reports = std::mem::take(&mut totals.reports);
+        let report = JsonReport {
+            run_id: run_id.to_string(),
+            dry_run,
+            summary: ReportTotals {
+                files_processed: totals.files_processed,
+                files_modified: totals.files_modified,
+                files_failed: reports.iter().filter(|file| file.status == "failed").count(),
+                files_skipped: totals.skip_kinds.values().sum(),
+                matches: totals.matches,
+                lines_added: totals.lines_added,
+                lines_removed: totals.lines_removed,
+            },
+            files: reports,
+        };
+        let rendered = if format == OutputFormat::Jsonl { report.render_lines() } else { report.render() };
+        match args.report_file {
+            Some(ref report_path) => std::fs::write(report_path, rendered)
+                .with_context(|| format!("Failed to write report: {}", report_path.display()))?,
+            None => print!("{rendered}"),
+        }
+    }
+
+    check_remaining(&totals.remaining)?;
+    check_consistent(&totals.occurrences)?;
+
+    if let Some(ref command) = args.post_cmd {
+        let env = [run_env.as_slice(), &[
+            ("RR_FILES_PROCESSED", totals.files_processed.to_string()),
+            ("RR_FILES_MODIFIED", totals.files_modified.to_string()),
+        ]].concat();
+        hooks::run_hook("post", command, &env)?;
+    }
+
+    if let Some((ref path, ref key)) = last_run
+        && totals.files_failed == 0
+    {
+        let mut runs = LastRuns::load(path);
+        runs.record(key, rerun::tree_hash(&filters)?, audit::now());
+        if let Err(e) = runs.save(path) {
+            eprintln!("Warning: failed to record run {run_id} for --force-rerun: {e:#}");
+        }
+    }
+
+    Ok(RunSummary {
+        run_id: run_id.to_string(),
+        success: true,
+        dry_run,
+        files_processed: totals.files_processed,
+        files_modified: totals.files_modified,
+        files_failed: totals.files_failed,
+        error: None,
+    })
+}
+
+/// Whether a run may be skipped as already applied: one writing files in
+/// place, over whole trees, whose every replacement follows from its
+/// command line and the files themselves.
+fn reruns_skippable(args: &Args, options: &ProcessOptions, quiet: bool) -> bool {
+    !options.dry_run
+        && options.mirror.is_none()
+        && !quiet
+        && args.files.is_empty()
+        && args.filelist.is_none()
+        && args.files_from.is_none()
+        && !args.replace_stdin
+        && args.replace_script.is_none()
+        && args.pre_file_cmd.is_none()
+}
+
+/// `replacement` with its `${env:VAR}` and `${date:FORMAT}` tokens filled in
+/// from the environment and the time `now`.
+fn stamp_replacement(replacement: &str, now: u64) -> Result<String> {
+    replacement::stamp(replacement, now, |name| std::env::var(name).ok())
+}
+
+/// Compiles the four values of one `--then`: `-p P2 -r R2`, in either order.
+fn then_rule(
+    values: &[String],
+    preserve_case: bool,
+    folding: CaseFolding,
+    stamp: &mut impl FnMut(&str) -> Result<String>,
+) -> Result<Rule> {
+    let mut pattern = None;
+    let mut replace = None;
+    for pair in values.chunks(2) {
+        match pair[0].as_str() {
+            "-p" | "--pattern" => pattern = Some(pair[1].clone()),
+            "-r" | "--replace" => replace = Some(pair[1].clone()),
+            other => anyhow::bail!("--then expects -p PATTERN -r REPLACEMENT, got '{other}'"),
+        }
+    }
+    let (Some(pattern), Some(replace)) = (pattern, replace) else {
+        anyhow::bail!("--then expects -p PATTERN -r REPLACEMENT");
+    };
+    RuleConfig { pattern, replace: stamp(&replace)?, extensions: None, preserve_case }.compile_with(folding)
+}
+
+/// Applies the changes saved by the last previewing dry run, leaving alone
+/// any file that changed since.
+fn run_apply_last(args: &Args) -> Result<()> {
+    let preview = Preview::load()?;
+    if args.verbose {
+        println!("Applying preview of run {}", preview.run_id);
+    }
+
+    let mut modified = 0;
+    let mut changed = 0;
+    for applied in preview.apply(&RealFs)? {
+        match applied {
+            Applied::Written(path) => {
+                modified += 1;
+                if args.verbose {
+                    println!("Applied: {}", path.display());
+                }
+            }
+            Applied::Changed(path) => {
+                changed += 1;
+                eprintln!("Not applied, changed since the preview: {}", path.display());
+            }
+        }
+    }
+    Preview::discard()?;
+
+    println!("\nSummary:");
+    println!("Files modified: {modified}");
+    if changed > 0 {
+        anyhow::bail!("{changed} file(s) changed since the preview and were left untouched");
+    }
+    Ok(())
+}
+
+/// Applies a patch written by `--emit-patch`, if every file in it still has
+/// the content the patch was made from.
+fn run_apply_patch(args: &Args, patch_path: &Path) -> Result<()> {
+    let text = std::fs::read_to_string(patch_path)
+        .with_context(|| format!("Failed to read patch: {}", patch_path.display()))?;
+    let files = patch::parse(&text).with_context(|| format!("Invalid patch: {}", patch_path.display()))?;
+
+    let mut modified = 0;
+    let mut changed = 0;
+    for applied in patch::apply(&RealFs, Path::new(&args.directory), &files)? {
+        match applied {
+            Applied::Written(path) => {
+                modified += 1;
+                if args.verbose {
+                    println!("Applied: {}", path.display());
+                }
+            }
+            Applied::Changed(path) => {
+                changed += 1;
+                eprintln!("Changed since the patch was made: {}", path.display());
+            }
+        }
+    }
+
+    println!("\nSummary:");
+    println!("Files modified: {modified}");
+    if changed > 0 {
+        anyhow::bail!("{changed} file(s) changed since the patch was made; nothing was applied");
+    }
+    Ok(())
+}
+
+/// Restores the files modified by a run from its undo journal, leaving alone
+/// any file that changed since.
+fn run_undo(args: &Args, run_id: Option<&str>) -> Result<()> {
+    let path = Journal::find(&Journal::dir(Path::new(&args.directory)), run_id)?;
+    let journal = Journal::load(&path)?;
+    if args.verbose {
+        println!("Undoing run {}", journal.run_id);
+    }
+
+    let mut restored = 0;
+    let mut changed = 0;
+    let mut missing = 0;
+    let undone = journal.undo(&RealFs)?;
+    for outcome in &undone {
+        match outcome {
+            Undone::Restored(path) => {
+                restored += 1;
+                if args.verbose {
+                    println!("Restored: {}", path.display());
+                }
+            }
+            Undone::Changed(path) => {
+                changed += 1;
+                eprintln!("Not restored, changed since run {}: {}", journal.run_id, path.display());
+            }
+            Undone::Missing(path) => {
+                missing += 1;
+                eprintln!("Not restored, no longer there: {}", path.display());
+            }
+            Undone::RenamedBack(rename) => {
+                if args.verbose {
+                    println!("Renamed back: {} -> {}", rename.to.display(), rename.from.display());
+                }
+            }
+            Undone::NotRenamedBack(rename) => {
+                missing += 1;
+                eprintln!("Not renamed back, as {} is gone or {} is taken", rename.to.display(), rename.from.display());
+            }
+        }
+    }
+    // Whatever couldn't be undone stays in the journal, to try again once
+    // it is put right
+    let left_over = journal.left_over(&undone);
+    if left_over.is_empty() {
+        std::fs::remove_file(&path).with_context(|| format!("Failed to remove undo journal: {}", path.display()))?;
+    } else {
+        left_over.save(path.parent().unwrap_or(Path::new(".")))?;
+    }
+
+    println!("\nSummary:");
+    println!("Files restored: {restored}");
+    let keep = format!("the undo journal keeps them, so put them back and run --undo {} again", journal.run_id);
+    if changed > 0 {
+        anyhow::bail!("{changed} file(s) changed since run {} and were left untouched; {keep}", journal.run_id);
+    }
+    if missing > 0 {
+        anyhow::bail!("{missing} path(s) of run {} were no longer there and were left as they are; {keep}", journal.run_id);
+    }
+    Ok(())
+}
+
+/// The report format: `--output`, or json when only `--report-file` is given.
+fn output_format(args: &Args) -> OutputFormat {
+    match (args.output, &args.report_file) {
+        (Some(format), _) => format,
+        (None, Some(_)) => OutputFormat::Json,
+        (None, None) => OutputFormat::Text,
+    }
+}
+
+/// Walks one root and processes its files, adding the results to `totals`.
+fn process_root(
+    args: &Args,
+    root: &Root,
+    options: &ProcessOptions,
+    throttle: Option<&Throttle>,
+    pool: Option<&ThreadPool>,
+    run_env: &[(&str, String)],
+    totals: &mut RunTotals,
+) -> Result<()> {
+    let filter = &root.filter;
+    let verbose = args.verbose && !totals.quiet;
+    if verbose {
+        println!("Base directory: {}", filter.base_dir().display());
+        println!("Starting directory walk from: {}", root.directory);
+    }
+
+    let stream = args.no_ordered_output && totals.sample.is_none() && !totals.quiet;
+    let mut seen_configs = HashSet::new();
+
+    // Files are processed a batch at a time so output can be emitted in
+    // traversal order without holding the whole run's output back
+    let batch_size = pool.map_or(1, |pool| pool.current_num_threads() * FILES_PER_THREAD);
+    let mut process_files = |files: &mut dyn Iterator<Item = WalkEntry>, totals: &mut RunTotals| -> Result<()> {
+        loop {
+            let batch: Vec<WalkEntry> = files.take(batch_size).collect();
+            if batch.is_empty() {
+                return Ok(());
+            }
+            if args.emit_filelist.is_some() {
+                totals.selected.extend(batch.iter().map(|entry| filter.absolute(&entry.path)));
+            }
+            let work = |entry: &WalkEntry| process_entry(args, filter, entry, options, throttle, run_env, stream);
+            let results: Vec<_> = match pool {
+                Some(pool) => pool.install(|| batch.par_iter().map(work).collect()),
+                None => batch.iter().map(work).collect(),
+            };
+
+            for (entry, result) in batch.iter().zip(results) {
+                let Some(Processed { settings, outcome, output }) = result? else {
+                    totals.files_failed += 1;
+                    continue;
+                };
+                let path = entry.path.as_path();
+
+                if let Some(ref config) = settings.config_file
+                    && verbose
+                    && seen_configs.insert(config.clone())
+                {
+                    println!("Using project config: {}", config.display());
+                }
+
+                // Emit the file's output as one block so reports stay in traversal order;
+                // when sampling, it is held back until the sample is known
+                let file_size = entry.metadata.len;
+                if totals.sample.is_none() && !totals.quiet && !output.is_empty() {
+                    io::stdout().lock().write_all(&output)?;
+                }
+
+                if matches!(output_format(args), OutputFormat::Json | OutputFormat::Jsonl) {
+                    totals.reports.push(FileReport::new(&outcome, options.dry_run));
+                }
+
+                let Some(change) = outcome.change else {
+                    if let Some(e) = outcome.error {
+                        eprintln!("Error processing {}: {}", path.display(), e);
+                        totals.files_failed += 1;
+                    }
+                    continue;
+                };
+
+                totals.files_processed += 1;
+                if args.rename_files || args.rename_dirs {
+                    totals.renamable.push((path.to_path_buf(), settings.rules.clone()));
+                }
+                if args.why_no_match {
+                    totals.scanned.push((path.to_path_buf(), settings.rules.clone()));
+                }
+                if args.top_files.is_some() {
+                    totals.file_matches.push(FileMatches { path: path.to_path_buf(), matches: change.matches, bytes: file_size });
+                }
+                totals.remaining.extend(change.remaining.iter().map(|m| (path.to_path_buf(), m.clone())));
+                totals.occurrences.extend(change.occurrences.iter().map(|o| (path.to_path_buf(), o.clone())));
+                totals.analysis.add(path, &change.mappings);
+                totals.found.extend(change.found.iter().map(|record| (path.to_path_buf(), record.clone())));
+                if let Some(ref extract) = args.extract_to
+                    && !change.found.is_empty()
+                {
+                    let target = extract.write(&options.root, path, &change.found)?;
+                    if options.verbose {
+                        println!("Extracted {} matches to {}", change.found.len(), target.display());
+                    }
+                }
+                if let Some(preview) = change.preview {
+                    // Absolute, so the preview can be applied from any directory
+                    totals.preview.push(PreviewFile { path: std::path::absolute(path)?, ..preview });
+                }
+                if change.further_changes > 0 {
+                    totals.files_not_idempotent += 1;
+                }
+                if change.changed_on_disk {
+                    totals.files_changed_on_disk += 1;
+                }
+                if change.shadow_diff.is_some() {
+                    totals.files_shadow_differs += 1;
+                }
+                if change.reused {
+                    totals.files_reused += 1;
+                }
+                if change.utf8_fallback.is_some() {
+                    totals.invalid_utf8.push(path.to_path_buf());
+                }
+                if options.match_budget.is_some_and(|budget| change.abandoned || change.match_time > budget) {
+                    totals.over_budget.push((path.to_path_buf(), change.match_time, change.abandoned));
+                }
+
+                if matches!(outcome.status, FileStatus::Modified) {
+                    totals.files_modified += 1;
+                    totals.matches += change.matches;
+                    totals.lines_added += change.lines_added;
+                    totals.lines_removed += change.lines_removed;
+                    let name = path.strip_prefix(&options.root).unwrap_or(path);
+                    if args.tree {
+                        totals.tree.push((name.to_path_buf(), change.matches));
+                    }
+                    let stat = (name.display().to_string(), change.lines_added, change.lines_removed);
+                    match totals.sample {
+                        Some(ref mut sample) => sample.offer(SampledFile { output, stat }),
+                        None => totals.diffstat.push(stat),
+                    }
+                    totals.patch.push_str(change.patch.as_deref().unwrap_or_default());
+                    if let Some(hash) = change.patch_hash {
+                        totals.patch_hashes.push((name.display().to_string(), hash));
+                    }
+                    if !options.dry_run {
+                        let path = std::path::absolute(path)?;
+                        let backup = options.backup.as_ref()
+                            .filter(|_| options.mirror.is_none())
+                            .map(|backup| backup.path_for(&path));
+                        totals.audited.push(AuditFile { path, matches: change.matches, backup });
+                    }
+                    if let Some(journal) = change.journal {
+                        // Absolute, so the run can be undone from any directory
+                        totals.journal.push(JournalFile { path: std::path::absolute(path)?, ..journal });
+                    }
+                    if let (Some(throttle), false) = (throttle, options.dry_run) {
+                        throttle.consume(file_size);
+                    }
+                    if let Some(ref command) = args.post_file_cmd {
+                        let env = [run_env, &[
+                            ("RR_FILE", path.display().to_string()),
+                            ("RR_MATCHES", change.matches.to_string()),
+                        ]].concat();
+                        if let Err(e) = hooks::run_hook("post-file", command, &env) {
+                            eprintln!("Error processing {}: {}", path.display(), e);
+                            totals.files_failed += 1;
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    // With worker threads and traversal order, files go to the workers as
+    // the walk finds them, through a queue that holds the walk back once
+    // `--queue-depth` files are waiting
+    if pool.is_some() && totals.listed.is_none() && args.sort == SortOrder::Path {
+        let (skipped, stats) = std::thread::scope(|scope| -> Result<_> {
+            let (sender, mut receiver) = queue::bounded(args.queue_depth);
+            let walker = scope.spawn(move || filter.walk_each(&mut |entry| {
+                sender.send(entry);
+            }));
+            let processed = process_files(&mut receiver, totals);
+            let stats = receiver.stats();
+            // Once processing fails, the walk stops waiting for room
+            drop(receiver);
+            let skipped = walker.join().expect("directory walk panicked");
+            processed?;
+            Ok((skipped?, stats))
+        })?;
+        record_skipped(args, &skipped, verbose, totals);
+        totals.queue.get_or_insert_default().merge(&stats);
+    } else {
+        let walk = match totals.listed {
+            Some(ref listed) => filter.walk_listed(listed),
+            None => filter.walk()?,
+        };
+        record_skipped(args, &walk.skipped, verbose, totals);
+        process_files(&mut sort_entries(walk.files, args.sort).into_iter(), totals)?;
+    }
+
+    if args.rename_files || args.rename_dirs {
+        rename_paths(args, root, options, totals)?;
+    }
+    Ok(())
+}
+
+/// Renames the files processed in `root`, and the directories holding them,
+/// once their contents are done.
+fn rename_paths(args: &Args, root: &Root, options: &ProcessOptions, totals: &mut RunTotals) -> Result<()> {
+    let files = std::mem::take(&mut totals.renamable);
+    let base = Path::new(&root.directory);
+    let renames = rename::plan(base, &files, args.rename_files, args.rename_dirs);
+    if options.dry_run {
+        rename::check(root.filter.fs(), &renames)?;
+    } else {
+        rename::apply(root.filter.fs(), &renames)?;
+        if options.journal {
+            for rename in &renames {
+                let (from, to) = (std::path::absolute(&rename.from)?, std::path::absolute(&rename.to)?);
+                totals.journal_renames.push(rename::Rename { from, to });
+            }
+        }
+    }
+    if !totals.quiet {
+        let label = if options.dry_run { "Rename" } else { "Renamed" };
+        for rename in &renames {
+            let from = rename.from.strip_prefix(&options.root).unwrap_or(&rename.from);
+            let to = rename.to.strip_prefix(&options.root).unwrap_or(&rename.to);
+            println!("{label}: {} -> {}", from.display(), to.display());
+        }
+    }
+    totals.paths_renamed += renames.len();
+    Ok(())
+}
+
+/// Reports and counts the entries a walk skipped.
+fn record_skipped(args: &Args, skipped: &[(PathBuf, SkipReason)], verbose: bool, totals: &mut RunTotals) {
+    for (path, reason) in skipped {
+        if verbose && matches!(reason, SkipReason::Ignored(_)) {
+            println!("Ignoring: {}", path.display());
+        }
+        if matches!(reason, SkipReason::Missing) {
+            eprintln!("Warning: {} is in the file list but no longer exists", path.display());
+        }
+        *totals.skip_kinds.entry(reason.kind()).or_default() += 1;
+    }
+    if args.explain_skips {
+        totals.skipped.extend(skipped.iter().map(|(path, reason)| (path.clone(), reason.to_string())));
+    }
+}
+
+/// One file's result, with the output it produced held back for the caller
+/// to emit.
+struct Processed {
+    settings: Arc<ProjectSettings>,
+    outcome: FileOutcome,
+    output: Vec<u8>,
+}
+
+/// The part of handling one file that can run on any thread: its pre-file
+/// hook, throttling and the replacement itself. `None` if the hook failed.
+/// With `stream`, the file's output is written as soon as it is complete.
+fn process_entry(
+    args: &Args,
+    filter: &FileFilter,
+    entry: &WalkEntry,
+    options: &ProcessOptions,
+    throttle: Option<&Throttle>,
+    run_env: &[(&str, String)],
+    stream: bool,
+) -> Result<Option<Processed>> {
+    let path = entry.path.as_path();
+    let settings = filter.settings_for(path)?;
+
+    if let Some(ref command) = args.pre_file_cmd {
+        let env = [run_env, &[("RR_FILE", path.display().to_string())]].concat();
+        if let Err(e) = hooks::run_hook("pre-file", command, &env) {
+            eprintln!("Error processing {}: {}", path.display(), e);
+            return Ok(None);
+        }
+    }
+
+    if let Some(throttle) = throttle {
+        throttle.consume(entry.metadata.len);
+    }
+
+    let file_options = ProcessOptions {
+        max_matches: settings.max_matches_per_file,
+        ..options.clone()
+    };
+    let mut output = Vec::new();
+    let outcome = process_path(filter.fs(), path, &settings.rules, &file_options, &mut output);
+    if stream {
+        io::stdout().lock().write_all(&output)?;
+        output.clear();
+    }
+    Ok(Some(Processed { settings, outcome, output }))
+}
+
+fn sort_entries(mut entries: Vec<WalkEntry>, order: SortOrder) -> Vec<WalkEntry> {
+    match order {
+        SortOrder::Path => entries.sort_by(|a, b| a.path.cmp(&b.path)),
+        SortOrder::Size => entries.sort_by(|a, b| (a.metadata.len, &a.path).cmp(&(b.metadata.len, &b.path))),
+        SortOrder::Mtime => entries.sort_by(|a, b| (a.metadata.modified, &a.path).cmp(&(b.metadata.modified, &b.path))),
+    }
+    entries
 }
 
 #[cfg(test)]
@@ -203,6 +2268,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::invalid_regex)]
     fn test_regex_creation() {
         assert!(Regex::new(r"\d+").is_ok());
         assert!(Regex::new(r"[").is_err());
@@ -0,0 +1,84 @@
+use anyhow::{Context, Result};
+use std::process::Command;
+use std::str::FromStr;
+
+use crate::report::RunSummary;
+
+const NOTIFICATION_TITLE: &str = "regex-replace";
+
+/// Where to announce that a run finished.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotifyTarget {
+    Desktop,
+    Webhook(String),
+}
+
+impl FromStr for NotifyTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "desktop" => Ok(NotifyTarget::Desktop),
+            Some(("webhook", url)) if !url.is_empty() => Ok(NotifyTarget::Webhook(url.to_string())),
+            _ => Err(format!("expected 'desktop' or 'webhook=URL', got '{s}'")),
+        }
+    }
+}
+
+/// Sends `summary` to `target`.
+pub fn send(target: &NotifyTarget, summary: &RunSummary) -> Result<()> {
+    match target {
+        NotifyTarget::Desktop => desktop(&summary.headline()),
+        NotifyTarget::Webhook(url) => {
+            let body = serde_json::to_string(summary)?;
+            ureq::post(url)
+                .set("Content-Type", "application/json")
+                .send_string(&body)
+                .with_context(|| format!("Failed to POST summary to {url}"))?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn desktop(message: &str) -> Result<()> {
+    let script = format!(
+        "display notification {:?} with title {:?}",
+        message, NOTIFICATION_TITLE
+    );
+    run(Command::new("osascript").arg("-e").arg(script))
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn desktop(message: &str) -> Result<()> {
+    run(Command::new("notify-send").arg(NOTIFICATION_TITLE).arg(message))
+}
+
+#[cfg(windows)]
+fn desktop(message: &str) -> Result<()> {
+    run(Command::new("msg").arg("*").arg(format!("{NOTIFICATION_TITLE}: {message}")))
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    let status = command.status().context("Failed to launch desktop notifier")?;
+    if !status.success() {
+        anyhow::bail!("Desktop notifier exited with {status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_notify_target() {
+        assert_eq!("desktop".parse(), Ok(NotifyTarget::Desktop));
+        assert_eq!(
+            "webhook=https://example.com/hook?a=b".parse(),
+            Ok(NotifyTarget::Webhook("https://example.com/hook?a=b".to_string()))
+        );
+        assert!("webhook=".parse::<NotifyTarget>().is_err());
+        assert!("email".parse::<NotifyTarget>().is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::filesystem::FileSystem;
+use crate::preview::{self, Applied, Hunk, PreviewFile};
+
+/// First line of a patch written by this tool. The lines below it, up to
+/// the first `diff --git`, give the SHA-256 of each file's content when the
+/// patch was made; `git apply` skips them like any text before a diff.
+const HEADER: &str = "regex-replace patch";
+
+/// The header recording the hash of each file a patch changes, by the name
+/// it has in the patch.
+pub fn header(files: &[(String, String)]) -> String {
+    let mut out = format!("{HEADER}\n");
+    for (name, hash) in files {
+        let _ = writeln!(out, "sha256 {hash} {name}");
+    }
+    out.push('\n');
+    out
+}
+
+/// Reads a patch written by this tool into the changes it makes to each
+/// file, with each file's path relative to the directory it was made in.
+pub fn parse(text: &str) -> Result<Vec<PreviewFile>> {
+    // Split on newlines alone, so a carriage return stays part of its line
+    let mut lines = text.split('\n').peekable();
+    if lines.next() != Some(HEADER) {
+        anyhow::bail!("Not a patch written by regex-replace: it has no header of file hashes; use git apply for other patches");
+    }
+    let mut hashes = Vec::new();
+    while let Some(line) = lines.next_if(|line| !line.starts_with("diff --git ")) {
+        if let Some((hash, name)) = line.strip_prefix("sha256 ").and_then(|rest| rest.split_once(' ')) {
+            hashes.push((name.to_string(), hash.to_string()));
+        }
+    }
+
+    let mut files = Vec::new();
+    while let Some(line) = lines.next() {
+        let Some(names) = line.strip_prefix("diff --git a/") else {
+            continue;
+        };
+        let name = names.split_once(" b/").map_or(names, |(old, _)| old);
+        let Some((_, hash)) = hashes.iter().find(|(hashed, _)| hashed == name) else {
+            anyhow::bail!("The patch header has no hash for {name}");
+        };
+        let mut hunks = Vec::new();
+        while let Some(line) = lines.next_if(|line| !line.starts_with("diff --git ")) {
+            if line.starts_with("rename ") {
+                anyhow::bail!("{name} is renamed by the patch; apply renames with git apply");
+            }
+            if let Some(range) = line.strip_prefix("@@ -") {
+                hunks.push(parse_hunk(range, &mut lines).with_context(|| format!("Invalid hunk for {name}: {line}"))?);
+            }
+        }
+        files.push(PreviewFile { path: name.into(), hash: hash.clone(), hunks });
+    }
+    Ok(files)
+}
+
+/// Reads one unified diff hunk, given the text after its `@@ -`, into the
+/// lines it replaces.
+fn parse_hunk<'a>(range: &str, lines: &mut std::iter::Peekable<impl Iterator<Item = &'a str>>) -> Result<Hunk> {
+    let (old, new) = range.split_once(" @@").and_then(|(ranges, _)| ranges.split_once(" +")).context("no line ranges")?;
+    let count = |range: &str| -> Result<(usize, usize)> {
+        let (start, len) = range.split_once(',').unwrap_or((range, "1"));
+        Ok((start.parse()?, len.parse()?))
+    };
+    let (old_start, old_lines) = count(old)?;
+    let (_, new_lines) = count(new)?;
+
+    let mut new_text: Vec<String> = Vec::new();
+    let (mut old_seen, mut new_seen) = (0, 0);
+    // Which sides the last line was on, for a following "\ No newline at end of file"
+    let mut last = (false, false);
+    while old_seen < old_lines || new_seen < new_lines || lines.peek().is_some_and(|line| line.starts_with('\\')) {
+        let line = lines.next().context("the hunk ends early")?;
+        let (in_old, in_new) = match line.chars().next() {
+            Some(' ') | None => (true, true),
+            Some('-') => (true, false),
+            Some('+') => (false, true),
+            Some('\\') => {
+                if last.1 && let Some(text) = new_text.last_mut() {
+                    text.pop();
+                }
+                continue;
+            }
+            _ => anyhow::bail!("unexpected line: {line}"),
+        };
+        if in_new {
+            new_text.push(format!("{}\n", line.get(1..).unwrap_or_default()));
+        }
+        old_seen += usize::from(in_old);
+        new_seen += usize::from(in_new);
+        last = (in_old, in_new);
+    }
+    // A hunk removing nothing is placed after the line it starts at
+    let start = if old_lines == 0 { old_start } else { old_start - 1 };
+    Ok(Hunk { start, old_lines, new_text: new_text.concat() })
+}
+
+/// Applies `files`, with paths relative to `base`, if every one of them
+/// still has the content the patch was made from. Otherwise nothing is
+/// written, and the files that changed are returned.
+pub fn apply(fs: &dyn FileSystem, base: &Path, files: &[PreviewFile]) -> Result<Vec<Applied>> {
+    let mut contents = Vec::new();
+    let mut changed = Vec::new();
+    for file in files {
+        let path = base.join(&file.path);
+        let content = fs.read_to_string(&path).with_context(|| format!("Failed to read file: {}", path.display()))?;
+        if preview::content_hash(&content) != file.hash {
+            changed.push(Applied::Changed(path));
+        } else {
+            contents.push((path, content, file));
+        }
+    }
+    if !changed.is_empty() {
+        return Ok(changed);
+    }
+
+    let mut applied = Vec::new();
+    for (path, content, file) in contents {
+        fs.write(&path, preview::apply_hunks(&content, &file.hunks).as_bytes())
+            .with_context(|| format!("Failed to write file: {}", path.display()))?;
+        applied.push(Applied::Written(path));
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff;
+    use crate::filesystem::MemoryFs;
+
+    fn patch_of(files: &[(&str, &str, &str)]) -> String {
+        let hashes: Vec<_> = files.iter().map(|(name, old, _)| (name.to_string(), preview::content_hash(old))).collect();
+        let sections: String = files.iter().map(|(name, old, new)| diff::render_git_patch(name, name, old, new)).collect();
+        header(&hashes) + sections.as_str()
+    }
+
+    #[test]
+    fn test_parse_and_apply() -> Result<()> {
+        let long: String = (1..=20).map(|n| format!("line {n}\n")).collect();
+        let files = [
+            ("src/a.txt", long.as_str(), &*long.replace("line 2\n", "LINE 2\n").replace("line 18\n", "")),
+            ("b.txt", "old", "new\nmore"),
+            ("c.txt", "", "added\n"),
+        ];
+        let fs = MemoryFs::new();
+        for (name, old, _) in &files {
+            fs.add_file(Path::new("/repo").join(name), *old);
+        }
+
+        let parsed = parse(&patch_of(&files))?;
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed[0].hunks.len(), 2);
+        let applied = apply(&fs, Path::new("/repo"), &parsed)?;
+        assert!(applied.iter().all(|applied| matches!(applied, Applied::Written(_))));
+        for (name, _, new) in &files {
+            assert_eq!(fs.contents(Path::new("/repo").join(name)).as_deref(), Some(*new), "{name}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuses_changed_files() -> Result<()> {
+        let fs = MemoryFs::new();
+        fs.add_file("/repo/a.txt", "old a\n");
+        fs.add_file("/repo/b.txt", "edited since\n");
+        let parsed = parse(&patch_of(&[("a.txt", "old a\n", "new a\n"), ("b.txt", "old b\n", "new b\n")]))?;
+
+        let applied = apply(&fs, Path::new("/repo"), &parsed)?;
+        assert_eq!(applied, [Applied::Changed("/repo/b.txt".into())]);
+        assert_eq!(fs.contents("/repo/a.txt").as_deref(), Some("old a\n"));
+
+        assert!(parse(&diff::render_git_patch("a.txt", "a.txt", "x\n", "y\n")).is_err());
+        Ok(())
+    }
+}
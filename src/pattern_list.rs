@@ -0,0 +1,132 @@
+use anyhow::{Context, Result};
+use regex::RegexBuilder;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::file_processor::Rule;
+
+/// Compiled size allowed for the pattern of a list, which for tens of
+/// thousands of entries is far beyond the regex crate's default.
+const SIZE_LIMIT: usize = 1 << 30;
+
+/// One line of a pattern list: the literal text to find and, if the line
+/// gave one after `=>`, what to replace it with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListEntry {
+    pub literal: String,
+    pub target: Option<String>,
+}
+
+/// Parses a pattern list, one literal per line, each optionally followed by
+/// `=>` and its own replacement. Blank lines are skipped; nothing else is
+/// special, so entries may hold regex metacharacters, `#` or spaces.
+pub fn parse(text: &str) -> Result<Vec<ListEntry>> {
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        let (literal, target) = match line.split_once("=>") {
+            Some((literal, target)) => (literal, Some(target.to_string())),
+            None => (line, None),
+        };
+        if literal.is_empty() {
+            anyhow::bail!("Line {} of the pattern list has nothing before '=>'", index + 1);
+        }
+        entries.push(ListEntry { literal: literal.to_string(), target });
+    }
+    Ok(entries)
+}
+
+/// The entries listed in `source`, or on standard input if it is `-`.
+pub fn read_from(source: &Path) -> Result<Vec<ListEntry>> {
+    let text = if source == Path::new("-") {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text).context("Failed to read the pattern list from standard input")?;
+        text
+    } else {
+        fs::read_to_string(source).with_context(|| format!("Failed to read pattern list: {}", source.display()))?
+    };
+    parse(&text)
+}
+
+/// One rule matching every literal of `entries` in a single pass. Each is
+/// replaced with its own target or, failing that, with `replacement`, taken
+/// literally. Where entries overlap, the longest wins.
+///
+/// The literals are compiled into one alternation, which the regex crate
+/// runs as a single Aho-Corasick automaton, so the cost of a search hardly
+/// grows with the length of the list.
+pub fn compile(entries: &[ListEntry], replacement: Option<&str>) -> Result<Rule> {
+    if entries.is_empty() {
+        anyhow::bail!("The pattern list is empty");
+    }
+    let mut targets = HashMap::new();
+    for entry in entries {
+        let target = match (&entry.target, replacement) {
+            (Some(target), _) => target.clone(),
+            (None, Some(replacement)) => replacement.to_string(),
+            (None, None) => anyhow::bail!("'{}' in the pattern list has no '=>' replacement and no --replace was given", entry.literal),
+        };
+        match targets.get(&entry.literal) {
+            Some(existing) if *existing != target => {
+                anyhow::bail!("'{}' is listed twice, with replacements '{existing}' and '{target}'", entry.literal);
+            }
+            _ => {
+                targets.insert(entry.literal.clone(), target);
+            }
+        }
+    }
+
+    let mut literals: Vec<&String> = targets.keys().collect();
+    // The first alternative that matches wins, so longer entries go first
+    literals.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    let pattern = literals.iter().map(|literal| regex::escape(literal)).collect::<Vec<_>>().join("|");
+    let regex = RegexBuilder::new(&pattern)
+        .size_limit(SIZE_LIMIT)
+        .dfa_size_limit(SIZE_LIMIT)
+        .build()
+        .context("Failed to compile the pattern list")?;
+    Ok(Rule::new(regex, replacement.unwrap_or_default()).with_targets(targets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_processor::{process_path, ProcessOptions};
+    use crate::filesystem::MemoryFs;
+
+    fn scrub(list: &str, replacement: Option<&str>, text: &str) -> Result<String> {
+        let rule = compile(&parse(list)?, replacement)?;
+        let fs = MemoryFs::new();
+        fs.add_file("/a.txt", text);
+        process_path(&fs, Path::new("/a.txt"), &[rule], &ProcessOptions::default(), &mut io::sink());
+        Ok(fs.contents("/a.txt").unwrap())
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let entries = parse("secret\r\n\nhost.internal=>example.com\na=>b=>c\n")?;
+        assert_eq!(entries, [
+            ListEntry { literal: "secret".to_string(), target: None },
+            ListEntry { literal: "host.internal".to_string(), target: Some("example.com".to_string()) },
+            ListEntry { literal: "a".to_string(), target: Some("b=>c".to_string()) },
+        ]);
+        assert!(parse("=>x").unwrap_err().to_string().contains("Line 1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile() -> Result<()> {
+        assert_eq!(scrub("abc\nabcdef\na.c\n", Some("[x]"), "abcdef abc a.c axc $1")?, "[x] [x] [x] axc $1");
+        assert_eq!(scrub("db1=>db-a\ndb12=>db-b\n", None, "db1 db12 db123")?, "db-a db-b db-b3");
+        assert!(compile(&parse("a\n")?, None).unwrap_err().to_string().contains("no '=>' replacement"));
+        assert!(compile(&parse("a=>b\na=>c\n")?, None).unwrap_err().to_string().contains("listed twice"));
+        assert!(compile(&parse("a=>b\na\n")?, Some("b")).is_ok());
+        assert!(compile(&[], Some("x")).is_err());
+        Ok(())
+    }
+}
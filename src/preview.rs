@@ -0,0 +1,184 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use similar::{DiffOp, TextDiff};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::filesystem::FileSystem;
+
+/// Name of the cached preview inside the cache directory.
+const PREVIEW_FILE: &str = "last-preview.json";
+
+/// A run of consecutive lines replaced by new text.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hunk {
+    /// First replaced line, counting from 0
+    pub start: usize,
+    /// Number of lines replaced, possibly 0 for a pure insertion
+    pub old_lines: usize,
+    pub new_text: String,
+}
+
+/// The changes a dry run showed for one file, and the content they apply to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreviewFile {
+    pub path: PathBuf,
+    /// SHA-256 of the content the hunks were computed against
+    pub hash: String,
+    pub hunks: Vec<Hunk>,
+}
+
+/// Everything a dry run would have changed, saved so `--apply-last` can
+/// apply exactly that.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Preview {
+    pub run_id: String,
+    pub files: Vec<PreviewFile>,
+}
+
+/// How applying one previewed file went.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Applied {
+    Written(PathBuf),
+    /// Left untouched because the file changed after the preview
+    Changed(PathBuf),
+}
+
+pub fn content_hash(content: impl AsRef<[u8]>) -> String {
+    Sha256::digest(content.as_ref()).iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The line hunks turning `old` into `new`.
+pub fn hunks(old: &str, new: &str) -> Vec<Hunk> {
+    let diff = TextDiff::from_lines(old, new);
+    let new_lines = diff.new_slices();
+    diff.ops()
+        .iter()
+        .filter(|op| !matches!(op, DiffOp::Equal { .. }))
+        .map(|op| Hunk {
+            start: op.old_range().start,
+            old_lines: op.old_range().len(),
+            new_text: new_lines[op.new_range()].concat(),
+        })
+        .collect()
+}
+
+/// Splices `hunks` (sorted, as [`hunks`] returns them) into `content`.
+pub fn apply_hunks(content: &str, hunks: &[Hunk]) -> String {
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let mut result = String::with_capacity(content.len());
+    let mut next = 0;
+    for hunk in hunks {
+        result.extend(lines[next..hunk.start].iter().copied());
+        result.push_str(&hunk.new_text);
+        next = hunk.start + hunk.old_lines;
+    }
+    result.extend(lines[next..].iter().copied());
+    result
+}
+
+impl PreviewFile {
+    pub fn new(path: &Path, old: &str, new: &str) -> Self {
+        Self { path: path.to_path_buf(), hash: content_hash(old), hunks: hunks(old, new) }
+    }
+}
+
+impl Preview {
+    /// Where the preview is cached: `$RR_CACHE_DIR`, else the user's cache
+    /// directory.
+    pub fn cache_path() -> Result<PathBuf> {
+        let dir = match env::var_os("RR_CACHE_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => match env::var_os("XDG_CACHE_HOME") {
+                Some(cache) => PathBuf::from(cache).join("regex-replace"),
+                None => PathBuf::from(env::var_os("HOME").context("Cannot find a cache directory: HOME is not set")?)
+                    .join(".cache/regex-replace"),
+            },
+        };
+        Ok(dir.join(PREVIEW_FILE))
+    }
+
+    pub fn save(&self) -> Result<PathBuf> {
+        let path = Self::cache_path()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create cache directory: {}", dir.display()))?;
+        }
+        fs::write(&path, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write preview: {}", path.display()))?;
+        Ok(path)
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::cache_path()?;
+        let text = fs::read_to_string(&path).with_context(|| {
+            format!("No preview to apply at {}; run with --dry-run --diff first", path.display())
+        })?;
+        serde_json::from_str(&text).with_context(|| format!("Invalid preview: {}", path.display()))
+    }
+
+    pub fn discard() -> Result<()> {
+        let path = Self::cache_path()?;
+        fs::remove_file(&path).with_context(|| format!("Failed to remove preview: {}", path.display()))
+    }
+
+    /// Writes every previewed change whose file still has the content it was
+    /// computed against, without matching anything again.
+    pub fn apply(&self, fs: &dyn FileSystem) -> Result<Vec<Applied>> {
+        let mut applied = Vec::new();
+        for file in &self.files {
+            let content = fs.read_to_string(&file.path)
+                .with_context(|| format!("Failed to read file: {}", file.path.display()))?;
+            if content_hash(&content) != file.hash {
+                applied.push(Applied::Changed(file.path.clone()));
+                continue;
+            }
+            fs.write(&file.path, apply_hunks(&content, &file.hunks).as_bytes())
+                .with_context(|| format!("Failed to write file: {}", file.path.display()))?;
+            applied.push(Applied::Written(file.path.clone()));
+        }
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+
+    #[test]
+    fn test_hunks_round_trip() {
+        let cases = [
+            ("a\nb\nc\n", "a\nB\nc\n"),
+            ("a\nb", "a\nb\nc"),
+            ("one\ntwo\nthree\n", "zero\none\nthree\n"),
+            ("x\r\ny\r\n", ""),
+        ];
+        for (old, new) in cases {
+            assert_eq!(apply_hunks(old, &hunks(old, new)), new, "{old:?} -> {new:?}");
+        }
+    }
+
+    #[test]
+    fn test_apply_refuses_changed_files() -> Result<()> {
+        let fs = MemoryFs::new();
+        fs.add_file("/a.txt", "old\nkeep\n");
+        fs.add_file("/b.txt", "old\n");
+        let preview = Preview {
+            run_id: String::new(),
+            files: vec![
+                PreviewFile::new(Path::new("/a.txt"), "old\nkeep\n", "new\nkeep\n"),
+                PreviewFile::new(Path::new("/b.txt"), "old\n", "new\n"),
+            ],
+        };
+        fs.add_file("/b.txt", "edited since\n");
+
+        let applied = preview.apply(&fs)?;
+        assert_eq!(applied, [Applied::Written("/a.txt".into()), Applied::Changed("/b.txt".into())]);
+        assert_eq!(fs.contents("/a.txt").as_deref(), Some("new\nkeep\n"));
+        assert_eq!(fs.contents("/b.txt").as_deref(), Some("edited since\n"));
+        Ok(())
+    }
+}
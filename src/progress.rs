@@ -0,0 +1,50 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::file_processor::FileOutcome;
+
+/// Reported to a progress callback after each file of a run.
+#[derive(Debug)]
+pub struct Progress<'a> {
+    /// Files finished so far, including this one
+    pub files_done: usize,
+    /// Files the run will process in total, not counting skipped entries
+    pub files_total: usize,
+    pub path: &'a Path,
+    pub outcome: &'a FileOutcome,
+}
+
+/// Asks a run to stop before its next file. Clones share the same flag, so
+/// one can be handed to a run and another kept to cancel it from elsewhere,
+/// such as a UI thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clones_share_cancellation() {
+        let token = CancellationToken::new();
+        let handle = token.clone();
+        assert!(!token.is_cancelled());
+        handle.cancel();
+        assert!(token.is_cancelled());
+    }
+}
@@ -0,0 +1,279 @@
+use anyhow::{Context, Result};
+use regex::RegexBuilder;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use toml::Spanned;
+
+use crate::case::{self, CaseFolding};
+use crate::file_processor::Rule;
+use crate::filesystem::FileSystem;
+use crate::ignore::{IgnorePattern, IgnoreRules};
+use crate::replacement::{self, ReplacementTemplate};
+
+/// Per-project configuration file marking a nested project root.
+pub const PROJECT_FILE: &str = ".rr.toml";
+
+/// Contents of a `.rr.toml` file. Every setting is optional; anything left
+/// out is inherited from the enclosing project or the command line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// File extensions to process, replacing the inherited list
+    pub extensions: Option<Vec<String>>,
+    /// Extra ignore globs, relative to the directory containing the file
+    #[serde(default)]
+    pub ignore: Vec<Spanned<String>>,
+    /// Skip files larger than this many bytes
+    pub max_file_size: Option<u64>,
+    /// Refuse to modify files with more matches than this
+    pub max_matches_per_file: Option<usize>,
+    /// Replacement rules, replacing the inherited rules
+    pub rules: Option<Vec<RuleConfig>>,
+    /// Text marking a file as generated, replacing the inherited list; an
+    /// empty list turns detection off
+    pub generated_markers: Option<Vec<String>>,
+}
+
+/// A replacement rule as written in a configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub pattern: String,
+    pub replace: String,
+    /// Only apply the rule to files with one of these extensions
+    pub extensions: Option<Vec<String>>,
+    /// Match regardless of case and give each replacement the casing of
+    /// the text it replaces
+    #[serde(default)]
+    pub preserve_case: bool,
+}
+
+impl RuleConfig {
+    pub fn compile(&self) -> Result<Rule> {
+        self.compile_with(CaseFolding::default())
+    }
+
+    /// Compiles the rule with `folding` deciding how case is folded, both in
+    /// matching, if the rule ignores case, and in preserving it.
+    pub fn compile_with(&self, folding: CaseFolding) -> Result<Rule> {
+        let pattern = if self.preserve_case || case::ignores_case(&self.pattern) {
+            folding.expand_pattern(&self.pattern)
+        } else {
+            self.pattern.clone()
+        };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(self.preserve_case)
+            .build()
+            .with_context(|| format!("Invalid regex pattern: {}", self.pattern))?;
+        ReplacementTemplate::parse(&self.replace)?;
+        replacement::validate(&self.replace, &regex)?;
+        let mut rule = Rule::new(regex, self.replace.clone());
+        if let Some(ref extensions) = self.extensions {
+            rule = rule.for_extensions(extensions.clone());
+        }
+        if self.preserve_case {
+            rule = rule.preserving_case().with_case_folding(folding);
+        }
+        Ok(rule)
+    }
+}
+
+/// Parses a rule given on the command line as `PATTERN=>REPLACEMENT`,
+/// optionally scoped to some extensions with a trailing `@EXT,EXT`. The
+/// pattern ends at the first `=>`; a trailing `@` followed by anything but
+/// extension names is part of the replacement.
+impl FromStr for RuleConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((pattern, rest)) = s.split_once("=>") else {
+            return Err(format!("expected 'PATTERN=>REPLACEMENT[@EXT,...]', got '{s}'"));
+        };
+        if pattern.is_empty() {
+            return Err(format!("rule '{s}' has an empty pattern"));
+        }
+        let scope = rest.rsplit_once('@').filter(|(_, exts)| {
+            !exts.is_empty()
+                && exts.split(',').all(|ext| !ext.is_empty() && ext.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+        });
+        let (replace, extensions) = match scope {
+            Some((replace, exts)) => (replace, Some(exts.split(',').map(String::from).collect())),
+            None => (rest, None),
+        };
+        Ok(Self { pattern: pattern.to_string(), replace: replace.to_string(), extensions, preserve_case: false })
+    }
+}
+
+/// The effective settings for one subtree of the search directory.
+#[derive(Debug, Default)]
+pub struct ProjectSettings {
+    /// The `.rr.toml` these settings were last refined by, if any
+    pub config_file: Option<PathBuf>,
+    pub extensions: Option<Vec<String>>,
+    /// Ignore rules contributed by this project and its enclosing projects
+    pub ignore: Vec<Arc<IgnoreRules>>,
+    pub max_file_size: Option<u64>,
+    pub max_matches_per_file: Option<usize>,
+    pub rules: Arc<Vec<Rule>>,
+    /// Markers of generated files; `None` means the built-in list
+    pub generated_markers: Option<Vec<String>>,
+}
+
+impl ProjectSettings {
+    /// Derives the settings of a nested project from its parent's.
+    fn refine(fs: &dyn FileSystem, parent: &ProjectSettings, dir: &Path, file: &Path) -> Result<Self> {
+        let text = fs.read_to_string(file)
+            .with_context(|| format!("Failed to read project config: {}", file.display()))?;
+        let config: ProjectConfig = toml::from_str(&text)
+            .with_context(|| format!("Invalid project config: {}", file.display()))?;
+
+        let mut ignore = parent.ignore.clone();
+        if !config.ignore.is_empty() {
+            let patterns = config.ignore.iter()
+                .map(|pattern| IgnorePattern {
+                    pattern: pattern.get_ref().clone(),
+                    source: Some((file.to_path_buf(), line_of(&text, pattern.span().start))),
+                })
+                .collect();
+            ignore.push(Arc::new(IgnoreRules::from_patterns(dir, patterns)?));
+        }
+
+        let rules = match config.rules {
+            Some(rules) => Arc::new(rules.iter().map(RuleConfig::compile).collect::<Result<Vec<_>>>()
+                .with_context(|| format!("Invalid rule in {}", file.display()))?),
+            None => parent.rules.clone(),
+        };
+
+        Ok(Self {
+            config_file: Some(file.to_path_buf()),
+            extensions: config.extensions.or_else(|| parent.extensions.clone()),
+            ignore,
+            max_file_size: config.max_file_size.or(parent.max_file_size),
+            max_matches_per_file: config.max_matches_per_file.or(parent.max_matches_per_file),
+            rules,
+            generated_markers: config.generated_markers.or_else(|| parent.generated_markers.clone()),
+        })
+    }
+
+    /// The first project ignore pattern matching the absolute `path`.
+    pub fn ignored_by(&self, path: &Path) -> Option<&IgnorePattern> {
+        self.ignore.iter().find_map(|rules| rules.matching_pattern(path))
+    }
+}
+
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset].matches('\n').count() + 1
+}
+
+/// Finds the effective settings for directories below the search root,
+/// discovering `.rr.toml` files on the way and caching the result.
+pub struct ProjectResolver {
+    fs: Arc<dyn FileSystem>,
+    search_root: PathBuf,
+    defaults: Arc<ProjectSettings>,
+    cache: Mutex<HashMap<PathBuf, Arc<ProjectSettings>>>,
+}
+
+impl ProjectResolver {
+    pub fn new(fs: Arc<dyn FileSystem>, search_root: &Path, defaults: ProjectSettings) -> Self {
+        Self {
+            fs,
+            search_root: search_root.to_path_buf(),
+            defaults: Arc::new(defaults),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Settings applying to entries directly inside the absolute directory `dir`.
+    pub fn settings_for(&self, dir: &Path) -> Result<Arc<ProjectSettings>> {
+        if let Some(settings) = self.cache.lock().unwrap().get(dir) {
+            return Ok(settings.clone());
+        }
+
+        let parent = match dir.parent() {
+            Some(parent) if dir != self.search_root && dir.starts_with(&self.search_root) => {
+                self.settings_for(parent)?
+            }
+            _ => self.defaults.clone(),
+        };
+
+        let config_file = dir.join(PROJECT_FILE);
+        let settings = if dir.starts_with(&self.search_root) && self.fs.is_file(&config_file) {
+            Arc::new(ProjectSettings::refine(&*self.fs, &parent, dir, &config_file)?)
+        } else {
+            parent
+        };
+
+        self.cache.lock().unwrap().insert(dir.to_path_buf(), settings.clone());
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::RealFs;
+    use regex::Regex;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_nested_projects_inherit_and_override() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path().canonicalize()?;
+        let service = root.join("services").join("api");
+        fs::create_dir_all(service.join("src"))?;
+        fs::write(root.join(PROJECT_FILE), "extensions = [\"rs\"]\nmax_file_size = 1000\n")?;
+        fs::write(service.join(PROJECT_FILE), "ignore = [\"generated/**\"]\n\n[[rules]]\npattern = \"v1\"\nreplace = \"v2\"\n")?;
+
+        let defaults = ProjectSettings {
+            rules: Arc::new(vec![Rule::new(Regex::new("a")?, "b")]),
+            ..Default::default()
+        };
+        let resolver = ProjectResolver::new(Arc::new(RealFs), &root, defaults);
+
+        let top = resolver.settings_for(&root.join("services"))?;
+        assert_eq!(top.extensions.as_deref(), Some(&["rs".to_string()][..]));
+        assert_eq!(top.rules[0].regex.as_str(), "a");
+
+        let nested = resolver.settings_for(&service.join("src"))?;
+        assert_eq!(nested.max_file_size, Some(1000));
+        assert_eq!(nested.rules[0].regex.as_str(), "v1");
+        assert_eq!(nested.config_file.as_deref(), Some(service.join(PROJECT_FILE).as_path()));
+
+        let pattern = nested.ignored_by(&service.join("generated/x.rs")).unwrap();
+        assert_eq!(pattern.source.as_ref().unwrap().1, 1);
+        assert!(top.ignored_by(&root.join("generated/x.rs")).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_rule_spec() {
+        let rule: RuleConfig = "#\\s*TODO=>// TODO@rs,js".parse().unwrap();
+        assert_eq!((rule.pattern.as_str(), rule.replace.as_str()), ("#\\s*TODO", "// TODO"));
+        assert_eq!(rule.extensions, Some(vec!["rs".to_string(), "js".to_string()]));
+
+        // A => in the replacement, an email-like @ and an empty replacement
+        let rule: RuleConfig = "a=>b=>c@d.com".parse().unwrap();
+        assert_eq!((rule.replace.as_str(), rule.extensions), ("b=>c@d.com", None));
+        assert_eq!("x=>@py".parse::<RuleConfig>().unwrap().replace, "");
+
+        assert!("no arrow".parse::<RuleConfig>().is_err());
+        assert!("=>x".parse::<RuleConfig>().is_err());
+    }
+
+    #[test]
+    fn test_invalid_config_is_reported() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path().canonicalize()?;
+        fs::write(root.join(PROJECT_FILE), "unknown_key = 1\n")?;
+
+        let resolver = ProjectResolver::new(Arc::new(RealFs), &root, ProjectSettings::default());
+        let err = resolver.settings_for(&root).unwrap_err();
+        assert!(format!("{err:#}").contains("Invalid project config"));
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How full a [`bounded`] queue got over its life.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Most items the queue may hold
+    pub depth: usize,
+    /// Items passed through it
+    pub sent: usize,
+    /// Most items waiting in it at once
+    pub max_queued: usize,
+    /// Times the sender had to wait for room
+    pub blocked: usize,
+    /// Time the sender spent waiting for room
+    pub blocked_time: Duration,
+}
+
+impl QueueStats {
+    /// Adds the stats of another queue, as for one walk per root.
+    pub fn merge(&mut self, other: &QueueStats) {
+        self.depth = self.depth.max(other.depth);
+        self.sent += other.sent;
+        self.max_queued = self.max_queued.max(other.max_queued);
+        self.blocked += other.blocked;
+        self.blocked_time += other.blocked_time;
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    queued: AtomicUsize,
+    sent: AtomicUsize,
+    max_queued: AtomicUsize,
+    blocked: AtomicUsize,
+    blocked_nanos: AtomicU64,
+}
+
+/// The sending end of a [`bounded`] queue.
+pub struct QueueSender<T> {
+    sender: SyncSender<T>,
+    counters: Arc<Counters>,
+    depth: usize,
+}
+
+/// The receiving end of a [`bounded`] queue, iterating until every sender
+/// is dropped.
+pub struct QueueReceiver<T> {
+    receiver: Receiver<T>,
+    counters: Arc<Counters>,
+    depth: usize,
+}
+
+/// A channel holding at most `depth` items, so a fast producer such as a
+/// directory walk waits for its consumers instead of buffering everything
+/// it finds. Keeps count of how full it gets.
+pub fn bounded<T>(depth: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+    let depth = depth.max(1);
+    let (sender, receiver) = mpsc::sync_channel(depth);
+    let counters = Arc::new(Counters::default());
+    (
+        QueueSender { sender, counters: counters.clone(), depth },
+        QueueReceiver { receiver, counters, depth },
+    )
+}
+
+impl<T> QueueSender<T> {
+    /// Queues `item`, waiting while the queue is full. Returns false if the
+    /// receiver is gone.
+    pub fn send(&self, item: T) -> bool {
+        let queued = self.counters.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        let sent = match self.sender.try_send(item) {
+            Ok(()) => true,
+            Err(TrySendError::Full(item)) => {
+                self.counters.blocked.fetch_add(1, Ordering::Relaxed);
+                let started = Instant::now();
+                let sent = self.sender.send(item).is_ok();
+                self.counters.blocked_nanos.fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                sent
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        };
+        if sent {
+            self.counters.sent.fetch_add(1, Ordering::Relaxed);
+            // A send that had to wait was counted before there was room for it
+            self.counters.max_queued.fetch_max(queued.min(self.depth), Ordering::Relaxed);
+        } else {
+            self.counters.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+        sent
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.depth,
+            sent: self.counters.sent.load(Ordering::Relaxed),
+            max_queued: self.counters.max_queued.load(Ordering::Relaxed),
+            blocked: self.counters.blocked.load(Ordering::Relaxed),
+            blocked_time: Duration::from_nanos(self.counters.blocked_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl<T> Iterator for QueueReceiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        let item = self.receiver.recv().ok()?;
+        self.counters.queued.fetch_sub(1, Ordering::SeqCst);
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_bounded_queue_applies_backpressure() {
+        let (sender, mut receiver) = bounded(2);
+        let producer = thread::spawn(move || {
+            for n in 0..10 {
+                assert!(sender.send(n));
+            }
+        });
+        // Let the producer fill the queue and block on it
+        thread::sleep(Duration::from_millis(50));
+        let received: Vec<i32> = receiver.by_ref().collect();
+        producer.join().unwrap();
+
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+        let stats = receiver.stats();
+        assert_eq!((stats.depth, stats.sent, stats.max_queued), (2, 10, 2));
+        assert!(stats.blocked >= 1);
+    }
+
+    #[test]
+    fn test_send_after_receiver_is_gone() {
+        let (sender, receiver) = bounded(1);
+        drop(receiver);
+        assert!(!sender.send(1));
+    }
+}
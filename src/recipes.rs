@@ -0,0 +1,73 @@
+use anyhow::Result;
+
+use crate::rule_pack::RulePack;
+
+/// Built-in codemods, each an ordinary rule pack shipped inside the binary.
+const RECIPES: &[&str] = &[
+    include_str!("../recipes/http-to-https-links.toml"),
+    include_str!("../recipes/log4j-to-slf4j.toml"),
+    include_str!("../recipes/python2-print.toml"),
+];
+
+/// Every built-in recipe, in registry order.
+pub fn all() -> Vec<RulePack> {
+    RECIPES
+        .iter()
+        .map(|source| RulePack::parse(source).expect("built-in recipes are valid rule packs"))
+        .collect()
+}
+
+pub fn find(name: &str) -> Result<RulePack> {
+    all()
+        .into_iter()
+        .find(|pack| pack.name == name)
+        .ok_or_else(|| anyhow::anyhow!("Unknown recipe: {name} (see 'recipes list')"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(name: &str, input: &str) -> String {
+        find(name).unwrap().compile_rules().unwrap().iter().fold(input.to_string(), |text, rule| {
+            rule.regex.replace_all(&text, rule.replacement.as_str()).into_owned()
+        })
+    }
+
+    #[test]
+    fn test_registry_is_valid() {
+        let packs = all();
+        assert_eq!(packs.len(), RECIPES.len());
+        for pack in &packs {
+            assert!(pack.compile_rules().is_ok(), "{}", pack.name);
+            assert!(pack.description.is_some(), "{}", pack.name);
+        }
+        assert!(find("no-such-recipe").is_err());
+    }
+
+    #[test]
+    fn test_http_to_https_links() {
+        assert_eq!(
+            apply("http-to-https-links", "See http://example.com/docs and http://localhost:8080 or http://127.0.0.1/"),
+            "See https://example.com/docs and http://localhost:8080 or http://127.0.0.1/"
+        );
+    }
+
+    #[test]
+    fn test_log4j_to_slf4j() {
+        let input = "import org.apache.log4j.LogManager;\nimport org.apache.log4j.Logger;\n\nprivate static final Logger LOG = LogManager.getLogger(Foo.class);\nLogger other = Logger.getLogger(\"x\");\n";
+        assert_eq!(
+            apply("log4j-to-slf4j", input),
+            "import org.slf4j.Logger;\nimport org.slf4j.LoggerFactory;\n\nprivate static final Logger LOG = LoggerFactory.getLogger(Foo.class);\nLogger other = LoggerFactory.getLogger(\"x\");\n"
+        );
+    }
+
+    #[test]
+    fn test_python2_print() {
+        let input = "print \"hello\"\nif x:\n    print x, y  \n    print\nprint(\"already\")\r\nprint 'crlf'\r\n";
+        assert_eq!(
+            apply("python2-print", input),
+            "print(\"hello\")\nif x:\n    print(x, y)\n    print()\nprint(\"already\")\r\nprint('crlf')\r\n"
+        );
+    }
+}
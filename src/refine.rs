@@ -0,0 +1,245 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::delta::Delta;
+use crate::diff::{self, DiffStyle};
+use crate::file_processor::find_replacements;
+use crate::filesystem::FileSystem;
+use crate::filter::FileFilter;
+
+/// Files shown in full by `show` before the rest are only counted.
+const SHOWN_FILES: usize = 20;
+
+/// The files of a tree held in memory, so patterns can be tried against
+/// them again and again without walking or reading the tree each time.
+pub struct Corpus<'a> {
+    fs: &'a dyn FileSystem,
+    files: Vec<(PathBuf, String)>,
+}
+
+/// The changes one pattern and replacement make to a [`Corpus`], staged as
+/// deltas against the loaded contents until they are applied.
+#[derive(Debug, Default)]
+pub struct Trial {
+    pub matches: usize,
+    /// Each changed file's index in the corpus and the change to it
+    pub changed: Vec<(usize, Delta)>,
+}
+
+impl<'a> Corpus<'a> {
+    /// Reads every file `filter` selects. Files that are not UTF-8 are left out.
+    pub fn load(filter: &'a FileFilter) -> Result<Self> {
+        let fs = filter.fs();
+        let mut files = Vec::new();
+        for entry in filter.walk()?.files {
+            if let Ok(content) = fs.read_to_string(&entry.path) {
+                files.push((entry.path, content));
+            }
+        }
+        Ok(Self { fs, files })
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn try_rule(&self, regex: &Regex, replacement: &str) -> Trial {
+        let mut trial = Trial::default();
+        for (index, (_, content)) in self.files.iter().enumerate() {
+            let replacements = find_replacements(content, regex, replacement);
+            if replacements.is_empty() {
+                continue;
+            }
+            trial.matches += replacements.len();
+            let delta = Delta::new(content, &replacements);
+            if !delta.is_empty() {
+                trial.changed.push((index, delta));
+            }
+        }
+        trial
+    }
+
+    /// Writes the changed files of `trial`, except those that changed on
+    /// disk since the corpus was read, which are returned instead.
+    pub fn apply(&self, trial: &Trial) -> Result<(usize, Vec<PathBuf>)> {
+        let mut written = 0;
+        let mut stale = Vec::new();
+        for (index, delta) in &trial.changed {
+            let (path, content) = &self.files[*index];
+            if self.fs.read_to_string(path).ok().as_ref() != Some(content) {
+                stale.push(path.clone());
+                continue;
+            }
+            self.fs.write(path, delta.apply(content).as_bytes())
+                .with_context(|| format!("Failed to write file: {}", path.display()))?;
+            written += 1;
+        }
+        Ok((written, stale))
+    }
+}
+
+/// A command typed into a refinement session.
+#[derive(Debug, PartialEq, Eq)]
+enum Input<'a> {
+    Pattern(&'a str),
+    Replacement(&'a str),
+    Show,
+    Apply,
+    Quit,
+    Help,
+    Unknown(&'a str),
+}
+
+fn parse(line: &str) -> Input<'_> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+    match command {
+        "p" | "pattern" => Input::Pattern(argument),
+        "r" | "replace" => Input::Replacement(argument),
+        "" | "s" | "show" => Input::Show,
+        "apply" => Input::Apply,
+        "q" | "quit" => Input::Quit,
+        "h" | "help" | "?" => Input::Help,
+        _ => Input::Unknown(command),
+    }
+}
+
+const HELP: &str = "\
+Commands:
+  p PATTERN    set the pattern
+  r TEXT       set the replacement (may be empty)
+  show         preview the changes (also an empty line)
+  apply        write the previewed changes and finish
+  quit         finish without writing anything
+";
+
+/// Runs an interactive refinement session: reads commands from `input`,
+/// editing the pattern and replacement and previewing them against the
+/// corpus, until `apply` or `quit`. Returns the number of files written.
+pub fn run_session(
+    corpus: &Corpus,
+    root: &Path,
+    mut pattern: Option<Regex>,
+    mut replacement: String,
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> Result<usize> {
+    writeln!(out, "Loaded {} files. Type 'help' for commands.", corpus.len())?;
+    let mut line = String::new();
+    loop {
+        write!(out, "> ")?;
+        out.flush()?;
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            writeln!(out)?;
+            return Ok(0);
+        }
+
+        match parse(&line) {
+            Input::Pattern(text) => match Regex::new(text) {
+                Ok(regex) => {
+                    pattern = Some(regex);
+                    show(corpus, root, pattern.as_ref(), &replacement, out, false)?;
+                }
+                Err(e) => writeln!(out, "Invalid regex pattern: {e}")?,
+            },
+            Input::Replacement(text) => {
+                replacement = text.to_string();
+                show(corpus, root, pattern.as_ref(), &replacement, out, false)?;
+            }
+            Input::Show => show(corpus, root, pattern.as_ref(), &replacement, out, true)?,
+            Input::Apply => {
+                let Some(ref regex) = pattern else {
+                    writeln!(out, "No pattern set")?;
+                    continue;
+                };
+                let (written, stale) = corpus.apply(&corpus.try_rule(regex, &replacement))?;
+                for path in &stale {
+                    writeln!(out, "Changed since it was loaded, left alone: {}", path.display())?;
+                }
+                writeln!(out, "Files modified: {written}")?;
+                return Ok(written);
+            }
+            Input::Quit => return Ok(0),
+            Input::Help => write!(out, "{HELP}")?,
+            Input::Unknown(command) => writeln!(out, "Unknown command '{command}'; type 'help' for commands")?,
+        }
+    }
+}
+
+/// Prints how the current pattern and replacement would change the corpus:
+/// a count, and with `diffs` the diff of each changed file.
+fn show(corpus: &Corpus, root: &Path, pattern: Option<&Regex>, replacement: &str, out: &mut dyn Write, diffs: bool) -> Result<()> {
+    let Some(regex) = pattern else {
+        writeln!(out, "No pattern set")?;
+        return Ok(());
+    };
+    let trial = corpus.try_rule(regex, replacement);
+    if diffs {
+        for (index, delta) in trial.changed.iter().take(SHOWN_FILES) {
+            let (path, content) = &corpus.files[*index];
+            let name = path.strip_prefix(root).unwrap_or(path).display().to_string();
+            write!(out, "{}", diff::render_diff(&name, content, &delta.apply(content), DiffStyle::Unified))?;
+        }
+        if trial.changed.len() > SHOWN_FILES {
+            writeln!(out, "... and {} more files", trial.changed.len() - SHOWN_FILES)?;
+        }
+    }
+    writeln!(
+        out,
+        "{} match{}, {} of {} files would change",
+        trial.matches,
+        if trial.matches == 1 { "" } else { "es" },
+        trial.changed.len(),
+        corpus.len()
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+    use crate::project::ProjectSettings;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_session_refines_then_applies() -> Result<()> {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/repo/a.rs", "let colour = 1;\n");
+        fs.add_file("/repo/b.rs", "colours();\n");
+        let filter = FileFilter::with_fs(fs.clone(), "/repo", ProjectSettings::default(), &[], false)?;
+        let corpus = Corpus::load(&filter)?;
+
+        // Files edited after loading are still read from memory when previewing
+        fs.add_file("/repo/a.rs", "let colour = 2;\n");
+        let mut input = "p colo(u)?r\nr color\np (\nshow\nbogus\np \\bcolour\\b\napply\n".as_bytes();
+        let mut out = Vec::new();
+        let written = run_session(&corpus, Path::new("/repo"), None, String::new(), &mut input, &mut out)?;
+        let out = String::from_utf8(out)?;
+
+        assert!(out.contains("2 matches, 2 of 2 files would change"));
+        assert!(out.contains("Invalid regex pattern"));
+        assert!(out.contains("+let color = 1;"));
+        assert!(out.contains("Unknown command 'bogus'"));
+        assert!(out.contains("1 match, 1 of 2 files would change"));
+        assert!(out.contains("Changed since it was loaded, left alone: /repo/a.rs"));
+        assert_eq!(written, 0);
+        assert_eq!(fs.contents("/repo/b.rs").as_deref(), Some("colours();\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("p foo bar\n"), Input::Pattern("foo bar"));
+        assert_eq!(parse("r\n"), Input::Replacement(""));
+        assert_eq!(parse("\r\n"), Input::Show);
+        assert_eq!(parse("quit"), Input::Quit);
+    }
+}
@@ -0,0 +1,132 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::ops::Range;
+
+use crate::file_processor::Replacement;
+
+/// Regions of a file opened by a match of `start` and closed by the next
+/// match of `end`, such as `BEGIN GENERATED` ... `END GENERATED` blocks.
+/// A region left open runs to the end of the file.
+#[derive(Debug, Clone)]
+pub struct Delimiters {
+    pub start: Regex,
+    pub end: Regex,
+}
+
+/// One region: `outer` takes in the delimiters, `inner` only what they enclose.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub outer: Range<usize>,
+    pub inner: Range<usize>,
+}
+
+impl Delimiters {
+    /// Compiles the delimiter patterns, which must not match the empty
+    /// string, as a region could then open anywhere.
+    pub fn new(start: &str, end: &str) -> Result<Self> {
+        let compile = |pattern: &str| -> Result<Regex> {
+            let regex = Regex::new(pattern).with_context(|| format!("Invalid region delimiter: {pattern}"))?;
+            if regex.is_match("") {
+                anyhow::bail!("Region delimiter {pattern} matches the empty string");
+            }
+            Ok(regex)
+        };
+        Ok(Self { start: compile(start)?, end: compile(end)? })
+    }
+
+    /// Every region of `content`, in order.
+    pub fn regions(&self, content: &str) -> Vec<Region> {
+        let mut regions = Vec::new();
+        let mut pos = 0;
+        while let Some(open) = self.start.find_at(content, pos) {
+            match self.end.find_at(content, open.end()) {
+                Some(close) => {
+                    regions.push(Region { outer: open.start()..close.end(), inner: open.end()..close.start() });
+                    pos = close.end();
+                }
+                None => {
+                    regions.push(Region { outer: open.start()..content.len(), inner: open.end()..content.len() });
+                    break;
+                }
+            }
+        }
+        regions
+    }
+}
+
+/// Where in a file replacements may be made: inside a region of any of
+/// `only_within`, if there are any, and outside every region of
+/// `not_within`.
+#[derive(Debug, Clone, Default)]
+pub struct RegionGuards {
+    pub only_within: Vec<Delimiters>,
+    pub not_within: Vec<Delimiters>,
+}
+
+impl RegionGuards {
+    pub fn is_empty(&self) -> bool {
+        self.only_within.is_empty() && self.not_within.is_empty()
+    }
+
+    /// The replacements of `content` the guards allow. A replacement is
+    /// inside a region if it lies wholly between the delimiters, and
+    /// outside one if it doesn't touch the region or its delimiters.
+    pub fn filter(&self, content: &str, replacements: Vec<Replacement>) -> Vec<Replacement> {
+        let inside: Vec<Range<usize>> = self.only_within.iter()
+            .flat_map(|delimiters| delimiters.regions(content))
+            .map(|region| region.inner)
+            .collect();
+        let outside: Vec<Range<usize>> = self.not_within.iter()
+            .flat_map(|delimiters| delimiters.regions(content))
+            .map(|region| region.outer)
+            .collect();
+        replacements.into_iter()
+            .filter(|rep| {
+                (self.only_within.is_empty() || inside.iter().any(|inner| inner.start <= rep.start && rep.end <= inner.end))
+                    && !outside.iter().any(|outer| rep.start < outer.end && outer.start < rep.end)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_processor::find_replacements;
+
+    const TEXT: &str = "x\n// BEGIN GENERATED\nx x\n// END GENERATED\nx\n// BEGIN GENERATED\nx";
+
+    fn guarded(guards: &RegionGuards, text: &str) -> String {
+        let replacements = guards.filter(text, find_replacements(text, &Regex::new("x").unwrap(), "y"));
+        crate::file_processor::apply_replacements(text, &replacements)
+    }
+
+    #[test]
+    fn test_regions() -> Result<()> {
+        let generated = Delimiters::new("BEGIN GENERATED", "END GENERATED")?;
+        let regions = generated.regions(TEXT);
+        assert_eq!(regions.len(), 2);
+        assert_eq!(&TEXT[regions[0].inner.clone()], "\nx x\n// ");
+        assert_eq!(&TEXT[regions[1].outer.clone()], "BEGIN GENERATED\nx");
+
+        assert!(Delimiters::new("a*", "b").is_err());
+        assert!(Delimiters::new("(", "b").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_guards() -> Result<()> {
+        let only = RegionGuards { only_within: vec![Delimiters::new("BEGIN GENERATED", "END GENERATED")?], ..Default::default() };
+        assert_eq!(guarded(&only, TEXT), "x\n// BEGIN GENERATED\ny y\n// END GENERATED\nx\n// BEGIN GENERATED\ny");
+
+        let not = RegionGuards { not_within: only.only_within.clone(), ..Default::default() };
+        assert_eq!(guarded(&not, TEXT), "y\n// BEGIN GENERATED\nx x\n// END GENERATED\ny\n// BEGIN GENERATED\nx");
+
+        // A delimiter is part of neither inside nor outside
+        let text = "<x>x</x>";
+        let tags = vec![Delimiters::new("<x>", "</x>")?];
+        assert_eq!(guarded(&RegionGuards { only_within: tags.clone(), ..Default::default() }, text), "<x>y</x>");
+        assert_eq!(guarded(&RegionGuards { not_within: tags, ..Default::default() }, text), text);
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::file_processor::Rule;
+use crate::filesystem::FileSystem;
+
+/// A file or directory to give a new name in the same directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// The last component of `path` with every rule that applies to it
+/// applied in turn, or `None` if that leaves it unchanged or empty.
+fn renamed(path: &Path, rules: &[Rule]) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let mut new_name = name.to_string();
+    for rule in rules.iter().filter(|rule| rule.applies_to(path)) {
+        new_name = rule.apply(&new_name);
+    }
+    (new_name != name && !new_name.is_empty() && !new_name.contains(['/', '\\']))
+        .then(|| path.with_file_name(new_name))
+}
+
+/// The renames the rules of each of `files` call for: the files' own names
+/// with `files_too`, and the names of the directories holding them below
+/// `base` with `dirs`. A directory takes the rules of the first file found
+/// in it. Children come before their directory, so every rename's source
+/// still exists when it is made.
+pub fn plan(base: &Path, files: &[(PathBuf, Arc<Vec<Rule>>)], files_too: bool, dirs: bool) -> Vec<Rename> {
+    let mut paths: BTreeMap<PathBuf, &[Rule]> = BTreeMap::new();
+    for (file, rules) in files {
+        if files_too {
+            paths.entry(file.clone()).or_insert(rules);
+        }
+        if dirs {
+            for dir in file.ancestors().skip(1).take_while(|dir| *dir != base && dir.starts_with(base)) {
+                paths.entry(dir.to_path_buf()).or_insert(rules);
+            }
+        }
+    }
+
+    let mut renames: Vec<Rename> = paths
+        .into_iter()
+        .filter_map(|(path, rules)| renamed(&path, rules).map(|to| Rename { from: path, to }))
+        .collect();
+    renames.sort_by(|a, b| {
+        b.from.components().count().cmp(&a.from.components().count()).then_with(|| a.from.cmp(&b.from))
+    });
+    renames
+}
+
+/// Makes `renames`, in order, after checking that none would overwrite an
+/// existing path or another rename's target.
+pub fn apply(fs: &dyn FileSystem, renames: &[Rename]) -> Result<()> {
+    check(fs, renames)?;
+    for rename in renames {
+        fs.rename(&rename.from, &rename.to)
+            .with_context(|| format!("Failed to rename {} to {}", rename.from.display(), rename.to.display()))?;
+    }
+    Ok(())
+}
+
+/// Fails if any of `renames` would overwrite an existing path or another
+/// rename's target.
+pub fn check(fs: &dyn FileSystem, renames: &[Rename]) -> Result<()> {
+    let mut targets = HashSet::new();
+    for rename in renames {
+        if fs.exists(&rename.to) || !targets.insert(&rename.to) {
+            anyhow::bail!("Cannot rename {}: {} already exists", rename.from.display(), rename.to.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+    use regex::Regex;
+
+    fn rules(pattern: &str, replacement: &str) -> Arc<Vec<Rule>> {
+        Arc::new(vec![Rule::new(Regex::new(pattern).unwrap(), replacement)])
+    }
+
+    fn rename(from: &str, to: &str) -> Rename {
+        Rename { from: PathBuf::from(from), to: PathBuf::from(to) }
+    }
+
+    #[test]
+    fn test_plan_and_apply() -> Result<()> {
+        let fs = MemoryFs::new();
+        fs.add_file("/src/old_mod/old_mod.rs", "");
+        fs.add_file("/src/old_mod/other.rs", "");
+        fs.add_file("/src/old_main.rs", "");
+        let rules = rules("old_", "new_");
+        let files: Vec<_> = ["/src/old_main.rs", "/src/old_mod/old_mod.rs", "/src/old_mod/other.rs"]
+            .into_iter()
+            .map(|file| (PathBuf::from(file), rules.clone()))
+            .collect();
+
+        assert_eq!(plan(Path::new("/src"), &files, true, false), [
+            rename("/src/old_mod/old_mod.rs", "/src/old_mod/new_mod.rs"),
+            rename("/src/old_main.rs", "/src/new_main.rs"),
+        ]);
+        assert_eq!(plan(Path::new("/src"), &files, false, true), [rename("/src/old_mod", "/src/new_mod")]);
+
+        let renames = plan(Path::new("/src"), &files, true, true);
+        apply(&fs, &renames)?;
+        assert!(fs.exists(Path::new("/src/new_mod/new_mod.rs")));
+        assert!(fs.exists(Path::new("/src/new_mod/other.rs")));
+        assert!(fs.exists(Path::new("/src/new_main.rs")));
+        assert!(!fs.exists(Path::new("/src/old_mod")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_renames_never_overwrite() {
+        let fs = MemoryFs::new();
+        fs.add_file("/a/old.txt", "old");
+        fs.add_file("/a/new.txt", "new");
+        let files = [(PathBuf::from("/a/old.txt"), rules("old", "new"))];
+        let err = apply(&fs, &plan(Path::new("/a"), &files, true, false)).unwrap_err();
+        assert!(err.to_string().contains("/a/new.txt already exists"));
+        assert_eq!(fs.contents("/a/old.txt").as_deref(), Some("old"));
+
+        let clash = [rename("/a/old.txt", "/a/x.txt"), rename("/a/new.txt", "/a/x.txt")];
+        assert!(check(&fs, &clash).is_err());
+    }
+}
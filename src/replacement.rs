@@ -0,0 +1,493 @@
+use anyhow::Result;
+use regex::{Captures, Regex};
+
+use crate::case::CaseStyle;
+
+/// How a formatted capture is aligned within its width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+/// A format specifier such as `>8`, `04` or `*^10`, written after a colon
+/// in `${group:spec}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Format {
+    fill: char,
+    align: Option<Align>,
+    /// Pad with zeros after any sign, as for numbers
+    zero: bool,
+    width: usize,
+}
+
+impl Format {
+    fn parse(spec: &str) -> Result<Self, String> {
+        let align_of = |c| match c {
+            '<' => Some(Align::Left),
+            '>' => Some(Align::Right),
+            '^' => Some(Align::Center),
+            _ => None,
+        };
+        let mut chars = spec.chars();
+        let (fill, align, rest) = match (chars.next(), chars.next()) {
+            (Some(fill), Some(second)) if align_of(second).is_some() => (fill, align_of(second), chars.as_str()),
+            (Some(first), _) if align_of(first).is_some() => (' ', align_of(first), &spec[1..]),
+            _ => (' ', None, spec),
+        };
+        let (zero, digits) = match rest.strip_prefix('0') {
+            Some(digits) if !digits.is_empty() => (true, digits),
+            _ => (false, rest),
+        };
+        let width = digits.parse().map_err(|_| {
+            format!("invalid format '{spec}': expected [[FILL]ALIGN][0]WIDTH or a case style such as upper or snake_case")
+        })?;
+        Ok(Self { fill, align, zero, width })
+    }
+
+    fn apply(&self, value: &str, out: &mut String) {
+        let len = value.chars().count();
+        if len >= self.width {
+            out.push_str(value);
+            return;
+        }
+        let padding = self.width - len;
+        let numeric = value.parse::<f64>().is_ok();
+        if self.zero && self.align.is_none() {
+            // Zeros go between the sign and the digits
+            let (sign, digits) = match value.strip_prefix(['-', '+']) {
+                Some(digits) if numeric => (&value[..1], digits),
+                _ => ("", value),
+            };
+            out.push_str(sign);
+            out.extend(std::iter::repeat_n('0', padding));
+            out.push_str(digits);
+            return;
+        }
+        // Like Rust's format!, numbers align right and other text left by default
+        let fill = if self.zero { '0' } else { self.fill };
+        let (before, after) = match self.align.unwrap_or(if numeric { Align::Right } else { Align::Left }) {
+            Align::Left => (0, padding),
+            Align::Right => (padding, 0),
+            Align::Center => (padding / 2, padding - padding / 2),
+        };
+        out.extend(std::iter::repeat_n(fill, before));
+        out.push_str(value);
+        out.extend(std::iter::repeat_n(fill, after));
+    }
+}
+
+/// One part of a replacement template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    /// Text expanded by the regex crate, which handles `$1`, `${name}` and `$$`
+    Plain(String),
+    /// `${group:spec}`: a capture group's value, converted to each case
+    /// style in turn and then formatted
+    Formatted { group: String, styles: Vec<CaseStyle>, format: Option<Format> },
+    /// `${counter}`: the match's number
+    Counter(Counter),
+}
+
+/// The options of a `${counter:...}` placeholder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Counter {
+    start: i64,
+    step: i64,
+    /// Zero-pad the number to this many digits
+    pad: usize,
+    /// Number matches across every file of a run rather than within each
+    global: bool,
+}
+
+impl Counter {
+    /// Parses the options after `${counter`, each `:key=value`.
+    fn parse(options: &str) -> Result<Self, String> {
+        let mut counter = Self { start: 1, step: 1, pad: 0, global: false };
+        for option in options.split(':').filter(|option| !option.is_empty()) {
+            let invalid = || format!("invalid counter option '{option}': expected start=N, step=N, pad=N or scope=file|global");
+            let (key, value) = option.split_once('=').ok_or_else(invalid)?;
+            match key {
+                "start" => counter.start = value.parse().map_err(|_| invalid())?,
+                "step" => counter.step = value.parse().map_err(|_| invalid())?,
+                "pad" => counter.pad = value.parse().map_err(|_| invalid())?,
+                "scope" => counter.global = match value {
+                    "file" => false,
+                    "global" => true,
+                    _ => return Err(invalid()),
+                },
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(counter)
+    }
+
+    fn apply(&self, ordinal: usize, out: &mut String) {
+        let value = self.start + self.step * ordinal as i64;
+        out.push_str(&format!("{value:0width$}", width = self.pad));
+    }
+}
+
+/// Parses the part of `${group:spec}` after the colon: any number of case
+/// styles, each followed by a colon, then optionally a format.
+fn parse_spec(spec: &str) -> Result<(Vec<CaseStyle>, Option<Format>), String> {
+    let mut styles = Vec::new();
+    let mut rest = spec;
+    while let Some(style) = CaseStyle::parse(rest.split(':').next().unwrap_or_default()) {
+        styles.push(style);
+        rest = rest.split_once(':').map_or("", |(_, after)| after);
+    }
+    let format = if rest.is_empty() && !styles.is_empty() { None } else { Some(Format::parse(rest)?) };
+    Ok((styles, format))
+}
+
+/// A replacement with its capture references parsed, extending the regex
+/// crate's `$1`/`${name}` syntax with format specifiers: `${1:>8}` right
+/// aligns group 1 in 8 columns, `${1:04}` zero-pads it to 4 digits and
+/// `${name:*^10}` centres it, filling with `*`. Case styles convert a
+/// capture first: `${1:upper}`, `${2:snake_case}` or `${1:camel:>12}`.
+///
+/// `${counter}` numbers the matches, from 1 by default; options follow
+/// as in `${counter:start=10:step=5:pad=3}`. The name `counter` is reserved
+/// for it, so a group of that name can't be referred to in braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplacementTemplate {
+    pieces: Vec<Piece>,
+}
+
+/// How a counter placeholder starts.
+const COUNTER: &str = "${counter";
+
+fn is_group_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl ReplacementTemplate {
+    /// Parses `replacement`, failing on an invalid format specifier.
+    pub fn parse(replacement: &str) -> Result<Self> {
+        let mut pieces = Vec::new();
+        let mut plain = String::new();
+        let mut rest = replacement;
+        while let Some(dollar) = rest.find('$') {
+            plain.push_str(&rest[..dollar]);
+            rest = &rest[dollar..];
+            if rest.starts_with("$$") {
+                plain.push_str("$$");
+                rest = &rest[2..];
+                continue;
+            }
+            if let Some(options) = rest.strip_prefix(COUNTER)
+                && let Some(close) = options.find('}')
+                && (close == 0 || options.starts_with(':'))
+            {
+                let counter = Counter::parse(&options[..close])
+                    .map_err(|e| anyhow::anyhow!("Invalid replacement {replacement}: {}{}}} has an {e}", COUNTER, &options[..close]))?;
+                pieces.push(Piece::Plain(std::mem::take(&mut plain)));
+                pieces.push(Piece::Counter(counter));
+                rest = &options[close + 1..];
+                continue;
+            }
+            let formatted = rest.strip_prefix("${")
+                .and_then(|body| body.find('}').map(|close| (&body[..close], close + 3)))
+                .and_then(|(body, len)| body.split_once(':').map(|(group, spec)| (group, spec, len)))
+                .filter(|(group, _, _)| is_group_name(group));
+            match formatted {
+                Some((group, spec, len)) => {
+                    let (styles, format) = parse_spec(spec)
+                        .map_err(|e| anyhow::anyhow!("Invalid replacement {replacement}: ${{{group}:{spec}}} has an {e}"))?;
+                    pieces.push(Piece::Plain(std::mem::take(&mut plain)));
+                    pieces.push(Piece::Formatted { group: group.to_string(), styles, format });
+                    rest = &rest[len..];
+                }
+                None => {
+                    plain.push('$');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        plain.push_str(rest);
+        pieces.push(Piece::Plain(plain));
+        pieces.retain(|piece| *piece != Piece::Plain(String::new()));
+        Ok(Self { pieces })
+    }
+
+    /// Like [`ReplacementTemplate::parse`], but a replacement with an invalid
+    /// format specifier is kept as the regex crate would expand it; rules
+    /// are validated when they are compiled.
+    pub fn lenient(replacement: &str) -> Self {
+        Self::parse(replacement).unwrap_or_else(|_| Self { pieces: vec![Piece::Plain(replacement.to_string())] })
+    }
+
+    /// Whether the replacement numbers matches with `${counter}`.
+    pub fn has_counter(&self) -> bool {
+        self.pieces.iter().any(|piece| matches!(piece, Piece::Counter(_)))
+    }
+
+    /// Whether a `${counter}` numbers matches across every file of a run.
+    pub fn counts_globally(&self) -> bool {
+        self.pieces.iter().any(|piece| matches!(piece, Piece::Counter(counter) if counter.global))
+    }
+
+    /// Appends the replacement for the match `caps` to `out`. `ordinal` is
+    /// the number of matches before this one, for `${counter}`.
+    pub fn expand(&self, caps: &Captures, ordinal: usize, out: &mut String) {
+        for piece in &self.pieces {
+            match piece {
+                Piece::Counter(counter) => counter.apply(ordinal, out),
+                Piece::Plain(text) => caps.expand(text, out),
+                Piece::Formatted { group, styles, format } => {
+                    let value = match group.parse::<usize>() {
+                        Ok(index) => caps.get(index),
+                        Err(_) => caps.name(group),
+                    };
+                    let mut value = value.map_or("", |m| m.as_str()).to_string();
+                    for style in styles {
+                        value = style.apply(&value);
+                    }
+                    match format {
+                        Some(format) => format.apply(&value, out),
+                        None => out.push_str(&value),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A capture group a replacement refers to, as written in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupRef {
+    /// The group's number or name
+    pub group: String,
+    /// Written as `${group}` rather than `$group`
+    pub braced: bool,
+}
+
+impl GroupRef {
+    /// The group's number, if it is referred to by one.
+    pub fn index(&self) -> Option<usize> {
+        self.group.parse().ok()
+    }
+}
+
+impl std::fmt::Display for GroupRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.braced { write!(f, "${{{}}}", self.group) } else { write!(f, "${}", self.group) }
+    }
+}
+
+/// Every group `replacement` refers to, in order, read the way the regex
+/// crate reads them: `$name` takes the longest run of letters, digits and
+/// underscores after the `$`, so `$1a` refers to a group named `1a`, not
+/// to group 1. A `$` starting no reference, and `$$`, are literal.
+pub fn group_refs(replacement: &str) -> Vec<GroupRef> {
+    let mut refs = Vec::new();
+    let mut rest = replacement;
+    while let Some(dollar) = rest.find('$') {
+        rest = &rest[dollar + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            rest = after;
+        } else if let Some(body) = rest.strip_prefix('{')
+            && let Some(close) = body.find('}')
+        {
+            if body[..close] == COUNTER[2..] || body[..close].starts_with(&format!("{}:", &COUNTER[2..])) {
+                rest = &body[close + 1..];
+                continue;
+            }
+            // A format specifier follows the group after a colon
+            let group = match body[..close].split_once(':') {
+                Some((group, _)) if is_group_name(group) => group,
+                _ => &body[..close],
+            };
+            refs.push(GroupRef { group: group.to_string(), braced: true });
+            rest = &body[close + 1..];
+        } else {
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            if len > 0 {
+                refs.push(GroupRef { group: rest[..len].to_string(), braced: false });
+                rest = &rest[len..];
+            }
+        }
+    }
+    refs
+}
+
+/// Fails if `replacement` refers to a group `regex` doesn't have, which
+/// would otherwise silently expand to nothing.
+pub fn validate(replacement: &str, regex: &Regex) -> Result<()> {
+    for group_ref in group_refs(replacement) {
+        let exists = match group_ref.index() {
+            Some(index) => index < regex.captures_len(),
+            None => regex.capture_names().flatten().any(|name| name == group_ref.group),
+        };
+        if exists {
+            continue;
+        }
+        let digits = group_ref.group.find(|c: char| !c.is_ascii_digit()).unwrap_or(0);
+        let hint = if !group_ref.braced && digits > 0 {
+            let (index, text) = group_ref.group.split_at(digits);
+            format!("; write ${{{index}}}{text} to follow group {index} with '{text}'")
+        } else {
+            let names: Vec<_> = regex.capture_names().flatten().collect();
+            let groups = match regex.captures_len() - 1 {
+                0 => "no capture groups".to_string(),
+                1 => "only group 1".to_string(),
+                n => format!("groups 1 to {n}"),
+            };
+            if names.is_empty() { format!("; the pattern has {groups}") } else { format!("; the pattern has {groups}, named {}", names.join(", ")) }
+        };
+        anyhow::bail!("Replacement {replacement} refers to {group_ref}, which pattern {regex} doesn't have{hint}");
+    }
+    Ok(())
+}
+
+/// `replacement` with every `${env:VAR}` replaced by the value of the
+/// environment variable `VAR`, as `env` gives it, and every `${date:FORMAT}`
+/// by the UTC time `now` formatted as [`audit::format_date`] does. Done once
+/// per run, so every file gets the same values; a `$` in a value is escaped
+/// so it is taken literally. Fails on a variable that isn't set.
+///
+/// [`audit::format_date`]: crate::audit::format_date
+pub fn stamp(replacement: &str, now: u64, env: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::with_capacity(replacement.len());
+    let mut rest = replacement;
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        rest = &rest[dollar..];
+        if rest.starts_with("$$") {
+            out.push_str("$$");
+            rest = &rest[2..];
+            continue;
+        }
+        let token = rest.strip_prefix("${")
+            .and_then(|body| body.find('}').map(|close| (&body[..close], close + 3)))
+            .and_then(|(body, len)| body.split_once(':').map(|(kind, arg)| (kind, arg, len)));
+        let (value, len) = match token {
+            Some(("env", name, len)) => {
+                let value = env(name)
+                    .ok_or_else(|| anyhow::anyhow!("Environment variable {name}, used in replacement {replacement}, is not set"))?;
+                (value, len)
+            }
+            Some(("date", format, len)) => {
+                let value = crate::audit::format_date(now, format)
+                    .map_err(|e| anyhow::anyhow!("Invalid replacement {replacement}: {e}"))?;
+                (value, len)
+            }
+            _ => {
+                out.push('$');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        out.push_str(&value.replace('$', "$$"));
+        rest = &rest[len..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn replace(pattern: &str, replacement: &str, text: &str) -> String {
+        let regex = Regex::new(pattern).unwrap();
+        let template = ReplacementTemplate::parse(replacement).unwrap();
+        let caps = regex.captures(text).unwrap();
+        let mut out = String::new();
+        template.expand(&caps, 0, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_format_specifiers() {
+        assert_eq!(replace(r"(\d+)", "${1:04}", "7"), "0007");
+        assert_eq!(replace(r"(-?\d+)", "${1:05}", "-42"), "-0042");
+        assert_eq!(replace(r"(\d+)", "[${1:>6}]", "42"), "[    42]");
+        assert_eq!(replace(r"(\d+)", "[${1:6}]", "42"), "[    42]");
+        assert_eq!(replace(r"(\w+)", "[${1:6}]", "ab"), "[ab    ]");
+        assert_eq!(replace(r"(?<name>\w+)", "[${name:*^7}]", "ab"), "[**ab***]");
+        assert_eq!(replace(r"(\w+)", "${1:<2}|", "long"), "long|");
+        assert_eq!(replace(r"(\w+)", "${1:03}", "ab"), "0ab");
+        // Plain references and escapes still work around formatted ones
+        assert_eq!(replace(r"(\w+) (\d+)", "$1: ${2:>3} $$ ${1}", "id 5"), "id:   5 $ id");
+        assert_eq!(replace(r"(\w+)", "${1}:${missing:3}.", "x"), "x:   .");
+        assert_eq!(replace(r"(\w+)", "${1:x", "x"), "${1:x");
+    }
+
+    #[test]
+    fn test_group_refs() {
+        let refs: Vec<String> = group_refs("$1 ${2} $$3 $name ${name:>4} $1a $ ${x").iter().map(|r| r.to_string()).collect();
+        assert_eq!(refs, ["$1", "${2}", "$name", "${name}", "$1a"]);
+    }
+
+    #[test]
+    fn test_validate() {
+        let regex = Regex::new(r"(\w+)@(?<host>\w+)").unwrap();
+        assert!(validate("$0 $1 ${2} ${host} ${host:>8} $$5", &regex).is_ok());
+        let err = |replacement: &str| validate(replacement, &regex).unwrap_err().to_string();
+        assert!(err("$3").contains("refers to $3"));
+        assert!(err("$3").contains("the pattern has groups 1 to 2, named host"));
+        assert!(err("${user}").contains("refers to ${user}"));
+        assert!(err("$1_suffix").contains("write ${1}_suffix to follow group 1 with '_suffix'"));
+        assert!(validate("$1", &Regex::new("a").unwrap()).unwrap_err().to_string().contains("no capture groups"));
+    }
+
+    #[test]
+    fn test_case_styles() {
+        assert_eq!(replace(r"(\w+)", "${1:upper}", "getUser"), "GETUSER");
+        assert_eq!(replace(r"(\w+)", "${1:lower}", "GetUser"), "getuser");
+        assert_eq!(replace(r"(\w+)", "${1:snake_case}", "HTTPServerError"), "http_server_error");
+        assert_eq!(replace(r"([\w-]+)", "${1:camelCase}", "user-id-2"), "userId2");
+        assert_eq!(replace(r"(\w+)", "${1:PascalCase}", "parse_json_v2"), "ParseJsonV2");
+        assert_eq!(replace(r"(\w+)", "${1:kebab}", "fetchURLList"), "fetch-url-list");
+        assert_eq!(replace(r"(\w+)", "${1:SCREAMING_SNAKE_CASE}", "maxRetryCount"), "MAX_RETRY_COUNT");
+        assert_eq!(replace(r"(?<name>\w+)", "[${name:snake:>10}]", "fooBar"), "[   foo_bar]");
+        assert_eq!(replace(r"(\w+)", "${1:lower:upper}", "aB"), "AB");
+        assert!(ReplacementTemplate::parse("${1:shouty}").unwrap_err().to_string().contains("case style"));
+    }
+
+    #[test]
+    fn test_counter() {
+        let numbered = |replacement: &str| -> Vec<String> {
+            let template = ReplacementTemplate::parse(replacement).unwrap();
+            let regex = Regex::new("x").unwrap();
+            regex.captures_iter("xxx").enumerate().map(|(ordinal, caps)| {
+                let mut out = String::new();
+                template.expand(&caps, ordinal, &mut out);
+                out
+            }).collect()
+        };
+        assert_eq!(numbered("t${counter}"), ["t1", "t2", "t3"]);
+        assert_eq!(numbered("${counter:start=10:step=5:pad=3}"), ["010", "015", "020"]);
+        assert_eq!(numbered("${counter:start=0:step=-2}"), ["0", "-2", "-4"]);
+        assert!(!ReplacementTemplate::parse("${counter}").unwrap().counts_globally());
+        assert!(ReplacementTemplate::parse("${counter:scope=global}").unwrap().counts_globally());
+        assert!(ReplacementTemplate::parse("${counter:begin=1}").unwrap_err().to_string().contains("invalid counter option 'begin=1'"));
+        assert_eq!(group_refs("${counter:pad=2} $1"), [GroupRef { group: "1".to_string(), braced: false }]);
+        // Only the exact name is the counter
+        assert_eq!(numbered("$counter ${counters}"), [" ", " ", " "]);
+    }
+
+    #[test]
+    fn test_invalid_format_is_reported() {
+        let err = ReplacementTemplate::parse("${1:>wide}").unwrap_err();
+        assert!(err.to_string().contains("${1:>wide} has an invalid format '>wide'"));
+    }
+
+    #[test]
+    fn test_stamp() -> Result<()> {
+        let env = |name: &str| match name {
+            "VERSION" => Some("1.4.0".to_string()),
+            "PRICE" => Some("$5".to_string()),
+            _ => None,
+        };
+        assert_eq!(stamp("v${env:VERSION} (${date:%Y-%m-%d}) $1", 1_700_000_000, env)?, "v1.4.0 (2023-11-14) $1");
+        assert_eq!(stamp("${env:PRICE} $${env:VERSION} ${1:upper}", 0, env)?, "$$5 $${env:VERSION} ${1:upper}");
+        assert!(stamp("${env:MISSING}", 0, env).unwrap_err().to_string().contains("MISSING"));
+        assert!(stamp("${date:%Q}", 0, env).is_err());
+        Ok(())
+    }
+}
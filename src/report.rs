@@ -0,0 +1,487 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::file_processor::{FileOutcome, FileStatus, MatchRecord};
+use crate::utf8::InvalidUtf8;
+
+/// Machine-readable outcome of a run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunSummary {
+    pub run_id: String,
+    pub success: bool,
+    pub dry_run: bool,
+    pub files_processed: usize,
+    pub files_modified: usize,
+    /// Files that couldn't be processed, or whose hooks failed
+    pub files_failed: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RunSummary {
+    /// One-line human readable description, used for notifications.
+    pub fn headline(&self) -> String {
+        match &self.error {
+            Some(error) => format!("Run failed: {error}"),
+            None if self.dry_run => format!(
+                "Dry run finished: {} of {} files would be modified",
+                self.files_modified, self.files_processed
+            ),
+            None => format!(
+                "Run finished: {} of {} files modified",
+                self.files_modified, self.files_processed
+            ),
+        }
+    }
+
+    /// One `key=value` line for wrapper scripts, written to stderr whatever
+    /// stdout carries: `rr: status=ok files=1234 modified=56 errors=2 ...`.
+    /// A run that failed outright counts as one error.
+    pub fn trailer(&self) -> String {
+        format!(
+            "rr: status={} files={} modified={} errors={} dry_run={} run_id={}",
+            if self.error.is_some() { "failed" } else { "ok" },
+            self.files_processed,
+            self.files_modified,
+            self.files_failed + usize::from(self.error.is_some()),
+            u8::from(self.dry_run),
+            self.run_id
+        )
+    }
+}
+
+/// Every replacement of a run as a JSON array, one object per match with its
+/// named groups under `captures`.
+pub fn render_matches_json(records: &[(PathBuf, MatchRecord)]) -> String {
+    let matches: Vec<Value> = records
+        .iter()
+        .map(|(path, record)| {
+            let captures: serde_json::Map<String, Value> = record.captures
+                .iter()
+                .map(|(name, value)| (name.clone(), json!(value)))
+                .collect();
+            json!({
+                "file": path.display().to_string(),
+                "line": record.line,
+                "match": record.text,
+                "replacement": record.replacement,
+                "captures": captures,
+            })
+        })
+        .collect();
+    serde_json::to_string_pretty(&matches).unwrap_or_default() + "\n"
+}
+
+/// Every replacement of a run as CSV, with one column per named group. Groups
+/// that did not participate in a match are left empty.
+pub fn render_matches_csv(records: &[(PathBuf, MatchRecord)]) -> String {
+    let mut names: Vec<&str> = Vec::new();
+    for (name, _) in records.iter().flat_map(|(_, record)| &record.captures) {
+        if !names.contains(&name.as_str()) {
+            names.push(name);
+        }
+    }
+
+    let mut out = String::new();
+    let header = ["file", "line", "match", "replacement"].into_iter().chain(names.iter().copied());
+    push_csv_row(&mut out, header.map(str::to_string));
+    for (path, record) in records {
+        let fields = [path.display().to_string(), record.line.to_string(), record.text.clone(), record.replacement.clone()];
+        let groups = names.iter().map(|name| {
+            record.captures
+                .iter()
+                .find(|(group, _)| group == name)
+                .and_then(|(_, value)| value.clone())
+                .unwrap_or_default()
+        });
+        push_csv_row(&mut out, fields.into_iter().chain(groups));
+    }
+    out
+}
+
+fn push_csv_row(out: &mut String, fields: impl Iterator<Item = String>) {
+    let fields: Vec<_> = fields
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field
+            }
+        })
+        .collect();
+    out.push_str(&fields.join(","));
+    out.push('\n');
+}
+
+/// Where one replacement was made, as reported by `--output json`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MatchPosition {
+    pub line: usize,
+    pub column: usize,
+    /// Byte offsets in the content the rule ran against
+    pub start: usize,
+    pub end: usize,
+    #[serde(rename = "match")]
+    pub text: String,
+    pub replacement: String,
+    /// With `--export-context`, the lines before the match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_before: Option<Vec<String>>,
+    /// With `--export-context`, the lines after the match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_after: Option<Vec<String>>,
+}
+
+/// One processed file in a `--output json` report.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileReport {
+    pub path: String,
+    /// `modified`, `unchanged`, `abandoned`, `changed_on_disk` or `failed`
+    pub status: &'static str,
+    /// Whether the file was rewritten, rather than only reported by a dry run
+    pub applied: bool,
+    pub match_count: usize,
+    pub matches: Vec<MatchPosition>,
+    /// `lossy` or `bytes` for a file that wasn't valid UTF-8, by how it was decoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invalid_utf8: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl FileReport {
+    pub fn new(outcome: &FileOutcome, dry_run: bool) -> Self {
+        let status = match outcome.status {
+            FileStatus::Modified => "modified",
+            FileStatus::Unchanged => "unchanged",
+            FileStatus::Abandoned => "abandoned",
+            FileStatus::ChangedOnDisk => "changed_on_disk",
+            FileStatus::Skipped(_) => "skipped",
+            FileStatus::Failed => "failed",
+        };
+        let matches = outcome.change.iter()
+            .flat_map(|change| &change.found)
+            .map(|record| MatchPosition {
+                line: record.line,
+                column: record.column,
+                start: record.start,
+                end: record.end,
+                text: record.text.clone(),
+                replacement: record.replacement.clone(),
+                context_before: record.context.as_ref().map(|context| context.before.clone()),
+                context_after: record.context.as_ref().map(|context| context.after.clone()),
+            })
+            .collect();
+        Self {
+            path: outcome.path.display().to_string(),
+            status,
+            applied: matches!(outcome.status, FileStatus::Modified) && !dry_run,
+            match_count: outcome.matches,
+            matches,
+            invalid_utf8: outcome.change.as_ref().and_then(|change| change.utf8_fallback).map(|decoding| match decoding {
+                InvalidUtf8::Bytes => "bytes",
+                _ => "lossy",
+            }),
+            error: outcome.error.as_ref().map(|e| format!("{e:#}")),
+        }
+    }
+}
+
+/// Totals of a run in a `--output json` report.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReportTotals {
+    pub files_processed: usize,
+    pub files_modified: usize,
+    pub files_failed: usize,
+    pub files_skipped: usize,
+    /// Matches in the modified files
+    pub matches: usize,
+    pub lines_added: usize,
+    pub lines_removed: usize,
+}
+
+/// The report written by `--output json`: every processed file with the
+/// position of each replacement, and the run's totals.
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonReport {
+    pub run_id: String,
+    pub dry_run: bool,
+    pub files: Vec<FileReport>,
+    pub summary: ReportTotals,
+}
+
+impl JsonReport {
+    pub fn render(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default() + "\n"
+    }
+
+    /// The report as JSON Lines, for `--output jsonl`: an object per match,
+    /// then one per file that failed, then the totals, each tagged with its
+    /// `type` so a reader can act on matches as they arrive.
+    pub fn render_lines(&self) -> String {
+        let mut out = String::new();
+        let mut push = |value: Value| {
+            out.push_str(&value.to_string());
+            out.push('\n');
+        };
+        for file in &self.files {
+            for position in &file.matches {
+                let mut line = json!({ "type": "match", "path": file.path, "applied": file.applied });
+                if let (Value::Object(line), Ok(Value::Object(fields))) = (&mut line, serde_json::to_value(position)) {
+                    line.extend(fields);
+                }
+                push(line);
+            }
+            if let Some(ref error) = file.error {
+                push(json!({ "type": "error", "path": file.path, "error": error }));
+            }
+        }
+        let mut summary = json!({ "type": "summary", "run_id": self.run_id, "dry_run": self.dry_run });
+        if let (Value::Object(summary), Ok(Value::Object(totals))) = (&mut summary, serde_json::to_value(&self.summary)) {
+            summary.extend(totals);
+        }
+        push(summary);
+        out
+    }
+}
+
+/// Matches found in one file, for ranking with [`render_top_files`].
+#[derive(Debug, Clone)]
+pub struct FileMatches {
+    pub path: PathBuf,
+    pub matches: usize,
+    /// Size of the file before the run
+    pub bytes: u64,
+}
+
+impl FileMatches {
+    pub fn per_kb(&self) -> f64 {
+        self.matches as f64 * 1024.0 / self.bytes.max(1) as f64
+    }
+}
+
+/// The `limit` files with the most matches, densest first among equals.
+pub fn render_top_files(files: &[FileMatches], limit: usize) -> String {
+    let mut ranked: Vec<_> = files.iter().filter(|file| file.matches > 0).collect();
+    ranked.sort_by(|a, b| {
+        b.matches.cmp(&a.matches)
+            .then(b.per_kb().total_cmp(&a.per_kb()))
+            .then(a.path.cmp(&b.path))
+    });
+    ranked.truncate(limit);
+
+    let width = ranked.first().map_or(1, |file| file.matches.to_string().len());
+    let mut out = format!("\nTop {} file{} by matches:\n", ranked.len(), if ranked.len() == 1 { "" } else { "s" });
+    for file in ranked {
+        out.push_str(&format!("  {:>width$} matches  {:>8.2}/KB  {}\n", file.matches, file.per_kb(), file.path.display()));
+    }
+    out
+}
+
+/// A directory or file in [`render_tree`], with the totals of the files
+/// below it.
+#[derive(Default)]
+struct TreeNode {
+    files: usize,
+    matches: usize,
+    children: BTreeMap<String, TreeNode>,
+}
+
+impl TreeNode {
+    fn render(&self, depth: usize, out: &mut String) {
+        for (name, node) in &self.children {
+            let indent = "  ".repeat(depth);
+            if node.children.is_empty() {
+                out.push_str(&format!("{indent}{name} ({} {})\n", node.matches, plural(node.matches, "match", "matches")));
+            } else {
+                out.push_str(&format!(
+                    "{indent}{name}/ ({} {}, {} {})\n",
+                    node.files,
+                    plural(node.files, "file", "files"),
+                    node.matches,
+                    plural(node.matches, "match", "matches")
+                ));
+                node.render(depth + 1, out);
+            }
+        }
+    }
+}
+
+fn plural<'a>(count: usize, one: &'a str, many: &'a str) -> &'a str {
+    if count == 1 { one } else { many }
+}
+
+/// The modified `files` and their match counts as an indented tree, each
+/// directory showing how many files below it changed and how many matches
+/// they had.
+pub fn render_tree(files: &[(PathBuf, usize)]) -> String {
+    let mut root = TreeNode::default();
+    for (path, matches) in files {
+        let mut node = &mut root;
+        for component in Path::new(path).components() {
+            node.files += 1;
+            node.matches += matches;
+            node = node.children.entry(component.as_os_str().to_string_lossy().into_owned()).or_default();
+        }
+        node.files += 1;
+        node.matches += matches;
+    }
+    let mut out = format!("\nChanged files ({} {}, {} {}):\n", root.files, plural(root.files, "file", "files"), root.matches, plural(root.matches, "match", "matches"));
+    root.render(1, &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_processor::MatchContext;
+
+    fn records() -> Vec<(PathBuf, MatchRecord)> {
+        vec![
+            (PathBuf::from("a.txt"), MatchRecord {
+                line: 3,
+                column: 5,
+                start: 40,
+                end: 49,
+                text: "id=7, \"x\"".to_string(),
+                replacement: "7".to_string(),
+                captures: vec![("id".to_string(), Some("7".to_string())), ("tag".to_string(), None)],
+                context: None,
+            }),
+            (PathBuf::from("b.txt"), MatchRecord {
+                line: 1,
+                column: 1,
+                start: 0,
+                end: 2,
+                text: "v2".to_string(),
+                replacement: "2".to_string(),
+                captures: vec![("version".to_string(), Some("2".to_string()))],
+                context: Some(MatchContext { before: Vec::new(), after: vec!["next".to_string()] }),
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_render_matches_csv() {
+        assert_eq!(render_matches_csv(&records()), "\
+file,line,match,replacement,id,tag,version
+a.txt,3,\"id=7, \"\"x\"\"\",7,7,,
+b.txt,1,v2,2,,,2
+");
+    }
+
+    #[test]
+    fn test_render_matches_json() {
+        let value: Value = serde_json::from_str(&render_matches_json(&records())).unwrap();
+        assert_eq!(value[0]["captures"], json!({ "id": "7", "tag": null }));
+        assert_eq!(value[1]["file"], "b.txt");
+    }
+
+    #[test]
+    fn test_file_report() {
+        use crate::file_processor::FileChange;
+        use std::time::Duration;
+
+        let (path, record) = records().remove(0);
+        let outcome = FileOutcome {
+            path,
+            status: FileStatus::Modified,
+            matches: 1,
+            bytes_delta: -8,
+            error: None,
+            duration: Duration::ZERO,
+            change: Some(FileChange { modified: true, matches: 1, found: vec![record], ..Default::default() }),
+        };
+        let value = serde_json::to_value(FileReport::new(&outcome, true)).unwrap();
+        assert_eq!(value, json!({
+            "path": "a.txt",
+            "status": "modified",
+            "applied": false,
+            "match_count": 1,
+            "matches": [{ "line": 3, "column": 5, "start": 40, "end": 49, "match": "id=7, \"x\"", "replacement": "7" }],
+        }));
+
+        let skipped = FileOutcome::skipped("b.txt".into(), crate::filter::SkipReason::Binary);
+        let failed = FileOutcome { status: FileStatus::Failed, error: Some(anyhow::anyhow!("denied")), ..skipped };
+        assert_eq!(serde_json::to_value(FileReport::new(&failed, false)).unwrap()["error"], "denied");
+    }
+
+    #[test]
+    fn test_render_lines() {
+        use crate::file_processor::FileChange;
+
+        let (path, record) = records().remove(1);
+        let outcome = FileOutcome {
+            path,
+            status: FileStatus::Modified,
+            matches: 1,
+            bytes_delta: 0,
+            error: None,
+            duration: std::time::Duration::ZERO,
+            change: Some(FileChange { modified: true, matches: 1, found: vec![record], ..Default::default() }),
+        };
+        let report = JsonReport {
+            run_id: "r1".to_string(),
+            dry_run: true,
+            files: vec![FileReport::new(&outcome, true)],
+            summary: ReportTotals { files_processed: 1, files_modified: 1, matches: 1, ..Default::default() },
+        };
+        let lines: Vec<Value> = report.render_lines().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], json!({
+            "type": "match", "path": "b.txt", "applied": false,
+            "line": 1, "column": 1, "start": 0, "end": 2, "match": "v2", "replacement": "2",
+            "context_before": [], "context_after": ["next"],
+        }));
+        assert_eq!(lines[1]["type"], "summary");
+        assert_eq!(lines[1]["run_id"], "r1");
+        assert_eq!(lines[1]["files_modified"], 1);
+    }
+
+    #[test]
+    fn test_trailer() {
+        let summary = RunSummary {
+            run_id: "abc".to_string(),
+            success: true,
+            files_processed: 12,
+            files_modified: 3,
+            files_failed: 2,
+            ..Default::default()
+        };
+        assert_eq!(summary.trailer(), "rr: status=ok files=12 modified=3 errors=2 dry_run=0 run_id=abc");
+        let failed = RunSummary { error: Some("boom".to_string()), dry_run: true, ..summary };
+        assert_eq!(failed.trailer(), "rr: status=failed files=12 modified=3 errors=3 dry_run=1 run_id=abc");
+    }
+
+    #[test]
+    fn test_render_top_files() {
+        let file = |path: &str, matches, bytes| FileMatches { path: PathBuf::from(path), matches, bytes };
+        let files = [file("a.rs", 3, 4096), file("b.rs", 12, 2048), file("c.rs", 3, 1024), file("d.rs", 0, 10)];
+        assert_eq!(render_top_files(&files, 2), "\
+\nTop 2 files by matches:
+  12 matches      6.00/KB  b.rs
+   3 matches      3.00/KB  c.rs
+");
+    }
+
+    #[test]
+    fn test_render_tree() {
+        let files = [
+            (PathBuf::from("src/billing/client.rs"), 3),
+            (PathBuf::from("README.md"), 1),
+            (PathBuf::from("src/billing/mod.rs"), 2),
+            (PathBuf::from("src/main.rs"), 2),
+        ];
+        assert_eq!(render_tree(&files), "\
+\nChanged files (4 files, 8 matches):
+  README.md (1 match)
+  src/ (3 files, 7 matches)
+    billing/ (2 files, 5 matches)
+      client.rs (3 matches)
+      mod.rs (2 matches)
+    main.rs (2 matches)
+");
+    }
+}
@@ -0,0 +1,157 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use crate::file_processor::{self, Rule};
+use crate::filter::FileFilter;
+use crate::preview::Preview;
+
+/// Name of the record of recent runs inside the cache directory.
+const LAST_RUNS_FILE: &str = "last-runs.json";
+
+/// Most runs remembered; the oldest are forgotten first.
+const MAX_RUNS: usize = 256;
+
+/// The tree each recent successful run left behind, by the run's options,
+/// so running the same again over a tree nobody has touched since can be
+/// skipped.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LastRuns {
+    runs: BTreeMap<String, LastRun>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct LastRun {
+    /// [`tree_hash`] of the directories once the run was done
+    tree: String,
+    /// Seconds since the Unix epoch
+    timestamp: u64,
+}
+
+impl LastRuns {
+    /// Where the record is kept: next to the cached preview.
+    pub fn path() -> Result<PathBuf> {
+        Ok(Preview::cache_path()?.with_file_name(LAST_RUNS_FILE))
+    }
+
+    /// Reads the record at `path`, or an empty one if there is none or it
+    /// can't be read, as it is only a shortcut.
+    pub fn load(path: &Path) -> Self {
+        fs::read_to_string(path).ok().and_then(|text| serde_json::from_str(&text).ok()).unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create cache directory: {}", dir.display()))?;
+        }
+        fs::write(path, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write run record: {}", path.display()))
+    }
+
+    /// Whether the run identified by `key` last left the tree as `tree` is.
+    pub fn already_applied(&self, key: &str, tree: &str) -> bool {
+        self.runs.get(key).is_some_and(|run| run.tree == tree)
+    }
+
+    /// Records that the run identified by `key` left the tree as `tree`.
+    pub fn record(&mut self, key: &str, tree: String, timestamp: u64) {
+        self.runs.insert(key.to_string(), LastRun { tree, timestamp });
+        while self.runs.len() > MAX_RUNS {
+            let oldest = self.runs.iter().min_by_key(|(_, run)| run.timestamp).map(|(key, _)| key.clone());
+            self.runs.remove(&oldest.expect("the record is not empty"));
+        }
+    }
+}
+
+/// Identifies a run by its directories and command line arguments.
+pub fn run_key(directories: &[PathBuf], args: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    for directory in directories {
+        hasher.update(directory.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+    }
+    hasher.update([0]);
+    for arg in args {
+        hasher.update(arg.as_bytes());
+        hasher.update([0]);
+    }
+    hex(&hasher.finalize())
+}
+
+/// A hash of every file `filters` select, by path, size and modification
+/// time, and of the rules each would be given, so a change to any file or
+/// `.rr.toml` changes it. Files are not read.
+pub fn tree_hash(filters: &[&FileFilter]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut rules_seen: HashMap<*const Vec<Rule>, String> = HashMap::new();
+    for filter in filters {
+        let walk = filter.walk()?;
+        for entry in &walk.files {
+            let settings = filter.settings_for(&entry.path)?;
+            let rules = rules_seen
+                .entry(Arc::as_ptr(&settings.rules))
+                .or_insert_with(|| file_processor::fingerprint(&settings.rules));
+            let modified = entry.metadata.modified
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            hasher.update(format!("{}\0{}\0{}\0{rules}\0", entry.path.display(), entry.metadata.len, modified.as_nanos()));
+        }
+        for (path, reason) in &walk.skipped {
+            hasher.update(format!("{}\0{reason}\0", path.display()));
+        }
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+    use crate::project::ProjectSettings;
+    use regex::Regex;
+
+    #[test]
+    fn test_tree_hash_follows_changes() -> Result<()> {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/tree/a.txt", "a");
+        let defaults = || ProjectSettings { rules: Arc::new(vec![Rule::new(Regex::new("a").unwrap(), "b")]), ..Default::default() };
+        let filter = FileFilter::with_fs(fs.clone(), "/tree", defaults(), &[], false)?;
+
+        let before = tree_hash(&[&filter])?;
+        assert_eq!(tree_hash(&[&filter])?, before);
+        fs.add_file("/tree/a.txt", "ab");
+        assert_ne!(tree_hash(&[&filter])?, before);
+
+        let other_rules = ProjectSettings { rules: Arc::new(vec![Rule::new(Regex::new("a").unwrap(), "c")]), ..Default::default() };
+        let other = FileFilter::with_fs(fs.clone(), "/tree", other_rules, &[], false)?;
+        assert_ne!(tree_hash(&[&other])?, tree_hash(&[&filter])?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_last_runs() {
+        let key = run_key(&[PathBuf::from("/tree")], &["-p".to_string(), "a".to_string()]);
+        assert_ne!(key, run_key(&[PathBuf::from("/tree")], &["-pa".to_string()]));
+
+        let mut runs = LastRuns::default();
+        assert!(!runs.already_applied(&key, "tree-1"));
+        runs.record(&key, "tree-1".to_string(), 1);
+        assert!(runs.already_applied(&key, "tree-1"));
+        assert!(!runs.already_applied(&key, "tree-2"));
+
+        for n in 0..MAX_RUNS {
+            runs.record(&n.to_string(), String::new(), n as u64 + 2);
+        }
+        assert_eq!(runs.runs.len(), MAX_RUNS);
+        assert!(!runs.already_applied(&key, "tree-1"));
+    }
+}
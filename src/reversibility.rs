@@ -0,0 +1,152 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::file_processor::{Replacement, Rule};
+
+/// How one distinct matched string was rewritten by one rule in one file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    /// The rule that made the replacement, as `/pattern/ -> "replacement"`
+    pub rule: String,
+    pub original: String,
+    pub output: String,
+    /// Occurrences of `output` in the file before the rule ran
+    pub preexisting: usize,
+}
+
+/// The distinct mappings made by `rule` on `content`.
+pub fn mappings(rule: &Rule, content: &str, replacements: &[Replacement]) -> Vec<Mapping> {
+    let label = format!("/{}/ -> {:?}", rule.regex, rule.replacement);
+    let distinct: BTreeSet<_> = replacements
+        .iter()
+        .map(|rep| (&content[rep.start..rep.end], rep.text.as_str()))
+        .collect();
+    distinct
+        .into_iter()
+        .map(|(original, output)| Mapping {
+            rule: label.clone(),
+            original: original.to_string(),
+            output: output.to_string(),
+            // Identity mappings change nothing, so nothing can be confused with them
+            preexisting: if output.is_empty() || output == original { 0 } else { content.matches(output).count() },
+        })
+        .collect()
+}
+
+/// Whether inverting each rule's replacement would restore the original
+/// text, accumulated over every file of a run.
+#[derive(Debug, Default)]
+pub struct Analysis {
+    /// Per rule, every output and the distinct strings that produced it
+    outputs: BTreeMap<String, BTreeMap<String, BTreeSet<String>>>,
+    /// Per rule and output, the files that already contained the output
+    preexisting: BTreeMap<String, BTreeMap<String, Vec<(PathBuf, usize)>>>,
+}
+
+impl Analysis {
+    pub fn add(&mut self, path: &Path, mappings: &[Mapping]) {
+        for mapping in mappings {
+            self.outputs
+                .entry(mapping.rule.clone())
+                .or_default()
+                .entry(mapping.output.clone())
+                .or_default()
+                .insert(mapping.original.clone());
+            if mapping.preexisting > 0 {
+                let files = self.preexisting.entry(mapping.rule.clone()).or_default().entry(mapping.output.clone()).or_default();
+                if !files.iter().any(|(file, _)| file == path) {
+                    files.push((path.to_path_buf(), mapping.preexisting));
+                }
+            }
+        }
+    }
+
+    fn problems(&self, rule: &str) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (output, originals) in self.outputs.get(rule).into_iter().flatten() {
+            if output.is_empty() {
+                let originals: Vec<_> = originals.iter().map(|o| format!("{o:?}")).collect();
+                problems.push(format!("deletes {}", originals.join(", ")));
+            } else if originals.len() > 1 {
+                let originals: Vec<_> = originals.iter().map(|o| format!("{o:?}")).collect();
+                problems.push(format!("collision: {} -> {:?}", originals.join(", "), output));
+            }
+        }
+        for (output, files) in self.preexisting.get(rule).into_iter().flatten() {
+            let files: Vec<_> = files
+                .iter()
+                .map(|(path, count)| format!("{} ({count}x)", path.display()))
+                .collect();
+            problems.push(format!("{:?} already occurs in {}", output, files.join(", ")));
+        }
+        problems
+    }
+
+    pub fn is_reversible(&self) -> bool {
+        self.outputs.keys().all(|rule| self.problems(rule).is_empty())
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::from("\nReversibility analysis:\n");
+        for rule in self.outputs.keys() {
+            let problems = self.problems(rule);
+            let verdict = if problems.is_empty() { "reversible" } else { "not reversible" };
+            let _ = writeln!(out, "  {rule}: {verdict}");
+            for problem in problems {
+                let _ = writeln!(out, "    {problem}");
+            }
+        }
+        if self.outputs.is_empty() {
+            out.push_str("  No replacements were made\n");
+        } else if self.is_reversible() {
+            out.push_str("  An inverse replacement would restore the original text\n");
+        } else {
+            out.push_str("  An inverse replacement would NOT safely undo this run\n");
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_processor::find_replacements;
+    use regex::Regex;
+
+    fn analyze(pattern: &str, replacement: &str, files: &[(&str, &str)]) -> Analysis {
+        let rule = Rule::new(Regex::new(pattern).unwrap(), replacement);
+        let mut analysis = Analysis::default();
+        for (name, content) in files {
+            let replacements = find_replacements(content, &rule.regex, replacement);
+            analysis.add(Path::new(name), &mappings(&rule, content, &replacements));
+        }
+        analysis
+    }
+
+    fn problems(analysis: &Analysis) -> Vec<String> {
+        let rule = analysis.outputs.keys().next().unwrap();
+        analysis.problems(rule)
+    }
+
+    #[test]
+    fn test_one_to_one_rename_is_reversible() {
+        let analysis = analyze(r"\bold_name\b", "new_name", &[("a.rs", "old_name(); old_name();")]);
+        assert!(analysis.is_reversible());
+    }
+
+    #[test]
+    fn test_collisions_and_preexisting_outputs() {
+        let analysis = analyze(r"colou?r", "color", &[("a.txt", "colour color"), ("b.txt", "color")]);
+        assert!(!analysis.is_reversible());
+        let problems = problems(&analysis);
+        assert_eq!(problems[0], r#"collision: "color", "colour" -> "color""#);
+        assert_eq!(problems[1], r#""color" already occurs in a.txt (1x)"#);
+    }
+
+    #[test]
+    fn test_deletion_is_not_reversible() {
+        let analysis = analyze(r"\s+$", "", &[("a.txt", "trailing   ")]);
+        assert!(problems(&analysis)[0].starts_with("deletes"));
+    }
+}
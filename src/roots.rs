@@ -0,0 +1,91 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::project::RuleConfig;
+
+/// Contents of a `--roots` file: several directory trees, each with its own
+/// rules and filters, processed together in one run.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RootsConfig {
+    #[serde(rename = "root")]
+    pub roots: Vec<RootConfig>,
+}
+
+/// One `[[root]]` entry.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RootConfig {
+    /// Directory to search, relative to the file declaring it
+    pub directory: PathBuf,
+    /// File extensions to process, replacing `--extensions`
+    pub extensions: Option<Vec<String>>,
+    /// Globs restricting the files processed, added to `--glob`
+    #[serde(default)]
+    pub globs: Vec<String>,
+    /// Replacement rules, replacing `--pattern`/`--replace`
+    pub rules: Option<Vec<RuleConfig>>,
+}
+
+impl RootsConfig {
+    /// Reads a roots file, resolving each directory against the file's own.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read roots file: {}", path.display()))?;
+        let mut config: RootsConfig = toml::from_str(&text)
+            .with_context(|| format!("Invalid roots file: {}", path.display()))?;
+        if config.roots.is_empty() {
+            anyhow::bail!("Roots file declares no [[root]] entries: {}", path.display());
+        }
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        for root in &mut config.roots {
+            root.directory = base.join(&root.directory);
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_load_resolves_directories() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("roots.toml");
+        fs::write(&path, r#"
+[[root]]
+directory = "web"
+extensions = ["js"]
+
+[[root.rules]]
+pattern = "http:"
+replace = "https:"
+
+[[root]]
+directory = "docs"
+globs = ["*.md"]
+"#)?;
+
+        let config = RootsConfig::load(&path)?;
+        assert_eq!(config.roots.len(), 2);
+        assert_eq!(config.roots[0].directory, temp_dir.path().join("web"));
+        assert_eq!(config.roots[0].rules.as_ref().unwrap()[0].replace, "https:");
+        assert_eq!(config.roots[1].globs, ["*.md"]);
+        assert!(config.roots[1].rules.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_rejects_empty() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("roots.toml");
+        fs::write(&path, "root = []\n")?;
+        assert!(RootsConfig::load(&path).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+
+use crate::file_processor::Rule;
+use crate::project::{ProjectSettings, RuleConfig};
+
+/// Newest rule pack format this version understands.
+pub const PACK_FORMAT: u32 = 1;
+
+/// A distributable bundle of named rules together with the files they are
+/// meant for and the limits they should run under.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RulePack {
+    /// Format version of the pack file itself
+    pub format: u32,
+    pub name: String,
+    /// Version of the pack's contents, chosen by its maintainers
+    pub version: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub scope: PackScope,
+    #[serde(default)]
+    pub limits: PackLimits,
+    pub rules: Vec<PackRule>,
+}
+
+/// Files a pack applies to.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackScope {
+    pub extensions: Option<Vec<String>>,
+    #[serde(default)]
+    pub globs: Vec<String>,
+}
+
+/// Safety limits a pack runs under.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackLimits {
+    pub max_file_size: Option<u64>,
+    pub max_matches_per_file: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackRule {
+    /// Identifies the rule within its pack, also accepted as `id`
+    #[serde(alias = "id")]
+    pub name: String,
+    pub description: Option<String>,
+    pub pattern: String,
+    pub replace: String,
+    /// Only apply the rule to files with one of these extensions
+    pub extensions: Option<Vec<String>>,
+    /// Match regardless of case and keep the casing of each match
+    #[serde(default)]
+    pub preserve_case: bool,
+    /// Labels for selecting groups of rules with `--only-rules` and `--skip-rules`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// A disabled rule only runs when `--only-rules` names it
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl PackRule {
+    /// Whether `selector` is the rule's name or one of its tags.
+    fn is_selected_by(&self, selector: &str) -> bool {
+        self.name == selector || self.tags.iter().any(|tag| tag == selector)
+    }
+}
+
+impl RulePack {
+    /// Loads a pack from a local path or an `http(s)://` URL.
+    pub fn load(source: &str) -> Result<Self> {
+        let text = if source.starts_with("http://") || source.starts_with("https://") {
+            ureq::get(source)
+                .call()
+                .with_context(|| format!("Failed to download rule pack: {source}"))?
+                .into_string()
+                .with_context(|| format!("Failed to download rule pack: {source}"))?
+        } else {
+            fs::read_to_string(source)
+                .with_context(|| format!("Failed to read rule pack: {source}"))?
+        };
+        Self::parse(&text).with_context(|| format!("Invalid rule pack: {source}"))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let pack: RulePack = toml::from_str(text)?;
+        if pack.format > PACK_FORMAT {
+            anyhow::bail!(
+                "Rule pack {} uses format {}, but only formats up to {} are supported; upgrade regex-replace",
+                pack.name, pack.format, PACK_FORMAT
+            );
+        }
+        if pack.rules.is_empty() {
+            anyhow::bail!("Rule pack {} has no rules", pack.name);
+        }
+        let mut names = HashSet::new();
+        if let Some(rule) = pack.rules.iter().find(|rule| !names.insert(&rule.name)) {
+            anyhow::bail!("Rule pack {} defines rule {} more than once", pack.name, rule.name);
+        }
+        Ok(pack)
+    }
+
+    /// Keeps the rules to run: the enabled ones, or with `only` those it
+    /// names or tags, less any `skip` names or tags. Naming a disabled rule
+    /// in `only` runs it; tagging it doesn't. Fails on a name or tag no rule
+    /// has, or if no rule is left.
+    pub fn select(&mut self, only: &[String], skip: &[String]) -> Result<()> {
+        if let Some(unknown) = only.iter().chain(skip).find(|selector| !self.rules.iter().any(|rule| rule.is_selected_by(selector))) {
+            anyhow::bail!("Rule pack {} has no rule named or tagged {unknown}", self.name);
+        }
+        self.rules.retain(|rule| {
+            let wanted = if only.is_empty() {
+                rule.enabled
+            } else if rule.enabled {
+                only.iter().any(|selector| rule.is_selected_by(selector))
+            } else {
+                only.contains(&rule.name)
+            };
+            wanted && !skip.iter().any(|selector| rule.is_selected_by(selector))
+        });
+        if self.rules.is_empty() {
+            anyhow::bail!("No rules of pack {} are left to run", self.name);
+        }
+        Ok(())
+    }
+
+    pub fn compile_rules(&self) -> Result<Vec<Rule>> {
+        self.rules
+            .iter()
+            .map(|rule| {
+                RuleConfig {
+                    pattern: rule.pattern.clone(),
+                    replace: rule.replace.clone(),
+                    extensions: rule.extensions.clone(),
+                    preserve_case: rule.preserve_case,
+                }
+                    .compile()
+                    .with_context(|| format!("Invalid rule {} in pack {}", rule.name, self.name))
+            })
+            .collect()
+    }
+
+    /// Layers the pack's rules, scope and limits over `settings` and `globs`.
+    pub fn apply(&self, settings: &mut ProjectSettings, globs: &mut Vec<String>) -> Result<()> {
+        settings.rules = self.compile_rules()?.into();
+        if self.scope.extensions.is_some() {
+            settings.extensions = self.scope.extensions.clone();
+        }
+        globs.extend(self.scope.globs.iter().cloned());
+        settings.max_file_size = self.limits.max_file_size.or(settings.max_file_size);
+        settings.max_matches_per_file = self.limits.max_matches_per_file.or(settings.max_matches_per_file);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACK: &str = r#"
+format = 1
+name = "api-v2"
+version = "1.3.0"
+
+[scope]
+extensions = ["js"]
+globs = ["src/**"]
+
+[limits]
+max_matches_per_file = 50
+
+[[rules]]
+name = "endpoint"
+pattern = 'api/v1/'
+replace = 'api/v2/'
+
+[[rules]]
+name = "client"
+pattern = 'ClientV1'
+replace = 'ClientV2'
+"#;
+
+    #[test]
+    fn test_apply_pack() -> Result<()> {
+        let pack = RulePack::parse(PACK)?;
+        let mut settings = ProjectSettings {
+            extensions: Some(vec!["ts".to_string()]),
+            max_file_size: Some(1000),
+            ..Default::default()
+        };
+        let mut globs = vec!["lib/**".to_string()];
+        pack.apply(&mut settings, &mut globs)?;
+
+        assert_eq!(settings.rules.len(), 2);
+        assert_eq!(settings.rules[1].regex.as_str(), "ClientV1");
+        assert_eq!(settings.extensions.as_deref(), Some(&["js".to_string()][..]));
+        assert_eq!(settings.max_file_size, Some(1000));
+        assert_eq!(settings.max_matches_per_file, Some(50));
+        assert_eq!(globs, ["lib/**", "src/**"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_rules() -> Result<()> {
+        let pack = r#"
+format = 1
+name = "cleanup"
+version = "1"
+
+[[rules]]
+id = "a"
+pattern = 'a'
+replace = 'A'
+tags = ["style"]
+
+[[rules]]
+id = "b"
+pattern = 'b'
+replace = 'B'
+tags = ["style", "risky"]
+
+[[rules]]
+id = "c"
+pattern = 'c'
+replace = 'C'
+enabled = false
+"#;
+        let selected = |only: &[&str], skip: &[&str]| -> Result<Vec<String>> {
+            let mut pack = RulePack::parse(pack)?;
+            let strings = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+            pack.select(&strings(only), &strings(skip))?;
+            Ok(pack.rules.into_iter().map(|rule| rule.name).collect())
+        };
+
+        assert_eq!(selected(&[], &[])?, ["a", "b"]);
+        assert_eq!(selected(&["style"], &["risky"])?, ["a"]);
+        assert_eq!(selected(&["b", "c"], &[])?, ["b", "c"]);
+        assert_eq!(selected(&[], &["a"])?, ["b"]);
+        assert!(selected(&["typo"], &[]).unwrap_err().to_string().contains("no rule named or tagged typo"));
+        assert!(selected(&[], &["style"]).unwrap_err().to_string().contains("No rules"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_newer_format_and_duplicate_names() {
+        let newer = PACK.replace("format = 1", "format = 2");
+        let err = RulePack::parse(&newer).unwrap_err();
+        assert!(err.to_string().contains("upgrade regex-replace"));
+
+        let duplicate = PACK.replace("name = \"client\"", "name = \"endpoint\"");
+        let err = RulePack::parse(&duplicate).unwrap_err();
+        assert!(err.to_string().contains("more than once"));
+    }
+}
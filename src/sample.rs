@@ -0,0 +1,105 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Small, seedable pseudo-random generator (SplitMix64). Good enough for
+/// picking samples, and reproducible from the seed on any platform.
+#[derive(Debug, Clone)]
+pub struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// A seed taken from the clock, for when none was given.
+    pub fn random_seed() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..bound`; `bound` must not be 0.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+/// Keeps a uniformly random sample of at most `size` items from a stream of
+/// unknown length (reservoir sampling), remembering each item's position so
+/// the sample can be put back in stream order.
+#[derive(Debug)]
+pub struct Reservoir<T> {
+    size: usize,
+    seen: usize,
+    rng: SplitMix64,
+    items: Vec<(usize, T)>,
+}
+
+impl<T> Reservoir<T> {
+    pub fn new(size: usize, seed: u64) -> Self {
+        Self { size, seen: 0, rng: SplitMix64::new(seed), items: Vec::new() }
+    }
+
+    /// Offers the next item of the stream, which is kept or dropped.
+    pub fn offer(&mut self, item: T) {
+        let position = self.seen;
+        self.seen += 1;
+        if self.items.len() < self.size {
+            self.items.push((position, item));
+        } else {
+            let slot = self.rng.below(self.seen as u64) as usize;
+            if slot < self.size {
+                self.items[slot] = (position, item);
+            }
+        }
+    }
+
+    /// How many items were offered in total.
+    pub fn seen(&self) -> usize {
+        self.seen
+    }
+
+    /// The sampled items, in the order they were offered.
+    pub fn into_sorted(mut self) -> Vec<T> {
+        self.items.sort_by_key(|(position, _)| *position);
+        self.items.into_iter().map(|(_, item)| item).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(size: usize, seed: u64) -> Vec<u32> {
+        let mut reservoir = Reservoir::new(size, seed);
+        for item in 0..1000 {
+            reservoir.offer(item);
+        }
+        assert_eq!(reservoir.seen(), 1000);
+        reservoir.into_sorted()
+    }
+
+    #[test]
+    fn test_sample_is_seeded_sorted_and_sized() {
+        let first = sample(10, 42);
+        assert_eq!(first.len(), 10);
+        assert!(first.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(first, sample(10, 42));
+        assert_ne!(first, sample(10, 43));
+        // Not just the start of the stream
+        assert!(first.iter().any(|&item| item >= 10));
+    }
+
+    #[test]
+    fn test_short_stream_is_kept_whole() {
+        let mut reservoir = Reservoir::new(5, 1);
+        reservoir.offer("a");
+        reservoir.offer("b");
+        assert_eq!(reservoir.into_sorted(), ["a", "b"]);
+    }
+}
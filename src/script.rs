@@ -0,0 +1,119 @@
+use anyhow::{Context, Result};
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use std::fs;
+use std::path::Path;
+
+use crate::file_processor::{MatchDecision, MatchHook, MatchInfo};
+
+/// Operations a script may take on one match, so a runaway loop fails its
+/// file instead of hanging the run.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// A [Rhai](https://rhai.rs) script run on every match to compute its
+/// replacement, for changes a replacement template can't express, such as
+/// arithmetic on matched numbers.
+///
+/// The script sees the match as variables: `text`, `groups` (indexed by
+/// group number, 0 being the whole match, `()` for groups that didn't
+/// take part), `named` (a map of the named groups), `path`, `line`,
+/// `column` and `replacement`, the rule's own replacement. The value of its
+/// last expression replaces the match; `()` leaves the match as it is.
+pub struct ReplaceScript {
+    name: String,
+    engine: Engine,
+    ast: AST,
+}
+
+impl ReplaceScript {
+    /// Compiles the script in `source`, named `name` in errors.
+    pub fn compile(name: &str, source: &str) -> Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine.compile(source).map_err(|e| anyhow::anyhow!("Failed to compile replace script {name}: {e}"))?;
+        Ok(Self { name: name.to_string(), engine, ast })
+    }
+
+    /// Reads and compiles the script at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = fs::read_to_string(path).with_context(|| format!("Failed to read replace script: {}", path.display()))?;
+        Self::compile(&path.display().to_string(), &source)
+    }
+
+    /// The replacement the script gives `info`, or `None` to leave it.
+    pub fn run(&self, info: &MatchInfo) -> Result<Option<String>> {
+        let optional = |text: Option<&str>| text.map_or(Dynamic::UNIT, |text| text.to_string().into());
+        let groups: Array = info.groups.iter().map(|group| optional(*group)).collect();
+        let named: Map = info.captures.iter()
+            .map(|(name, value)| (name.as_str().into(), optional(value.as_deref())))
+            .collect();
+
+        let mut scope = Scope::new();
+        scope.push("text", info.text.to_string());
+        scope.push("groups", groups);
+        scope.push("named", named);
+        scope.push("path", info.path.display().to_string());
+        scope.push("line", info.line as i64);
+        scope.push("column", info.column as i64);
+        scope.push("replacement", info.replacement.to_string());
+
+        let value: Dynamic = self.engine.eval_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|e| anyhow::anyhow!("Replace script {} failed: {e}", self.name))?;
+        Ok(if value.is_unit() {
+            None
+        } else if value.is_string() {
+            Some(value.into_string().expect("checked to be a string"))
+        } else {
+            Some(value.to_string())
+        })
+    }
+
+    /// A hook replacing every match with what the script gives it, failing
+    /// the file if the script fails.
+    pub fn into_hook(self) -> MatchHook {
+        MatchHook::new(move |info| match self.run(info) {
+            Ok(Some(text)) => MatchDecision::Replace(text),
+            Ok(None) => MatchDecision::Veto,
+            Err(e) => MatchDecision::Fail(e.to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_processor::{process_path, FileStatus, ProcessOptions, Rule};
+    use crate::filesystem::MemoryFs;
+    use regex::Regex;
+    use std::io;
+
+    fn run_script(script: &str, pattern: &str, text: &str) -> (FileStatus, String) {
+        let fs = MemoryFs::new();
+        fs.add_file("/src/a.txt", text);
+        let options = ProcessOptions { on_match: Some(ReplaceScript::compile("test", script).unwrap().into_hook()), ..Default::default() };
+        let rule = Rule::new(Regex::new(pattern).unwrap(), "");
+        let outcome = process_path(&fs, Path::new("/src/a.txt"), &[rule], &options, &mut io::sink());
+        (outcome.status, fs.contents("/src/a.txt").unwrap())
+    }
+
+    #[test]
+    fn test_replace_script() {
+        let double = r#"if groups[1] == "skip" { () } else { groups[1].parse_int() * 2 }"#;
+        assert_eq!(run_script(double, r"v(\d+|skip)", "v1 v21 vskip").1, "2 42 vskip");
+
+        let named = r#"`${named.key.to_upper()}@${line}:${column} in ${path}`"#;
+        assert_eq!(run_script(named, r"(?<key>[a-z]+)=", "\n x=1").1, "\n X@2:2 in /src/a.txt1");
+
+        let (status, content) = run_script("groups[1].parse_int()", r"v(\w+)", "v1 vx");
+        assert!(matches!(status, FileStatus::Failed));
+        assert_eq!(content, "v1 vx");
+
+        let (status, _) = run_script("loop {}", "v", "v");
+        assert!(matches!(status, FileStatus::Failed));
+    }
+
+    #[test]
+    fn test_syntax_errors_are_reported_up_front() {
+        let err = ReplaceScript::compile("broken.rhai", "groups[1] +").err().unwrap();
+        assert!(err.to_string().contains("broken.rhai"), "{err}");
+    }
+}
@@ -0,0 +1,62 @@
+use anyhow::Result;
+use regex::Regex;
+use std::path::PathBuf;
+
+use crate::file_processor::LineIndex;
+use crate::filter::FileFilter;
+
+/// One match found by a search.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    pub path: PathBuf,
+    pub line: usize,
+    /// 1-based, in characters
+    pub column: usize,
+    pub text: String,
+}
+
+/// Every match of `regex` in the files `filter` selects, in path order, for
+/// seeing what a pattern catches before writing its replacement. Files that
+/// can't be read as UTF-8 are left out, as nothing is changed in them.
+pub fn search_tree(filter: &FileFilter, regex: &Regex) -> Result<Vec<Hit>> {
+    let fs = filter.fs();
+    let mut hits = Vec::new();
+    for entry in filter.walk()?.files {
+        let Ok(content) = fs.read_to_string(&entry.path) else {
+            continue;
+        };
+        let lines = LineIndex::new(&content);
+        hits.extend(regex.find_iter(&content).map(|m| Hit {
+            path: entry.path.clone(),
+            line: lines.line_of(m.start()),
+            column: lines.column_of(&content, m.start()),
+            text: m.as_str().to_string(),
+        }));
+    }
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+    use crate::project::ProjectSettings;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_search_tree() -> Result<()> {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/src/a.rs", "let x = foo();\nfoo_bar(foo);\n");
+        fs.add_file("/src/b.rs", "nothing\n");
+        fs.add_file("/src/c.bin", b"foo \xff".to_vec());
+        let filter = FileFilter::with_fs(fs.clone(), "/src", ProjectSettings::default(), &[], false)?;
+
+        let hits = search_tree(&filter, &Regex::new(r"foo\w*")?)?;
+        let found: Vec<(usize, usize, &str)> = hits.iter().map(|hit| (hit.line, hit.column, hit.text.as_str())).collect();
+        assert_eq!(found, [(1, 9, "foo"), (2, 1, "foo_bar"), (2, 9, "foo")]);
+        assert!(hits.iter().all(|hit| hit.path == Path::new("/src/a.rs")));
+        assert_eq!(fs.contents("/src/a.rs").as_deref(), Some("let x = foo();\nfoo_bar(foo);\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use crate::audit::{self, AuditFile, AuditRecord};
+use crate::file_processor::{apply_replacements, LineIndex, Replacement, Rule};
+use crate::filesystem::{FileSystem, Metadata, RealFs};
+use crate::filter::FileFilter;
+use crate::preview::content_hash;
+use crate::project::{ProjectSettings, RuleConfig, PROJECT_FILE};
+use crate::undo::{Journal, JournalFile};
+use crate::{gitignore, ignore};
+
+/// Names of the files whose contents decide which files a directory preview
+/// covers.
+const CONFIG_FILES: [&str; 4] = [ignore::IGNORE_FILE, PROJECT_FILE, gitignore::GITIGNORE_FILE, gitignore::IGNORE_FILE];
+
+/// Hex digits of a configuration version shown in responses and the log.
+const VERSION_LEN: usize = 12;
+
+/// One line of input: a request and the id its response carries back.
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    #[serde(default)]
+    id: Value,
+    #[serde(flatten)]
+    request: Request,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "lowercase")]
+enum Request {
+    /// The replacements a pattern makes in a buffer, or in every file of a
+    /// directory
+    Preview(Preview),
+    /// Makes some of the replacements a preview returned
+    Apply(Apply),
+    Shutdown,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Preview {
+    pattern: String,
+    replace: String,
+    #[serde(default)]
+    preserve_case: bool,
+    /// The buffer to preview in; without it, every file below `directory`
+    text: Option<String>,
+    #[serde(default = "current_dir")]
+    directory: PathBuf,
+    extensions: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Apply {
+    hunks: Vec<Hunk>,
+    /// The buffer to apply to, returned edited; without it, `path` is
+    /// rewritten
+    text: Option<String>,
+    path: Option<PathBuf>,
+    /// Where the undo journal of a rewritten file is kept, as for `--undo`
+    #[serde(default = "current_dir")]
+    directory: PathBuf,
+}
+
+fn current_dir() -> PathBuf {
+    PathBuf::from(".")
+}
+
+/// One replacement, by byte offsets into the text previewed, with where it
+/// starts for display.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hunk {
+    #[serde(default)]
+    pub line: usize,
+    /// 1-based, in characters
+    #[serde(default)]
+    pub column: usize,
+    pub start: usize,
+    pub end: usize,
+    pub old: String,
+    pub new: String,
+}
+
+/// Answers requests read from `input`, one JSON object per line, with one
+/// JSON line each on `output`, until a `shutdown` request or the end of
+/// the input. A request that fails gets an `error` response; the server
+/// carries on.
+///
+/// Each directory preview reads the ignore files and `.rr.toml` files of
+/// the tree afresh, so edits to them apply from the next request. The
+/// version of that configuration it used is in its response and logged to
+/// `log`, noting when it differs from the last preview of the directory.
+pub fn serve(input: impl BufRead, mut output: impl Write, mut log: impl Write) -> Result<()> {
+    let mut versions = HashMap::new();
+    for line in input.lines() {
+        let line = line.context("Failed to read request")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, result, shutdown) = match serde_json::from_str::<Envelope>(&line) {
+            Ok(Envelope { id, request: Request::Shutdown }) => (id, Ok(Value::Null), true),
+            Ok(Envelope { id, request }) => (id, handle(request, &mut versions, &mut log), false),
+            Err(e) => (Value::Null, Err(anyhow::anyhow!("Invalid request: {e}")), false),
+        };
+        let response = match result {
+            Ok(result) => json!({ "id": id, "result": result }),
+            Err(e) => json!({ "id": id, "error": format!("{e:#}") }),
+        };
+        writeln!(output, "{response}")?;
+        output.flush()?;
+        if shutdown {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Answers one request; `versions` holds the configuration version of
+/// each directory previewed so far.
+fn handle(request: Request, versions: &mut HashMap<PathBuf, String>, log: &mut impl Write) -> Result<Value> {
+    match request {
+        Request::Preview(preview) => {
+            let rule = RuleConfig {
+                pattern: preview.pattern,
+                replace: preview.replace,
+                extensions: None,
+                preserve_case: preview.preserve_case,
+            }
+            .compile()?;
+            if let Some(text) = preview.text {
+                let hunks = hunks(&rule, &text);
+                let edited = apply_replacements(&text, &replacements(&hunks));
+                return Ok(json!({ "hunks": hunks, "text": edited }));
+            }
+            let defaults = ProjectSettings { extensions: preview.extensions, ..Default::default() };
+            let recorder = Arc::new(ConfigRecorder::new(Arc::new(RealFs)));
+            let filter = FileFilter::with_fs(recorder.clone(), &preview.directory.display().to_string(), defaults, &[], false)?;
+            let mut files = Vec::new();
+            for entry in filter.walk()?.files {
+                let Ok(content) = recorder.read_to_string(&entry.path) else {
+                    continue;
+                };
+                let hunks = hunks(&rule, &content);
+                if !hunks.is_empty() {
+                    files.push(json!({ "path": entry.path, "hunks": hunks }));
+                }
+            }
+
+            let version = recorder.version();
+            let directory = preview.directory.display();
+            match versions.insert(preview.directory.clone(), version.clone()) {
+                Some(ref previous) if *previous != version => {
+                    writeln!(log, "serve: configuration of {directory} changed from {previous} to {version}; reloaded")?;
+                }
+                _ => {}
+            }
+            writeln!(log, "serve: preview of {directory} used configuration {version}")?;
+            Ok(json!({ "files": files, "config": version }))
+        }
+        Request::Apply(apply) => match (apply.text, apply.path) {
+            (Some(text), _) => Ok(json!({ "text": apply_hunks(&text, apply.hunks)? })),
+            (None, Some(path)) => {
+                let applied = apply.hunks.len();
+                let run_id = apply_to_file(&path, apply.hunks, &apply.directory, log)?;
+                Ok(json!({ "path": path, "applied": applied, "run_id": run_id }))
+            }
+            (None, None) => anyhow::bail!("apply needs the text of a buffer or the path of a file"),
+        },
+        Request::Shutdown => Ok(Value::Null),
+    }
+}
+
+/// Reads through to another filesystem, keeping the hash of every
+/// configuration file read, so a preview can tell which version of the
+/// configuration it used.
+struct ConfigRecorder {
+    inner: Arc<dyn FileSystem>,
+    read: Mutex<BTreeMap<PathBuf, String>>,
+}
+
+impl ConfigRecorder {
+    fn new(inner: Arc<dyn FileSystem>) -> Self {
+        Self { inner, read: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// A hash of the path and content of each configuration file read so
+    /// far, the same whenever the same files hold the same text.
+    fn version(&self) -> String {
+        let read = self.read.lock().unwrap();
+        let files: String = read.iter().map(|(path, hash)| format!("{}\0{hash}\n", path.display())).collect();
+        content_hash(files)[..VERSION_LEN].to_string()
+    }
+}
+
+impl FileSystem for ConfigRecorder {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let contents = self.inner.read(path)?;
+        if path.file_name().is_some_and(|name| CONFIG_FILES.iter().any(|config| name == *config)) {
+            let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+            self.read.lock().unwrap().insert(path, content_hash(&contents));
+        }
+        Ok(contents)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.inner.write(path, contents)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.inner.metadata(path)
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(dir)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.inner.canonicalize(path)
+    }
+}
+
+/// The replacements `rule` makes in `content`, as hunks.
+pub fn hunks(rule: &Rule, content: &str) -> Vec<Hunk> {
+    let lines = LineIndex::new(content);
+    rule.replacements(content)
+        .into_iter()
+        .map(|rep| Hunk {
+            line: lines.line_of(rep.start),
+            column: lines.column_of(content, rep.start),
+            start: rep.start,
+            end: rep.end,
+            old: content[rep.start..rep.end].to_string(),
+            new: rep.text,
+        })
+        .collect()
+}
+
+fn replacements(hunks: &[Hunk]) -> Vec<Replacement> {
+    hunks.iter().map(|hunk| Replacement { start: hunk.start, end: hunk.end, text: hunk.new.clone(), captures: Vec::new() }).collect()
+}
+
+/// `content` with `hunks` made, refusing any whose text has changed since
+/// the preview, so an edit made in between is never overwritten.
+pub fn apply_hunks(content: &str, mut hunks: Vec<Hunk>) -> Result<String> {
+    hunks.sort_by_key(|hunk| hunk.start);
+    let mut end = 0;
+    for hunk in &hunks {
+        if hunk.start < end {
+            anyhow::bail!("Hunks overlap at byte {}", hunk.start);
+        }
+        if content.get(hunk.start..hunk.end) != Some(hunk.old.as_str()) {
+            anyhow::bail!("Hunk at byte {} no longer matches \"{}\"; preview again", hunk.start, hunk.old);
+        }
+        end = hunk.end;
+    }
+    Ok(apply_replacements(content, &replacements(&hunks)))
+}
+
+/// Makes `hunks` in the file at `path` as a run of its own, journalled
+/// under `directory` so `--undo` reverses it and recorded in the audit
+/// log, and returns the run's ID.
+fn apply_to_file(path: &Path, hunks: Vec<Hunk>, directory: &Path, log: &mut impl Write) -> Result<String> {
+    let path = std::path::absolute(path)?;
+    let content = std::fs::read_to_string(&path).with_context(|| format!("Failed to read file: {}", path.display()))?;
+    let matches = hunks.len();
+    let edited = apply_hunks(&content, hunks).with_context(|| format!("Not applied to {}", path.display()))?;
+    std::fs::write(&path, &edited).with_context(|| format!("Failed to write file: {}", path.display()))?;
+
+    let run_id = Uuid::new_v4().to_string();
+    let journal = Journal {
+        run_id: run_id.clone(),
+        timestamp: audit::now(),
+        files: vec![JournalFile::new(&path, &content, &edited)],
+        renames: Vec::new(),
+    };
+    journal.save(&Journal::dir(directory))?;
+    let record = AuditRecord {
+        run_id: run_id.clone(),
+        timestamp: journal.timestamp,
+        user: audit::current_user(),
+        directory: std::path::absolute(directory)?,
+        command: std::env::args().collect(),
+        pattern: None,
+        replacement: None,
+        rule_pack: None,
+        files: vec![AuditFile { path: path.clone(), matches, backup: None }],
+    };
+    if let Err(e) = audit::log_path().and_then(|log_path| record.append_to(&log_path)) {
+        writeln!(log, "serve: failed to record run {run_id} in the audit log: {e:#}")?;
+    }
+    writeln!(log, "serve: applied {matches} hunk(s) to {} as run {run_id}", path.display())?;
+    Ok(run_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(requests: &str) -> Vec<Value> {
+        let mut output = Vec::new();
+        serve(requests.as_bytes(), &mut output, io::sink()).unwrap();
+        String::from_utf8(output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_preview_and_apply_buffer() {
+        let responses = exchange(concat!(
+            r#"{"id": 1, "method": "preview", "params": {"pattern": "(\\w+)_v1", "replace": "${1}_v2", "text": "a_v1\nb_v1 é c_v1\n"}}"#, "\n",
+            r#"{"id": 2, "method": "apply", "params": {"text": "a_v1\n", "hunks": [{"start": 0, "end": 4, "old": "a_v1", "new": "a_v2"}]}}"#, "\n",
+            r#"{"id": 3, "method": "apply", "params": {"text": "a_v0\n", "hunks": [{"start": 0, "end": 4, "old": "a_v1", "new": "a_v2"}]}}"#, "\n",
+            r#"{"id": 4, "method": "shutdown"}"#, "\n",
+            r#"{"id": 5, "method": "preview"}"#, "\n",
+        ));
+
+        assert_eq!(responses.len(), 4);
+        let preview = &responses[0]["result"];
+        assert_eq!(preview["text"], "a_v2\nb_v2 é c_v2\n");
+        assert_eq!(preview["hunks"][2], json!({"line": 2, "column": 8, "start": 13, "end": 17, "old": "c_v1", "new": "c_v2"}));
+        assert_eq!(responses[1], json!({"id": 2, "result": {"text": "a_v2\n"}}));
+        assert!(responses[2]["error"].as_str().unwrap().contains("no longer matches"));
+        assert_eq!(responses[3], json!({"id": 4, "result": null}));
+    }
+
+    /// Hands the server one request per read, running `before` just ahead
+    /// of request number `at`, so it happens while the server is running.
+    struct Scripted<F: FnMut()> {
+        requests: std::collections::VecDeque<String>,
+        at: usize,
+        sent: usize,
+        before: F,
+    }
+
+    impl<F: FnMut()> io::Read for Scripted<F> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(request) = self.requests.pop_front() else {
+                return Ok(0);
+            };
+            if self.sent == self.at {
+                (self.before)();
+            }
+            self.sent += 1;
+            buf[..request.len()].copy_from_slice(request.as_bytes());
+            Ok(request.len())
+        }
+    }
+
+    #[test]
+    fn test_preview_reloads_changed_config() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        std::fs::write(temp_dir.path().join("a.txt"), "old")?;
+        std::fs::write(temp_dir.path().join("b.txt"), "old")?;
+        std::fs::write(temp_dir.path().join(".rr_ignore"), "*.log\n")?;
+        let preview = format!(
+            "{}\n",
+            json!({"method": "preview", "params": {"pattern": "old", "replace": "new", "directory": temp_dir.path()}})
+        );
+        let input = Scripted {
+            requests: vec![preview.clone(); 3].into(),
+            at: 2,
+            sent: 0,
+            before: || std::fs::write(temp_dir.path().join(".rr_ignore"), "*.log\nb.txt\n").unwrap(),
+        };
+
+        let (mut output, mut log) = (Vec::new(), Vec::new());
+        serve(io::BufReader::new(input), &mut output, &mut log)?;
+        let responses: Vec<Value> = String::from_utf8(output)?.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let files = |n: usize| responses[n]["result"]["files"].as_array().unwrap().len();
+        let config = |n: usize| responses[n]["result"]["config"].as_str().unwrap().to_string();
+        assert_eq!((files(0), files(1), files(2)), (2, 2, 1));
+        assert_eq!(config(0), config(1));
+        assert_ne!(config(1), config(2));
+
+        let log = String::from_utf8(log)?;
+        assert_eq!(log.matches("used configuration").count(), 3, "{log}");
+        assert!(log.contains(&format!("changed from {} to {}; reloaded", config(1), config(2))), "{log}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let responses = exchange("not json\n{\"id\": 7, \"method\": \"preview\", \"params\": {\"pattern\": \"(\", \"replace\": \"\", \"text\": \"\"}}\n");
+        assert!(responses[0]["error"].as_str().unwrap().starts_with("Invalid request"));
+        assert_eq!(responses[1]["id"], 7);
+        assert!(responses[1]["error"].is_string());
+
+        let overlapping = vec![
+            Hunk { line: 1, column: 1, start: 0, end: 2, old: "ab".into(), new: "x".into() },
+            Hunk { line: 1, column: 2, start: 1, end: 2, old: "b".into(), new: "y".into() },
+        ];
+        assert!(apply_hunks("ab", overlapping).is_err());
+    }
+}
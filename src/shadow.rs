@@ -0,0 +1,100 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::Stdio;
+use uuid::Uuid;
+
+use crate::hooks;
+
+/// Runs `command`, another tool doing the same job, on `content`, the
+/// content of the file at `path`, and returns what it made of it. The
+/// command runs in a fresh sandbox directory holding a copy of the file, so
+/// it can't touch the real one.
+///
+/// A command containing `{}` edits the copy in place, as `sed -i -E '...' {}`
+/// does: `{}` is replaced with the copy's path and the result is the copy
+/// afterwards. Any other command is a filter: the content is piped to it and
+/// its standard output is the result. Either way `RR_FILE` holds the path
+/// of the real file.
+pub fn run(command: &str, path: &Path, content: &str) -> Result<String> {
+    let sandbox = std::env::temp_dir().join(format!("rr-shadow-{}", Uuid::new_v4()));
+    fs::create_dir_all(&sandbox)
+        .with_context(|| format!("Failed to create shadow sandbox: {}", sandbox.display()))?;
+    let result = run_in(&sandbox, command, path, content);
+    let _ = fs::remove_dir_all(&sandbox);
+    result
+}
+
+fn run_in(sandbox: &Path, command: &str, path: &Path, content: &str) -> Result<String> {
+    // Named after the file's extension only, so the path needs no more quoting than the sandbox's
+    let copy = match path.extension() {
+        Some(ext) => sandbox.join("input").with_extension(ext),
+        None => sandbox.join("input"),
+    };
+    let in_place = command.contains("{}");
+    if in_place {
+        fs::write(&copy, content).with_context(|| format!("Failed to write shadow copy: {}", copy.display()))?;
+    }
+
+    let mut child = hooks::shell(&command.replace("{}", &quoted(&copy)))
+        .current_dir(sandbox)
+        .env("RR_FILE", path)
+        .stdin(if in_place { Stdio::null() } else { Stdio::piped() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run shadow command: {command}"))?;
+    let output = std::thread::scope(|scope| {
+        if let Some(mut stdin) = child.stdin.take() {
+            // Written from another thread so a command producing output as it
+            // reads can't fill its stdout pipe and stall
+            scope.spawn(move || {
+                let _ = stdin.write_all(content.as_bytes());
+            });
+        }
+        child.wait_with_output()
+    })
+    .with_context(|| format!("Failed to run shadow command: {command}"))?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "Shadow command failed ({}): {command}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    if in_place {
+        fs::read_to_string(&copy).with_context(|| format!("Failed to read shadow copy: {}", copy.display()))
+    } else {
+        String::from_utf8(output.stdout).context("Shadow command output is not UTF-8")
+    }
+}
+
+#[cfg(unix)]
+fn quoted(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', r"'\''"))
+}
+
+#[cfg(windows)]
+fn quoted(path: &Path) -> String {
+    format!("\"{}\"", path.display())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_shadow_command() -> Result<()> {
+        let path = Path::new("/repo/notes.txt");
+        assert_eq!(run("sed -E 's/a+/b/g'", path, "aaa x a\n")?, "b x b\n");
+        assert_eq!(run("sed -i.orig -E 's/x/y/' {}", path, "x x\n")?, "y x\n");
+        assert_eq!(run("printf %s \"$RR_FILE\"", path, "")?, "/repo/notes.txt");
+
+        let err = run("echo broken >&2; exit 3", path, "x").unwrap_err();
+        assert!(err.to_string().contains("broken"), "{err}");
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use walkdir::WalkDir;
+
+use crate::file_processor::{self, RemainingMatch};
+use crate::filter;
+
+/// Matches `{{name}}`, allowing whitespace inside the braces.
+static PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_.-]*)\s*\}\}").unwrap());
+
+/// One change made (or, in a dry run, planned) while instantiating a template.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TemplateChange {
+    Updated(PathBuf),
+    Renamed(PathBuf, PathBuf),
+    /// A match of the `remaining` pattern left in a file; line 0 means its name
+    Remaining(PathBuf, RemainingMatch),
+}
+
+/// Reads a variables file: a flat TOML table of strings, numbers or booleans.
+pub fn load_vars(path: &Path) -> Result<HashMap<String, String>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read variables file: {}", path.display()))?;
+    let table: toml::Table = toml::from_str(&text)
+        .with_context(|| format!("Invalid variables file: {}", path.display()))?;
+
+    table
+        .into_iter()
+        .map(|(name, value)| {
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => value.to_string(),
+                _ => anyhow::bail!("Variable {name} in {} must be a string, number or boolean", path.display()),
+            };
+            Ok((name, value))
+        })
+        .collect()
+}
+
+/// Replaces every placeholder with a known value, leaving unknown ones as they are.
+pub fn substitute(text: &str, vars: &HashMap<String, String>) -> String {
+    PLACEHOLDER
+        .replace_all(text, |caps: &Captures| match vars.get(&caps[1]) {
+            Some(value) => value.clone(),
+            None => caps[0].to_string(),
+        })
+        .into_owned()
+}
+
+/// Copies the template tree `from` to `to`, which must not exist yet.
+pub fn copy_tree(from: &Path, to: &Path) -> Result<()> {
+    if to.exists() {
+        anyhow::bail!("Destination already exists: {}", to.display());
+    }
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let target = to.join(entry.path().strip_prefix(from)?);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            fs::copy(entry.path(), &target)
+                .with_context(|| format!("Failed to copy {}", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Substitutes placeholders in the contents and names of everything below
+/// `dir`. Binary files keep their contents but may still be renamed. Matches
+/// of `remaining` in the results are reported rather than treated as errors.
+pub fn instantiate(
+    dir: &Path,
+    vars: &HashMap<String, String>,
+    dry_run: bool,
+    remaining: Option<&Regex>,
+) -> Result<Vec<TemplateChange>> {
+    let mut changes = Vec::new();
+
+    // Children come before their directory, so renaming never moves a path still to be visited
+    let walker = WalkDir::new(dir)
+        .min_depth(1)
+        .contents_first(true)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| e.file_name() != ".git");
+
+    for entry in walker {
+        let entry = entry?;
+        let path = entry.path();
+
+        if entry.file_type().is_file() && !filter::is_binary(path)? {
+            let content = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let updated = substitute(&content, vars);
+            if let Some(regex) = remaining {
+                changes.extend(file_processor::find_remaining(&updated, regex)
+                    .into_iter()
+                    .map(|m| TemplateChange::Remaining(path.to_path_buf(), m)));
+            }
+            if updated != content {
+                if !dry_run {
+                    fs::write(path, updated)
+                        .with_context(|| format!("Failed to write {}", path.display()))?;
+                }
+                changes.push(TemplateChange::Updated(path.to_path_buf()));
+            }
+        }
+
+        let name = entry.file_name().to_string_lossy();
+        let renamed = substitute(&name, vars);
+        let target = path.with_file_name(&renamed);
+        if let Some(regex) = remaining {
+            changes.extend(regex.find_iter(&renamed).map(|m| {
+                TemplateChange::Remaining(target.clone(), RemainingMatch { line: 0, text: m.as_str().to_string() })
+            }));
+        }
+        if renamed != name {
+            if target.exists() {
+                anyhow::bail!("Cannot rename {}: {} already exists", path.display(), target.display());
+            }
+            if !dry_run {
+                fs::rename(path, &target)
+                    .with_context(|| format!("Failed to rename {}", path.display()))?;
+            }
+            changes.push(TemplateChange::Renamed(path.to_path_buf(), target));
+        }
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn vars() -> HashMap<String, String> {
+        HashMap::from([
+            ("name".to_string(), "demo".to_string()),
+            ("port".to_string(), "8080".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_substitute() {
+        assert_eq!(
+            substitute("{{name}} on {{ port }}, {{missing}}, {name}", &vars()),
+            "demo on 8080, {{missing}}, {name}"
+        );
+    }
+
+    #[test]
+    fn test_load_vars() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("vars.toml");
+        fs::write(&path, "name = \"demo\"\nport = 8080\ndebug = true\n")?;
+        let vars = load_vars(&path)?;
+        assert_eq!(vars["port"], "8080");
+        assert_eq!(vars["debug"], "true");
+
+        fs::write(&path, "list = [1, 2]\n")?;
+        assert!(load_vars(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_instantiate_contents_and_names() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let dir = temp_dir.path();
+        fs::create_dir_all(dir.join("{{name}}/src"))?;
+        fs::write(dir.join("{{name}}/src/{{name}}.rs"), "const PORT: u16 = {{port}};")?;
+        fs::write(dir.join("README.md"), "# {{name}}")?;
+
+        let changes = instantiate(dir, &vars(), false, None)?;
+        assert_eq!(changes.len(), 4);
+        assert_eq!(fs::read_to_string(dir.join("demo/src/demo.rs"))?, "const PORT: u16 = 8080;");
+        assert_eq!(fs::read_to_string(dir.join("README.md"))?, "# demo");
+        assert!(!dir.join("{{name}}").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_instantiate_reports_remaining() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let dir = temp_dir.path();
+        fs::write(dir.join("{{name}}-{{kind}}.txt"), "{{name}}\n{{owner}}")?;
+
+        let regex = Regex::new(r"\{\{\w+\}\}")?;
+        let changes = instantiate(dir, &vars(), true, Some(&regex))?;
+        let remaining: Vec<_> = changes.iter()
+            .filter_map(|change| match change {
+                TemplateChange::Remaining(path, m) => Some((path.file_name().unwrap().to_owned(), m.line, m.text.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(remaining, [
+            ("{{name}}-{{kind}}.txt".into(), 2, "{{owner}}"),
+            ("demo-{{kind}}.txt".into(), 0, "{{kind}}"),
+        ]);
+        Ok(())
+    }
+}
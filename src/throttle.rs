@@ -0,0 +1,78 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Rate limiter for file I/O, expressed in megabytes per second.
+///
+/// Every read or write reports its size via `consume`, which sleeps just long
+/// enough to keep the average transfer rate since creation under the limit.
+pub struct Throttle {
+    bytes_per_sec: f64,
+    state: Mutex<ThrottleState>,
+}
+
+struct ThrottleState {
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub fn new(megabytes_per_sec: f64) -> Self {
+        Self {
+            bytes_per_sec: megabytes_per_sec * 1024.0 * 1024.0,
+            state: Mutex::new(ThrottleState {
+                start: Instant::now(),
+                bytes: 0,
+            }),
+        }
+    }
+
+    pub fn consume(&self, bytes: u64) {
+        let delay = {
+            let mut state = self.state.lock().unwrap();
+            state.bytes += bytes;
+            let expected = Duration::from_secs_f64(state.bytes as f64 / self.bytes_per_sec);
+            expected.saturating_sub(state.start.elapsed())
+        };
+
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// Lowers the scheduling priority of the current process to niceness 10.
+#[cfg(unix)]
+pub fn lower_priority() -> std::io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 10) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn lower_priority() -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle_delays_when_over_budget() {
+        // 1 MB/s, so half a megabyte should take roughly half a second
+        let throttle = Throttle::new(1.0);
+        let start = Instant::now();
+        throttle.consume(512 * 1024);
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_throttle_no_delay_under_budget() {
+        let throttle = Throttle::new(1000.0);
+        let start = Instant::now();
+        throttle.consume(1024);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
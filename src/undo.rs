@@ -0,0 +1,328 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::filesystem::FileSystem;
+use crate::preview::{content_hash, hunks};
+use crate::rename::Rename;
+
+/// Directory, inside the search directory, holding one journal per run.
+pub const UNDO_DIR: &str = ".rr_undo";
+
+/// A span of a file's content after the run, and the text it replaced.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEdit {
+    /// Byte offsets into the content the run wrote
+    pub start: usize,
+    pub end: usize,
+    pub original: String,
+}
+
+/// Everything needed to restore one file a run modified.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalFile {
+    pub path: PathBuf,
+    /// SHA-256 of the content the run wrote, to detect later edits
+    pub hash: String,
+    pub edits: Vec<JournalEdit>,
+    /// The whole original content of a file that wasn't valid UTF-8, which
+    /// is put back in place of `edits`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original: Option<Vec<u8>>,
+}
+
+/// The modifications of one run, saved so `--undo` can reverse them.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Journal {
+    pub run_id: String,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub files: Vec<JournalFile>,
+    /// Paths the run renamed once their contents were done, in the order
+    /// made; `files` has the paths from before
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub renames: Vec<Rename>,
+}
+
+/// How restoring one journalled file went.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Undone {
+    Restored(PathBuf),
+    /// Left untouched because the file changed after the run
+    Changed(PathBuf),
+    /// Not there to restore, having been moved or removed after the run
+    Missing(PathBuf),
+    RenamedBack(Rename),
+    /// Not renamed back because the new path is gone or the old one is
+    /// taken again
+    NotRenamedBack(Rename),
+}
+
+impl JournalFile {
+    /// The journal entry for a run that rewrote `path` from `old` to `new`.
+    pub fn new(path: &Path, old: &str, new: &str) -> Self {
+        let mut starts = vec![0];
+        starts.extend(new.split_inclusive('\n').scan(0, |end, line| {
+            *end += line.len();
+            Some(*end)
+        }));
+        let edits = hunks(new, old)
+            .into_iter()
+            .map(|hunk| JournalEdit {
+                start: starts[hunk.start],
+                end: starts[hunk.start + hunk.old_lines],
+                original: hunk.new_text,
+            })
+            .collect();
+        Self { path: path.to_path_buf(), hash: content_hash(new), edits, original: None }
+    }
+
+    /// The journal entry for a run that rewrote `path`, a file that wasn't
+    /// valid UTF-8, from the bytes `old` to `new`.
+    pub fn with_bytes(path: &Path, old: &[u8], new: &[u8]) -> Self {
+        Self { path: path.to_path_buf(), hash: content_hash(new), edits: Vec::new(), original: Some(old.to_vec()) }
+    }
+
+    /// Puts the original text back into `content`, the content the run wrote.
+    pub fn restore(&self, content: &str) -> String {
+        let mut result = String::with_capacity(content.len());
+        let mut last = 0;
+        for edit in &self.edits {
+            result.push_str(&content[last..edit.start]);
+            result.push_str(&edit.original);
+            last = edit.end;
+        }
+        result.push_str(&content[last..]);
+        result
+    }
+}
+
+impl Journal {
+    /// The journal directory for runs over `directory`.
+    pub fn dir(directory: &Path) -> PathBuf {
+        directory.join(UNDO_DIR)
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<PathBuf> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create undo directory: {}", dir.display()))?;
+        let path = dir.join(format!("{}.json", self.run_id));
+        fs::write(&path, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write undo journal: {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// The journal file of run `id` (or an unambiguous prefix of it) in
+    /// `dir`, or of the most recent run if no ID is given.
+    pub fn find(dir: &Path, id: Option<&str>) -> Result<PathBuf> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => anyhow::bail!("No runs to undo in {}", dir.display()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read undo directory: {}", dir.display())),
+        };
+        let mut journals = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                journals.push(path);
+            }
+        }
+
+        match id {
+            None => {
+                let modified = |path: &PathBuf| fs::metadata(path).and_then(|m| m.modified()).ok();
+                journals.into_iter()
+                    .max_by_key(|path| (modified(path), path.clone()))
+                    .with_context(|| format!("No runs to undo in {}", dir.display()))
+            }
+            Some(id) => {
+                let mut matching = journals.into_iter().filter(|path| {
+                    path.file_stem().is_some_and(|stem| stem.to_string_lossy().starts_with(id))
+                });
+                match (matching.next(), matching.next()) {
+                    (Some(path), None) => Ok(path),
+                    (Some(_), Some(_)) => anyhow::bail!("Run ID prefix {id} is ambiguous"),
+                    (None, _) => anyhow::bail!("No run {id} to undo in {}", dir.display()),
+                }
+            }
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read undo journal: {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("Invalid undo journal: {}", path.display()))
+    }
+
+    /// Reverses the run's renames, latest first, then restores every
+    /// journalled file that still has the content the run wrote.
+    pub fn undo(&self, fs: &dyn FileSystem) -> Result<Vec<Undone>> {
+        let mut undone = Vec::new();
+        for rename in self.renames.iter().rev() {
+            if !fs.exists(&rename.to) || fs.exists(&rename.from) {
+                undone.push(Undone::NotRenamedBack(rename.clone()));
+                continue;
+            }
+            fs.rename(&rename.to, &rename.from)
+                .with_context(|| format!("Failed to rename {} back to {}", rename.to.display(), rename.from.display()))?;
+            undone.push(Undone::RenamedBack(rename.clone()));
+        }
+        for file in &self.files {
+            if !fs.exists(&file.path) {
+                undone.push(Undone::Missing(file.path.clone()));
+                continue;
+            }
+            let content = fs.read(&file.path)
+                .with_context(|| format!("Failed to read file: {}", file.path.display()))?;
+            if content_hash(&content) != file.hash {
+                undone.push(Undone::Changed(file.path.clone()));
+                continue;
+            }
+            let restored = match file.original {
+                Some(ref original) => original.clone(),
+                None => file.restore(&String::from_utf8(content)?).into_bytes(),
+            };
+            fs.write(&file.path, &restored)
+                .with_context(|| format!("Failed to write file: {}", file.path.display()))?;
+            undone.push(Undone::Restored(file.path.clone()));
+        }
+        Ok(undone)
+    }
+
+    /// The part of the journal that `undone`, the outcome of
+    /// [`Journal::undo`], didn't manage, to try again once those paths are
+    /// put right.
+    pub fn left_over(&self, undone: &[Undone]) -> Journal {
+        let files = self.files
+            .iter()
+            .filter(|file| undone.iter().any(|u| matches!(u, Undone::Changed(p) | Undone::Missing(p) if *p == file.path)))
+            .cloned()
+            .collect();
+        let renames = self.renames
+            .iter()
+            .filter(|rename| undone.iter().any(|u| matches!(u, Undone::NotRenamedBack(r) if r == *rename)))
+            .cloned()
+            .collect();
+        Journal { run_id: self.run_id.clone(), timestamp: self.timestamp, files, renames }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.renames.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_journal_restores_original() {
+        let cases = [
+            ("a\nb\nc\n", "a\nB\nc\n"),
+            ("a\nb", "a\nb\nc"),
+            ("one\ntwo\nthree\n", "zero\none\nthree\n"),
+            ("x\r\ny\r\n", ""),
+            ("", "new\n"),
+        ];
+        for (old, new) in cases {
+            let file = JournalFile::new(Path::new("f"), old, new);
+            assert_eq!(file.restore(new), old, "{old:?} -> {new:?}");
+        }
+    }
+
+    #[test]
+    fn test_undo_skips_files_changed_since() -> Result<()> {
+        let fs = MemoryFs::new();
+        fs.add_file("/a.txt", "new\nkeep\n");
+        fs.add_file("/b.txt", "edited since\n");
+        let journal = Journal {
+            run_id: "run".to_string(),
+            timestamp: 0,
+            files: vec![
+                JournalFile::new(Path::new("/a.txt"), "old\nkeep\n", "new\nkeep\n"),
+                JournalFile::new(Path::new("/b.txt"), "old\n", "new\n"),
+            ],
+            ..Default::default()
+        };
+
+        let undone = journal.undo(&fs)?;
+        assert_eq!(undone, [Undone::Restored("/a.txt".into()), Undone::Changed("/b.txt".into())]);
+        assert_eq!(fs.contents("/a.txt").as_deref(), Some("old\nkeep\n"));
+        assert_eq!(fs.contents("/b.txt").as_deref(), Some("edited since\n"));
+
+        // What was left can be undone once the file is put back
+        let left_over = journal.left_over(&undone);
+        assert_eq!(left_over.files, journal.files[1..]);
+        fs.add_file("/b.txt", "new\n");
+        assert_eq!(left_over.undo(&fs)?, [Undone::Restored("/b.txt".into())]);
+        assert!(left_over.left_over(&[Undone::Restored("/b.txt".into())]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_restores_invalid_utf8() -> Result<()> {
+        let fs = MemoryFs::new();
+        fs.add_file("/a.txt", b"caf\xe9 v2\n".to_vec());
+        let journal = Journal {
+            run_id: "run".to_string(),
+            timestamp: 0,
+            files: vec![JournalFile::with_bytes(Path::new("/a.txt"), b"caf\xe9 v1\n", b"caf\xe9 v2\n")],
+            ..Default::default()
+        };
+
+        assert_eq!(journal.undo(&fs)?, [Undone::Restored("/a.txt".into())]);
+        assert_eq!(fs.read(Path::new("/a.txt"))?, b"caf\xe9 v1\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_reverses_renames() -> Result<()> {
+        let fs = MemoryFs::new();
+        fs.add_file("/new/new_a.txt", "new\n");
+        fs.add_file("/old_b.txt", "in the way\n");
+        let rename = |from: &str, to: &str| Rename { from: from.into(), to: to.into() };
+        let journal = Journal {
+            files: vec![
+                JournalFile::new(Path::new("/old/old_a.txt"), "old\n", "new\n"),
+                JournalFile::new(Path::new("/gone.txt"), "old\n", "new\n"),
+            ],
+            renames: vec![
+                rename("/old/old_a.txt", "/old/new_a.txt"),
+                rename("/old_b.txt", "/new_b.txt"),
+                rename("/old", "/new"),
+            ],
+            ..Default::default()
+        };
+
+        let undone = journal.undo(&fs)?;
+        assert_eq!(undone, [
+            Undone::RenamedBack(rename("/old", "/new")),
+            Undone::NotRenamedBack(rename("/old_b.txt", "/new_b.txt")),
+            Undone::RenamedBack(rename("/old/old_a.txt", "/old/new_a.txt")),
+            Undone::Restored("/old/old_a.txt".into()),
+            Undone::Missing("/gone.txt".into()),
+        ]);
+        assert_eq!(fs.contents("/old/old_a.txt").as_deref(), Some("old\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_journal() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let dir = Journal::dir(temp_dir.path());
+        assert!(Journal::find(&dir, None).is_err());
+
+        let first = Journal { run_id: "abc-1".to_string(), ..Default::default() }.save(&dir)?;
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let second = Journal { run_id: "abd-2".to_string(), ..Default::default() }.save(&dir)?;
+
+        assert_eq!(Journal::find(&dir, None)?, second);
+        assert_eq!(Journal::find(&dir, Some("abc"))?, first);
+        assert!(Journal::find(&dir, Some("ab")).unwrap_err().to_string().contains("ambiguous"));
+        assert_eq!(Journal::load(&first)?.run_id, "abc-1");
+        Ok(())
+    }
+}
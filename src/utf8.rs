@@ -0,0 +1,104 @@
+use anyhow::Result;
+use std::borrow::Cow;
+
+/// The first of the 256 private-use characters that stand in for the bytes
+/// of invalid sequences when a file is matched as bytes: byte `b` becomes
+/// the character `ESCAPE_BASE + b`.
+const ESCAPE_BASE: u32 = 0x10FE00;
+
+/// What to do with a file that isn't valid UTF-8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+    /// Fail the file
+    #[default]
+    Fail,
+    /// Replace each invalid sequence with U+FFFD, which is what is written back
+    Lossy,
+    /// Match the file's bytes, writing invalid sequences back as they were
+    Bytes,
+}
+
+impl InvalidUtf8 {
+    /// The text to match in `bytes`, which aren't valid UTF-8.
+    pub fn decode(self, bytes: &[u8]) -> Result<String> {
+        match self {
+            Self::Fail => anyhow::bail!("File is not valid UTF-8"),
+            Self::Lossy => Ok(String::from_utf8_lossy(bytes).into_owned()),
+            Self::Bytes => escape(bytes),
+        }
+    }
+
+    /// The bytes to write for `text`, decoded by [`InvalidUtf8::decode`].
+    pub fn encode(self, text: &str) -> Cow<'_, [u8]> {
+        match self {
+            Self::Bytes => Cow::Owned(unescape(text)),
+            _ => Cow::Borrowed(text.as_bytes()),
+        }
+    }
+
+    /// What the decoding did, for warnings.
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Fail => "failed",
+            Self::Lossy => "invalid sequences were replaced with U+FFFD",
+            Self::Bytes => "matched byte for byte",
+        }
+    }
+}
+
+fn is_escape(c: char) -> bool {
+    (ESCAPE_BASE..=ESCAPE_BASE + 0xFF).contains(&u32::from(c))
+}
+
+/// `bytes` as text, with each byte of an invalid sequence replaced by its
+/// stand-in character.
+fn escape(bytes: &[u8]) -> Result<String> {
+    let mut text = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        if chunk.valid().chars().any(is_escape) {
+            anyhow::bail!("File already holds the characters U+10FE00 to U+10FEFF, so can't be matched as bytes");
+        }
+        text.push_str(chunk.valid());
+        text.extend(chunk.invalid().iter().map(|&byte| char::from_u32(ESCAPE_BASE + u32::from(byte)).expect("a private-use character")));
+    }
+    Ok(text)
+}
+
+/// The bytes `text` was escaped from, with any stand-in characters
+/// replacements brought in turned back into bytes too.
+fn unescape(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        if is_escape(c) {
+            bytes.push((u32::from(c) - ESCAPE_BASE) as u8);
+        } else {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes_round_trip() -> Result<()> {
+        let bytes = b"caf\xe9 \xff\xfe ok \xe2\x82";
+        let text = InvalidUtf8::Bytes.decode(bytes)?;
+        assert!(text.starts_with("caf") && text.contains(" ok "));
+        assert_eq!(InvalidUtf8::Bytes.encode(&text), &bytes[..]);
+        assert_eq!(InvalidUtf8::Bytes.encode(&text.replace("ok", "fine")), &b"caf\xe9 \xff\xfe fine \xe2\x82"[..]);
+
+        assert!(InvalidUtf8::Bytes.decode("\u{10FE41}\u{ff}".as_bytes()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lossy() -> Result<()> {
+        let text = InvalidUtf8::Lossy.decode(b"a\xffb")?;
+        assert_eq!(text, "a\u{FFFD}b");
+        assert_eq!(InvalidUtf8::Lossy.encode(&text), "a\u{FFFD}b".as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,14 @@
+use std::process::Command;
+use tempfile::TempDir;
+
+/// `cargo run --`, keeping the audit log and caches in `isolated` rather
+/// than the user's own state and cache directories, where they would
+/// outlast the test and could make another test's run look like a repeat.
+pub fn cargo_run(isolated: &TempDir) -> Command {
+    let mut command = Command::new("cargo");
+    command
+        .args(["run", "--"])
+        .env("RR_STATE_DIR", isolated.path().join("state"))
+        .env("RR_CACHE_DIR", isolated.path().join("cache"));
+    command
+}
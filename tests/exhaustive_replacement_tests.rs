@@ -1,11 +1,14 @@
 use std::fs;
-use std::process::Command;
 use tempfile::TempDir;
 use std::path::Path;
 
+mod common;
+use common::cargo_run;
+
 fn run_replacement(dir: &Path, pattern: &str, replacement: &str, extra_args: &[&str]) -> std::process::Output {
-    let mut cmd = Command::new("cargo");
-    cmd.args(&["run", "--", "-p", pattern, "-r", replacement, "-d"])
+    let isolated = TempDir::new().unwrap();
+    let mut cmd = cargo_run(&isolated);
+    cmd.args(["-p", pattern, "-r", replacement, "-d"])
        .arg(dir);
     
     for arg in extra_args {
@@ -414,7 +417,7 @@ fn test_incremental_replacements() {
     fs::write(temp_dir.path().join("increment.txt"), "111").unwrap();
     
     // Chain of replacements
-    for (from, to) in vec![("111", "222"), ("222", "333"), ("333", "444"), ("444", "555")] {
+    for (from, to) in [("111", "222"), ("222", "333"), ("333", "444"), ("444", "555")] {
         let output = run_replacement(temp_dir.path(), from, to, &[]);
         assert!(output.status.success());
     }
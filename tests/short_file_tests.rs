@@ -1,9 +1,12 @@
 use std::fs;
-use std::process::Command;
 use tempfile::TempDir;
 
+mod common;
+use common::cargo_run;
+
 #[test]
 fn test_single_character_replacement() {
+    let isolated = TempDir::new().unwrap();
     let temp_dir = TempDir::new().unwrap();
     
     // Single character files
@@ -12,8 +15,8 @@ fn test_single_character_replacement() {
     fs::write(temp_dir.path().join("x.txt"), "X").unwrap();
     
     // Replace digit
-    let output = Command::new("cargo")
-        .args(&["run", "--", "-p", "1", "-r", "9", "-d"])
+    let output = cargo_run(&isolated)
+        .args(["-p", "1", "-r", "9", "-d"])
         .arg(temp_dir.path())
         .output()
         .expect("Failed to execute command");
@@ -26,12 +29,13 @@ fn test_single_character_replacement() {
 
 #[test]
 fn test_two_character_file() {
+    let isolated = TempDir::new().unwrap();
     let temp_dir = TempDir::new().unwrap();
     
     fs::write(temp_dir.path().join("ab.txt"), "ab").unwrap();
     
-    let output = Command::new("cargo")
-        .args(&["run", "--", "-p", "ab", "-r", "XY", "-d"])
+    let output = cargo_run(&isolated)
+        .args(["-p", "ab", "-r", "XY", "-d"])
         .arg(temp_dir.path())
         .output()
         .expect("Failed to execute command");
@@ -42,12 +46,13 @@ fn test_two_character_file() {
 
 #[test]
 fn test_three_character_file() {
+    let isolated = TempDir::new().unwrap();
     let temp_dir = TempDir::new().unwrap();
     
     fs::write(temp_dir.path().join("num.txt"), "111").unwrap();
     
-    let output = Command::new("cargo")
-        .args(&["run", "--", "-p", "111", "-r", "222", "-d"])
+    let output = cargo_run(&isolated)
+        .args(["-p", "111", "-r", "222", "-d"])
         .arg(temp_dir.path())
         .output()
         .expect("Failed to execute command");
@@ -58,12 +63,13 @@ fn test_three_character_file() {
 
 #[test]
 fn test_newline_only_file() {
+    let isolated = TempDir::new().unwrap();
     let temp_dir = TempDir::new().unwrap();
     
     fs::write(temp_dir.path().join("newline.txt"), "\n").unwrap();
     
-    let output = Command::new("cargo")
-        .args(&["run", "--", "-p", r"\n", "-r", "NEWLINE", "-d"])
+    let output = cargo_run(&isolated)
+        .args(["-p", r"\n", "-r", "NEWLINE", "-d"])
         .arg(temp_dir.path())
         .output()
         .expect("Failed to execute command");
@@ -74,12 +80,13 @@ fn test_newline_only_file() {
 
 #[test]
 fn test_space_only_file() {
+    let isolated = TempDir::new().unwrap();
     let temp_dir = TempDir::new().unwrap();
     
     fs::write(temp_dir.path().join("space.txt"), " ").unwrap();
     
-    let output = Command::new("cargo")
-        .args(&["run", "--", "-p", " ", "-r", "_", "-d"])
+    let output = cargo_run(&isolated)
+        .args(["-p", " ", "-r", "_", "-d"])
         .arg(temp_dir.path())
         .output()
         .expect("Failed to execute command");
@@ -90,6 +97,7 @@ fn test_space_only_file() {
 
 #[test]
 fn test_very_short_content_with_regex() {
+    let isolated = TempDir::new().unwrap();
     let temp_dir = TempDir::new().unwrap();
     
     // Test regex on very short content
@@ -98,8 +106,8 @@ fn test_very_short_content_with_regex() {
     fs::write(temp_dir.path().join("letter.txt"), "z").unwrap();
     
     // Replace any character with X
-    let output = Command::new("cargo")
-        .args(&["run", "--", "-p", ".", "-r", "X", "-d"])
+    let output = cargo_run(&isolated)
+        .args(["-p", ".", "-r", "X", "-d"])
         .arg(temp_dir.path())
         .output()
         .expect("Failed to execute command");
@@ -112,6 +120,7 @@ fn test_very_short_content_with_regex() {
 
 #[test]
 fn test_multiple_short_files_batch() {
+    let isolated = TempDir::new().unwrap();
     let temp_dir = TempDir::new().unwrap();
     
     // Create 10 files with just "1"
@@ -119,8 +128,8 @@ fn test_multiple_short_files_batch() {
         fs::write(temp_dir.path().join(format!("f{}.txt", i)), "1").unwrap();
     }
     
-    let output = Command::new("cargo")
-        .args(&["run", "--", "-p", "1", "-r", "X", "-d"])
+    let output = cargo_run(&isolated)
+        .args(["-p", "1", "-r", "X", "-d"])
         .arg(temp_dir.path())
         .output()
         .expect("Failed to execute command");
@@ -142,13 +151,14 @@ fn test_multiple_short_files_batch() {
 
 #[test]
 fn test_empty_file_handling() {
+    let isolated = TempDir::new().unwrap();
     let temp_dir = TempDir::new().unwrap();
     
     // Create empty file
     fs::write(temp_dir.path().join("empty.txt"), "").unwrap();
     
-    let output = Command::new("cargo")
-        .args(&["run", "--", "-p", "anything", "-r", "something", "-d"])
+    let output = cargo_run(&isolated)
+        .args(["-p", "anything", "-r", "something", "-d"])
         .arg(temp_dir.path())
         .output()
         .expect("Failed to execute command");
@@ -162,13 +172,14 @@ fn test_empty_file_handling() {
 
 #[test]
 fn test_single_line_no_newline() {
+    let isolated = TempDir::new().unwrap();
     let temp_dir = TempDir::new().unwrap();
     
     // File with no trailing newline
     fs::write(temp_dir.path().join("no_newline.txt"), "hello").unwrap();
     
-    let output = Command::new("cargo")
-        .args(&["run", "--", "-p", "hello", "-r", "goodbye", "-d"])
+    let output = cargo_run(&isolated)
+        .args(["-p", "hello", "-r", "goodbye", "-d"])
         .arg(temp_dir.path())
         .output()
         .expect("Failed to execute command");